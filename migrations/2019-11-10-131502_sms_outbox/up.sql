@@ -0,0 +1,10 @@
+CREATE TABLE sms_outbox (
+	id SERIAL PRIMARY KEY,
+	phone_number VARCHAR NOT NULL,
+	text VARCHAR NOT NULL,
+	state INT NOT NULL DEFAULT 0,
+	attempts INT NOT NULL DEFAULT 0,
+	next_attempt TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT now(),
+	last_error VARCHAR,
+	ts TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,4 @@
+ALTER TABLE sms_outbox DROP COLUMN modem;
+ALTER TABLE recipients DROP COLUMN last_modem;
+ALTER TABLE recipients DROP COLUMN modem;
+ALTER TABLE messages DROP COLUMN modem;
@@ -0,0 +1,4 @@
+ALTER TABLE messages ADD COLUMN modem VARCHAR;
+ALTER TABLE recipients ADD COLUMN modem VARCHAR;
+ALTER TABLE recipients ADD COLUMN last_modem VARCHAR;
+ALTER TABLE sms_outbox ADD COLUMN modem VARCHAR;
@@ -0,0 +1 @@
+ALTER TABLE recipients ADD COLUMN transliterate BOOLEAN NOT NULL DEFAULT false;
@@ -0,0 +1,8 @@
+CREATE TABLE sms_usage (
+	id SERIAL PRIMARY KEY,
+	phone_number VARCHAR NOT NULL,
+	modem VARCHAR NOT NULL,
+	segments INT NOT NULL,
+	ts TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
+CREATE INDEX sms_usage_ts ON sms_usage (ts);
@@ -0,0 +1,12 @@
+ALTER TABLE recipients ADD COLUMN replyable BOOLEAN NOT NULL DEFAULT true;
+-- This must match util::describe_unreplyable (which sets the column for new
+-- recipients); change both together.
+--
+-- Addresses are stored as the type-of-address octet in hex, followed by one
+-- octet (two hex characters) per digit. Type-of-number 101 (D0 or 50) is
+-- alphanumeric; anything that isn't international (91 or 11) and has six
+-- digits or fewer is a short code.
+UPDATE recipients SET replyable = false
+	WHERE whatsapp = false
+	AND (substr(phone_number, 1, 1) IN ('D', '5')
+	     OR (substr(phone_number, 1, 1) NOT IN ('9', '1') AND length(phone_number) <= 2 + 2 * 6));
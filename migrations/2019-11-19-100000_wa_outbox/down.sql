@@ -0,0 +1 @@
+DROP TABLE wa_outbox;
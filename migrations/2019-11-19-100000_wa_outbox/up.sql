@@ -0,0 +1,7 @@
+CREATE TABLE wa_outbox (
+	id SERIAL PRIMARY KEY,
+	jid VARCHAR NOT NULL,
+	text VARCHAR NOT NULL,
+	mid VARCHAR NOT NULL UNIQUE,
+	ts TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT now()
+);
@@ -0,0 +1,2 @@
+ALTER TABLE messages DROP COLUMN wa_msgid;
+ALTER TABLE wa_outbox DROP COLUMN reply_to;
@@ -0,0 +1,2 @@
+ALTER TABLE messages ADD COLUMN wa_msgid VARCHAR;
+ALTER TABLE wa_outbox ADD COLUMN reply_to VARCHAR;
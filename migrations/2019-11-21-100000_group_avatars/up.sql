@@ -0,0 +1 @@
+ALTER TABLE groups ADD COLUMN avatar_url VARCHAR;
@@ -16,6 +16,10 @@ macro_rules! extract_command {
 pub enum GhostCommand {
     ChangeNick(String),
     SetWhatsapp(bool),
+    SetModem(Option<String>),
+    SetTransliterate(bool),
+    SetReplyable(bool),
+    Avatar,
     PresenceSubscribe,
     Remove
 }
@@ -27,6 +31,17 @@ The following commands are available:
     Change the ghost's nickname to \x1dnew_nick\x0f.
 \x02WHATSAPP\x0f \x1dtrue|false\x0f
     Enable or disable WhatsApp mode for this recipient.
+\x02MODEM\x0f \x1d[name]\x0f
+    Send SMS messages to this recipient through the modem called \x1dname\x0f.
+    Not specifying a \x1dname\x0f goes back to using the modem they last messaged you on.
+\x02TRANSLITERATE\x0f \x1dtrue|false\x0f
+    Replace characters that don't fit in the GSM 7-bit alphabet (like emoji and curly quotes) in SMS messages
+    to this recipient, so they don't have to be sent as UCS-2 (which fits fewer characters in each SMS).
+\x02REPLYABLE\x0f \x1dtrue|false\x0f
+    Set whether this recipient can be sent messages. Ghosts for alphanumeric senders (like banks) and short codes
+    aren't replyable to begin with, so replying to them is refused instead of failing at the modem.
+\x02AVATAR\x0f
+    Show the URL of this recipient's avatar (their WhatsApp profile picture), and refresh it.
 \x02REMOVE\x0f \x0307(aliases \x02KILL\x02, \x02DIE\x02)\x0f
     Remove this recipient, causing them to disconnect.
 \x02PRESUB\x0f
@@ -48,6 +63,31 @@ The following commands are available:
                     None
                 }
             },
+            ("modem", &[name]) => {
+                Some(GhostCommand::SetModem(Some(name.to_owned())))
+            },
+            ("modem", &[]) => {
+                Some(GhostCommand::SetModem(None))
+            },
+            ("transliterate", &[value]) => {
+                if let Ok(b) = value.parse() {
+                    Some(GhostCommand::SetTransliterate(b))
+                }
+                else {
+                    None
+                }
+            },
+            ("replyable", &[value]) => {
+                if let Ok(b) = value.parse() {
+                    Some(GhostCommand::SetReplyable(b))
+                }
+                else {
+                    None
+                }
+            },
+            ("avatar", _) => {
+                Some(GhostCommand::Avatar)
+            },
             ("presub", _) => {
                 Some(GhostCommand::PresenceSubscribe)
             },
@@ -61,21 +101,49 @@ The following commands are available:
 pub enum ModemCommand {
     GetCsq,
     GetReg,
+    PrintReceipts,
+    ListPartials,
     Reinit,
-    TempPath(Option<String>)
+    TempPath(Option<String>),
+    List,
+    Status,
+    Ussd(Option<String>),
+    PhonebookImport,
+    Usage
 }
 impl ModemCommand {
     pub fn help() -> &'static str {
         "\x02*** MODEM subcommand ***\x0f
+If you have more than one modem, put the modem's name before the subcommand to only ask that modem
+(e.g. \x11MODEM work SIGNAL\x11); otherwise, all modems will respond.
 The following commands are available:
+\x02LIST\x0f
+    List the configured modems (and other SMS backends, like HTTP gateways).
+\x02STATUS\x0f
+    Show what state each modem is in.
 \x02SIGNAL\x0f \x0307(alias \x02CSQ\x02)\x0f
     Get the current signal strength, as reported by the modem.
 \x02REGISTRATION\x0f \x0307(alias \x02REG\x02)\x0f
     Get the modem's registration state.
+\x02RECEIPTS\x0f \x0307(alias \x02ACKS\x02)\x0f
+    Print delivery reports for recently sent messages.
+    \x1fTip: Set the \x11delivery_reports\x11 option in the configuration to request delivery reports.\x1f
+\x02PARTIALS\x0f
+    List concatenated messages that are still waiting for some of their parts to arrive.
+    \x1fTip: Change the \x11reassembly_timeout_secs\x11 option in the configuration to control how long to wait.\x1f
 \x02RESTART\x0f
     Reinitialize the connection to the modem.
+\x02USSD\x0f \x1d[code]\x0f
+    Send the USSD code \x1dcode\x0f (e.g. \x11*100#\x11) through the default modem, or the named one.
+    If the response is a menu, use this command again to send your choice. Not specifying a \x1dcode\x0f ends the session.
+    \x1fTip: If the responses look like gibberish, try the \x11ussd_pdu\x11 option in the configuration.\x1f
+\x02USAGE\x0f
+    Show how many SMS messages have been sent today and this month, and any sending budgets.
+\x02PHONEBOOK IMPORT\x0f
+    Read the contacts on the SIM (and in the modem's own phonebook), and use their names as nicks.
+    New recipients are created for contacts that don't have one; nicks you've chosen yourself are left alone.
 \x02PATH\x0f \x1d[temp_path]\x0f
-    \x1f\x02Temporarily\x0f sets the modem path to \x1dtemp_path\x0f.
+    \x1f\x02Temporarily\x0f sets the modem path to \x1dtemp_path\x0f (for the default modem, if no name is given).
     Not specifying a value for \x1dtemp_path\x0f will disable the modem.
     \x1fTip: Change the \x11modem_path\x11 option in the configuration to make this permanent.\x1f
 \x02*** End of subcommand help ***\x0f"
@@ -85,11 +153,19 @@ The following commands are available:
         match (cmd, rest) {
             ("signal", _) | ("csq", _) => Some(ModemCommand::GetCsq),
             ("registration", _) | ("reg", _) => Some(ModemCommand::GetReg),
+            ("receipts", _) | ("acks", _) => Some(ModemCommand::PrintReceipts),
+            ("partials", _) => Some(ModemCommand::ListPartials),
             ("restart", _) => Some(ModemCommand::Reinit),
             ("path", &[path]) => {
                 Some(ModemCommand::TempPath(Some(path.to_owned())))
             },
             ("path", _) => Some(ModemCommand::TempPath(None)),
+            ("list", _) => Some(ModemCommand::List),
+            ("status", _) => Some(ModemCommand::Status),
+            ("phonebook", &["import"]) => Some(ModemCommand::PhonebookImport),
+            ("usage", _) => Some(ModemCommand::Usage),
+            ("ussd", &[]) => Some(ModemCommand::Ussd(None)),
+            ("ussd", code) => Some(ModemCommand::Ussd(Some(code.join(" ")))),
             _ => None
         }
     }
@@ -148,7 +224,8 @@ The following commands are available:
 \x02CHATS\x0f
     List all WhatsApp chats you're currently part of.
 \x02RECEIPTS\x0f \x0307(alias \x02ACKS\x02)\x0f
-    Print delivery reports for recently sent messages.
+    Print delivery reports for recently sent messages (including media that's still uploading).
+    \x1fTip: Send media by messaging a WhatsApp ghost or group with \x11!send <url-or-path> [caption]\x11.\x1f
 \x02REBUILD\x0f
     Refresh group metadata for all WhatsApp groups you're currently in.
     This command will usually not be required, and is mainly useful for debugging.
@@ -171,7 +248,43 @@ pub enum GroupCommand {
         jid: Jid,
         chan: String
     },
-    Unbridge(String)
+    Unbridge(String),
+    Create {
+        chan: String,
+        subject: String,
+        nicks: Vec<String>
+    },
+    Leave(String),
+    InviteLink {
+        chan: String,
+        revoke: bool
+    },
+    Join(String)
+}
+/// Get the code out of a WA group invite link (or just a code).
+fn parse_invite_link(link: &str) -> Option<String> {
+    let code = link.trim_end_matches('/').rsplit('/').next()?;
+    if code.len() == 0 || !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    Some(code.to_owned())
+}
+/// Split a group subject off the start of `inp`. It's one word, unless it's
+/// in double quotes.
+fn parse_subject<'a, 'b>(inp: &'a [&'b str]) -> Option<(String, &'a [&'b str])> {
+    let first = inp.get(0)?;
+    if !first.starts_with('"') {
+        return Some((first.to_string(), &inp[1..]));
+    }
+    let end = inp.iter()
+        .enumerate()
+        .position(|(i, w)| w.ends_with('"') && (i > 0 || w.len() > 1))?;
+    let subject = inp[..end + 1].join(" ");
+    let subject = subject[1..subject.len() - 1].trim().to_owned();
+    if subject.len() == 0 {
+        return None;
+    }
+    Some((subject, &inp[end + 1..]))
 }
 impl GroupCommand {
     pub fn help() -> &'static str {
@@ -181,6 +294,16 @@ The following commands are available:
     Bridges the WhatsApp groupchat with JID \x1djid\x0f to the IRC channel \x1dchan\x0f.
 \x02UNBRIDGE\x0f \x1dchan\x0f
     Unbridges the IRC channel \x1dchan\x0f.
+\x02CREATE\x0f \x1dchan\x0f \x1dsubject\x0f \x1dnick\x0f [\x1dnick\x0f...]
+    Creates a new WhatsApp group called \x1dsubject\x0f with the WhatsApp ghosts \x1dnick\x0f, and bridges it to the IRC channel \x1dchan\x0f.
+    Put \x1dsubject\x0f in double quotes if it has spaces in it.
+\x02LEAVE\x0f \x1dchan\x0f
+    Leaves the WhatsApp group bridged to \x1dchan\x0f, and unbridges it.
+\x02INVITE-LINK\x0f \x1dchan\x0f [REVOKE]
+    Gets the invite link for the WhatsApp group bridged to \x1dchan\x0f.
+    With REVOKE, makes a new link instead, so the old one stops working.
+\x02JOIN\x0f \x1dlink\x0f
+    Joins the WhatsApp group with the invite link \x1dlink\x0f, and bridges it to a new channel.
 \x02*** End of subcommand help ***\x0f"
     }
     pub fn parse(inp: &[&str]) -> Option<Self> {
@@ -205,6 +328,29 @@ The following commands are available:
                     None
                 }
             },
+            ("create", rest) if rest.len() >= 3 && rest[0].starts_with("#") => {
+                let (subject, nicks) = parse_subject(&rest[1..])?;
+                if nicks.len() == 0 {
+                    return None;
+                }
+                Some(GroupCommand::Create {
+                    chan: rest[0].to_owned(),
+                    subject,
+                    nicks: nicks.iter().map(|x| x.to_string()).collect()
+                })
+            },
+            ("leave", &[chan]) if chan.starts_with("#") => {
+                Some(GroupCommand::Leave(chan.to_owned()))
+            },
+            ("invite-link", &[chan]) if chan.starts_with("#") => {
+                Some(GroupCommand::InviteLink { chan: chan.to_owned(), revoke: false })
+            },
+            ("invite-link", &[chan, revoke]) if chan.starts_with("#") && revoke.eq_ignore_ascii_case("revoke") => {
+                Some(GroupCommand::InviteLink { chan: chan.to_owned(), revoke: true })
+            },
+            ("join", &[link]) => {
+                parse_invite_link(link).map(GroupCommand::Join)
+            },
             _ => None
         }
     }
@@ -237,13 +383,46 @@ The following commands are available:
         }
     }
 }
+pub enum PduCommand {
+    List,
+    Show(i32),
+    Decode(String),
+    Retry
+}
+impl PduCommand {
+    pub fn help() -> &'static str {
+       "\x02*** PDU subcommand ***\x0f
+These commands help with debugging messages the bridge can't make sense of.
+The following commands are available:
+\x02LIST\x0f
+    List SMS messages that haven't been delivered to IRC yet (e.g. because they can't be decoded, or are waiting for more parts).
+\x02SHOW\x0f \x1did\x0f
+    Show the PDU of the stored message \x1did\x0f, in hex and decoded field by field.
+\x02DECODE\x0f \x1dhex\x0f
+    Decode the SMS-DELIVER PDU \x1dhex\x0f (including the SMSC address, as the modem reports it).
+\x02RETRY\x0f
+    Try to deliver all the stored messages again (e.g. after upgrading sms-irc).
+\x02*** End of subcommand help ***\x0f"
+    }
+    pub fn parse(inp: &[&str]) -> Option<Self> {
+        extract_command!(inp, cmd, rest);
+        match (cmd, rest) {
+            ("list", &[]) => Some(PduCommand::List),
+            ("show", &[id]) => id.trim_start_matches('#').parse().ok().map(PduCommand::Show),
+            ("decode", hex) if hex.len() > 0 => Some(PduCommand::Decode(hex.join(""))),
+            ("retry", &[]) => Some(PduCommand::Retry),
+            _ => None
+        }
+    }
+}
 pub enum AdminCommand {
     Ghost(String, GhostCommand),
     Contact(ContactCommand),
     Whatsapp(WhatsappCommand),
-    Modem(ModemCommand),
+    Modem(Option<String>, ModemCommand),
     Group(GroupCommand),
     Insp(InspCommand),
+    Pdu(PduCommand),
     Help(Option<String>)
 }
 impl AdminCommand {
@@ -263,6 +442,8 @@ The following commands are available. Use \x02HELP\x02 \x1dcommand\x0f to get mo
 \x02GROUP\x0f \x1dsubcommand\x0f
     Used to bridge and unbridge group chats to/from IRC channels.
     (Currently, only WhatsApp group chats are supported.)
+\x02PDU\x0f \x1dsubcommand\x0f
+    Inspects and decodes raw SMS PDUs, for debugging.
 \x02INSP\x0f \x1dsubcommand\x0f
     If sms-irc is using the InspIRCd server-to-server link, provides some debug commands.
 \x02HELP\x0f \x1d[command]\x0f
@@ -288,13 +469,25 @@ The following commands are available. Use \x02HELP\x02 \x1dcommand\x0f to get mo
                     .map(|x| AdminCommand::Group(x))
             },
             "modem" => {
-                ModemCommand::parse(&inp[1..])
-                    .map(|x| AdminCommand::Modem(x))
+                if let Some(mc) = ModemCommand::parse(&inp[1..]) {
+                    return Some(AdminCommand::Modem(None, mc));
+                }
+                // MODEM <name> <subcommand>
+                if inp.len() < 3 {
+                    return None;
+                }
+                let name = inp[1].to_owned();
+                ModemCommand::parse(&inp[2..])
+                    .map(|x| AdminCommand::Modem(Some(name), x))
             },
             "insp" => {
                 InspCommand::parse(&inp[1..])
                     .map(|x| AdminCommand::Insp(x))
             },
+            "pdu" => {
+                PduCommand::parse(&inp[1..])
+                    .map(|x| AdminCommand::Pdu(x))
+            },
             "ghost" => {
                 if inp.len() < 3 {
                     return None;
@@ -320,6 +513,7 @@ pub fn help_page(query: Option<&str>) -> Option<&'static str> {
         Some("whatsapp") => Some(WhatsappCommand::help()),
         Some("group") => Some(GroupCommand::help()),
         Some("insp") => Some(InspCommand::help()),
+        Some("pdu") => Some(PduCommand::help()),
         None => Some(AdminCommand::help()),
         _ => None
     }
@@ -0,0 +1,34 @@
+//! A fake AT-command modem, served over a pseudo-terminal, for testing sms-irc's
+//! SMS handling without any hardware.
+//!
+//! Run it (optionally giving a path to symlink the pseudo-terminal to), point
+//! `modem_path` at the device it prints out, then type commands at it to inject
+//! messages and faults; `help` lists them. Messages the bridge sends are printed
+//! out as PDUs.
+
+use std::io::{self, BufRead};
+use sms_irc::fake_modem::FakeModem;
+
+fn main() -> io::Result<()> {
+    let modem = FakeModem::start()?;
+    if let Some(link) = ::std::env::args().nth(1) {
+        let _ = ::std::fs::remove_file(&link);
+        ::std::os::unix::fs::symlink(modem.path(), &link)?;
+        println!("[+] fake modem listening on {} (linked from {})", modem.path(), link);
+    }
+    else {
+        println!("[+] fake modem listening on {}", modem.path());
+    }
+    println!("[*] type 'help' for a list of commands (or 'quit' to exit)");
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        if line.trim() == "quit" {
+            break;
+        }
+        if let Err(e) = modem.control(&line) {
+            println!("[!] {}", e);
+        }
+    }
+    Ok(())
+}
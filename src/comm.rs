@@ -8,38 +8,115 @@ use crate::config::Config;
 use crate::store::Store;
 use tokio_core::reactor::Handle;
 use crate::whatsapp_media::MediaResult;
+use crate::whatsapp_upload::UploadStep;
+use crate::modem_health::HealthSample;
+use crate::modem_phonebook::PhonebookStep;
 
 pub enum ModemCommand {
     DoCmgl,
     CmglComplete(Vec<SmsMessage>),
     CmglFailed(failure::Error),
     SendMessage(PduAddress, String),
+    ProcessOutbox,
+    OutboxSent(i32, Vec<u32>),
+    OutboxFailed(i32, failure::Error),
+    /// Only some parts of a message were sent (and the rest failed).
+    OutboxPartlySent(i32, Vec<u32>, failure::Error),
     RequestCsq,
     RequestReg,
+    PrintReceipts,
+    ListPartials,
+    CheckPartials,
     ForceReinit,
     UpdatePath(Option<String>),
     CommandTimeout,
+    /// Look for a modem identified by USB IDs (sent by a timer, or when a tty
+    /// is plugged in or unplugged). If a tty was just plugged in, this is its
+    /// name, like `ttyUSB2`.
+    CheckUsb(Option<String>),
     MakeContact(PduAddress),
+    /// Send the command to the named modem only.
+    ForModem(String, Box<ModemCommand>),
+    /// Set (or clear) the modem to send SMS messages to this nick through.
+    SetRecipientModem(String, Option<String>),
+    /// List stored SMS messages that haven't been delivered to IRC.
+    ListPdus,
+    /// Dump and decode a stored message's PDU.
+    ShowPdu(i32),
+    /// Decode a PDU given as hex.
+    DecodePdu(String),
+    /// Set whether SMS messages to a recipient (by nick) are transliterated.
+    SetRecipientTransliterate(String, bool),
+    /// Set whether a recipient (by nick) can be sent SMS messages.
+    SetRecipientReplyable(String, bool),
+    ListModems,
+    RequestStatus,
+    /// A message that arrived as text (e.g. from an HTTP gateway webhook).
+    IncomingText(PduAddress, String),
+    /// Show how many SMS messages have been sent today and this month.
+    RequestUsage,
+    /// Import the SIM and modem phonebooks into recipients.
+    PhonebookImport,
+    Phonebook(PhonebookStep),
+    /// The result of a modem health check.
+    HealthSampled(HealthSample),
+    /// Send a USSD code (or reply to a USSD menu); `None` ends the session.
+    Ussd(Option<String>),
+    /// A +CUSD response arrived, with the given status and text.
+    UssdReceived(u32, Option<String>),
 }
 pub enum WhatsappCommand {
     StartRegistration,
     LogonIfSaved,
-    SendGroupMessage(String, String),
-    SendDirectMessage(PduAddress, String),
+    /// Send a message to a group (by channel), optionally quoting the WA
+    /// message with the given ID.
+    SendGroupMessage(String, String, Option<String>),
+    SendDirectMessage(PduAddress, String, Option<String>),
     GroupAssociate(Jid, String),
     GroupList,
     GroupRemove(String),
+    /// Create a group (subject, nicks of participants) and bridge it to a channel.
+    GroupCreate(String, String, Vec<String>),
+    /// Leave the group bridged to a channel, and unbridge it.
+    GroupLeave(String),
+    /// Get (or, if `true`, revoke) the invite link for a group (by channel).
+    GroupInviteLink(String, bool),
+    /// Join a group with an invite code.
+    GroupJoin(String),
     GroupUpdateAll,
     MediaFinished(MediaResult),
+    MediaUpload(UploadStep),
     PrintAcks,
     MakeContact(PduAddress),
-    SubscribePresence(PduAddress)
+    SubscribePresence(PduAddress),
+    /// An avatar for the JID was downloaded (to the URL), or not.
+    AvatarFinished(Jid, crate::util::Result<String>),
+    /// Show (and refresh) the avatar of a ghost, by nick.
+    GetAvatar(String),
+    /// Change a group (by channel), because someone (by nick) changed its
+    /// channel on IRC.
+    GroupChange(String, String, GroupChange)
+}
+/// A change to a WA group, made on IRC.
+#[derive(Debug, Clone)]
+pub enum GroupChange {
+    /// `/TOPIC`: set the group subject.
+    Subject(String),
+    /// `/INVITE`: add a participant.
+    Add(PduAddress),
+    /// `/KICK`: remove a participant.
+    Remove(PduAddress),
+    /// `/MODE +o`: make a participant a group admin.
+    Promote(PduAddress),
+    /// `/MODE -o`: stop a participant being a group admin.
+    Demote(PduAddress)
 }
 #[allow(dead_code)]
 pub enum ContactFactoryCommand {
     ProcessMessages,
     ProcessGroups,
-    ProcessAvatars,
+    /// The avatar of a contact or group (by JID) changed.
+    ProcessAvatar(Jid),
     SetupContact(PduAddress),
     DropContact(PduAddress),
     QueryContact(PduAddress, i32),
@@ -62,6 +139,12 @@ pub enum ControlBotCommand {
     Log(String),
     ReportFailure(String),
     CommandResponse(String),
+    /// Send someone an IRC numeric (nick, numeric, arguments, text), e.g. to
+    /// report a group change that couldn't be made.
+    ReportNumeric(String, String, Vec<String>, String),
+    /// Send someone a server NOTICE (nick, text), for errors there's no
+    /// sensible numeric for.
+    ReportNotice(String, String),
     ProcessGroups
 }
 pub struct InitParameters<'a, T: 'a> {
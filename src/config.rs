@@ -1,3 +1,5 @@
+use std::collections::BTreeMap;
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     pub database_url: String,
@@ -10,6 +12,12 @@ pub struct Config {
     #[serde(default)]
     pub modem: ModemConfig,
     #[serde(default)]
+    pub modems: Vec<ModemConfig>,
+    #[serde(default)]
+    pub http_gateways: Vec<HttpGatewayConfig>,
+    #[serde(default)]
+    pub smpp: Vec<SmppConfig>,
+    #[serde(default)]
     pub whatsapp: WhatsappConfig,
     #[serde(default)]
     pub logging: LoggingConfig
@@ -25,8 +33,20 @@ pub struct LoggingConfig {
 }
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct ModemConfig {
+    #[serde(default)]
+    pub name: Option<String>,
     pub modem_path: Option<String>,
     #[serde(default)]
+    pub usb_vendor: Option<String>,
+    #[serde(default)]
+    pub usb_product: Option<String>,
+    #[serde(default)]
+    pub usb_serial: Option<String>,
+    #[serde(default)]
+    pub usb_interface: Option<u8>,
+    #[serde(default)]
+    pub usb_poll_ms: Option<u32>,
+    #[serde(default)]
     pub cmgl_secs: Option<u32>,
     #[serde(default)]
     pub restart_delay_ms: Option<u32>,
@@ -34,6 +54,110 @@ pub struct ModemConfig {
     pub restart_timeout_ms: Option<u32>,
     #[serde(default)]
     pub command_timeout_ms: Option<u32>,
+    #[serde(default)]
+    pub reassembly_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub confirm_segments: Option<usize>,
+    #[serde(default)]
+    pub confirm_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub outbox_retry_ms: Option<u32>,
+    #[serde(default)]
+    pub outbox_max_attempts: Option<u32>,
+    #[serde(default)]
+    pub delivery_reports: bool,
+    #[serde(default)]
+    pub ussd_pdu: bool,
+    #[serde(default)]
+    pub health_check_secs: Option<u64>,
+    #[serde(default)]
+    pub health_history: Option<usize>,
+    #[serde(default)]
+    pub health_min_rssi: Option<u32>,
+    #[serde(default)]
+    pub daily_segment_budget: Option<u32>,
+    #[serde(default)]
+    pub recipient_daily_segment_budget: Option<u32>,
+    #[serde(default)]
+    pub segments_per_minute: Option<u32>,
+    #[serde(default)]
+    pub pin: Option<String>,
+    #[serde(default)]
+    pub pin_file: Option<String>,
+    #[serde(default)]
+    pub pin_min_attempts: Option<u32>,
+    #[serde(default)]
+    pub reject_calls: bool,
+    #[serde(default)]
+    pub call_reply: Option<String>,
+    #[serde(default)]
+    pub receipt_check_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub receipt_warn_ms: Option<u64>,
+    #[serde(default)]
+    pub receipt_expiry_ms: Option<u64>,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct HttpGatewayConfig {
+    pub name: String,
+    pub send_url: String,
+    pub send_template: String,
+    #[serde(default)]
+    pub send_content_type: Option<String>,
+    #[serde(default)]
+    pub send_headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub send_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub webhook_listen: Option<String>,
+    #[serde(default)]
+    pub webhook_token: Option<String>,
+    #[serde(default)]
+    pub webhook_from_field: Option<String>,
+    #[serde(default)]
+    pub webhook_text_field: Option<String>,
+    #[serde(default)]
+    pub outbox_retry_ms: Option<u32>,
+    #[serde(default)]
+    pub outbox_max_attempts: Option<u32>,
+    #[serde(default)]
+    pub daily_segment_budget: Option<u32>,
+    #[serde(default)]
+    pub recipient_daily_segment_budget: Option<u32>,
+    #[serde(default)]
+    pub segments_per_minute: Option<u32>,
+}
+#[derive(Deserialize, Debug, Clone)]
+pub struct SmppConfig {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub system_id: String,
+    pub password: String,
+    #[serde(default)]
+    pub system_type: Option<String>,
+    #[serde(default)]
+    pub source_addr: Option<String>,
+    #[serde(default)]
+    pub delivery_receipts: bool,
+    #[serde(default)]
+    pub enquire_link_secs: Option<u64>,
+    #[serde(default)]
+    pub response_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub reconnect_min_ms: Option<u64>,
+    #[serde(default)]
+    pub reconnect_max_ms: Option<u64>,
+    #[serde(default)]
+    pub outbox_retry_ms: Option<u32>,
+    #[serde(default)]
+    pub outbox_max_attempts: Option<u32>,
+    #[serde(default)]
+    pub daily_segment_budget: Option<u32>,
+    #[serde(default)]
+    pub recipient_daily_segment_budget: Option<u32>,
+    #[serde(default)]
+    pub segments_per_minute: Option<u32>,
 }
 #[derive(Deserialize, Debug, Clone, Default)]
 pub struct WhatsappConfig {
@@ -64,7 +188,9 @@ pub struct WhatsappConfig {
     #[serde(default)]
     pub backoff_time_ms: Option<u64>,
     #[serde(default)]
-    pub track_presence: bool
+    pub track_presence: bool,
+    #[serde(default)]
+    pub quote_history: Option<usize>
 }
 #[derive(Deserialize, Debug, Clone)]
 pub struct IrcClientConfig {
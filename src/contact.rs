@@ -14,11 +14,12 @@ use irc::client::ext::ClientExt;
 use irc::proto::command::Command;
 use irc::proto::response::Response;
 use irc::proto::message::Message;
-use crate::models::Recipient;
+use crate::models::{Recipient, Message as DbMessage};
 use crate::config::IrcClientConfig;
 use irc::client::data::config::Config as IrcConfig;
 use crate::util::{self, Result};
-use crate::sender_common::Sender;
+use crate::sender_common::{Sender, ReassemblyTimeouts};
+use crate::sms_compose::{SmsComposer, PendingSms, ComposeConfig};
 
 pub struct ContactManager {
     irc: PackedIrcClient,
@@ -34,6 +35,8 @@ pub struct ContactManager {
     presence: Option<String>,
     channels: Vec<String>,
     webirc_password: Option<String>,
+    reassembly_timeouts: ReassemblyTimeouts,
+    pending_sms: PendingSms,
     wa_tx: UnboundedSender<WhatsappCommand>,
     modem_tx: UnboundedSender<ModemCommand>,
     pub tx: UnboundedSender<ContactManagerCommand>,
@@ -80,6 +83,14 @@ impl Sender for ContactManager {
         self.irc.0.send_privmsg(to, msg)?;
         Ok(())
     }
+    fn reassembly_timeout(&mut self, backend: Option<&str>) -> u64 {
+        self.reassembly_timeouts.get(backend)
+    }
+}
+impl SmsComposer for ContactManager {
+    fn pending_sms(&mut self) -> &mut PendingSms {
+        &mut self.pending_sms
+    }
 }
 impl ContactManager {
     pub fn add_command(&self, cmd: ContactManagerCommand) {
@@ -125,14 +136,23 @@ impl ContactManager {
         let msgs = self.store.get_messages_for_recipient(&self.addr)?;
         for msg in msgs {
             debug!("Processing message #{}", msg.id);
-            if msg.pdu.is_some() {
-                let pdu = DeliverPdu::try_from(msg.pdu.as_ref().unwrap() as &[u8])?;
+            if msg.source == DbMessage::SOURCE_NOTICE {
+                self.process_msg_plain("", msg)?;
+            }
+            else if msg.source == DbMessage::SOURCE_SMS {
                 if self.wa_mode {
                     self.wa_mode = false;
                     self.store.update_recipient_wa(&self.addr, self.wa_mode)?;
                     self.irc.0.send_notice(&self.admin, "Notice: SMS mode automatically enabled.")?;
                 }
-                self.process_msg_pdu("", msg, pdu)?;
+                if msg.pdu.is_some() {
+                    let pdu = DeliverPdu::try_from(msg.pdu.as_ref().unwrap() as &[u8])?;
+                    self.process_msg_pdu("", msg, pdu)?;
+                }
+                else {
+                    // SMS backends without PDUs (e.g. HTTP gateways) just give us text.
+                    self.process_msg_plain("", msg)?;
+                }
             }
             else {
                 if !self.wa_mode {
@@ -219,10 +239,13 @@ impl ContactManager {
                     if target == self.nick {
                         debug!("{} -> {}: {}", from[0], self.addr, mesg); 
                         if self.wa_mode {
-                            self.wa_tx.unbounded_send(WhatsappCommand::SendDirectMessage(self.addr.clone(), mesg)).unwrap();
+                            self.wa_tx.unbounded_send(WhatsappCommand::SendDirectMessage(self.addr.clone(), mesg, None)).unwrap();
                         }
                         else {
-                            self.modem_tx.unbounded_send(ModemCommand::SendMessage(self.addr.clone(), mesg)).unwrap();
+                            let addr = self.addr.clone();
+                            if let Some(text) = self.compose_sms("", &addr, mesg)? {
+                                self.modem_tx.unbounded_send(ModemCommand::SendMessage(addr, text)).unwrap();
+                            }
                         }
                     }
                 }
@@ -280,6 +303,8 @@ impl ContactManager {
         let wa_tx = p.cm.wa_tx.clone();
         let admin = p.cfg2.admin_nick.clone();
         let webirc_password = p.cfg2.webirc_password.clone();
+        let reassembly_timeouts = ReassemblyTimeouts::new(p.cfg);
+        let pending_sms = PendingSms::new(ComposeConfig::new(p.cfg));
         let cfg = Box::into_raw(Box::new(IrcConfig {
             nickname: Some(recip.nick),
             alt_nicks: Some(vec!["smsirc_fallback".to_string()]),
@@ -317,7 +342,8 @@ impl ContactManager {
                             admin_is_online: true,
                             presence: None,
                             channels: vec![],
-                            addr, store, modem_tx, tx, rx, admin, nick, wa_tx, webirc_password
+                            addr, store, modem_tx, tx, rx, admin, nick, wa_tx, webirc_password,
+                            reassembly_timeouts, pending_sms
                         })
                     },
                     Err(e) => {
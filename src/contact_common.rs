@@ -64,7 +64,8 @@ pub trait ContactManagerManager {
         else {
             info!("No contact exists yet for {}; asking for its creation", a);
             match src {
-                Message::SOURCE_SMS => {
+                // Notices only come from the modem at the moment.
+                Message::SOURCE_SMS | Message::SOURCE_NOTICE => {
                     self.m_tx().unbounded_send(ModemCommand::MakeContact(a))
                         .unwrap();
                 },
@@ -52,8 +52,9 @@ impl Future for ContactFactory {
                 ForwardCommand(addr, cmd) => self.forward_cmd(&addr, cmd)?,
                 ForwardCommandByNick(nick, cmd) => self.forward_cmd_by_nick(&nick, cmd)?,
                 SubscribePresenceByNick(nick) => self.subscribe_presence_by_nick(nick),
-                ProcessAvatars => {
-                    // FIXME: implement
+                ProcessAvatar(_) => {
+                    // Nowhere to show avatars as a client; they can be
+                    // looked at with GHOST <nick> AVATAR instead.
                 }
             }
         }
@@ -118,7 +118,7 @@ impl ControlBot {
                     }
                     else if self.channels.contains(&target) {
                         debug!("Received group message in {}: {}", target, mesg);
-                        self.wa_tx.unbounded_send(WhatsappCommand::SendGroupMessage(target, mesg))
+                        self.wa_tx.unbounded_send(WhatsappCommand::SendGroupMessage(target, mesg, None))
                             .unwrap();
                     }
                     else {
@@ -157,6 +157,13 @@ impl ControlBot {
             CommandResponse(resp) => {
                 self.control_response(&resp)?;
             },
+            ReportNumeric(nick, _, args, text) => {
+                // We're just a client here, so we can't send numerics.
+                self.irc.0.send_privmsg(&nick, &format!("{}: \x02\x0304{}: {}\x0f", nick, args.join(" "), text))?;
+            },
+            ReportNotice(nick, text) => {
+                self.irc.0.send_notice(&nick, &text)?;
+            },
             ProcessGroups => self.process_groups()?
         }
         Ok(())
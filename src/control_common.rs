@@ -3,7 +3,7 @@
 use crate::comm::{WhatsappCommand, ContactFactoryCommand, ContactManagerCommand, ModemCommand};
 use crate::util::Result;
 use crate::models::Recipient;
-use crate::admin::{InspCommand, AdminCommand, GhostCommand, GroupCommand, ContactCommand};
+use crate::admin::{InspCommand, AdminCommand, GhostCommand, GroupCommand, ContactCommand, PduCommand};
 use crate::admin::ModemCommand as AdminModemCommand;
 use crate::admin::WhatsappCommand as AdminWhatsappCommand;
 use crate::models::Message;
@@ -51,6 +51,18 @@ pub trait ControlCommon {
                     SetWhatsapp(n) => {
                         c = Some(ContactManagerCommand::SetWhatsapp(n));
                     },
+                    SetModem(m) => {
+                        self.m_send(ModemCommand::SetRecipientModem(nick.clone(), m));
+                    },
+                    SetTransliterate(b) => {
+                        self.m_send(ModemCommand::SetRecipientTransliterate(nick.clone(), b));
+                    },
+                    SetReplyable(b) => {
+                        self.m_send(ModemCommand::SetRecipientReplyable(nick.clone(), b));
+                    },
+                    Avatar => {
+                        self.wa_send(WhatsappCommand::GetAvatar(nick.clone()));
+                    },
                     PresenceSubscribe => {
                         self.cf_send(ContactFactoryCommand::SubscribePresenceByNick(nick.clone()));
                     },
@@ -63,16 +75,28 @@ pub trait ControlCommon {
                 }
                 self.control_response("Ghost command executed.")?;
             },
-            AdminCommand::Modem(mc) => {
+            AdminCommand::Modem(name, mc) => {
                 use self::AdminModemCommand::*;
 
                 let cts = match mc {
                     GetCsq => ModemCommand::RequestCsq,
                     GetReg => ModemCommand::RequestReg,
+                    PrintReceipts => ModemCommand::PrintReceipts,
+                    ListPartials => ModemCommand::ListPartials,
                     Reinit => ModemCommand::ForceReinit,
                     TempPath(s) => ModemCommand::UpdatePath(s),
+                    List => ModemCommand::ListModems,
+                    Status => ModemCommand::RequestStatus,
+                    Ussd(c) => ModemCommand::Ussd(c),
+                    PhonebookImport => ModemCommand::PhonebookImport,
+                    Usage => ModemCommand::RequestUsage,
                 };
-                self.m_send(cts);
+                if let Some(name) = name {
+                    self.m_send(ModemCommand::ForModem(name, Box::new(cts)));
+                }
+                else {
+                    self.m_send(cts);
+                }
             },
             AdminCommand::Whatsapp(wac) => {
                 use self::AdminWhatsappCommand::*;
@@ -91,7 +115,11 @@ pub trait ControlCommon {
 
                 let cts = match gc {
                     BridgeWhatsapp { jid, chan } => WhatsappCommand::GroupAssociate(jid, chan),
-                    Unbridge(ch) => WhatsappCommand::GroupRemove(ch)
+                    Unbridge(ch) => WhatsappCommand::GroupRemove(ch),
+                    Create { chan, subject, nicks } => WhatsappCommand::GroupCreate(chan, subject, nicks),
+                    Leave(ch) => WhatsappCommand::GroupLeave(ch),
+                    InviteLink { chan, revoke } => WhatsappCommand::GroupInviteLink(chan, revoke),
+                    Join(code) => WhatsappCommand::GroupJoin(code)
                 };
                 self.wa_send(cts);
             },
@@ -103,6 +131,19 @@ pub trait ControlCommon {
                 };
                 self.cf_send(ContactFactoryCommand::QueryContact(addr, src));
             },
+            AdminCommand::Pdu(pc) => {
+                use self::PduCommand::*;
+
+                match pc {
+                    List => self.m_send(ModemCommand::ListPdus),
+                    Show(id) => self.m_send(ModemCommand::ShowPdu(id)),
+                    Decode(hex) => self.m_send(ModemCommand::DecodePdu(hex)),
+                    Retry => {
+                        self.cf_send(ContactFactoryCommand::ProcessMessages);
+                        self.control_response("Retrying delivery of stored messages.")?;
+                    }
+                }
+            },
             AdminCommand::Insp(ic) => {
                 if !self.process_insp(ic)? {
                     self.control_response("Error: InspIRCd link inactive!")?;
@@ -0,0 +1,656 @@
+//! A fake AT-command modem, served over a pseudo-terminal, for testing sms-irc's
+//! SMS handling without any hardware.
+//!
+//! This is driven by the `fake-modem` binary (for poking at by hand), and by
+//! the integration tests (which script it through `FakeModem::control`).
+
+use chrono::prelude::*;
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+use std::{ptr, thread};
+use crate::gsm7;
+
+pub static HELP: &str = "Commands:
+  sms <from> <text>                     deliver a message (split into parts if it's too long)
+  part <from> <ref> <total> <n> <text>  deliver part <n> of <total> of concatenated message <ref>
+  pdu <hex>                             deliver a raw PDU (as +CMGL would show it, SMSC address included)
+  cmti <index>                          send a +CMTI for <index>, whether or not it exists
+  urcs on|off                           whether to send +CMTI when messages arrive (if +CNMI asked for it)
+  timeout [n]                           don't respond to the next <n> commands (default 1)
+  fail-send [n]                         fail the next <n> +CMGS commands (default 1)
+  garbage [text]                        send some junk down the line
+  csq <rssi> <ber>                      set the signal quality
+  creg <stat>                           set the registration status
+  sim ready|puk|none                    set the SIM state
+  sim pin <pin> [attempts]              lock the SIM with <pin> (default 3 attempts left)
+  list                                  list stored messages";
+
+struct StoredMessage {
+    index: u32,
+    read: bool,
+    /// The PDU, including the SMSC address.
+    pdu: Vec<u8>
+}
+
+struct ModemState {
+    tty: File,
+    echo: bool,
+    /// Whether the bridge asked for +CMTI notifications.
+    cnmi: bool,
+    urcs: bool,
+    messages: Vec<StoredMessage>,
+    next_index: u32,
+    next_mr: u8,
+    csms_ref: u8,
+    rssi: u32,
+    ber: u32,
+    creg_mode: u32,
+    creg_stat: u32,
+    /// "READY", "SIM PIN", "SIM PUK" or "NONE" (no SIM inserted).
+    sim_state: &'static str,
+    sim_pin: String,
+    pin_attempts: u32,
+    /// Number of commands to ignore.
+    swallow: u32,
+    /// Number of +CMGS commands to fail.
+    fail_sends: u32,
+    /// Set while we're waiting for the PDU after a +CMGS.
+    awaiting_pdu: Option<usize>,
+    /// Where to put the SMS-SUBMIT PDUs the bridge sends.
+    submits: mpsc::Sender<Vec<u8>>
+}
+impl ModemState {
+    fn write(&mut self, data: &str) {
+        if let Err(e) = self.tty.write_all(data.as_bytes()) {
+            eprintln!("[!] write to tty failed: {}", e);
+        }
+    }
+    fn respond(&mut self, lines: &[String], result: &str) {
+        let mut out = String::new();
+        for line in lines {
+            out += &format!("\r\n{}\r\n", line);
+        }
+        out += &format!("\r\n{}\r\n", result);
+        self.write(&out);
+    }
+    fn ok(&mut self, lines: &[String]) {
+        self.respond(lines, "OK");
+    }
+    fn error(&mut self) {
+        self.respond(&[], "ERROR");
+    }
+    /// Handle one line (or PDU) from the bridge.
+    fn handle_line(&mut self, line: &str) {
+        if let Some(len) = self.awaiting_pdu.take() {
+            self.handle_submit(len, line);
+            return;
+        }
+        let line = line.trim();
+        if line.len() == 0 {
+            return;
+        }
+        println!("[<] {}", line);
+        if self.swallow > 0 {
+            self.swallow -= 1;
+            println!("[*] ignoring that ({} more to ignore)", self.swallow);
+            return;
+        }
+        let upper = line.to_uppercase();
+        if !upper.starts_with("AT") {
+            self.error();
+            return;
+        }
+        let cmd = &upper[2..];
+        let (name, arg) = match cmd.find(|c| c == '=' || c == '?') {
+            Some(i) => (&cmd[..i], &cmd[i..]),
+            None => (cmd, "")
+        };
+        match (name, arg) {
+            ("", "") | ("Z", "") => self.ok(&[]),
+            ("E0", "") => {
+                self.echo = false;
+                self.ok(&[]);
+            },
+            ("E1", "") => {
+                self.echo = true;
+                self.ok(&[]);
+            },
+            ("+CMGF", "=0") => self.ok(&[]),
+            ("+CMGF", "?") => self.ok(&["+CMGF: 0".into()]),
+            ("+CMGF", _) => {
+                println!("[!] only PDU mode (+CMGF=0) is supported");
+                self.error();
+            },
+            ("+CNMI", "?") => {
+                let mt = if self.cnmi { 1 } else { 0 };
+                self.ok(&[format!("+CNMI: 2,{},0,0,0", mt)]);
+            },
+            ("+CNMI", x) if x.starts_with('=') => {
+                self.cnmi = x[1..].split(',').nth(1)
+                    .map(|x| x.trim() != "0")
+                    .unwrap_or(false);
+                self.ok(&[]);
+            },
+            ("+CMGL", x) if x.starts_with('=') => {
+                match x[1..].trim().parse::<u32>() {
+                    Ok(stat) => self.list_messages(stat),
+                    Err(_) => self.error()
+                }
+            },
+            ("+CMGD", x) if x.starts_with('=') => {
+                let mut args = x[1..].split(',').map(|x| x.trim().parse::<u32>());
+                match (args.next(), args.next()) {
+                    (Some(Ok(idx)), None) | (Some(Ok(idx)), Some(Ok(0))) => self.delete_message(idx),
+                    (_, Some(Ok(flag))) => self.delete_messages(flag),
+                    _ => self.error()
+                }
+            },
+            ("+CMGS", x) if x.starts_with('=') => {
+                match x[1..].trim().parse::<usize>() {
+                    Ok(len) => {
+                        self.awaiting_pdu = Some(len);
+                        self.write("\r\n> ");
+                    },
+                    Err(_) => self.error()
+                }
+            },
+            ("+CPIN", "?") if self.sim_state == "NONE" => self.respond(&[], "+CME ERROR: 10"),
+            ("+CPIN", "?") => {
+                let resp = format!("+CPIN: {}", self.sim_state);
+                self.ok(&[resp]);
+            },
+            ("^CPIN", "?") if self.sim_state == "NONE" => self.respond(&[], "+CME ERROR: 10"),
+            ("^CPIN", "?") => {
+                let code = if self.sim_state == "READY" { "" } else { self.sim_state };
+                let times = if self.sim_state == "SIM PIN" { self.pin_attempts.to_string() } else { "".into() };
+                let resp = format!("^CPIN: {},{},10,{},10,3", code, times, self.pin_attempts);
+                self.ok(&[resp]);
+            },
+            ("+CPIN", x) if x.starts_with('=') => {
+                // The command was uppercased, but PINs are just digits.
+                let pin = x[1..].trim().trim_matches('"').to_owned();
+                self.enter_pin(&pin);
+            },
+            ("+CSQ", "") => {
+                let resp = format!("+CSQ: {},{}", self.rssi, self.ber);
+                self.ok(&[resp]);
+            },
+            ("+CREG", "?") => {
+                let resp = format!("+CREG: {},{}", self.creg_mode, self.creg_stat);
+                self.ok(&[resp]);
+            },
+            ("+CREG", x) if x.starts_with('=') => {
+                match x[1..].trim().parse() {
+                    Ok(m) => {
+                        self.creg_mode = m;
+                        self.ok(&[]);
+                    },
+                    Err(_) => self.error()
+                }
+            },
+            _ => {
+                println!("[!] unsupported command");
+                self.error();
+            }
+        }
+    }
+    fn enter_pin(&mut self, pin: &str) {
+        if self.sim_state != "SIM PIN" {
+            println!("[!] SIM isn't waiting for a PIN");
+            self.respond(&[], "+CME ERROR: 3");
+        }
+        else if pin == self.sim_pin {
+            println!("[*] SIM unlocked");
+            self.sim_state = "READY";
+            self.pin_attempts = 3;
+            self.ok(&[]);
+        }
+        else {
+            self.pin_attempts = self.pin_attempts.saturating_sub(1);
+            println!("[!] wrong PIN ({} attempts left)", self.pin_attempts);
+            if self.pin_attempts == 0 {
+                println!("[!] SIM is now PUK-locked");
+                self.sim_state = "SIM PUK";
+            }
+            self.respond(&[], "+CME ERROR: 16");
+        }
+    }
+    fn list_messages(&mut self, stat: u32) {
+        let mut lines = vec![];
+        for msg in self.messages.iter_mut() {
+            let matches = match stat {
+                0 => !msg.read,
+                1 => msg.read,
+                4 => true,
+                _ => false
+            };
+            if !matches {
+                continue;
+            }
+            let tpdu_len = msg.pdu.len() - 1 - msg.pdu[0] as usize;
+            lines.push(format!("+CMGL: {},{},,{}", msg.index, if msg.read { 1 } else { 0 }, tpdu_len));
+            lines.push(to_hex(&msg.pdu));
+            msg.read = true;
+        }
+        self.ok(&lines);
+    }
+    fn delete_message(&mut self, idx: u32) {
+        let len = self.messages.len();
+        self.messages.retain(|m| m.index != idx);
+        if self.messages.len() == len {
+            self.respond(&[], "+CMS ERROR: 321");
+        }
+        else {
+            self.ok(&[]);
+        }
+    }
+    fn delete_messages(&mut self, flag: u32) {
+        // We don't store sent messages, so 1, 2 and 3 all mean "the read ones".
+        match flag {
+            1 | 2 | 3 => self.messages.retain(|m| !m.read),
+            4 => self.messages.clear(),
+            _ => {
+                self.error();
+                return;
+            }
+        }
+        self.ok(&[]);
+    }
+    fn handle_submit(&mut self, len: usize, hex: &str) {
+        let pdu = match from_hex(hex.trim()) {
+            Some(p) if p.len() > 0 && p.len() == len + 1 + p[0] as usize => p,
+            _ => {
+                println!("[!] invalid SMS-SUBMIT PDU (length {}): {}", len, hex.trim());
+                self.respond(&[], "+CMS ERROR: 304");
+                return;
+            }
+        };
+        if self.fail_sends > 0 {
+            self.fail_sends -= 1;
+            println!("[*] failing SMS-SUBMIT: {}", to_hex(&pdu));
+            self.respond(&[], "+CMS ERROR: 500");
+            return;
+        }
+        let mr = self.next_mr;
+        self.next_mr = self.next_mr.wrapping_add(1);
+        println!("[>] SMS-SUBMIT (reference {}): {}", mr, to_hex(&pdu));
+        if let Some(sub) = decode_submit(&pdu) {
+            println!("[>] to {}: {}", sub.to, sub.text);
+        }
+        self.ok(&[format!("+CMGS: {}", mr)]);
+        // Nobody might be listening, which is fine.
+        let _ = self.submits.send(pdu);
+    }
+    fn store(&mut self, pdu: Vec<u8>) {
+        let index = self.next_index;
+        self.next_index += 1;
+        self.messages.push(StoredMessage { index, read: false, pdu });
+        println!("[+] stored message at index {}", index);
+        if self.cnmi && self.urcs {
+            self.write(&format!("\r\n+CMTI: \"SM\",{}\r\n", index));
+        }
+    }
+    /// Handle one line of input from the user.
+    fn control(&mut self, line: &str) -> Result<(), String> {
+        let args = line.split_whitespace().collect::<Vec<_>>();
+        let rest = |n: usize| args[n..].join(" ");
+        let num = |s: &str| s.parse::<u32>().map_err(|_| format!("'{}' isn't a number", s));
+        match args.get(0).cloned().unwrap_or("") {
+            "" => {},
+            "help" => println!("{}", HELP),
+            "sms" if args.len() >= 3 => {
+                let csms_ref = self.csms_ref;
+                self.csms_ref = self.csms_ref.wrapping_add(1);
+                for pdu in deliver_pdus(args[1], &rest(2), csms_ref) {
+                    self.store(pdu);
+                }
+            },
+            "part" if args.len() >= 6 => {
+                let (rf, total, n) = (num(args[2])?, num(args[3])?, num(args[4])?);
+                let udh = vec![0x05, 0x00, 0x03, rf as u8, total as u8, n as u8];
+                let (dcs, units) = encode_text(&rest(5));
+                self.store(deliver_pdu(args[1], Some(udh), dcs, &units));
+            },
+            "pdu" if args.len() == 2 => {
+                let pdu = from_hex(args[1]).ok_or("invalid hex")?;
+                if pdu.len() == 0 || pdu.len() < 1 + pdu[0] as usize {
+                    Err("PDU is too short")?
+                }
+                self.store(pdu);
+            },
+            "cmti" if args.len() == 2 => {
+                let idx = num(args[1])?;
+                self.write(&format!("\r\n+CMTI: \"SM\",{}\r\n", idx));
+            },
+            "urcs" if args.len() == 2 => {
+                self.urcs = args[1] == "on";
+            },
+            "timeout" => {
+                self.swallow = if args.len() > 1 { num(args[1])? } else { 1 };
+            },
+            "fail-send" => {
+                self.fail_sends = if args.len() > 1 { num(args[1])? } else { 1 };
+            },
+            "garbage" => {
+                let junk = if args.len() > 1 { rest(1) } else { "\u{7f}ERR#@!\u{0}~~".into() };
+                self.write(&format!("\r\n{}\r\n", junk));
+            },
+            "csq" if args.len() == 3 => {
+                self.rssi = num(args[1])?;
+                self.ber = num(args[2])?;
+            },
+            "creg" if args.len() == 2 => {
+                self.creg_stat = num(args[1])?;
+            },
+            "sim" if args.len() >= 2 => {
+                self.sim_state = match args[1] {
+                    "ready" => "READY",
+                    "puk" => "SIM PUK",
+                    "none" => "NONE",
+                    "pin" if args.len() >= 3 => {
+                        self.sim_pin = args[2].into();
+                        self.pin_attempts = if args.len() > 3 { num(args[3])? } else { 3 };
+                        "SIM PIN"
+                    },
+                    _ => Err("usage: sim ready|puk|none, or sim pin <pin> [attempts]")?
+                };
+            },
+            "list" => {
+                for msg in self.messages.iter() {
+                    println!("{:>4} {} {}", msg.index, if msg.read { "read  " } else { "unread" }, to_hex(&msg.pdu));
+                }
+                println!("{} message(s)", self.messages.len());
+            },
+            _ => Err("invalid command (try 'help')")?
+        }
+        Ok(())
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|x| format!("{:02X}", x)).collect()
+}
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+/// A message the bridge sent, decoded from its SMS-SUBMIT PDU.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submitted {
+    pub to: String,
+    /// The concatenated message reference, number of parts and part number,
+    /// if this is part of a concatenated message.
+    pub part: Option<(u8, u8, u8)>,
+    pub text: String
+}
+/// Decode an SMS-SUBMIT PDU (including the SMSC address).
+///
+/// Only the GSM 7-bit default alphabet (without the extension table) and
+/// UCS-2 are understood, which is all sms-irc sends.
+pub fn decode_submit(pdu: &[u8]) -> Option<Submitted> {
+    let pdu = pdu.get(1 + *pdu.get(0)? as usize..)?;
+    let first = *pdu.get(0)?;
+    if first & 0x03 != 0x01 {
+        return None;
+    }
+    // TP-MR comes before the destination address.
+    let da_digits = *pdu.get(2)? as usize;
+    let da_type = *pdu.get(3)?;
+    let da_len = (da_digits + 1) / 2;
+    let mut to = if da_type & 0x70 == 0x10 { "+".to_owned() } else { String::new() };
+    for &o in pdu.get(4..4 + da_len)? {
+        for &d in &[o & 0x0F, o >> 4] {
+            // The last octet is padded with 0xF if there are an odd number of digits.
+            if d < 10 {
+                to.push((b'0' + d) as char);
+            }
+        }
+    }
+    // Skip TP-PID.
+    let mut pos = 4 + da_len + 1;
+    let dcs = *pdu.get(pos)?;
+    pos += 1;
+    // TP-VP, whose length depends on TP-VPF.
+    pos += match (first >> 3) & 0x03 {
+        0 => 0,
+        2 => 1,
+        _ => 7
+    };
+    let udl = *pdu.get(pos)? as usize;
+    let ud = pdu.get(pos + 1..)?;
+    let udh_len = if first & 0x40 != 0 { *ud.get(0)? as usize + 1 } else { 0 };
+    let udh = ud.get(..udh_len)?;
+    let part = if udh_len >= 6 && udh[1] == 0x00 && udh[2] == 0x03 {
+        Some((udh[3], udh[4], udh[5]))
+    }
+    else {
+        None
+    };
+    let text = if dcs & 0x0C == 0x00 {
+        // TP-UDL counts septets, including the (padded) UDH.
+        let udh_septets = (udh_len * 8 + 6) / 7;
+        gsm7::unpack_septets(ud)
+            .get(udh_septets..udl)?
+            .iter()
+            .map(|&x| gsm7::gsm7_to_char(x))
+            .collect()
+    }
+    else {
+        let units = ud.get(udh_len..udl)?
+            .chunks(2)
+            .map(|x| ((x[0] as u16) << 8) | *x.get(1).unwrap_or(&0) as u16)
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    };
+    Some(Submitted { to, part, text })
+}
+/// Encode `text` as GSM 7-bit septets if we can, or UCS-2 otherwise.
+fn encode_text(text: &str) -> (u8, Vec<u8>) {
+    if let Some(septets) = text.chars().map(gsm7::char_to_gsm7).collect::<Option<Vec<_>>>() {
+        return (0x00, septets);
+    }
+    let ucs2 = text.encode_utf16()
+        .flat_map(|x| vec![(x >> 8) as u8, x as u8])
+        .collect();
+    (0x08, ucs2)
+}
+/// Encode a TP-OA: a phone number, or an alphanumeric sender if it isn't one.
+fn encode_address(addr: &str) -> Vec<u8> {
+    let number = addr.trim_start_matches('+');
+    if number.len() == 0 || !number.chars().all(|c| c.is_ascii_digit()) {
+        let septets = addr.chars()
+            .map(|x| gsm7::char_to_gsm7(x).unwrap_or(0x3F))
+            .collect::<Vec<_>>();
+        let mut ret = vec![((septets.len() * 7 + 3) / 4) as u8, 0xD0];
+        ret.extend(gsm7::pack_septets(&septets, 0));
+        return ret;
+    }
+    let digits = number.bytes().map(|x| x - b'0').collect::<Vec<_>>();
+    let mut ret = vec![digits.len() as u8, if addr.starts_with('+') { 0x91 } else { 0x81 }];
+    for pair in digits.chunks(2) {
+        ret.push((pair.get(1).cloned().unwrap_or(0x0F) << 4) | pair[0]);
+    }
+    ret
+}
+fn timestamp() -> Vec<u8> {
+    let ts = Utc::now();
+    let swap = |x: u32| -> u8 { (((x % 10) << 4) | (x / 10)) as u8 };
+    vec![
+        swap(ts.year() as u32 % 100),
+        swap(ts.month()),
+        swap(ts.day()),
+        swap(ts.hour()),
+        swap(ts.minute()),
+        swap(ts.second()),
+        0x00
+    ]
+}
+/// Make an SMS-DELIVER PDU (with an empty SMSC address), from septets or UCS-2 octets.
+fn deliver_pdu(from: &str, udh: Option<Vec<u8>>, dcs: u8, units: &[u8]) -> Vec<u8> {
+    let mut pdu = vec![0x00];
+    pdu.push(if udh.is_some() { 0x44 } else { 0x04 });
+    pdu.extend(encode_address(from));
+    pdu.push(0x00);
+    pdu.push(dcs);
+    pdu.extend(timestamp());
+    let udh = udh.unwrap_or_default();
+    if dcs == 0x00 {
+        let fill_bits = (7 - (udh.len() * 8) % 7) % 7;
+        let udh_septets = (udh.len() * 8 + fill_bits) / 7;
+        pdu.push((udh_septets + units.len()) as u8);
+        pdu.extend(&udh);
+        pdu.extend(gsm7::pack_septets(units, fill_bits));
+    }
+    else {
+        pdu.push((udh.len() + units.len()) as u8);
+        pdu.extend(&udh);
+        pdu.extend(units);
+    }
+    pdu
+}
+/// Make the SMS-DELIVER PDUs for `text`, splitting it up if it won't fit in one.
+fn deliver_pdus(from: &str, text: &str, csms_ref: u8) -> Vec<Vec<u8>> {
+    let (dcs, units) = encode_text(text);
+    let (max, part_len) = if dcs == 0x00 { (160, 153) } else { (140, 134) };
+    if units.len() <= max {
+        return vec![deliver_pdu(from, None, dcs, &units)];
+    }
+    let chunks = units.chunks(part_len).collect::<Vec<_>>();
+    let total = chunks.len();
+    chunks.into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let udh = vec![0x05, 0x00, 0x03, csms_ref, total as u8, (i + 1) as u8];
+            deliver_pdu(from, Some(udh), dcs, chunk)
+        })
+        .collect()
+}
+/// Open a pseudo-terminal, returning the master and the slave's path.
+fn open_pty() -> io::Result<(File, String)> {
+    let mut master = 0;
+    let mut slave = 0;
+    unsafe {
+        if libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut tio = ::std::mem::zeroed();
+        if libc::tcgetattr(slave, &mut tio) == 0 {
+            libc::cfmakeraw(&mut tio);
+            libc::tcsetattr(slave, libc::TCSANOW, &tio);
+        }
+        let name = libc::ttyname(slave);
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        // The slave fd is deliberately leaked: keeping it open stops reads
+        // on the master failing whenever sms-irc closes the device.
+        let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+        Ok((File::from_raw_fd(master), name))
+    }
+}
+
+/// A running fake modem.
+pub struct FakeModem {
+    state: Arc<Mutex<ModemState>>,
+    path: String,
+    submits: mpsc::Receiver<Vec<u8>>
+}
+impl FakeModem {
+    /// Start a fake modem on a new pseudo-terminal.
+    pub fn start() -> io::Result<Self> {
+        let (tty, path) = open_pty()?;
+        let mut reader = tty.try_clone()?;
+        let (submits_tx, submits) = mpsc::channel();
+        let state = Arc::new(Mutex::new(ModemState {
+            tty,
+            echo: true,
+            cnmi: false,
+            urcs: true,
+            messages: vec![],
+            next_index: 0,
+            next_mr: 0,
+            csms_ref: 0,
+            rssi: 20,
+            ber: 99,
+            creg_mode: 0,
+            creg_stat: 1,
+            sim_state: "READY",
+            sim_pin: "0000".into(),
+            pin_attempts: 3,
+            swallow: 0,
+            fail_sends: 0,
+            awaiting_pdu: None,
+            submits: submits_tx
+        }));
+        let state2 = state.clone();
+        thread::spawn(move || {
+            let mut line = vec![];
+            let mut buf = [0; 1024];
+            loop {
+                let n = match reader.read(&mut buf) {
+                    Ok(n) => n,
+                    Err(e) => {
+                        eprintln!("[!] read from tty failed: {}", e);
+                        return;
+                    }
+                };
+                let mut modem = state2.lock().unwrap();
+                if modem.echo {
+                    let echoed = String::from_utf8_lossy(&buf[..n]).into_owned();
+                    modem.write(&echoed);
+                }
+                for &b in buf[..n].iter() {
+                    let pdu_mode = modem.awaiting_pdu.is_some();
+                    match b {
+                        // Ctrl-Z ends a PDU; Esc cancels it.
+                        0x1A if pdu_mode => {
+                            let l = String::from_utf8_lossy(&line).into_owned();
+                            modem.handle_line(&l);
+                            line.clear();
+                        },
+                        0x1B if pdu_mode => {
+                            modem.awaiting_pdu = None;
+                            println!("[*] SMS-SUBMIT cancelled");
+                            modem.ok(&[]);
+                            line.clear();
+                        },
+                        b'\r' | b'\n' if !pdu_mode => {
+                            let l = String::from_utf8_lossy(&line).into_owned();
+                            modem.handle_line(&l);
+                            line.clear();
+                        },
+                        b'\r' | b'\n' => {},
+                        b => line.push(b)
+                    }
+                }
+            }
+        });
+        Ok(Self { state, path, submits })
+    }
+    /// The path of the pseudo-terminal to point `modem_path` at.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    /// Run one of the commands listed in `HELP`.
+    pub fn control(&self, line: &str) -> Result<(), String> {
+        self.state.lock().unwrap().control(line)
+    }
+    /// How many messages are stored on the (fake) SIM.
+    pub fn stored_messages(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+    /// Wait for the bridge to send a message, returning its SMS-SUBMIT PDU
+    /// (including the SMSC address; see `decode_submit`).
+    pub fn next_submit(&self, timeout: Duration) -> Option<Vec<u8>> {
+        self.submits.recv_timeout(timeout).ok()
+    }
+}
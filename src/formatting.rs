@@ -0,0 +1,285 @@
+//! Translating text formatting between WhatsApp (`*bold*`, `_italic_`,
+//! `~strikethrough~` and ```` ```monospace``` ````) and IRC control codes.
+
+static IRC_BOLD: char = '\x02';
+static IRC_ITALIC: char = '\x1d';
+static IRC_STRIKE: char = '\x1e';
+static IRC_MONOSPACE: char = '\x11';
+static IRC_UNDERLINE: char = '\x1f';
+static IRC_REVERSE: char = '\x16';
+static IRC_RESET: char = '\x0f';
+static IRC_COLOUR: char = '\x03';
+static IRC_HEX_COLOUR: char = '\x04';
+/// Put after literal formatting characters that WA would otherwise treat as
+/// formatting, to stop it doing so.
+static WA_ESCAPE: char = '\u{200b}';
+static WA_MONOSPACE: &str = "```";
+
+fn irc_code_for(marker: char) -> Option<char> {
+    match marker {
+        '*' => Some(IRC_BOLD),
+        '_' => Some(IRC_ITALIC),
+        '~' => Some(IRC_STRIKE),
+        _ => None
+    }
+}
+/// Whether a WA formatting marker at `i` can start a span: it has to be at the
+/// start of a word, and followed by something that isn't whitespace (or an
+/// escape).
+fn can_open(chars: &[char], i: usize) -> bool {
+    let after_ok = chars.get(i + 1).map(|&c| !c.is_whitespace() && c != WA_ESCAPE).unwrap_or(false);
+    let before_ok = i == 0 || !chars[i - 1].is_alphanumeric();
+    after_ok && before_ok
+}
+/// Whether a WA formatting marker at `i` can end a span: it has to be at the end
+/// of a word, and come after something that isn't whitespace.
+fn can_close(chars: &[char], i: usize) -> bool {
+    let before_ok = i > 0 && !chars[i - 1].is_whitespace();
+    let after_ok = chars.get(i + 1).map(|c| !c.is_alphanumeric()).unwrap_or(true);
+    before_ok && after_ok
+}
+fn starts_with_at(chars: &[char], i: usize, pat: &str) -> bool {
+    pat.chars().enumerate().all(|(j, c)| chars.get(i + j) == Some(&c))
+}
+/// Which characters in `chars` are part of a URL (a word with `://` in it).
+fn url_mask(chars: &[char]) -> Vec<bool> {
+    let mut ret = vec![false; chars.len()];
+    let mut start = 0;
+    for i in 0..=chars.len() {
+        if i == chars.len() || chars[i].is_whitespace() {
+            if (start..i).any(|j| starts_with_at(chars, j, "://")) {
+                for m in &mut ret[start..i] {
+                    *m = true;
+                }
+            }
+            start = i + 1;
+        }
+    }
+    ret
+}
+fn wa_to_irc_inner(chars: &[char], out: &mut String) {
+    // WA doesn't format anything in links.
+    let urls = url_mask(chars);
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if starts_with_at(chars, i, WA_MONOSPACE) {
+            let start = i + WA_MONOSPACE.len();
+            let end = (start..chars.len()).find(|&j| starts_with_at(chars, j, WA_MONOSPACE));
+            if let Some(end) = end.filter(|&e| e > start) {
+                // Nothing inside monospace is formatting.
+                out.push(IRC_MONOSPACE);
+                out.extend(&chars[start..end]);
+                out.push(IRC_MONOSPACE);
+                i = end + WA_MONOSPACE.len();
+                continue;
+            }
+        }
+        if let Some(code) = irc_code_for(c) {
+            if !urls[i] && can_open(chars, i) {
+                let end = (i + 2..chars.len())
+                    .take_while(|&j| chars[j] != '\n')
+                    .find(|&j| chars[j] == c && !urls[j] && can_close(chars, j));
+                if let Some(end) = end {
+                    out.push(code);
+                    wa_to_irc_inner(&chars[i + 1..end], out);
+                    out.push(code);
+                    i = end + 1;
+                    continue;
+                }
+            }
+        }
+        let escaped = c == WA_ESCAPE && i > 0 &&
+            (irc_code_for(chars[i - 1]).is_some() || chars[i - 1] == '`');
+        if !escaped {
+            out.push(c);
+        }
+        i += 1;
+    }
+}
+/// Convert WA formatting in `text` to IRC control codes.
+pub fn wa_to_irc(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut ret = String::with_capacity(text.len());
+    wa_to_irc_inner(&chars, &mut ret);
+    ret
+}
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    strike: bool,
+    monospace: bool
+}
+impl Style {
+    fn open(&self) -> String {
+        if self.monospace {
+            return WA_MONOSPACE.into();
+        }
+        let mut ret = String::new();
+        if self.bold { ret.push('*'); }
+        if self.italic { ret.push('_'); }
+        if self.strike { ret.push('~'); }
+        ret
+    }
+    fn close(&self) -> String {
+        self.open().chars().rev().collect()
+    }
+}
+/// Skip the colour numbers after a `\x03` (like `4` or `04,12`), starting at `i`.
+fn skip_colour(chars: &[char], mut i: usize, hex: bool) -> usize {
+    let (digits, max) = if hex { (16, 6) } else { (10, 2) };
+    let count = |i: usize| (i..chars.len())
+        .take(max)
+        .take_while(|&j| chars[j].is_digit(digits))
+        .count();
+    let n = count(i);
+    if n == 0 {
+        return i;
+    }
+    i += n;
+    if chars.get(i) == Some(&',') {
+        let m = count(i + 1);
+        if m > 0 {
+            i += 1 + m;
+        }
+    }
+    i
+}
+/// Whether the literal character at `i` would be treated as WA formatting,
+/// because it can open a span and something later closes it (using the same
+/// rules as `wa_to_irc_inner()`).
+fn would_format(chars: &[char], urls: &[bool], i: usize) -> bool {
+    let c = chars[i];
+    if starts_with_at(chars, i, WA_MONOSPACE) {
+        let start = i + WA_MONOSPACE.len();
+        return (start + 1..chars.len()).any(|j| starts_with_at(chars, j, WA_MONOSPACE));
+    }
+    irc_code_for(c).is_some() && can_open(chars, i) &&
+        (i + 2..chars.len())
+        .take_while(|&j| chars[j] != '\n')
+        .any(|j| chars[j] == c && !urls[j] && can_close(chars, j))
+}
+/// Stop literal formatting characters in unformatted text being treated as WA
+/// formatting. URLs are left alone, so they still work.
+fn escape_wa(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let urls = url_mask(&chars);
+    let mut ret = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        ret.push(c);
+        if !urls[i] && would_format(&chars, &urls, i) {
+            ret.push(WA_ESCAPE);
+        }
+    }
+    ret
+}
+fn push_segment(out: &mut String, style: Style, text: &str) {
+    let text = if style.monospace { text.to_owned() } else { escape_wa(text) };
+    let core = text.trim();
+    if core.len() == 0 || style == Style::default() {
+        out.push_str(&text);
+        return;
+    }
+    // WA only notices markers next to non-whitespace, so they go inside any
+    // surrounding spaces.
+    let start = text.find(core).unwrap();
+    out.push_str(&text[..start]);
+    out.push_str(&style.open());
+    out.push_str(core);
+    out.push_str(&style.close());
+    out.push_str(&text[start + core.len()..]);
+}
+/// Convert IRC control codes in `text` to WA formatting, stripping the ones
+/// WA can't do (like colours and underline).
+pub fn irc_to_wa(text: &str) -> String {
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut ret = String::with_capacity(text.len());
+    let mut style = Style::default();
+    let mut segment = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        let mut new = style;
+        if c == IRC_BOLD { new.bold = !new.bold; }
+        else if c == IRC_ITALIC { new.italic = !new.italic; }
+        else if c == IRC_STRIKE { new.strike = !new.strike; }
+        else if c == IRC_MONOSPACE { new.monospace = !new.monospace; }
+        else if c == IRC_RESET { new = Style::default(); }
+        else if c == IRC_COLOUR { i = skip_colour(&chars, i, false); continue; }
+        else if c == IRC_HEX_COLOUR { i = skip_colour(&chars, i, true); continue; }
+        else if c == IRC_UNDERLINE || c == IRC_REVERSE { continue; }
+        else {
+            segment.push(c);
+            continue;
+        }
+        if new != style {
+            push_segment(&mut ret, style, &segment);
+            segment.clear();
+            style = new;
+        }
+    }
+    push_segment(&mut ret, style, &segment);
+    ret
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(irc: &str, wa: &str) {
+        assert_eq!(irc_to_wa(irc), wa);
+        assert_eq!(wa_to_irc(wa), irc);
+    }
+
+    #[test]
+    fn simple() {
+        round_trip("plain text", "plain text");
+        round_trip("\x02bold\x02 and \x1ditalic\x1d", "*bold* and _italic_");
+        round_trip("\x1estruck\x1e", "~struck~");
+    }
+
+    #[test]
+    fn nesting() {
+        assert_eq!(irc_to_wa("\x02bold \x1dboth\x1d\x02"), "*bold* *_both_*");
+        assert_eq!(wa_to_irc("*bold* *_both_*"), "\x02bold\x02 \x02\x1dboth\x1d\x02");
+        assert_eq!(wa_to_irc("*bold _both_ bold*"), "\x02bold \x1dboth\x1d bold\x02");
+    }
+
+    #[test]
+    fn monospace() {
+        round_trip("\x11let x = *y_z;\x11", "```let x = *y_z;```");
+        assert_eq!(wa_to_irc("``````"), "``````");
+    }
+
+    #[test]
+    fn spaces_stay_outside_markers() {
+        assert_eq!(irc_to_wa("a\x02 bold \x02b"), "a *bold* b");
+    }
+
+    #[test]
+    fn stripped_codes() {
+        assert_eq!(irc_to_wa("\x0304,12red\x03 \x1funder\x1f \x0fdone"), "red under done");
+    }
+
+    #[test]
+    fn escaping() {
+        round_trip("*not bold*", "*\u{200b}not bold*");
+        round_trip("_a_ ~b~", "_\u{200b}a_ ~\u{200b}b~");
+        round_trip("```not mono```", "`\u{200b}``not mono```");
+    }
+
+    #[test]
+    fn unmatched_markers_not_escaped() {
+        round_trip("2 * 3 = 6", "2 * 3 = 6");
+        round_trip("*nope", "*nope");
+        round_trip("snake_case_name", "snake_case_name");
+        round_trip("_one\ntwo_", "_one\ntwo_");
+    }
+
+    #[test]
+    fn urls_untouched() {
+        round_trip("see https://x/_next/ here", "see https://x/_next/ here");
+        round_trip("*see* https://x/*y*", "*\u{200b}see* https://x/*y*");
+    }
+}
@@ -0,0 +1,114 @@
+//! The GSM 7-bit default alphabet.
+//!
+//! This module doesn't depend on anything else in sms-irc, so the `fake-modem`
+//! tool can use it too.
+
+/// The GSM 03.38 default alphabet, indexed by septet value (0x1B is the escape to
+/// the extension table, which we don't support).
+static GSM7_BASIC: &str = "@£$¥èéùìòÇ\nØø\rÅåΔ_ΦΓΛΩΠΨΣΘΞ\u{1b}ÆæßÉ !\"#¤%&'()*+,-./0123456789:;<=>?¡ABCDEFGHIJKLMNOPQRSTUVWXYZÄÖÑÜ§¿abcdefghijklmnopqrstuvwxyzäöñüà";
+
+/// Characters in the extension table, which take two septets (the escape, then
+/// the character).
+static GSM7_EXTENSION: &str = "^{}\\[~]|€";
+
+/// Get the septet for `ch` in the GSM 7-bit default alphabet, if it has one.
+pub fn char_to_gsm7(ch: char) -> Option<u8> {
+    if ch == '\u{1b}' {
+        return None;
+    }
+    GSM7_BASIC.chars().position(|x| x == ch).map(|x| x as u8)
+}
+/// How many septets `ch` takes up in GSM 7-bit encoding (including the extension
+/// table), if it can be encoded at all.
+pub fn gsm7_len(ch: char) -> Option<usize> {
+    if char_to_gsm7(ch).is_some() {
+        Some(1)
+    }
+    else if GSM7_EXTENSION.contains(ch) {
+        Some(2)
+    }
+    else {
+        None
+    }
+}
+/// Pack septets into octets (3GPP TS 23.038 § 6.1.2.1), starting after
+/// `fill_bits` bits of padding.
+///
+/// If the last octet has 7 bits to spare, they're filled with a carriage
+/// return, so it isn't mistaken for an `@` (3GPP TS 23.038 § 6.1.2.3.1).
+pub fn pack_septets(septets: &[u8], fill_bits: usize) -> Vec<u8> {
+    let mut ret = vec![];
+    let mut acc: u32 = 0;
+    let mut bits = fill_bits;
+    for &s in septets {
+        acc |= ((s & 0x7F) as u32) << bits;
+        bits += 7;
+        while bits >= 8 {
+            ret.push((acc & 0xFF) as u8);
+            acc >>= 8;
+            bits -= 8;
+        }
+    }
+    if bits == 1 {
+        acc |= 0x0D << 1;
+    }
+    if bits > 0 {
+        ret.push((acc & 0xFF) as u8);
+    }
+    ret
+}
+/// Get the character for a septet in the GSM 7-bit default alphabet.
+pub fn gsm7_to_char(septet: u8) -> char {
+    GSM7_BASIC.chars().nth((septet & 0x7F) as usize).unwrap_or('?')
+}
+/// Unpack octets into septets (the reverse of `pack_septets`, with no fill bits).
+pub fn unpack_septets(octets: &[u8]) -> Vec<u8> {
+    let mut ret = vec![];
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for &o in octets {
+        acc |= (o as u32) << bits;
+        bits += 8;
+        while bits >= 7 {
+            ret.push((acc & 0x7F) as u8);
+            acc >>= 7;
+            bits -= 7;
+        }
+    }
+    // If the last octet had 7 bits of padding, that's not a septet; if it's a
+    // carriage return, it's padding too (3GPP TS 23.038 § 6.1.2.3.1).
+    if octets.len() % 7 == 0 && ret.last() == Some(&0x0D) {
+        ret.pop();
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn septets(s: &str) -> Vec<u8> {
+        s.chars().map(|x| char_to_gsm7(x).unwrap()).collect()
+    }
+
+    #[test]
+    fn seven_septets_are_padded_with_cr() {
+        let packed = pack_septets(&septets("*100*1#"), 0);
+        assert_eq!(packed, vec![0xAA, 0x18, 0x0C, 0xA6, 0x8A, 0x8D, 0x1A]);
+        assert_eq!(unpack_septets(&packed), septets("*100*1#"));
+    }
+
+    #[test]
+    fn eight_septets_need_no_padding() {
+        let packed = pack_septets(&septets("*100*11#"), 0);
+        assert_eq!(packed.len(), 7);
+        assert_eq!(unpack_septets(&packed), septets("*100*11#"));
+    }
+
+    #[test]
+    fn short_strings_round_trip() {
+        for s in &["", "a", "hello", "*#06#", "@@@@@@@"] {
+            assert_eq!(unpack_septets(&pack_septets(&septets(s), 0)), septets(s), "{:?}", s);
+        }
+    }
+}
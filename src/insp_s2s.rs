@@ -7,24 +7,53 @@ use irc::proto::command::Command;
 use futures::{Future, Async, Poll, Stream, Sink, self};
 use futures::future::Either;
 use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use crate::comm::{ControlBotCommand, ContactFactoryCommand, InitParameters, WhatsappCommand, ModemCommand, ContactManagerCommand};
+use crate::comm::{ControlBotCommand, ContactFactoryCommand, InitParameters, WhatsappCommand, ModemCommand, ContactManagerCommand, GroupChange};
 use crate::store::Store;
 use huawei_modem::pdu::{PduAddress, DeliverPdu};
 use std::collections::{HashSet, HashMap};
 use failure::Error;
-use crate::models::Recipient;
-use crate::util::Result;
+use crate::models::{Recipient, Message as DbMessage};
+use crate::util::{self, Result};
 use crate::contact_common::ContactManagerManager;
-use crate::sender_common::Sender;
+use crate::sender_common::{Sender, ReassemblyTimeouts};
+use crate::sms_compose::{SmsComposer, PendingSms, ComposeConfig};
 use crate::control_common::ControlCommon;
 use crate::insp_user::InspUser;
 use crate::config::InspConfig;
 use crate::admin::InspCommand;
 use std::net::{SocketAddr, ToSocketAddrs};
+use whatsappweb::Jid;
 
 pub static INSP_PROTOCOL_CAPAB: &str = "PROTOCOL=1202";
 pub static INSP_PROTOCOL_VERSION: &str = "1202";
 
+/// Get the `+o` and `-o` changes (as whether it's `+o`, and the target) from
+/// the modes and parameters of an FMODE.
+fn op_changes(modes: &str, mut params: &[String]) -> Vec<(bool, String)> {
+    let mut ret = vec![];
+    let mut adding = true;
+    for c in modes.chars() {
+        let takes_param = match c {
+            '+' => { adding = true; continue; },
+            '-' => { adding = false; continue; },
+            // FIXME: modules can add other modes with parameters; we
+            // should really look at CHANMODES in the remote CAPAB.
+            'q' | 'a' | 'o' | 'h' | 'v' | 'b' | 'e' | 'I' | 'k' => true,
+            'l' => adding,
+            _ => false
+        };
+        if !takes_param {
+            continue;
+        }
+        if let Some((param, rest)) = params.split_first() {
+            if c == 'o' {
+                ret.push((adding, param.clone()));
+            }
+            params = rest;
+        }
+    }
+    ret
+}
 struct InspContact {
     uuid: String,
     channels: Vec<String>,
@@ -60,6 +89,8 @@ pub struct InspLink {
     outbox: Vec<Message>,
     channels: HashSet<String>,
     state: LinkState,
+    reassembly_timeouts: ReassemblyTimeouts,
+    pending_sms: PendingSms,
 }
 
 impl Future for InspLink {
@@ -111,8 +142,12 @@ impl ContactManagerManager for InspLink {
             channels: vec![],
             wa_mode: recip.whatsapp
         });
-        self.contacts_uuid_pdua.insert(uuid, addr.clone());
+        self.contacts_uuid_pdua.insert(uuid.clone(), addr.clone());
         if self.state == LinkState::Linked {
+            // (If we aren't linked yet, `process_avatars()` sends it once we are.)
+            if let Some(ref url) = recip.avatar_url {
+                self.send_sid_line("METADATA", vec![&uuid, "avatar"], Some(url))?;
+            }
             self.process_groups_for_recipient(&addr)?;
         }
         Ok(())
@@ -177,6 +212,9 @@ impl ContactManagerManager for InspLink {
                     ct.wa_mode = wam;
                     self.set_wa_state(&a, wam)?;
                 },
+                ContactManagerCommand::ProcessMessages => {
+                    self.process_messages()?;
+                },
                 _ => {}
             }
         }
@@ -272,6 +310,14 @@ impl Sender for InspLink {
         self.contact_message(uid, "PRIVMSG", to, msg)?;
         Ok(())
     }
+    fn reassembly_timeout(&mut self, backend: Option<&str>) -> u64 {
+        self.reassembly_timeouts.get(backend)
+    }
+}
+impl SmsComposer for InspLink {
+    fn pending_sms(&mut self) -> &mut PendingSms {
+        &mut self.pending_sms
+    }
 }
 impl InspLink {
     fn _make_addr_and_codec(cfg: &InspConfig) -> Result<(SocketAddr, IrcCodec)> {
@@ -299,6 +345,8 @@ impl InspLink {
         let cb_tx = p.cm.cb_tx.clone();
         let wa_tx = p.cm.wa_tx.clone();
         let m_tx = p.cm.modem_tx.clone();
+        let reassembly_timeouts = ReassemblyTimeouts::new(p.cfg);
+        let pending_sms = PendingSms::new(ComposeConfig::new(p.cfg));
         let (addr, codec) = match Self::_make_addr_and_codec(&cfg) {
             Ok(x) => x,
             Err(e) => return Either::B(futures::future::err(e))
@@ -322,6 +370,7 @@ impl InspLink {
                     channels: HashSet::new(),
                     remote_sid: "XXX".into(),
                     state: LinkState::TcpConnected,
+                    reassembly_timeouts, pending_sms
                 }
             })
             .map_err(|e| e.into());
@@ -440,26 +489,45 @@ impl InspLink {
                 }
                 else if self.channels.contains(&target) {
                     debug!("Sending WA group message for {}", target);
-                    self.wa_tx.unbounded_send(WhatsappCommand::SendGroupMessage(target, msg))
+                    self.wa_tx.unbounded_send(WhatsappCommand::SendGroupMessage(target, msg, None))
                         .unwrap()
                 }
                 else {
-                    if let Some(addr) = self.contacts_uuid_pdua.get(&target) {
-                        if let Some(ct) = self.contacts.get(&addr) {
-                            if ct.wa_mode {
-                                debug!("Sending WA DM for {}", target);
-                                self.wa_tx.unbounded_send(WhatsappCommand::SendDirectMessage(addr.clone(), msg))
-                                    .unwrap();
-                            }
-                            else {
-                                debug!("Sending modem DM for {}", target);
-                                self.m_tx.unbounded_send(ModemCommand::SendMessage(addr.clone(), msg))
-                                    .unwrap();
-                            }
+                    let addr = self.contacts_uuid_pdua.get(&target).cloned();
+                    let wa_mode = addr.as_ref()
+                        .and_then(|a| self.contacts.get(a))
+                        .map(|ct| ct.wa_mode);
+                    if let (Some(addr), Some(wa_mode)) = (addr, wa_mode) {
+                        if wa_mode {
+                            debug!("Sending WA DM for {}", target);
+                            self.wa_tx.unbounded_send(WhatsappCommand::SendDirectMessage(addr, msg, None))
+                                .unwrap();
+                        }
+                        else if let Some(text) = self.compose_sms(&target, &addr, msg)? {
+                            debug!("Sending modem DM for {}", target);
+                            self.m_tx.unbounded_send(ModemCommand::SendMessage(addr, text))
+                                .unwrap();
                         }
                     }
                 }
             },
+            Command::TOPIC(chan, Some(topic)) => {
+                if self.is_admin(&prefix) && self.channels.contains(&chan) {
+                    // Forget the topic, so it gets put back if the change fails.
+                    self.channel_topics.remove(&chan);
+                    self.send_group_change(chan, GroupChange::Subject(topic));
+                }
+            },
+            Command::KICK(chan, target, _) => {
+                if self.is_admin(&prefix) && self.channels.contains(&chan) {
+                    if let Some(addr) = self.group_change_addr(&target)? {
+                        self.send_group_change(chan, GroupChange::Remove(addr));
+                    }
+                }
+            },
+            Command::INVITE(target, chan) => {
+                self.handle_invite(&prefix, &target, chan)?;
+            },
             Command::ERROR(details) => {
                 Err(format_err!("Error from server: {}", details))?
             },
@@ -537,6 +605,30 @@ impl InspLink {
                             user.displayed_hostname = host;
                         }
                     },
+                    "INVITE" => {
+                        // Newer servers add an expiry time on the end.
+                        if args.len() >= 2 {
+                            let chan = args[1].clone();
+                            self.handle_invite(&prefix, &args[0], chan)?;
+                        }
+                    },
+                    "FMODE" => {
+                        // :<uid> FMODE <chan> <ts> <modes> {<params>}
+                        if args.len() < 3 || !self.is_admin(&prefix) || !self.channels.contains(&args[0]) {
+                            return Ok(());
+                        }
+                        for (adding, target) in op_changes(&args[2], &args[3..]) {
+                            if let Some(addr) = self.group_change_addr(&target)? {
+                                let change = if adding {
+                                    GroupChange::Promote(addr)
+                                }
+                                else {
+                                    GroupChange::Demote(addr)
+                                };
+                                self.send_group_change(args[0].clone(), change);
+                            }
+                        }
+                    },
                     "BURST" => {
                         debug!("Receiving burst");
                     },
@@ -566,17 +658,100 @@ impl InspLink {
         info!("Link established to remote server.");
         self.outbox.push(Message::new(Some(&self.control_uuid), "JOIN", vec![&self.cfg.log_chan], None)?);
         self.process_groups()?;
+        self.process_avatars()?;
         self.process_messages()?;
         Ok(())
     }
+    /// Send the avatar URLs of contacts and groups that have one as METADATA.
+    fn process_avatars(&mut self) -> Result<()> {
+        for recip in self.store.get_all_recipients()? {
+            let addr = recip.get_addr()?;
+            if let (Some(uuid), Some(url)) = (self.contacts.get(&addr).map(|ct| ct.uuid.clone()), recip.avatar_url) {
+                self.send_sid_line("METADATA", vec![&uuid, "avatar"], Some(&url))?;
+            }
+        }
+        for grp in self.store.get_all_groups()? {
+            if let Some(url) = grp.avatar_url {
+                self.send_sid_line("METADATA", vec![&grp.channel, "avatar"], Some(&url))?;
+            }
+        }
+        Ok(())
+    }
+    /// Send the avatar URL of one contact or group as METADATA, since it changed.
+    fn process_avatar(&mut self, jid: &Jid) -> Result<()> {
+        if self.state != LinkState::Linked {
+            // We send them all once we're linked.
+            return Ok(());
+        }
+        let (target, url) = if jid.is_group {
+            match self.store.get_group_by_jid_opt(jid)? {
+                Some(grp) => (grp.channel, grp.avatar_url),
+                None => return Ok(())
+            }
+        }
+        else {
+            let addr = match util::jid_to_address(jid) {
+                Some(a) => a,
+                None => return Ok(())
+            };
+            let uuid = match self.contacts.get(&addr) {
+                Some(ct) => ct.uuid.clone(),
+                None => return Ok(())
+            };
+            (uuid, self.store.get_recipient_by_addr_opt(&addr)?.and_then(|r| r.avatar_url))
+        };
+        // The avatar was removed if there isn't one; an empty value deletes
+        // the key.
+        let url = url.unwrap_or_default();
+        self.send_sid_line("METADATA", vec![&target, "avatar"], Some(&url))?;
+        Ok(())
+    }
     fn admin_uuid(&mut self) -> Option<String> {
+        let nick = self.cfg.admin_nick.clone();
+        self.uuid_for_nick(&nick)
+    }
+    fn uuid_for_nick(&mut self, nick: &str) -> Option<String> {
         for (uuid, user) in self.users.iter() {
-            if user.nick == self.cfg.admin_nick {
+            if user.nick == nick {
                 return Some(uuid.to_owned());
             }
         }
         None
     }
+    fn handle_invite(&mut self, prefix: &str, target: &str, chan: String) -> Result<()> {
+        if self.is_admin(prefix) && self.channels.contains(&chan) {
+            if let Some(addr) = self.group_change_addr(target)? {
+                self.send_group_change(chan, GroupChange::Add(addr));
+            }
+        }
+        Ok(())
+    }
+    /// Get the address of the user with UUID `target` for a group change,
+    /// telling the admin with ERR_NOSUCHNICK if they aren't one of our contacts.
+    fn group_change_addr(&mut self, target: &str) -> Result<Option<PduAddress>> {
+        if let Some(addr) = self.contacts_uuid_pdua.get(target) {
+            return Ok(Some(addr.clone()));
+        }
+        let admin = self.cfg.admin_nick.clone();
+        match self.users.get(target).map(|u| u.nick.clone()) {
+            Some(nick) => self.report_numeric(&admin, "401", &[nick], "That nick isn't a WhatsApp user.")?,
+            None => self.report_numeric(&admin, "401", &[target.to_owned()], "No such nick.")?
+        }
+        Ok(None)
+    }
+    fn report_numeric(&mut self, nick: &str, num: &str, args: &[String], text: &str) -> Result<()> {
+        let line = format!(":{} {} {} {} :{}", self.cfg.server_name, num, nick, args.join(" "), text);
+        self.push_line(nick, &line)
+    }
+    /// Ask for a change to the WA group bridged to `chan`, made by the admin.
+    fn send_group_change(&mut self, chan: String, change: GroupChange) {
+        let origin = self.cfg.admin_nick.clone();
+        self.wa_tx.unbounded_send(WhatsappCommand::GroupChange(chan, origin, change))
+            .unwrap();
+    }
+    fn is_admin(&mut self, uuid: &str) -> bool {
+        self.admin_uuid().as_ref().map(|x| x as &str) == Some(uuid)
+    }
     fn process_groups_for_recipient(&mut self, a: &PduAddress) -> Result<()> {
         // FIXME: It becomes delicious copypasta, they shall eat it.
 
@@ -662,11 +837,7 @@ impl InspLink {
             ForwardCommand(a, cmd) => self.forward_cmd(&a, cmd)?,
             ForwardCommandByNick(a, cmd) => self.forward_cmd_by_nick(&a, cmd)?,
             SubscribePresenceByNick(nick) => self.subscribe_presence_by_nick(nick),
-            ProcessAvatars => {
-                // FIXME: implement
-                //
-                // We'd need IRCv3 METADATA or something for this.
-            }
+            ProcessAvatar(jid) => self.process_avatar(&jid)?
         }
         Ok(())
     }
@@ -690,6 +861,13 @@ impl InspLink {
             CommandResponse(resp) => {
                 self.control_response(&resp)?;
             },
+            ReportNumeric(nick, num, args, text) => {
+                self.report_numeric(&nick, &num, &args, &text)?;
+            },
+            ReportNotice(nick, text) => {
+                let line = format!(":{} NOTICE {} :{}", self.cfg.server_name, nick, text);
+                self.push_line(&nick, &line)?;
+            },
             ProcessGroups => self.process_groups()?,
         }
         Ok(())
@@ -751,13 +929,22 @@ impl InspLink {
                 let ct = self.contacts.get(&addr).unwrap();
                 (ct.uuid.clone(), ct.wa_mode)
             };
-            if msg.pdu.is_some() {
-                let pdu = DeliverPdu::try_from(msg.pdu.as_ref().unwrap() as &[u8])?;
+            if msg.source == DbMessage::SOURCE_NOTICE {
+                self.process_msg_plain(&uuid, msg)?;
+            }
+            else if msg.source == DbMessage::SOURCE_SMS {
                 if is_wa {
                     self.set_wa_state(&addr, false)?;
                     self.contact_message(&uuid, "NOTICE", &auid, "Notice: SMS mode automatically enabled.")?;
                 }
-                self.process_msg_pdu(&uuid, msg, pdu)?;
+                if msg.pdu.is_some() {
+                    let pdu = DeliverPdu::try_from(msg.pdu.as_ref().unwrap() as &[u8])?;
+                    self.process_msg_pdu(&uuid, msg, pdu)?;
+                }
+                else {
+                    // SMS backends without PDUs (e.g. HTTP gateways) just give us text.
+                    self.process_msg_plain(&uuid, msg)?;
+                }
             }
             else {
                 if !is_wa {
@@ -769,6 +956,16 @@ impl InspLink {
         }
         Ok(())
     }
+    /// Send a raw line to a local user of the server, by nick.
+    fn push_line(&mut self, nick: &str, line: &str) -> Result<()> {
+        if let Some(uuid) = self.uuid_for_nick(nick) {
+            self.send_sid_line("PUSH", vec![&uuid], Some(line))?;
+        }
+        else {
+            warn!("Couldn't send line to {}, who isn't here: {}", nick, line);
+        }
+        Ok(())
+    }
     fn send_sid_line(&mut self, cmd: &str, args: Vec<&str>, suffix: Option<&str>) -> Result<()> {
         let m = Message::new(Some(&self.cfg.sid), cmd, args, suffix)?;
         self.send(m);
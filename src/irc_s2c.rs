@@ -5,19 +5,22 @@
 use tokio_core::net::{TcpListener, Incoming, TcpStream};
 use tokio_codec::Framed;
 use irc::proto::IrcCodec;
-use irc::proto::message::Message;
+use irc::proto::message::{Message, Tag};
 use irc::proto::command::Command;
+use irc::proto::mode::{Mode, ChannelMode};
 use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
 use futures::{Future, Async, Poll, Stream, Sink, self};
 use failure::{Error, format_err};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::collections::VecDeque;
 use std::collections::HashMap;
-use huawei_modem::pdu::DeliverPdu;
+use huawei_modem::pdu::{PduAddress, DeliverPdu};
 
 use crate::util::Result;
-use crate::sender_common::Sender;
+use crate::sender_common::{Sender, ReassemblyTimeouts};
+use crate::sms_compose::{SmsComposer, PendingSms, ComposeConfig};
 use crate::irc_s2c_registration::{PendingIrcConnectionWrapper, RegistrationInformation};
+use crate::irc_s2c_v3::IrcCap;
 use crate::config::IrcServerConfig;
 use crate::comm::InitParameters;
 use crate::models::Group;
@@ -48,6 +51,8 @@ pub struct IrcConnection {
     wa_outbox: VecDeque<WhatsappCommand>,
     m_outbox: VecDeque<ModemCommand>,
     cf_outbox: VecDeque<ContactFactoryCommand>,
+    reassembly_timeouts: ReassemblyTimeouts,
+    pending_sms: PendingSms,
     new: bool
 }
 
@@ -58,6 +63,8 @@ pub struct IrcServer {
     m_tx: UnboundedSender<ModemCommand>,
     _cfg: IrcServerConfig,
     store: Store,
+    reassembly_timeouts: ReassemblyTimeouts,
+    compose_cfg: ComposeConfig,
     incoming: Incoming,
     connections: Vec<IrcConnection>,
     pending: Vec<PendingIrcConnectionWrapper>
@@ -90,7 +97,7 @@ impl Future for IrcServer {
         while let Async::Ready(inc) = self.incoming.poll()? {
             let (ts, sa) = inc.ok_or(format_err!("TCP listener stopped"))?;
             info!("New connection from {}", sa);
-            let pending = PendingIrcConnectionWrapper::from_incoming(ts, sa, self.store.clone())?;
+            let pending = PendingIrcConnectionWrapper::from_incoming(ts, sa, self.store.clone(), self.reassembly_timeouts.clone(), self.compose_cfg)?;
             self.pending.push(pending);
         }
         let mut to_remove = vec![];
@@ -148,8 +155,10 @@ impl IrcServer {
         let listener = TcpListener::bind(&addr, &p.hdl)?;
         info!("Listening on {} for connections", addr);
         let incoming = listener.incoming();
+        let reassembly_timeouts = ReassemblyTimeouts::new(p.cfg);
+        let compose_cfg = ComposeConfig::new(p.cfg);
         Ok(Self {
-            store, _cfg: cfg, incoming,
+            store, _cfg: cfg, incoming, reassembly_timeouts, compose_cfg,
             cb_rx: p.cm.cb_rx.take().unwrap(),
             cf_rx: p.cm.cf_rx.take().unwrap(),
             wa_tx: p.cm.wa_tx.clone(),
@@ -167,7 +176,9 @@ impl IrcServer {
         Ok(())
     }
     pub fn handle_contact(&mut self, cfc: ContactFactoryCommand) -> Result<()> {
-        if let ContactFactoryCommand::ProcessMessages = cfc {
+        use self::ContactFactoryCommand::*;
+
+        if let ProcessMessages | ForwardCommand(_, ContactManagerCommand::ProcessMessages) = cfc {
             for c in self.connections.iter_mut() {
                 if let Err(e) = c.process_messages() {
                     warn!("Connection on {} failed to process messages: {}", c.addr, e);
@@ -183,10 +194,13 @@ impl IrcConnection {
         sock: Framed<TcpStream, IrcCodec>,
         addr: SocketAddr,
         store: Store,
-        reginfo: RegistrationInformation
+        reginfo: RegistrationInformation,
+        reassembly_timeouts: ReassemblyTimeouts,
+        compose_cfg: ComposeConfig
         ) -> Self {
         Self {
-            sock, addr, reginfo, store,
+            sock, addr, reginfo, store, reassembly_timeouts,
+            pending_sms: PendingSms::new(compose_cfg),
             outbox: vec![],
             joined_groups: HashMap::new(),
             wa_outbox: VecDeque::new(),
@@ -208,6 +222,14 @@ impl IrcConnection {
             CommandResponse(thing) => {
                 self.outbox.push(Message::new(Some("root"), "PRIVMSG", vec!["&smsirc"], Some(&thing))?);
             },
+            ReportNumeric(nick, num, args, text) => {
+                let mut new_args = vec![&nick as &str];
+                new_args.extend(args.iter().map(|x| x as &str));
+                self.outbox.push(Message::new(Some(&SERVER_NAME), &num, new_args, Some(&text as &str))?);
+            },
+            ReportNotice(nick, text) => {
+                self.outbox.push(Message::new(Some(&SERVER_NAME), "NOTICE", vec![&nick as &str], Some(&text as &str))?);
+            },
             ProcessGroups => {}
         }
         Ok(())
@@ -319,7 +341,40 @@ impl IrcConnection {
         self.reply_s2c("376", vec![], "End of /MOTD command.")?;
         Ok(())
     }
+    fn whois(&mut self, nick: &str) -> Result<()> {
+        if let Some(recip) = self.store.get_recipient_by_nick_opt(nick)? {
+            let realname = recip.notify.clone().unwrap_or_else(|| recip.phone_number.clone());
+            self.reply_s2c("311", vec![&recip.nick, &recip.nick, SERVER_NAME, "*"], Some(&realname as &str))?;
+            let via = if recip.whatsapp { "WhatsApp" } else { "SMS" };
+            let desc = format!("is a {} contact ({})", via, recip.phone_number);
+            self.reply_s2c("320", vec![&recip.nick], Some(&desc as &str))?;
+            if let Some(ref url) = recip.avatar_url {
+                let desc = format!("has an avatar at {}", url);
+                self.reply_s2c("320", vec![&recip.nick], Some(&desc as &str))?;
+            }
+        }
+        else {
+            self.reply_s2c("401", vec![nick], "No such nick.")?;
+        }
+        self.reply_s2c("318", vec![nick], "End of /WHOIS list.")?;
+        Ok(())
+    }
+    /// Get the address of `nick` for a group change, replying with
+    /// ERR_NOSUCHNICK if there's no such recipient.
+    fn group_change_addr(&mut self, nick: &str) -> Result<Option<PduAddress>> {
+        match self.store.get_recipient_by_nick_opt(nick)? {
+            Some(recip) => Ok(Some(recip.get_addr()?)),
+            None => {
+                self.reply_s2c("401", vec![nick], "Unknown nickname.")?;
+                Ok(None)
+            }
+        }
+    }
     fn handle_remote_message(&mut self, msg: Message) -> Result<()> {
+        // With message-tags, the client can tell us which message it's replying to.
+        let reply_to = msg.tags.as_ref()
+            .and_then(|tags| tags.iter().find(|t| t.0 == "+draft/reply"))
+            .and_then(|t| t.1.clone());
         match msg.command {
             Command::PING(tok, _) => {
                 self.reply_s2c("PONG", vec![], Some(&tok as &str))?;
@@ -339,11 +394,60 @@ impl IrcConnection {
                 self.reply_s2c("442", vec![&chan], Some("You may not part."))?;
             },
             Command::ChannelMODE(target, modes) => {
-                if modes.len() > 0 {
-                    self.reply_s2c("482", vec![&target], Some("You may not alter channel modes."))?;
+                let ops_only = modes.iter().all(|m| match *m {
+                    Mode::Plus(ChannelMode::Oper, Some(_)) | Mode::Minus(ChannelMode::Oper, Some(_)) => true,
+                    _ => false
+                });
+                if modes.len() == 0 {
+                    self.reply_s2c("324", vec![&target, "+nt"], None)?;
+                }
+                else if ops_only && self.joined_groups.contains_key(&target) {
+                    for m in modes {
+                        let (promote, nick) = match m {
+                            Mode::Plus(_, Some(n)) => (true, n),
+                            Mode::Minus(_, Some(n)) => (false, n),
+                            _ => unreachable!()
+                        };
+                        if let Some(addr) = self.group_change_addr(&nick)? {
+                            let change = if promote {
+                                GroupChange::Promote(addr)
+                            }
+                            else {
+                                GroupChange::Demote(addr)
+                            };
+                            self.wa_outbox.push_back(WhatsappCommand::GroupChange(target.clone(), self.reginfo.nick.clone(), change));
+                        }
+                    }
                 }
                 else {
-                    self.reply_s2c("324", vec![&target, "+nt"], None)?;
+                    self.reply_s2c("482", vec![&target], Some("You may only alter +o in WhatsApp group channels."))?;
+                }
+            },
+            Command::TOPIC(chan, topic) => {
+                let current = self.joined_groups.get(&chan).map(|g| g.topic.clone());
+                match (current, topic) {
+                    (None, _) => {
+                        self.reply_s2c("403", vec![&chan], "No such channel.")?;
+                    },
+                    (Some(cur), None) => {
+                        self.reply_s2c("332", vec![&chan], Some(&cur as &str))?;
+                    },
+                    (Some(_), Some(topic)) => {
+                        self.wa_outbox.push_back(WhatsappCommand::GroupChange(chan, self.reginfo.nick.clone(), GroupChange::Subject(topic)));
+                    }
+                }
+            },
+            Command::WHOIS(_, nick) => {
+                self.whois(&nick)?;
+            },
+            Command::KICK(chan, nick, _) => {
+                if let Some(addr) = self.group_change_addr(&nick)? {
+                    self.wa_outbox.push_back(WhatsappCommand::GroupChange(chan, self.reginfo.nick.clone(), GroupChange::Remove(addr)));
+                }
+            },
+            Command::INVITE(nick, chan) => {
+                if let Some(addr) = self.group_change_addr(&nick)? {
+                    self.wa_outbox.push_back(WhatsappCommand::GroupChange(chan, self.reginfo.nick.clone(), GroupChange::Add(addr)));
                 }
             },
             Command::PRIVMSG(target, msg) => {
@@ -352,16 +456,16 @@ impl IrcConnection {
                 }
                 else if target.starts_with("#") {
                     // FIXME: check the channel actually exists
-                    self.wa_outbox.push_back(WhatsappCommand::SendGroupMessage(target, msg));
+                    self.wa_outbox.push_back(WhatsappCommand::SendGroupMessage(target, msg, reply_to));
                 }
                 else {
                     if let Some(recip) = self.store.get_recipient_by_nick_opt(&target)? { 
                         let addr = recip.get_addr()?;
                         if recip.whatsapp {
-                            self.wa_outbox.push_back(WhatsappCommand::SendDirectMessage(addr, msg));
+                            self.wa_outbox.push_back(WhatsappCommand::SendDirectMessage(addr, msg, reply_to));
                         }
-                        else {
-                            self.m_outbox.push_back(ModemCommand::SendMessage(addr, msg));
+                        else if let Some(text) = self.compose_sms(&target, &addr, msg)? {
+                            self.m_outbox.push_back(ModemCommand::SendMessage(addr, text));
                         }
                     }
                     else {
@@ -411,4 +515,23 @@ impl Sender for IrcConnection {
         self.reply_from_nick(from_nick, "PRIVMSG", vec![to], Some(&msg as &str))?;
         Ok(())
     }
+    fn send_irc_message_with_id(&mut self, from_nick: &str, to: &str, msg: &str, msgid: Option<&str>) -> Result<()> {
+        self.send_irc_message(from_nick, to, msg)?;
+        if let Some(id) = msgid {
+            if self.reginfo.caps.contains(&IrcCap::MessageTags) {
+                if let Some(m) = self.outbox.last_mut() {
+                    m.tags = Some(vec![Tag("msgid".into(), Some(id.into()))]);
+                }
+            }
+        }
+        Ok(())
+    }
+    fn reassembly_timeout(&mut self, backend: Option<&str>) -> u64 {
+        self.reassembly_timeouts.get(backend)
+    }
+}
+impl SmsComposer for IrcConnection {
+    fn pending_sms(&mut self) -> &mut PendingSms {
+        &mut self.pending_sms
+    }
 }
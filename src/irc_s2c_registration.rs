@@ -13,6 +13,8 @@ use crate::util::Result;
 use crate::irc_s2c_v3::{IrcCap, SUPPORTED_CAPS};
 use crate::irc_s2c::{IrcConnection, SERVER_NAME};
 use crate::store::Store;
+use crate::sms_compose::ComposeConfig;
+use crate::sender_common::ReassemblyTimeouts;
 
 pub struct RegistrationInformation {
     pub nick: String,
@@ -30,13 +32,15 @@ struct PendingIrcConnection {
     caps: Vec<IrcCap>,
     outbox: Vec<Message>,
     store: Store,
+    reassembly_timeouts: ReassemblyTimeouts,
+    compose_cfg: ComposeConfig,
     new: bool
 }
 pub struct PendingIrcConnectionWrapper {
     inner: Option<PendingIrcConnection>
 }
 impl PendingIrcConnectionWrapper {
-    pub fn from_incoming(ts: TcpStream, sa: SocketAddr, store: Store) -> Result<Self> {
+    pub fn from_incoming(ts: TcpStream, sa: SocketAddr, store: Store, reassembly_timeouts: ReassemblyTimeouts, compose_cfg: ComposeConfig) -> Result<Self> {
         let codec = IrcCodec::new("utf8")?;
         let ic = PendingIrcConnection {
             sock: Framed::new(ts, codec),
@@ -48,6 +52,8 @@ impl PendingIrcConnectionWrapper {
             caps: vec![],
             outbox: vec![],
             store,
+            reassembly_timeouts,
+            compose_cfg,
             new: true
         };
         Ok(Self { inner: Some(ic) })
@@ -80,7 +86,7 @@ impl Future for PendingIrcConnectionWrapper {
                 realname: conn.realname.unwrap(),
                 caps: conn.caps
             };
-            let ret = IrcConnection::from_pending(conn.sock, conn.addr, conn.store, reginfo);
+            let ret = IrcConnection::from_pending(conn.sock, conn.addr, conn.store, reginfo, conn.reassembly_timeouts, conn.compose_cfg);
             Ok(Async::Ready(ret))
         }
         else {
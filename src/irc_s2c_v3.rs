@@ -1,20 +1,25 @@
 //! IRCv3 support for IRC s2c.
 
-pub static SUPPORTED_CAPS: &str = "away-notify";
+pub static SUPPORTED_CAPS: &str = "away-notify message-tags";
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IrcCap {
     /// `away-notify` extension
     ///
     /// https://ircv3.net/specs/extensions/away-notify-3.1
-    AwayNotify
+    AwayNotify,
+    /// `message-tags` extension
+    ///
+    /// https://ircv3.net/specs/extensions/message-tags
+    MessageTags
 }
 impl IrcCap {
     pub fn cap_name(&self) -> &'static str {
         use self::IrcCap::*;
 
         match *self {
-            AwayNotify => "away-notify"
+            AwayNotify => "away-notify",
+            MessageTags => "message-tags"
         }
     }
     pub fn from_cap_name(cn: &str) -> Option<Self> {
@@ -22,6 +27,7 @@ impl IrcCap {
 
         match cn {
             "away-notify" => Some(AwayNotify),
+            "message-tags" => Some(MessageTags),
             _ => None
         }
     }
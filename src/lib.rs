@@ -0,0 +1,57 @@
+//! The guts of sms-irc, as a library so the integration tests can get at them.
+
+#![allow(proc_macro_derive_resolution_fallback)]
+
+#[macro_use] extern crate diesel;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate failure;
+#[macro_use] extern crate log;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate diesel_migrations;
+extern crate whatsappweb_eta as whatsappweb;
+
+pub mod config;
+pub mod logging;
+pub mod store;
+pub mod modem;
+pub mod modem_receipts;
+pub mod modem_health;
+pub mod modem_phonebook;
+pub mod modem_sim;
+pub mod modem_budget;
+pub mod modem_usb;
+pub mod modem_router;
+pub mod sms_backend;
+pub mod sms_http;
+pub mod sms_smpp;
+pub mod sms_smpp_pdu;
+pub mod sms_compose;
+pub mod pdu_debug;
+pub mod comm;
+#[macro_use]
+pub mod util;
+pub mod gsm7;
+#[cfg(feature = "fake-modem")]
+pub mod fake_modem;
+pub mod schema;
+pub mod models;
+pub mod contact;
+pub mod contact_factory;
+pub mod contact_common;
+pub mod admin;
+pub mod control;
+pub mod control_common;
+pub mod sender_common;
+pub mod whatsapp;
+pub mod whatsapp_media;
+pub mod whatsapp_conn;
+pub mod whatsapp_msg;
+pub mod whatsapp_ack;
+pub mod whatsapp_upload;
+pub mod whatsapp_history;
+pub mod formatting;
+pub mod insp_s2s;
+pub mod insp_user;
+pub mod irc_s2c;
+pub mod irc_s2c_registration;
+pub mod irc_s2c_v3;
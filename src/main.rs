@@ -1,52 +1,18 @@
-#![allow(proc_macro_derive_resolution_fallback)]
-
-#[macro_use] extern crate diesel;
-#[macro_use] extern crate serde_derive;
-#[macro_use] extern crate failure;
 #[macro_use] extern crate log;
-#[macro_use] extern crate lazy_static;
-#[macro_use] extern crate diesel_migrations;
-extern crate whatsappweb_eta as whatsappweb;
-
-mod config;
-mod logging;
-mod store;
-mod modem;
-mod comm;
-#[macro_use]
-mod util;
-mod schema;
-mod models;
-mod contact;
-mod contact_factory;
-mod contact_common;
-mod admin;
-mod control;
-mod control_common;
-mod sender_common;
-mod whatsapp;
-mod whatsapp_media;
-mod whatsapp_conn;
-mod whatsapp_msg;
-mod whatsapp_ack;
-mod insp_s2s;
-mod insp_user;
-mod irc_s2c;
-mod irc_s2c_registration;
-mod irc_s2c_v3;
 
-use crate::config::Config;
-use crate::store::Store;
-use crate::modem::ModemManager;
-use crate::control::ControlBot;
-use crate::comm::{ChannelMaker, InitParameters};
+use sms_irc::config::Config;
+use sms_irc::store::Store;
+use sms_irc::modem_router::ModemRouter;
+use sms_irc::control::ControlBot;
+use sms_irc::comm::{ChannelMaker, InitParameters};
 use futures::{Future, Stream};
-use crate::contact_factory::ContactFactory;
+use sms_irc::contact_factory::ContactFactory;
 use tokio_core::reactor::Core;
-use crate::insp_s2s::InspLink;
-use crate::irc_s2c::IrcServer;
-use crate::whatsapp::WhatsappManager;
+use sms_irc::insp_s2s::InspLink;
+use sms_irc::irc_s2c::IrcServer;
+use sms_irc::whatsapp::WhatsappManager;
 use tokio_signal::unix::{Signal, SIGHUP};
+use sms_irc::logging;
 use std::path::Path;
 
 fn main() -> Result<(), failure::Error> {
@@ -105,19 +71,19 @@ fn main() -> Result<(), failure::Error> {
     debug!("Initializing tokio");
     let mut core = Core::new()?;
     let hdl = core.handle();
-    debug!("Initializing modem");
-    let mm = ModemManager::new(InitParameters {
+    debug!("Initializing modems");
+    let mr = ModemRouter::new(InitParameters {
         cfg: &config,
         cfg2: &(),
         store: store.clone(),
         cm: &mut cm,
         hdl: &hdl
-    });
-    hdl.spawn(mm.map_err(|e| {
+    })?;
+    hdl.spawn(mr.map_err(|e| {
         // FIXME: restartability
 
-        error!("ModemManager failed: {}", e);
-        panic!("modemmanager failed");
+        error!("ModemRouter failed: {}", e);
+        panic!("modemrouter failed");
     }));
     let stream = Signal::new(SIGHUP).flatten_stream();
     hdl.spawn(stream.for_each(|i| {
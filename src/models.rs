@@ -1,4 +1,4 @@
-use crate::schema::{recipients, messages, groups, wa_persistence, wa_msgids};
+use crate::schema::{recipients, messages, groups, wa_persistence, wa_msgids, wa_outbox, sms_outbox, sms_usage};
 use serde_json::Value;
 use chrono::NaiveDateTime;
 use huawei_modem::pdu::PduAddress;
@@ -13,6 +13,14 @@ pub struct Recipient {
     pub avatar_url: Option<String>,
     pub notify: Option<String>,
     pub nicksrc: i32,
+    /// Modem to send SMS messages through (set by the user)
+    pub modem: Option<String>,
+    /// Modem we last received an SMS message from this recipient on
+    pub last_modem: Option<String>,
+    /// Whether to transliterate SMS messages to them into the GSM 7-bit alphabet
+    pub transliterate: bool,
+    /// Whether they can be sent messages (alphanumeric senders and short codes can't)
+    pub replyable: bool,
 }
 impl Recipient {
     /// Nick source: migrated from previous sms-irc install
@@ -27,6 +35,33 @@ impl Recipient {
     pub const NICKSRC_WA_NOTIFY: i32 = 3;
     /// Nick source: from a nick collision
     pub const NICKSRC_COLLISION: i32 = 4;
+    /// Nick source: from the SIM or modem phonebook
+    pub const NICKSRC_PHONEBOOK: i32 = 5;
+    /// Whether a nick from `newsrc` should automatically replace one from `oldsrc`.
+    pub fn should_replace_nick(oldsrc: i32, newsrc: i32) -> bool {
+        match (oldsrc, newsrc) {
+            // Migrated nicks should always be changed.
+            (Recipient::NICKSRC_MIGRATED, _) => true,
+            // Don't replace ugly phone numbers with more ugly phone numbers.
+            (Recipient::NICKSRC_AUTO, Recipient::NICKSRC_AUTO) => false,
+            // But replace ugly phone numbers with anything else!
+            (Recipient::NICKSRC_AUTO, _) => true,
+            // Replace WA notify (i.e. name the contact gave themselves) with WA contact
+            // (i.e. name the user gave the contact on their phone's address book)
+            (Recipient::NICKSRC_WA_NOTIFY, Recipient::NICKSRC_WA_CONTACT) => true,
+            // Allow users to update their address book names.
+            (Recipient::NICKSRC_WA_CONTACT, Recipient::NICKSRC_WA_CONTACT) => true,
+            // Update WA notify values as well.
+            (Recipient::NICKSRC_WA_NOTIFY, Recipient::NICKSRC_WA_NOTIFY) => true,
+            // Phonebook names are address book names too, but the SIM's address
+            // book is probably more out of date than the phone's.
+            (Recipient::NICKSRC_WA_NOTIFY, Recipient::NICKSRC_PHONEBOOK) => true,
+            (Recipient::NICKSRC_PHONEBOOK, Recipient::NICKSRC_PHONEBOOK) => true,
+            (Recipient::NICKSRC_PHONEBOOK, Recipient::NICKSRC_WA_CONTACT) => true,
+            // Other changes are probably unwanted.
+            _ => false
+        }
+    }
     pub fn get_addr(&self) -> Result<PduAddress> {
         let addr = util::un_normalize_address(&self.phone_number)
             .ok_or(format_err!("invalid address {} in db", self.phone_number))?;
@@ -41,7 +76,8 @@ pub struct NewRecipient<'a> {
     pub whatsapp: bool,
     pub avatar_url: Option<&'a str>,
     pub notify: Option<&'a str>,
-    pub nicksrc: i32
+    pub nicksrc: i32,
+    pub replyable: bool
 }
 #[derive(Queryable, Debug)]
 pub struct Message {
@@ -52,11 +88,17 @@ pub struct Message {
     pub group_target: Option<i32>,
     pub text: Option<String>,
     pub source: i32,
-    pub ts: NaiveDateTime
+    pub ts: NaiveDateTime,
+    pub modem: Option<String>,
+    /// The WhatsApp message ID, if it came from WhatsApp.
+    pub wa_msgid: Option<String>
 }
 impl Message {
     pub const SOURCE_SMS: i32 = 0;
     pub const SOURCE_WA: i32 = 1;
+    /// A notice from the bridge itself about the recipient (e.g. a missed
+    /// call), which doesn't change whether they're in SMS or WhatsApp mode.
+    pub const SOURCE_NOTICE: i32 = 2;
 
     pub fn get_addr(&self) -> Result<PduAddress> {
         let addr = util::un_normalize_address(&self.phone_number)
@@ -65,13 +107,51 @@ impl Message {
     }
 }
 #[derive(Queryable, Debug)]
+pub struct OutgoingSms {
+    pub id: i32,
+    pub phone_number: String,
+    pub text: String,
+    pub state: i32,
+    pub attempts: i32,
+    pub next_attempt: NaiveDateTime,
+    pub last_error: Option<String>,
+    pub ts: NaiveDateTime,
+    pub modem: Option<String>
+}
+impl OutgoingSms {
+    /// Waiting to be sent (or waiting to be retried)
+    pub const STATE_QUEUED: i32 = 0;
+    /// Currently being sent to the modem
+    pub const STATE_SENDING: i32 = 1;
+    /// Accepted by the modem
+    pub const STATE_SENT: i32 = 2;
+    /// Gave up after too many attempts
+    pub const STATE_FAILED: i32 = 3;
+
+    pub fn get_addr(&self) -> Result<PduAddress> {
+        let addr = util::un_normalize_address(&self.phone_number)
+            .ok_or(format_err!("invalid address {} in db", self.phone_number))?;
+        Ok(addr)
+    }
+}
+/// A record of SMS segments sent, for budgeting.
+#[derive(Queryable, Debug)]
+pub struct SmsUsage {
+    pub id: i32,
+    pub phone_number: String,
+    pub modem: String,
+    pub segments: i32,
+    pub ts: NaiveDateTime
+}
+#[derive(Queryable, Debug)]
 pub struct Group {
     pub id: i32,
     pub jid: String,
     pub channel: String,
     pub participants: Vec<i32>,
     pub admins: Vec<i32>,
-    pub topic: String
+    pub topic: String,
+    pub avatar_url: Option<String>
 }
 #[derive(Insertable, Queryable, Debug)]
 #[table_name="wa_persistence"]
@@ -84,6 +164,28 @@ pub struct PersistenceData {
 pub struct WaMessageId {
     pub mid: String
 }
+/// A WhatsApp message waiting to be sent.
+///
+/// The message ID is chosen when it's queued, so it can be checked against
+/// `wa_msgids` to avoid sending it twice.
+#[derive(Queryable, Debug)]
+pub struct OutgoingWaMessage {
+    pub id: i32,
+    pub jid: String,
+    pub text: String,
+    pub mid: String,
+    pub ts: NaiveDateTime,
+    /// The ID of the message this is a reply to, if any.
+    pub reply_to: Option<String>
+}
+#[derive(Insertable)]
+#[table_name="wa_outbox"]
+pub struct NewOutgoingWaMessage<'a> {
+    pub jid: &'a str,
+    pub text: &'a str,
+    pub mid: &'a str,
+    pub reply_to: Option<&'a str>
+}
 #[derive(Insertable)]
 #[table_name="groups"]
 pub struct NewGroup<'a> {
@@ -100,6 +202,7 @@ pub struct NewMessage<'a> {
     pub pdu: &'a [u8],
     pub csms_data: Option<i32>,
     pub source: i32,
+    pub modem: Option<&'a str>,
 }
 #[derive(Insertable)]
 #[table_name="messages"]
@@ -108,5 +211,22 @@ pub struct NewPlainMessage<'a> {
     pub group_target: Option<i32>,
     pub text: &'a str,
     pub source: i32,
+    pub ts: NaiveDateTime,
+    pub modem: Option<&'a str>,
+    pub wa_msgid: Option<&'a str>
+}
+#[derive(Insertable)]
+#[table_name="sms_outbox"]
+pub struct NewOutgoingSms<'a> {
+    pub phone_number: &'a str,
+    pub text: &'a str,
+    pub modem: &'a str
+}
+#[derive(Insertable)]
+#[table_name="sms_usage"]
+pub struct NewSmsUsage<'a> {
+    pub phone_number: &'a str,
+    pub modem: &'a str,
+    pub segments: i32,
     pub ts: NaiveDateTime
 }
@@ -3,18 +3,37 @@
 use huawei_modem::{HuaweiModem, cmd};
 use futures::{self, Future, Stream, Poll, Async, IntoFuture};
 use tokio_core::reactor::Handle;
-use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
-use huawei_modem::at::AtResponse;
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use huawei_modem::at::{AtResponse, AtResponsePacket, AtCommand, AtValue};
 use crate::comm::{ModemCommand, ContactFactoryCommand, ControlBotCommand, InitParameters};
 use tokio_timer::{Delay, Interval, Timeout};
 use std::time::{Instant, Duration};
 use crate::store::Store;
+use crate::config::ModemConfig;
 use huawei_modem::cmd::sms::SmsMessage;
 use huawei_modem::pdu::{Pdu, PduAddress};
 use huawei_modem::gsm_encoding::GsmMessageData;
 use failure::Error;
-use crate::util::{self, Result};
+use crate::util::Result;
+use crate::models::OutgoingSms;
+use crate::sender_common::DEFAULT_REASSEMBLY_TIMEOUT_SECS;
+use crate::modem_receipts::{SmsReceiptTracker, StatusReport};
+use crate::modem_health::{self, HealthMonitor, HealthSample};
+use crate::modem_phonebook::{self, PhonebookStep, PhonebookEntry, PHONEBOOK_STORAGES};
+use crate::modem_sim::{self, SimError, SimUnlock};
+use crate::modem_budget::SmsBudget;
+use crate::modem_usb::{self, UsbId};
+use crate::sms_backend::{SmsBackend, backend_prefix, spawn_outbox_timer, spawn_partials_timer};
+use crate::gsm7;
+use std::collections::HashMap;
 use std::mem;
+use chrono::Utc;
+
+/// Calls from the same number within this many seconds of the last ring are
+/// treated as the same call (the modem repeats +CLIP for every ring).
+static CALL_DEBOUNCE_SECS: u64 = 60;
+/// Don't send a caller the call auto-reply more often than this.
+static CALL_REPLY_COOLDOWN_SECS: u64 = 3600;
 
 macro_rules! command_timeout {
     ($self:ident, $fut:expr) => {{
@@ -31,32 +50,83 @@ macro_rules! command_timeout {
 enum ModemInner {
     Uninitialized,
     Disabled,
+    /// The modem is identified by USB IDs, and isn't plugged in.
+    Unplugged,
     Waiting(Delay),
     Initializing(Box<dyn Future<Item = HuaweiModem, Error = Error>>),
     Running {
         modem: HuaweiModem,
         urc_rx: UnboundedReceiver<AtResponse>
     },
+    /// Something's wrong with the SIM that needs a human to fix, so we've
+    /// stopped trying to restart the modem.
+    SimProblem {
+        msg: String,
+        reported: bool
+    },
 }
 impl ModemInner {
-    fn init_future(path: &str, hdl: &Handle, timeout_ms: u32) -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
+    fn set_cnmi(modem: &mut HuaweiModem, delivery_reports: bool) -> Box<dyn Future<Item = (), Error = Error>> {
+        if delivery_reports {
+            // +CNMI=2,1,0,1,0: as below, but also route status reports
+            // straight to us as +CDS URCs.
+            let cmd = AtCommand::Equals {
+                param: "+CNMI".into(),
+                value: AtValue::Array(vec![
+                    AtValue::Integer(2),
+                    AtValue::Integer(1),
+                    AtValue::Integer(0),
+                    AtValue::Integer(1),
+                    AtValue::Integer(0)
+                ])
+            };
+            Box::new(modem.send_raw(cmd)
+                     .map_err(|e| Error::from(e))
+                     .and_then(|pkt| pkt.assert_ok().map_err(|e| Error::from(e))))
+        }
+        else {
+            Box::new(cmd::sms::set_new_message_indications(modem,
+                                                           cmd::sms::NewMessageNotification::SendDirectlyOrBuffer,
+                                                           cmd::sms::NewMessageStorage::StoreAndNotify)
+                     .map_err(|e| Error::from(e)))
+        }
+    }
+    fn set_clip(modem: &mut HuaweiModem) -> Box<dyn Future<Item = (), Error = Error>> {
+        // Ask for +CLIP URCs, so we know who's calling.
+        let cmd = AtCommand::Equals {
+            param: "+CLIP".into(),
+            value: AtValue::Integer(1)
+        };
+        Box::new(modem.send_raw(cmd)
+                 .map_err(|e| Error::from(e))
+                 .and_then(|pkt| pkt.assert_ok().map_err(|e| Error::from(e))))
+    }
+    fn init_future(path: &str, hdl: &Handle, timeout_ms: u32, delivery_reports: bool, sim: &SimUnlock) -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
         info!("Initializing modem {}", path);
         let modem = HuaweiModem::new_from_path(path, hdl);
+        let sim = sim.clone();
         let fut = modem.into_future()
             .map_err(|e| Error::from(e))
+            .and_then(move |modem| {
+                info!("Checking SIM state");
+                modem_sim::unlock(modem, sim)
+            })
             .and_then(move |mut modem| {
                 info!("Configuring modem settings");
+                let clip = Self::set_clip(&mut modem);
                 cmd::sms::set_sms_textmode(&mut modem, false)
                     .map_err(|e| Error::from(e))
-                    .join(cmd::sms::set_new_message_indications(&mut modem,
-                                                                cmd::sms::NewMessageNotification::SendDirectlyOrBuffer,
-                                                                cmd::sms::NewMessageStorage::StoreAndNotify)
-                          .map_err(|e| Error::from(e)))
+                    .join(Self::set_cnmi(&mut modem, delivery_reports))
                     .then(move |res| {
                         if let Err(e) = res {
                             warn!("Failed to set +CNMI: {}", e);
                         }
-                        Ok(modem)
+                        clip.then(move |res| {
+                            if let Err(e) = res {
+                                warn!("Failed to set +CLIP: {}", e);
+                            }
+                            Ok(modem)
+                        })
                     })
             });
         Box::new(Timeout::new(fut, Duration::from_millis(timeout_ms as _)).map_err(|e| {
@@ -92,14 +162,36 @@ impl ModemInner {
         }
     }
     // return value: whether or not the modem was just freshly reinitialized
-    pub fn poll(&mut self, modem_path: &Option<String>, hdl: &Handle, delay_ms: u32, timeout_ms: u32) -> bool {
+    //
+    // If the modem is identified by `usb`, `modem_path` gets updated with
+    // wherever it is now.
+    pub fn poll(&mut self, modem_path: &mut Option<String>, usb: Option<&UsbId>, hdl: &Handle, delay_ms: u32, timeout_ms: u32, delivery_reports: bool, sim: &SimUnlock) -> bool {
         use self::ModemInner::*;
 
         loop {
             match mem::replace(self, Uninitialized) {
                 Uninitialized => {
+                    if let Some(id) = usb {
+                        match id.resolve() {
+                            Ok(Some(p)) => {
+                                info!("Found modem ({}) at {}", id, p);
+                                *modem_path = Some(p);
+                            },
+                            Ok(None) => {
+                                info!("Modem ({}) isn't plugged in", id);
+                                *modem_path = None;
+                                *self = Unplugged;
+                                break;
+                            },
+                            Err(e) => {
+                                error!("Failed to look for the modem ({}) in sysfs: {}", id, e);
+                                *self = Waiting(Self::make_delay(delay_ms));
+                                continue;
+                            }
+                        }
+                    }
                     if let Some(ref path) = modem_path {
-                        *self = Initializing(Self::init_future(path, hdl, timeout_ms));
+                        *self = Initializing(Self::init_future(path, hdl, timeout_ms, delivery_reports, sim));
                     }
                     else {
                         info!("Modem is disabled");
@@ -137,6 +229,14 @@ impl ModemInner {
                             break;
                         },
                         Err(e) => {
+                            if let Some(e) = e.downcast_ref::<SimError>() {
+                                error!("Modem SIM problem: {}", e);
+                                *self = SimProblem {
+                                    msg: e.0.clone(),
+                                    reported: false
+                                };
+                                break;
+                            }
                             error!("Modem initialization failed: {}", e);
                             *self = Waiting(Self::make_delay(delay_ms));
                         }
@@ -157,7 +257,7 @@ macro_rules! get_modem {
             Ok(m) => m,
             Err(e) => {
                 warn!("Modem operation failed: {}", e);
-                let err = format!("{} failed: {}", $desc, e);
+                let err = format!("{}{} failed: {}", $self.prefix, $desc, e);
                 $self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err))
                     .unwrap();
                 return;
@@ -166,13 +266,42 @@ macro_rules! get_modem {
     }
 }
 pub struct ModemManager {
+    name: String,
+    /// Prefix for messages to the admin, so they know which modem is talking.
+    prefix: String,
+    /// Whether this modem sends messages queued before there was a choice of modem.
+    is_default: bool,
     inner: ModemInner,
     store: Store,
     handle: Handle,
     modem_path: Option<String>,
+    /// The modem's USB identification, if it's found that way rather than by `modem_path`.
+    usb: Option<UsbId>,
     delay_ms: u32,
     timeout_ms: u32,
     cmd_timeout_ms: u32,
+    outbox_retry_ms: u32,
+    outbox_max_attempts: u32,
+    outbox_busy: bool,
+    reassembly_timeout: u64,
+    delivery_reports: bool,
+    sim: SimUnlock,
+    cds_pending: bool,
+    /// Whether USSD strings are sent and received as packed GSM 7-bit hex.
+    ussd_pdu: bool,
+    /// Whether the network is waiting for a reply to a USSD menu.
+    ussd_session: bool,
+    /// Entries read so far, while importing the phonebooks.
+    phonebook: Option<Vec<PhonebookEntry>>,
+    reject_calls: bool,
+    call_reply: Option<String>,
+    /// When we last heard each caller's phone ring.
+    last_rings: HashMap<String, Instant>,
+    /// When we last sent each caller the call auto-reply.
+    last_call_replies: HashMap<String, Instant>,
+    receipts: SmsReceiptTracker,
+    health: HealthMonitor,
+    budget: SmsBudget,
     rx: UnboundedReceiver<ModemCommand>,
     cf_tx: UnboundedSender<ContactFactoryCommand>,
     int_tx: UnboundedSender<ModemCommand>,
@@ -189,46 +318,104 @@ impl Future for ModemManager {
 
             let msg = msg.expect("rx stopped producing");
             match msg {
-                DoCmgl => self.cmgl(),
+                DoCmgl => self.check_messages()?,
                 CmglComplete(msgs) => self.cmgl_complete(msgs)?,
                 CmglFailed(e) => self.cmgl_failed(e),
-                SendMessage(addr, msg) => self.send_message(addr, msg),
+                SendMessage(addr, msg) => self.send_message(addr, msg)?,
+                ProcessOutbox => self.process_outbox()?,
+                OutboxSent(id, refs) => self.outbox_sent(id, refs)?,
+                OutboxFailed(id, e) => self.outbox_failed(id, e)?,
+                OutboxPartlySent(id, refs, e) => self.outbox_partly_sent(id, refs, e)?,
                 RequestCsq => self.request_csq(),
                 RequestReg => self.request_reg(),
+                PrintReceipts => self.print_receipts(),
+                ListPartials => self.list_partials()?,
+                CheckPartials => self.check_stale_partials()?,
+                RequestStatus => self.request_status()?,
+                RequestUsage => self.request_usage()?,
                 ForceReinit => self.reinit_modem(),
                 UpdatePath(p) => self.update_path(p),
+                CheckUsb(added) => self.check_usb(added),
                 CommandTimeout => self.command_timeout(),
-                MakeContact(a) => self.make_contact(a)?
+                MakeContact(a) => self.make_contact(a)?,
+                HealthSampled(s) => self.health.record(s),
+                PhonebookImport => self.phonebook_import(),
+                Phonebook(step) => self.phonebook_step(step)?,
+                Ussd(code) => self.send_ussd(code),
+                UssdReceived(m, text) => self.on_ussd(m, text),
+                ForModem(..) | SetRecipientModem(..) | SetRecipientTransliterate(..) | SetRecipientReplyable(..) | ListPdus | ShowPdu(_) | DecodePdu(_) | ListModems | IncomingText(..) => {
+                    warn!("Modem {} got a command meant for the modem router", self.name);
+                }
             }
         }
+        self.receipts.poll()?;
+        if self.health.check_due()? {
+            self.health_check();
+        }
         Ok(Async::NotReady)
     }
 }
 impl ModemManager {
     fn poll_modem(&mut self) {
-        if self.inner.poll(&self.modem_path, &self.handle, self.delay_ms, self.timeout_ms) {
+        if self.inner.poll(&mut self.modem_path, self.usb.as_ref(), &self.handle, self.delay_ms, self.timeout_ms, self.delivery_reports, &self.sim) {
             self.cmgl();
+            if let Err(e) = self.process_outbox() {
+                error!("Failed to process SMS outbox: {}", e);
+            }
+        }
+        if let ModemInner::SimProblem { ref msg, ref mut reported } = self.inner {
+            if !*reported {
+                *reported = true;
+                let err = format!("{}Modem stopped: {}", self.prefix, msg);
+                self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err))
+                    .unwrap();
+            }
         }
         if let Err(e) = self.poll_urc_rx() {
             self.report_modem_error(e);
         }
     }
-    fn make_contact(&mut self, addr: PduAddress) -> Result<()> {
-        if self.store.get_recipient_by_addr_opt(&addr)?.is_none() {
-            let nick = util::make_nick_for_address(&addr);
-            self.store.store_recipient(&addr, &nick)?;
-            info!("Creating new SMS recipient for {} (nick {})", addr, nick);
-            self.cf_tx.unbounded_send(ContactFactoryCommand::SetupContact(addr.clone()))
-                .unwrap();
-            self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessMessages).unwrap();
-        }
-        Ok(())
-    }
     fn update_path(&mut self, path: Option<String>) {
         info!("Updating modem path to {:?}", path);
+        if let Some(id) = self.usb.take() {
+            info!("No longer looking for the modem by {}", id);
+        }
         self.modem_path = path;
         self.reinit_modem();
     }
+    /// See whether the modem's been plugged in, unplugged, or moved to a
+    /// different tty, and reinitialize it if so.
+    ///
+    /// `added` is the tty that was just plugged in, if we know: if that's the
+    /// modem's, it's been replugged (maybe too quickly to see it go away), so
+    /// it needs reinitializing even if it's at the same path as before.
+    fn check_usb(&mut self, added: Option<String>) {
+        let found = match self.usb {
+            Some(ref id) => match id.resolve() {
+                Ok(p) => p,
+                Err(e) => {
+                    warn!("Failed to look for the modem ({}) in sysfs: {}", id, e);
+                    return;
+                }
+            },
+            None => return
+        };
+        let replugged = match (added, found.as_ref()) {
+            (Some(name), Some(p)) => *p == format!("/dev/{}", name),
+            _ => false
+        };
+        if found == self.modem_path && !replugged {
+            return;
+        }
+        let msg = match found {
+            Some(ref p) => format!("{}Modem appeared at \x02{}\x02; reinitializing", self.prefix, p),
+            None => format!("{}Modem was unplugged", self.prefix)
+        };
+        info!("{}", msg);
+        self.cb_tx.unbounded_send(ControlBotCommand::Log(msg))
+            .unwrap();
+        self.reinit_modem();
+    }
     fn reinit_modem(&mut self) {
         self.inner.report_error(format_err!("Reinitialization requested"), 0);
         self.poll_modem();
@@ -243,38 +430,385 @@ impl ModemManager {
     }
     fn poll_urc_rx(&mut self) -> Result<()> {
         let mut do_cmgl = false;
+        let mut reports = vec![];
+        let mut ussd = vec![];
+        let mut calls = vec![];
+        let ussd_pdu = self.ussd_pdu;
         if let Some(urc_rx) = self.inner.get_urc_rx() {
             while let Async::Ready(urc) = urc_rx.poll().unwrap() {
                 let urc = urc.ok_or(format_err!("urc_rx stopped producing"))?;
                 trace!("received URC: {:?}", urc);
-                if let AtResponse::InformationResponse { param, .. } = urc {
-                    if param == "+CMTI" {
-                        debug!("received CMTI indication");
-                        do_cmgl = true;
-                    }
+                match urc {
+                    AtResponse::InformationResponse { param, response } => {
+                        if param == "+CMTI" {
+                            debug!("received CMTI indication");
+                            do_cmgl = true;
+                        }
+                        else if param == "+CDS" {
+                            // The status report PDU itself follows on the next line.
+                            debug!("received CDS indication");
+                            self.cds_pending = true;
+                        }
+                        else if param == "+CLIP" {
+                            debug!("received CLIP indication");
+                            calls.push(parse_clip(&response));
+                        }
+                        else if param == "+CUSD" {
+                            debug!("received USSD response");
+                            ussd.extend(parse_cusd(&response, ussd_pdu));
+                        }
+                    },
+                    AtResponse::Unknown(line) => {
+                        if mem::replace(&mut self.cds_pending, false) {
+                            reports.push(line);
+                        }
+                    },
+                    _ => {}
                 }
             }
         }
+        for line in reports {
+            self.on_cds_pdu(line.trim());
+        }
+        for (m, text) in ussd {
+            self.on_ussd(m, text);
+        }
+        for caller in calls {
+            self.on_incoming_call(caller);
+        }
         if do_cmgl {
             self.cmgl();
         }
         Ok(())
     }
-    pub fn new<T>(p: InitParameters<T>) -> Self {
-        let modem_path = p.cfg.modem.modem_path.clone();
+    fn on_cds_pdu(&mut self, hex: &str) {
+        use huawei_modem::pdu::HexData;
+
+        trace!("status report PDU: {}", hex);
+        let res = HexData::decode(hex)
+            .map_err(|e| format_err!("invalid hex: {}", e))
+            .and_then(|data| StatusReport::from_pdu(&data));
+        match res {
+            Ok(rpt) => self.receipts.on_status_report(rpt),
+            Err(e) => warn!("Failed to parse status report: {}", e)
+        }
+    }
+    fn send_ussd(&mut self, code: Option<String>) {
+        let tx = self.int_tx.clone();
+        let cb_tx = self.cb_tx.clone();
+        let prefix = self.prefix.clone();
+        let ussd_pdu = self.ussd_pdu;
+        let value = match code {
+            Some(ref c) => {
+                if self.ussd_session {
+                    debug!("Replying to USSD menu with {}", c);
+                }
+                let c = if ussd_pdu { encode_ussd_pdu(c) } else { c.clone() };
+                AtValue::Array(vec![
+                    AtValue::Integer(1),
+                    AtValue::String(c),
+                    AtValue::Integer(15)
+                ])
+            },
+            None => AtValue::Integer(2)
+        };
+        let ending = code.is_none();
+        let mut modem = get_modem!(self, "Sending USSD");
+        let cmd = AtCommand::Equals {
+            param: "+CUSD".into(),
+            value
+        };
+        let fut = modem.send_raw(cmd)
+            .map_err(|e| Error::from(e))
+            .and_then(|pkt| -> Result<AtResponsePacket> {
+                pkt.assert_ok()?;
+                Ok(pkt)
+            });
+        let fut = command_timeout!(self, fut)
+            .then(move |res| {
+                match res {
+                    Ok(pkt) => {
+                        // Some modems send the +CUSD before the OK, so it
+                        // doesn't come through as a URC.
+                        for resp in pkt.responses {
+                            if let AtResponse::InformationResponse { param, response } = resp {
+                                if param == "+CUSD" {
+                                    if let Some((m, text)) = parse_cusd(&response, ussd_pdu) {
+                                        tx.unbounded_send(ModemCommand::UssdReceived(m, text)).unwrap();
+                                    }
+                                }
+                            }
+                        }
+                        if ending {
+                            let res = format!("{}USSD session ended.", prefix);
+                            cb_tx.unbounded_send(ControlBotCommand::CommandResponse(res)).unwrap();
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Error sending USSD: {}", e);
+                        let err = format!("{}Sending USSD failed: {}", prefix, e);
+                        cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err)).unwrap();
+                    }
+                }
+                Ok(())
+            });
+        if ending {
+            self.ussd_session = false;
+        }
+        self.handle.spawn(fut);
+    }
+    fn on_ussd(&mut self, m: u32, text: Option<String>) {
+        debug!("USSD response (status {}): {:?}", m, text);
+        self.ussd_session = m == 1;
+        if let Some(text) = text {
+            for line in text.lines().filter(|x| x.trim().len() > 0) {
+                self.respond(&format!("USSD: {}", line));
+            }
+        }
+        let note = match m {
+            0 => return,
+            1 => "reply with \x02MODEM USSD <reply>\x02, or end the session with \x02MODEM USSD\x02",
+            2 => "session ended by the network",
+            3 => "answered by another client",
+            4 => "not supported by the network",
+            5 => "network timed out",
+            _ => "unknown USSD status"
+        };
+        self.respond(&format!("\x1d({})\x0f", note));
+    }
+    fn on_incoming_call(&mut self, caller: Option<PduAddress>) {
+        let caller = match caller {
+            Some(c) => c,
+            None => {
+                if self.ring_is_new("") {
+                    info!("Incoming call from a withheld number");
+                    self.respond("Incoming call from a withheld number.");
+                    if self.reject_calls {
+                        self.hang_up();
+                    }
+                }
+                return;
+            }
+        };
+        let num = caller.to_string();
+        if !self.ring_is_new(&num) {
+            return;
+        }
+        info!("Incoming call from {}", caller);
+        // This is stored as a notice, not an SMS, so that it doesn't switch
+        // a WhatsApp contact back into SMS mode.
+        match self.store.store_notice_message(&caller, "\x01ACTION tried to call you\x01", &self.name) {
+            Ok(_) => self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessMessages).unwrap(),
+            Err(e) => error!("Failed to store call notification: {}", e)
+        }
+        if self.reject_calls {
+            self.hang_up();
+        }
+        if let Some(reply) = self.call_reply.clone() {
+            let replied_recently = self.last_call_replies.get(&num)
+                .map(|t| t.elapsed() < Duration::from_secs(CALL_REPLY_COOLDOWN_SECS))
+                .unwrap_or(false);
+            if !replied_recently {
+                self.last_call_replies.insert(num, Instant::now());
+                if let Err(e) = self.send_message(caller, reply) {
+                    error!("Failed to queue call auto-reply: {}", e);
+                }
+            }
+        }
+    }
+    /// Note that `num` is ringing, returning whether this is a new call.
+    fn ring_is_new(&mut self, num: &str) -> bool {
+        let debounce = Duration::from_secs(CALL_DEBOUNCE_SECS);
+        self.last_rings.retain(|_, t| t.elapsed() < debounce);
+        self.last_rings.insert(num.to_owned(), Instant::now()).is_none()
+    }
+    fn hang_up(&mut self) {
+        let prefix = self.prefix.clone();
+        let cb_tx = self.cb_tx.clone();
+        let mut modem = get_modem!(self, "Hanging up");
+        let chup = modem.send_raw(AtCommand::Execute { command: "+CHUP".into() })
+            .map_err(|e| Error::from(e))
+            .and_then(|pkt| pkt.assert_ok().map_err(|e| Error::from(e)));
+        // Not every modem hangs up voice calls with ATH, and not every modem
+        // has +CHUP, so send both; it only failed if they both did.
+        let ath = modem.send_raw(AtCommand::Execute { command: "H".into() })
+            .map_err(|e| Error::from(e))
+            .and_then(|pkt| pkt.assert_ok().map_err(|e| Error::from(e)));
+        let fut = chup.then(move |chup_res| {
+            ath.then(move |ath_res| {
+                match (chup_res, ath_res) {
+                    (Err(e), Err(_)) => Err(e),
+                    _ => Ok(())
+                }
+            })
+        });
+        let fut = command_timeout!(self, fut)
+            .then(move |res| {
+                if let Err(e) = res {
+                    warn!("Error hanging up: {}", e);
+                    let err = format!("{}Hanging up incoming call failed: {}", prefix, e);
+                    cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err)).unwrap();
+                }
+                Ok(())
+            });
+        self.handle.spawn(fut);
+    }
+    fn phonebook_import(&mut self) {
+        if self.phonebook.is_some() {
+            self.respond("Already importing the phonebook; hang on a bit.");
+            return;
+        }
+        self.phonebook = Some(vec![]);
+        self.phonebook_range(PHONEBOOK_STORAGES[0]);
+    }
+    fn phonebook_name(storage: &str) -> &'static str {
+        match storage {
+            "SM" => "SIM",
+            "ME" => "modem",
+            _ => "other"
+        }
+    }
+    /// Select the `storage` phonebook, and send a command that's run after that.
+    fn phonebook_command(&mut self, storage: &'static str, cmd: AtCommand) -> Option<Box<dyn Future<Item = AtResponsePacket, Error = Error>>> {
+        let modem = match self.inner.get_modem() {
+            Ok(m) => m,
+            Err(e) => {
+                self.phonebook = None;
+                self.report_failure(&format!("Reading the phonebook failed: {}", e));
+                return None;
+            }
+        };
+        let cpbs = AtCommand::Equals {
+            param: "+CPBS".into(),
+            value: AtValue::String(storage.into())
+        };
+        let select = modem.send_raw(cpbs)
+            .map_err(|e| Error::from(e))
+            .and_then(|pkt| pkt.assert_ok().map_err(|e| Error::from(e)));
+        let fut = modem.send_raw(cmd)
+            .map_err(|e| Error::from(e))
+            .and_then(|pkt| -> Result<AtResponsePacket> {
+                pkt.assert_ok()?;
+                Ok(pkt)
+            });
+        Some(Box::new(select.join(fut).map(|(_, pkt)| pkt)))
+    }
+    fn phonebook_range(&mut self, storage: &'static str) {
+        let tx = self.int_tx.clone();
+        let fut = match self.phonebook_command(storage, AtCommand::Test { param: "+CPBR".into() }) {
+            Some(f) => f,
+            None => return
+        };
+        let fut = command_timeout!(self, fut)
+            .then(move |res| {
+                let range = match res {
+                    Ok(pkt) => modem_phonebook::parse_cpbr_range(&pkt),
+                    Err(e) => {
+                        warn!("Error getting {} phonebook size: {}", storage, e);
+                        None
+                    }
+                };
+                tx.unbounded_send(ModemCommand::Phonebook(PhonebookStep::Range(storage, range))).unwrap();
+                Ok(())
+            });
+        self.handle.spawn(fut);
+    }
+    fn phonebook_read(&mut self, storage: &'static str, first: u32, last: u32) {
+        let tx = self.int_tx.clone();
+        let cmd = AtCommand::Equals {
+            param: "+CPBR".into(),
+            value: AtValue::Array(vec![AtValue::Integer(first), AtValue::Integer(last)])
+        };
+        let fut = match self.phonebook_command(storage, cmd) {
+            Some(f) => f,
+            None => return
+        };
+        let fut = command_timeout!(self, fut)
+            .then(move |res| {
+                let entries = match res {
+                    Ok(pkt) => modem_phonebook::parse_cpbr_entries(&pkt),
+                    Err(e) => {
+                        warn!("Error reading {} phonebook: {}", storage, e);
+                        vec![]
+                    }
+                };
+                tx.unbounded_send(ModemCommand::Phonebook(PhonebookStep::Entries(storage, entries))).unwrap();
+                Ok(())
+            });
+        self.handle.spawn(fut);
+    }
+    fn phonebook_step(&mut self, step: PhonebookStep) -> Result<()> {
+        let storage = match step {
+            PhonebookStep::Range(storage, Some((first, last))) => {
+                self.phonebook_read(storage, first, last);
+                return Ok(());
+            },
+            PhonebookStep::Range(storage, None) => {
+                self.respond(&format!("Couldn't read the {} phonebook.", Self::phonebook_name(storage)));
+                storage
+            },
+            PhonebookStep::Entries(storage, entries) => {
+                self.respond(&format!("Read \x02{}\x02 entries from the {} phonebook.", entries.len(), Self::phonebook_name(storage)));
+                if let Some(ref mut pb) = self.phonebook {
+                    pb.extend(entries);
+                }
+                storage
+            }
+        };
+        let next = PHONEBOOK_STORAGES.iter()
+            .skip_while(|&&x| x != storage)
+            .nth(1);
+        if let Some(next) = next {
+            self.phonebook_range(next);
+            return Ok(());
+        }
+        let entries = match self.phonebook.take() {
+            Some(e) => e,
+            None => return Ok(())
+        };
+        let stats = modem_phonebook::import_entries(&mut self.store, &mut self.cf_tx, entries)?;
+        self.respond(&format!("Phonebook imported: \x02{}\x02 new, \x02{}\x02 renamed, {} unchanged, {} left alone (nick chosen elsewhere), {} invalid.",
+                              stats.created, stats.renamed, stats.unchanged, stats.kept, stats.invalid));
+        Ok(())
+    }
+    fn print_receipts(&mut self) {
+        for line in self.receipts.print_receipts() {
+            let line = format!("{}{}", self.prefix, line);
+            self.cb_tx.unbounded_send(ControlBotCommand::CommandResponse(line))
+                .unwrap();
+        }
+    }
+    /// Get a sender for commands to this modem.
+    pub fn sender(&self) -> UnboundedSender<ModemCommand> {
+        self.int_tx.clone()
+    }
+    pub fn new(p: InitParameters<ModemConfig>, name: String, is_default: bool) -> Result<Self> {
+        let modem_path = p.cfg2.modem_path.clone();
+        let usb = UsbId::new(&p.cfg2)
+            .map_err(|e| format_err!("invalid USB identification for modem {}: {}", name, e))?;
+        let usb_poll_ms = p.cfg2.usb_poll_ms.unwrap_or(2000);
         let handle = p.hdl.clone();
-        let cs = p.cfg.modem.cmgl_secs;
-        let delay_ms = p.cfg.modem.restart_delay_ms.unwrap_or(5000);
-        let timeout_ms = p.cfg.modem.restart_timeout_ms.unwrap_or(30000);
-        let cmd_timeout_ms = p.cfg.modem.command_timeout_ms.unwrap_or(30000);
-        let rx = p.cm.modem_rx.take().unwrap();
-        let int_tx = p.cm.modem_tx.clone();
+        let cs = p.cfg2.cmgl_secs;
+        let delay_ms = p.cfg2.restart_delay_ms.unwrap_or(5000);
+        let timeout_ms = p.cfg2.restart_timeout_ms.unwrap_or(30000);
+        let cmd_timeout_ms = p.cfg2.command_timeout_ms.unwrap_or(30000);
+        let outbox_retry_ms = p.cfg2.outbox_retry_ms.unwrap_or(10000);
+        let outbox_max_attempts = p.cfg2.outbox_max_attempts.unwrap_or(10);
+        let delivery_reports = p.cfg2.delivery_reports;
+        // A `[[modems]]` entry can override the global `[modem]` setting.
+        let reassembly_timeout = p.cfg2.reassembly_timeout_secs
+            .or(p.cfg.modem.reassembly_timeout_secs)
+            .unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT_SECS);
+        let prefix = backend_prefix(p.cfg, &name);
+        let receipts = SmsReceiptTracker::new(&p);
+        let health = HealthMonitor::new(&p, &prefix);
+        let (int_tx, rx) = mpsc::unbounded();
         let cf_tx = p.cm.cf_tx.clone();
         let cb_tx = p.cm.cb_tx.clone();
 
         int_tx.unbounded_send(ModemCommand::DoCmgl).unwrap();
         let cs = cs.unwrap_or(30);
-        let int_tx_timer = p.cm.modem_tx.clone();
+        let int_tx_timer = int_tx.clone();
         let timer = Interval::new(Instant::now(), Duration::new(cs as _, 0))
             .map_err(|e| {
                 error!("CMGL timer failed: {}", e);
@@ -285,20 +819,67 @@ impl ModemManager {
                 Ok(())
             });
         p.hdl.spawn(timer);
-        let store = p.store;
-        let inner = ModemInner::Uninitialized;
-        Self {
-            rx, store, cf_tx, handle, int_tx, cb_tx, inner, modem_path, delay_ms, timeout_ms, cmd_timeout_ms
+        spawn_outbox_timer(p.hdl, outbox_retry_ms, int_tx.clone());
+        spawn_partials_timer(p.hdl, int_tx.clone());
+        if usb.is_some() {
+            int_tx.unbounded_send(ModemCommand::CheckUsb(None)).unwrap();
+        }
+        let watching = match usb {
+            Some(_) => match modem_usb::watch_hotplug(int_tx.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    warn!("Can't listen for USB hotplug events ({}); checking for modem {} every {}ms instead", e, name, usb_poll_ms);
+                    false
+                }
+            },
+            None => true
+        };
+        if !watching {
+            let int_tx_usb = int_tx.clone();
+            let usb_timer = Interval::new(Instant::now(), Duration::from_millis(usb_poll_ms as _))
+                .map_err(|e| {
+                    error!("USB check timer failed: {}", e);
+                    panic!("timer failed!");
+                }).for_each(move |_| {
+                    int_tx_usb.unbounded_send(ModemCommand::CheckUsb(None)).unwrap();
+                    Ok(())
+                });
+            p.hdl.spawn(usb_timer);
         }
+        let mut store = p.store;
+        match store.requeue_sending_sms() {
+            Ok(n) if n > 0 => info!("Requeued {} SMS messages that were being sent at shutdown", n),
+            Ok(_) => {},
+            Err(e) => error!("Failed to requeue outgoing SMS messages: {}", e)
+        }
+        let budget = SmsBudget::new(store.clone(), &name, p.cfg2.daily_segment_budget, p.cfg2.recipient_daily_segment_budget, p.cfg2.segments_per_minute);
+        let inner = ModemInner::Uninitialized;
+        Ok(Self {
+            name, prefix, is_default,
+            rx, store, cf_tx, handle, int_tx, cb_tx, inner, modem_path, usb, delay_ms, timeout_ms, cmd_timeout_ms,
+            outbox_retry_ms, outbox_max_attempts, delivery_reports, receipts, health, reassembly_timeout,
+            outbox_busy: false,
+            sim: SimUnlock::new(&p.cfg2),
+            budget,
+            cds_pending: false,
+            ussd_pdu: p.cfg2.ussd_pdu,
+            ussd_session: false,
+            phonebook: None,
+            reject_calls: p.cfg2.reject_calls,
+            call_reply: p.cfg2.call_reply.clone(),
+            last_rings: HashMap::new(),
+            last_call_replies: HashMap::new()
+        })
     }
     fn request_reg(&mut self) {
         let tx = self.cb_tx.clone();
+        let prefix = self.prefix.clone();
         let mut modem = get_modem!(self, "Getting registration");
         let fut = command_timeout!(self, cmd::network::get_registration(&mut modem))
             .then(move |res| {
                 match res {
                     Ok(res) => {
-                        let res = format!("Registration state: \x02{}\x0f", res);
+                        let res = format!("{}Registration state: \x02{}\x0f", prefix, res);
                         tx.unbounded_send(ControlBotCommand::CommandResponse(res)).unwrap();
                     },
                     Err(e) => warn!("Error getting registration: {}", e)
@@ -309,12 +890,13 @@ impl ModemManager {
     }
     fn request_csq(&mut self) {
         let tx = self.cb_tx.clone();
+        let prefix = self.prefix.clone();
         let mut modem = get_modem!(self, "Getting signal quality");
         let fut = command_timeout!(self, cmd::network::get_signal_quality(&mut modem))
             .then(move |res| {
                 match res {
                     Ok(res) => {
-                        let res = format!("RSSI: \x02{}\x0f | BER: \x02{}\x0f", res.rssi, res.ber);
+                        let res = format!("{}RSSI: \x02{}\x0f | BER: \x02{}\x0f", prefix, res.rssi, res.ber);
                         tx.unbounded_send(ControlBotCommand::CommandResponse(res)).unwrap();
                     }
                     Err(e) => warn!("Error getting signal quality: {}", e)
@@ -323,6 +905,42 @@ impl ModemManager {
             });
         self.handle.spawn(fut);
     }
+    fn health_check(&mut self) {
+        let tx = self.int_tx.clone();
+        // Modem errors get reported elsewhere; don't go on about them here.
+        let mut modem = match self.inner.get_modem() {
+            Ok(m) => m,
+            Err(_) => return
+        };
+        trace!("Checking modem health");
+        let csq = cmd::network::get_signal_quality(&mut modem)
+            .then(|res| -> Result<Option<u32>> {
+                Ok(res.ok().map(|x| x.rssi as u32))
+            });
+        let creg = modem.send_raw(AtCommand::Read { param: "+CREG".into() })
+            .then(|res| -> Result<Option<u32>> {
+                Ok(res.ok().and_then(|pkt| modem_health::parse_creg(&pkt)))
+            });
+        let cops = modem.send_raw(AtCommand::Read { param: "+COPS".into() })
+            .then(|res| -> Result<Option<String>> {
+                Ok(res.ok().and_then(|pkt| modem_health::parse_cops(&pkt)))
+            });
+        let fut = command_timeout!(self, csq.join3(creg, cops))
+            .then(move |res| {
+                match res {
+                    Ok((rssi, reg, operator)) => {
+                        let sample = HealthSample {
+                            ts: Utc::now(),
+                            rssi, reg, operator
+                        };
+                        tx.unbounded_send(ModemCommand::HealthSampled(sample)).unwrap();
+                    },
+                    Err(e) => warn!("Error checking modem health: {}", e)
+                }
+                Ok(())
+            });
+        self.handle.spawn(fut);
+    }
     fn cmgl_complete(&mut self, msgs: Vec<SmsMessage>) -> Result<()> {
         use huawei_modem::cmd::sms::{MessageStatus, DeletionOptions};
 
@@ -333,27 +951,10 @@ impl ModemManager {
             if msg.status != MessageStatus::ReceivedUnread {
                 continue;
             }
-            let data = msg.pdu.get_message_data();
-            let csms_data = match data.decode_message() {
-                Ok(m) => {
-                    m.udh
-                        .and_then(|x| x.get_concatenated_sms_data())
-                        .map(|x| x.reference as i32)
-                },
-                Err(e) => {
-                    // The error is reported when the message is sent
-                    // via the ContactManager, not here.
-                    debug!("Error decoding message - but it'll be reported later: {:?}", e);
-                    None
-                }
-            };
-            if let Some(d) = csms_data {
-                trace!("Message is concatenated: {:?}", d);
-            }
-            let addr = msg.pdu.originating_address;
-            self.store.store_sms_message(&addr, &msg.raw_pdu, csms_data)?;
+            self.store_deliver_pdu(&msg.pdu, &msg.raw_pdu)?;
         }
         self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessMessages).unwrap();
+        self.check_stale_partials()?;
         let mut modem = match self.inner.get_modem() {
             Ok(m) => m,
             Err(e) => {
@@ -371,34 +972,44 @@ impl ModemManager {
     fn cmgl_failed(&mut self, e: Error) {
         self.report_modem_error(format_err!("+CMGL failed: {}", e));
     }
-    fn send_message(&mut self, addr: PduAddress, msg: String) {
-        let data = GsmMessageData::encode_message(&msg);
-        let parts = data.len();
-        let mut modem = get_modem!(self, "Sending message");
-        debug!("Sending {}-part message to {}...", parts, addr);
-        trace!("Message content: {}", msg);
-        let mut futs = vec![];
-        for (i, part) in data.into_iter().enumerate() {
-            debug!("Sending part {}/{} of message to {}...", i+1, parts, addr);
-            let pdu = Pdu::make_simple_message(addr.clone(), part);
-            trace!("PDU: {:?}", pdu);
-            futs.push(cmd::sms::send_sms_pdu(&mut modem, &pdu)
-                      .map_err(|e| e.into()));
+    fn outbox_sent(&mut self, id: i32, refs: Vec<u32>) -> Result<()> {
+        self.outbox_busy = false;
+        info!("Outgoing SMS #{} sent!", id);
+        debug!("Message references: {:?}", refs);
+        self.store.update_outgoing_sms_state(id, OutgoingSms::STATE_SENT)?;
+        let sms = self.store.get_outgoing_sms_by_id(id)?;
+        let addr = sms.get_addr()?;
+        self.record_usage(&addr, refs.len())?;
+        if self.delivery_reports {
+            self.receipts.register_send(id, addr, sms.text, refs);
         }
-        let a1 = addr.clone();
-        let cb_tx = self.cb_tx.clone();
-        let fut = futures::future::join_all(futs)
-            .map(move |res| {
-                info!("Message to {} sent!", a1);
-                debug!("Message ids: {:?}", res);
-            }).map_err(move |e: ::failure::Error| {
-                // FIXME: retrying?
-                warn!("Failed to send message to {}: {}", addr, e);
-                let emsg = format!("Failed to send message to {}: {}", addr, e);
-                cb_tx.unbounded_send(ControlBotCommand::ReportFailure(emsg))
-                    .unwrap();
-            });
-        self.handle.spawn(fut);
+        self.process_outbox()?;
+        Ok(())
+    }
+    /// Some parts of outbox message `id` were sent, but not all of them.
+    /// Retrying would send the ones that got through again, so give up
+    /// and let the admin decide what to do.
+    fn outbox_partly_sent(&mut self, id: i32, refs: Vec<u32>, e: Error) -> Result<()> {
+        self.outbox_busy = false;
+        let sms = self.store.get_outgoing_sms_by_id(id)?;
+        let addr = sms.get_addr()?;
+        warn!("Outgoing SMS #{} to {} partly sent: {}", id, addr, e);
+        debug!("Message references: {:?}", refs);
+        let err = e.to_string();
+        self.store.update_outgoing_sms_retry(id, OutgoingSms::STATE_FAILED, sms.attempts + 1, sms.next_attempt, &err)?;
+        self.record_usage(&addr, refs.len())?;
+        if self.delivery_reports {
+            self.receipts.register_send(id, addr.clone(), sms.text, refs);
+        }
+        self.report_failure(&format!("Failed to send all of message to {} (not retrying): {}", addr, err));
+        self.process_outbox()?;
+        Ok(())
+    }
+    fn outbox_failed(&mut self, id: i32, e: Error) -> Result<()> {
+        self.outbox_busy = false;
+        self.record_send_failure(id, e)?;
+        self.process_outbox()?;
+        Ok(())
     }
     fn cmgl(&mut self) {
         use huawei_modem::cmd::sms::MessageStatus;
@@ -427,3 +1038,225 @@ impl ModemManager {
         }
     }
 }
+impl SmsBackend for ModemManager {
+    fn backend_name(&self) -> &str {
+        &self.name
+    }
+    fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+    fn outbox_retry_ms(&self) -> u32 {
+        self.outbox_retry_ms
+    }
+    fn outbox_max_attempts(&self) -> u32 {
+        self.outbox_max_attempts
+    }
+    fn reassembly_timeout(&self) -> u64 {
+        self.reassembly_timeout
+    }
+    fn store(&mut self) -> &mut Store {
+        &mut self.store
+    }
+    fn cf_tx(&mut self) -> &mut UnboundedSender<ContactFactoryCommand> {
+        &mut self.cf_tx
+    }
+    fn cb_tx(&mut self) -> &mut UnboundedSender<ControlBotCommand> {
+        &mut self.cb_tx
+    }
+    fn budget(&mut self) -> &mut SmsBudget {
+        &mut self.budget
+    }
+    fn check_messages(&mut self) -> Result<()> {
+        self.cmgl();
+        Ok(())
+    }
+    fn request_status(&mut self) -> Result<()> {
+        let path = self.modem_path.clone().unwrap_or("(disabled)".into());
+        let state = match self.inner {
+            ModemInner::Uninitialized => "uninitialized",
+            ModemInner::Disabled => "disabled",
+            ModemInner::Unplugged => "unplugged",
+            ModemInner::Waiting(_) => "waiting to restart",
+            ModemInner::Initializing(_) => "initializing",
+            ModemInner::Running { .. } => "running",
+            ModemInner::SimProblem { .. } => "stopped (SIM problem)"
+        };
+        self.respond(&format!("Modem at \x02{}\x02 is \x02{}\x02", path, state));
+        if let Some(ref id) = self.usb {
+            let msg = format!("Found by {}", id);
+            self.respond(&msg);
+        }
+        if let ModemInner::SimProblem { ref msg, .. } = self.inner {
+            let msg = format!("SIM problem: {}", msg);
+            self.respond(&msg);
+        }
+        let summary = self.health.summary();
+        if summary.len() > 0 {
+            for line in summary {
+                self.respond(&line);
+            }
+        }
+        else if let ModemInner::Running { .. } = self.inner {
+            self.request_reg();
+            self.request_csq();
+        }
+        Ok(())
+    }
+    fn process_outbox(&mut self) -> Result<()> {
+        // Messages are sent one at a time, so that the modem isn't flooded
+        // with requests and things go out in the right order.
+        if self.outbox_busy {
+            return Ok(());
+        }
+        if self.inner.get_modem().is_err() {
+            trace!("Not processing SMS outbox; modem not running");
+            return Ok(());
+        }
+        let (sms, addr, parts) = match self.next_sendable_sms()? {
+            Some(s) => s,
+            None => return Ok(())
+        };
+        let data = GsmMessageData::encode_message(&sms.text);
+        self.store.update_outgoing_sms_state(sms.id, OutgoingSms::STATE_SENDING)?;
+        let delivery_reports = self.delivery_reports;
+        let mut modem = self.inner.get_modem()?;
+        debug!("Sending {}-part message #{} to {} (attempt {})...", parts, sms.id, addr, sms.attempts + 1);
+        trace!("Message content: {}", sms.text);
+        let mut futs = vec![];
+        for (i, part) in data.into_iter().enumerate() {
+            debug!("Sending part {}/{} of message to {}...", i+1, parts, addr);
+            let mut pdu = Pdu::make_simple_message(addr.clone(), part);
+            // TP-SRR: ask the SMSC to send us a status report.
+            pdu.first_octet.srr = delivery_reports;
+            trace!("PDU: {:?}", pdu);
+            futs.push(cmd::sms::send_sms_pdu(&mut modem, &pdu)
+                      .map_err(Error::from));
+        }
+        let tx = self.int_tx.clone();
+        let id = sms.id;
+        // We can't use `command_timeout!` here, because that treats every
+        // failure as a timeout; a rejected message shouldn't reset the modem.
+        // Each part is timed out on its own, so we know which ones the SMSC
+        // accepted if some of them fail.
+        let timeout = Duration::from_millis(self.cmd_timeout_ms as _);
+        let futs = futs.into_iter()
+            .map(|f| Timeout::new(f, timeout).then(|r| Ok::<_, ()>(r)))
+            .collect::<Vec<_>>();
+        let fut = futures::future::join_all(futs)
+            .map(move |results| {
+                let mut refs = vec![];
+                let mut err = None;
+                let mut timed_out = false;
+                for res in results {
+                    match res {
+                        Ok(r) => refs.push(r),
+                        Err(e) => {
+                            let e = if let Some(e) = e.into_inner() {
+                                e
+                            }
+                            else {
+                                timed_out = true;
+                                format_err!("Modem command timeout reached")
+                            };
+                            err = err.or(Some(e));
+                        }
+                    }
+                }
+                if timed_out {
+                    tx.unbounded_send(ModemCommand::CommandTimeout).unwrap();
+                }
+                let cmd = match err {
+                    None => ModemCommand::OutboxSent(id, refs),
+                    Some(e) if refs.len() == 0 => ModemCommand::OutboxFailed(id, e),
+                    Some(e) => {
+                        let e = format_err!("only {} of {} parts were sent: {}", refs.len(), parts, e);
+                        ModemCommand::OutboxPartlySent(id, refs, e)
+                    }
+                };
+                tx.unbounded_send(cmd).unwrap();
+            });
+        self.outbox_busy = true;
+        self.handle.spawn(fut);
+        Ok(())
+    }
+}
+/// Get the caller's number out of a +CLIP URC (if it wasn't withheld).
+fn parse_clip(val: &AtValue) -> Option<PduAddress> {
+    let vals = match *val {
+        AtValue::Array(ref v) => v as &[AtValue],
+        ref x => ::std::slice::from_ref(x)
+    };
+    let num = match vals.get(0) {
+        Some(AtValue::String(s)) if s.len() > 0 => s,
+        _ => return None
+    };
+    // Type 145 means an international number, which might not have a '+'.
+    let num = match vals.get(1) {
+        Some(AtValue::Integer(145)) if !num.starts_with('+') => format!("+{}", num),
+        _ => num.clone()
+    };
+    match num.parse() {
+        Ok(a) => Some(a),
+        Err(e) => {
+            warn!("Invalid caller ID {}: {:?}", num, e);
+            None
+        }
+    }
+}
+/// Get the status and text out of a +CUSD response.
+fn parse_cusd(val: &AtValue, ussd_pdu: bool) -> Option<(u32, Option<String>)> {
+    let vals = match *val {
+        AtValue::Array(ref v) => v as &[AtValue],
+        ref x => ::std::slice::from_ref(x)
+    };
+    let m = match vals.get(0) {
+        Some(AtValue::Integer(m)) => *m,
+        _ => {
+            warn!("Invalid +CUSD response: {:?}", val);
+            return None;
+        }
+    };
+    let dcs = match vals.get(2) {
+        Some(AtValue::Integer(d)) => *d,
+        _ => 15
+    };
+    let text = match vals.get(1) {
+        Some(AtValue::String(s)) => Some(decode_ussd(s, dcs, ussd_pdu)),
+        _ => None
+    };
+    Some((m, text))
+}
+/// Decode the text of a USSD response with data coding scheme `dcs`.
+fn decode_ussd(s: &str, dcs: u32, ussd_pdu: bool) -> String {
+    use huawei_modem::pdu::HexData;
+
+    // UCS-2 responses are always hex-encoded.
+    if dcs == 72 {
+        if let Ok(data) = HexData::decode(s) {
+            let units = data.chunks(2)
+                .map(|x| ((x[0] as u16) << 8) | *x.get(1).unwrap_or(&0) as u16)
+                .collect::<Vec<_>>();
+            return String::from_utf16_lossy(&units);
+        }
+    }
+    else if ussd_pdu {
+        if let Ok(data) = HexData::decode(s) {
+            return gsm7::unpack_septets(&data).into_iter()
+                .map(gsm7::gsm7_to_char)
+                .collect();
+        }
+    }
+    s.to_owned()
+}
+/// Encode a USSD string as packed GSM 7-bit hex, as some modems want.
+fn encode_ussd_pdu(s: &str) -> String {
+    let septets = s.chars()
+        .map(|x| gsm7::char_to_gsm7(x).unwrap_or(0x3F))
+        .collect::<Vec<_>>();
+    gsm7::pack_septets(&septets, 0).iter()
+        .map(|x| format!("{:02X}", x))
+        .collect()
+}
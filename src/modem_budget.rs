@@ -0,0 +1,130 @@
+//! Limits on how much an SMS backend sends, so that mistakes (like pasting a
+//! huge log into a ghost) don't get expensive.
+
+use huawei_modem::pdu::PduAddress;
+use chrono::prelude::*;
+use std::collections::{VecDeque, HashMap};
+use std::time::{Instant, Duration};
+
+use crate::models::SmsUsage;
+use crate::store::Store;
+use crate::util::{self, Result};
+
+/// How many recipients to list in MODEM USAGE.
+static USAGE_TOP_RECIPIENTS: usize = 5;
+
+pub enum BudgetCheck {
+    Allowed,
+    /// Over the rate limit; try again later.
+    RateLimited,
+    /// Over budget; don't send it at all (for the given reason).
+    Refused(String)
+}
+pub struct SmsBudget {
+    store: Store,
+    /// The backend whose usage we're limiting.
+    backend: String,
+    daily: Option<u32>,
+    recipient_daily: Option<u32>,
+    per_minute: Option<u32>,
+    /// When each segment sent in the last minute was sent.
+    recent: VecDeque<Instant>
+}
+fn start_of_day() -> NaiveDateTime {
+    Local::today().and_hms(0, 0, 0).naive_utc()
+}
+fn start_of_month() -> NaiveDateTime {
+    Local::today().with_day(1).unwrap().and_hms(0, 0, 0).naive_utc()
+}
+fn total(usage: &[SmsUsage]) -> u32 {
+    usage.iter().map(|u| u.segments as u32).sum()
+}
+impl SmsBudget {
+    /// Make a budget for `backend`, from its `daily_segment_budget`,
+    /// `recipient_daily_segment_budget` and `segments_per_minute` settings.
+    pub fn new(store: Store, backend: &str, daily: Option<u32>, recipient_daily: Option<u32>, per_minute: Option<u32>) -> Self {
+        Self {
+            store,
+            backend: backend.to_owned(),
+            daily, recipient_daily, per_minute,
+            recent: VecDeque::new()
+        }
+    }
+    /// Check whether sending `segments` segments to `addr` is allowed.
+    pub fn check(&mut self, addr: &PduAddress, segments: usize) -> Result<BudgetCheck> {
+        let segments = segments as u32;
+        if let Some(max) = self.per_minute {
+            let now = Instant::now();
+            while self.recent.front().map(|&t| now.duration_since(t) > Duration::from_secs(60)).unwrap_or(false) {
+                self.recent.pop_front();
+            }
+            // Something longer than the limit has to go out eventually, so
+            // it's let through once nothing else has been sent for a minute.
+            if self.recent.len() > 0 && self.recent.len() as u32 + segments > max {
+                return Ok(BudgetCheck::RateLimited);
+            }
+        }
+        if self.daily.is_none() && self.recipient_daily.is_none() {
+            return Ok(BudgetCheck::Allowed);
+        }
+        let today = self.store.get_sms_usage_since(&self.backend, start_of_day())?;
+        if let Some(max) = self.daily {
+            let used = total(&today);
+            if used + segments > max {
+                return Ok(BudgetCheck::Refused(format!("it's {} SMS segments, and {} of today's budget of {} have been used already", segments, used, max)));
+            }
+        }
+        if let Some(max) = self.recipient_daily {
+            let num = util::normalize_address(addr);
+            let used = today.iter()
+                .filter(|u| u.phone_number == num)
+                .map(|u| u.segments as u32)
+                .sum::<u32>();
+            if used + segments > max {
+                return Ok(BudgetCheck::Refused(format!("it's {} SMS segments, and {} of today's budget of {} for this recipient have been used already", segments, used, max)));
+            }
+        }
+        Ok(BudgetCheck::Allowed)
+    }
+    /// Note that `segments` segments were just sent.
+    pub fn note_sent(&mut self, segments: usize) {
+        if self.per_minute.is_some() {
+            let now = Instant::now();
+            self.recent.extend(::std::iter::repeat(now).take(segments));
+        }
+    }
+    /// Describe usage today and this month, for MODEM USAGE.
+    pub fn report(&mut self) -> Result<Vec<String>> {
+        let month = self.store.get_sms_usage_since(&self.backend, start_of_month())?;
+        let day_start = start_of_day();
+        let today = month.iter()
+            .filter(|u| u.ts >= day_start)
+            .collect::<Vec<_>>();
+        let today_total = today.iter().map(|u| u.segments as u32).sum::<u32>();
+        let budget = self.daily.map(|x| format!(" (budget: {})", x)).unwrap_or_default();
+        let mut lines = vec![
+            format!("Today: \x02{}\x02 segments in {} messages{}", today_total, today.len(), budget),
+            format!("This month: \x02{}\x02 segments in {} messages", total(&month), month.len())
+        ];
+        let mut by_recip: HashMap<&str, u32> = HashMap::new();
+        for u in today.iter() {
+            *by_recip.entry(u.phone_number.as_str()).or_insert(0) += u.segments as u32;
+        }
+        let mut by_recip = by_recip.into_iter().collect::<Vec<_>>();
+        by_recip.sort_by(|a, b| b.1.cmp(&a.1));
+        for (num, segs) in by_recip.into_iter().take(USAGE_TOP_RECIPIENTS) {
+            let nick = match util::un_normalize_address(num) {
+                Some(a) => self.store.get_recipient_by_addr_opt(&a)?.map(|r| r.nick),
+                None => None
+            };
+            lines.push(format!("- {}: {} segments today", nick.unwrap_or(num.to_owned()), segs));
+        }
+        if let Some(max) = self.recipient_daily {
+            lines.push(format!("Budget per recipient: {} segments a day", max));
+        }
+        if let Some(max) = self.per_minute {
+            lines.push(format!("Rate limit: {} segments a minute", max));
+        }
+        Ok(lines)
+    }
+}
@@ -0,0 +1,202 @@
+//! Keeps an eye on the modem's signal and network registration, and alerts if they go bad.
+
+use tokio_timer::Interval;
+use huawei_modem::at::{AtResponse, AtResponsePacket, AtValue};
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use futures::sync::mpsc::UnboundedSender;
+use std::time::{Instant, Duration};
+use futures::{Async, Stream};
+
+use crate::comm::{ControlBotCommand, InitParameters};
+use crate::config::ModemConfig;
+use crate::util::Result;
+
+/// How many alerts to remember for MODEM STATUS.
+static MAX_EVENTS: usize = 10;
+
+/// One reading of the modem's state.
+#[derive(Debug, Clone)]
+pub struct HealthSample {
+    pub ts: DateTime<Utc>,
+    /// +CSQ RSSI (0-31, or 99 for unknown).
+    pub rssi: Option<u32>,
+    /// +CREG registration status.
+    pub reg: Option<u32>,
+    /// Network operator name, from +COPS.
+    pub operator: Option<String>
+}
+impl HealthSample {
+    fn is_registered(&self) -> bool {
+        self.reg == Some(1) || self.reg == Some(5)
+    }
+    fn is_roaming(&self) -> bool {
+        self.reg == Some(5)
+    }
+    fn signal_below(&self, min: u32) -> bool {
+        // 99 means "not known or not detectable".
+        self.rssi.map(|r| r < min || r == 99).unwrap_or(false)
+    }
+}
+pub fn describe_reg(reg: u32) -> &'static str {
+    match reg {
+        0 => "not registered",
+        1 => "registered (home network)",
+        2 => "searching for a network",
+        3 => "registration denied",
+        5 => "registered (roaming)",
+        _ => "unknown registration state"
+    }
+}
+pub fn describe_rssi(rssi: u32) -> String {
+    match rssi {
+        0..=31 => format!("{} ({} dBm)", rssi, -113 + 2 * rssi as i32),
+        _ => "unknown".into()
+    }
+}
+fn find_response(pkt: &AtResponsePacket, name: &str) -> Option<AtValue> {
+    pkt.responses.iter()
+        .filter_map(|r| match *r {
+            AtResponse::InformationResponse { ref param, ref response } if param == name => Some(response.clone()),
+            _ => None
+        })
+        .next()
+}
+/// Get the registration status out of a `+CREG?` response.
+pub fn parse_creg(pkt: &AtResponsePacket) -> Option<u32> {
+    match find_response(pkt, "+CREG")? {
+        AtValue::Array(v) => match v.get(1) {
+            Some(&AtValue::Integer(stat)) => Some(stat),
+            _ => None
+        },
+        _ => None
+    }
+}
+/// Get the operator name out of a `+COPS?` response.
+pub fn parse_cops(pkt: &AtResponsePacket) -> Option<String> {
+    match find_response(pkt, "+COPS")? {
+        AtValue::Array(v) => match v.get(2) {
+            Some(&AtValue::String(ref op)) => Some(op.clone()),
+            _ => None
+        },
+        _ => None
+    }
+}
+
+pub struct HealthMonitor {
+    cb_tx: UnboundedSender<ControlBotCommand>,
+    prefix: String,
+    history: VecDeque<HealthSample>,
+    events: VecDeque<(DateTime<Utc>, String)>,
+    history_len: usize,
+    min_rssi: u32,
+    timer: Option<Interval>,
+}
+impl HealthMonitor {
+    pub fn new(p: &InitParameters<ModemConfig>, prefix: &str) -> Self {
+        let cb_tx = p.cm.cb_tx.clone();
+        let check_secs = p.cfg2.health_check_secs.unwrap_or(60);
+        // A check interval of 0 turns the monitor off.
+        let timer = if check_secs > 0 {
+            Some(Interval::new(Instant::now(), Duration::new(check_secs, 0)))
+        }
+        else {
+            None
+        };
+        Self {
+            prefix: prefix.to_owned(),
+            history: VecDeque::new(),
+            events: VecDeque::new(),
+            history_len: p.cfg2.health_history.unwrap_or(60),
+            min_rssi: p.cfg2.health_min_rssi.unwrap_or(5),
+            cb_tx, timer
+        }
+    }
+    /// Returns whether it's time to take another sample.
+    pub fn check_due(&mut self) -> Result<bool> {
+        let mut due = false;
+        if let Some(ref mut timer) = self.timer {
+            while let Async::Ready(_) = timer.poll()? {
+                due = true;
+            }
+        }
+        Ok(due)
+    }
+    fn alert(&mut self, msg: String) {
+        warn!("Modem health: {}", msg);
+        self.events.push_back((Utc::now(), msg.clone()));
+        while self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(format!("{}{}", self.prefix, msg)))
+            .unwrap();
+    }
+    pub fn record(&mut self, sample: HealthSample) {
+        trace!("Modem health sample: {:?}", sample);
+        // Things are assumed to be fine before the first sample, so we
+        // alert if they aren't to begin with.
+        let (was_registered, was_roaming, was_weak) = match self.history.back() {
+            Some(s) => (s.is_registered(), s.is_roaming(), s.signal_below(self.min_rssi)),
+            None => (true, false, false)
+        };
+        // Don't alert about things we couldn't find out.
+        if sample.reg.is_some() {
+            if was_registered && !sample.is_registered() {
+                self.alert(format!("Modem lost network registration ({})", describe_reg(sample.reg.unwrap())));
+            }
+            else if !was_registered && sample.is_registered() {
+                self.alert("Modem is registered with the network again".into());
+            }
+            if !was_roaming && sample.is_roaming() {
+                let op = sample.operator.clone().unwrap_or("an unknown network".into());
+                self.alert(format!("Modem is now roaming, on \x02{}\x02", op));
+            }
+        }
+        if sample.rssi.is_some() {
+            if !was_weak && sample.signal_below(self.min_rssi) {
+                self.alert(format!("Modem signal is weak (RSSI {})", describe_rssi(sample.rssi.unwrap())));
+            }
+            else if was_weak && !sample.signal_below(self.min_rssi) {
+                self.alert(format!("Modem signal has recovered (RSSI {})", describe_rssi(sample.rssi.unwrap())));
+            }
+        }
+        self.history.push_back(sample);
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+    }
+    /// Summarise the recent history, for MODEM STATUS.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![];
+        let latest = match self.history.back() {
+            Some(s) => s,
+            None => return lines
+        };
+        let now = Utc::now();
+        lines.push(format!("Signal: \x02{}\x02 | Network: \x02{}\x02 on \x02{}\x02 (checked {}s ago)",
+                           latest.rssi.map(describe_rssi).unwrap_or("unknown".into()),
+                           latest.reg.map(describe_reg).unwrap_or("unknown"),
+                           latest.operator.clone().unwrap_or("unknown operator".into()),
+                           (now - latest.ts).num_seconds()));
+        let rssis = self.history.iter()
+            .filter_map(|s| s.rssi)
+            .filter(|&r| r != 99)
+            .collect::<Vec<_>>();
+        let oldest = self.history.front().unwrap();
+        let span = (now - oldest.ts).num_minutes();
+        if rssis.len() > 0 {
+            let min = rssis.iter().min().unwrap();
+            let max = rssis.iter().max().unwrap();
+            let avg = rssis.iter().sum::<u32>() / rssis.len() as u32;
+            lines.push(format!("Last {}m: signal min/avg/max \x02{}/{}/{}\x02; registered for \x02{}/{}\x02 checks, roaming for \x02{}\x02",
+                               span, min, avg, max,
+                               self.history.iter().filter(|s| s.is_registered()).count(),
+                               self.history.len(),
+                               self.history.iter().filter(|s| s.is_roaming()).count()));
+        }
+        for &(ts, ref msg) in self.events.iter() {
+            lines.push(format!("- {}m ago: {}", (now - ts).num_minutes(), msg));
+        }
+        lines
+    }
+}
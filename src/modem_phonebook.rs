@@ -0,0 +1,147 @@
+//! Importing contacts from the SIM (and modem) phonebooks.
+
+use huawei_modem::at::{AtResponse, AtResponsePacket, AtValue};
+use huawei_modem::pdu::PduAddress;
+use futures::sync::mpsc::UnboundedSender;
+use std::collections::BTreeMap;
+
+use crate::comm::{ContactFactoryCommand, ContactManagerCommand};
+use crate::models::Recipient;
+use crate::store::Store;
+use crate::util::{self, Result};
+
+/// The phonebooks we read, in order: the SIM's, then the modem's own.
+/// Names from later ones win.
+pub static PHONEBOOK_STORAGES: &[&str] = &["SM", "ME"];
+
+#[derive(Debug, Clone)]
+pub struct PhonebookEntry {
+    pub number: String,
+    pub name: String
+}
+/// A step in reading the phonebooks (they have to be read a bit at a time).
+#[derive(Debug)]
+pub enum PhonebookStep {
+    /// We found out the range of indices in a phonebook (or couldn't).
+    Range(&'static str, Option<(u32, u32)>),
+    /// We read the entries in a phonebook.
+    Entries(&'static str, Vec<PhonebookEntry>),
+}
+#[derive(Default)]
+pub struct ImportStats {
+    pub created: usize,
+    pub renamed: usize,
+    pub unchanged: usize,
+    /// Entries we didn't use, because the user (or something else) chose the nick.
+    pub kept: usize,
+    pub invalid: usize
+}
+
+fn find_range(val: &AtValue) -> Option<(u32, u32)> {
+    match *val {
+        AtValue::Range(r) => Some(r),
+        AtValue::Array(ref v) | AtValue::BracketedArray(ref v) => v.iter().filter_map(find_range).next(),
+        _ => None
+    }
+}
+/// Get the index range out of a `+CPBR=?` response.
+pub fn parse_cpbr_range(pkt: &AtResponsePacket) -> Option<(u32, u32)> {
+    pkt.responses.iter()
+        .filter_map(|r| match *r {
+            AtResponse::InformationResponse { ref param, ref response } if param == "+CPBR" => find_range(response),
+            _ => None
+        })
+        .next()
+}
+/// Get the entries out of a `+CPBR=<first>,<last>` response.
+pub fn parse_cpbr_entries(pkt: &AtResponsePacket) -> Vec<PhonebookEntry> {
+    let mut ret = vec![];
+    for r in pkt.responses.iter() {
+        if let AtResponse::InformationResponse { ref param, response: AtValue::Array(ref v) } = *r {
+            if param != "+CPBR" {
+                continue;
+            }
+            if let (Some(AtValue::String(num)), Some(AtValue::String(name))) = (v.get(1), v.get(3)) {
+                // Type 145 means an international number, which might not have a '+'.
+                let number = match v.get(2) {
+                    Some(AtValue::Integer(145)) if !num.starts_with('+') => format!("+{}", num),
+                    _ => num.clone()
+                };
+                ret.push(PhonebookEntry { number, name: name.trim().to_owned() });
+            }
+        }
+    }
+    ret
+}
+/// Find a nick for `addr` based on `nick`, that nobody else is using.
+fn free_nick(store: &mut Store, addr: &PduAddress, nick: &str) -> Result<Option<String>> {
+    let num = util::normalize_address(addr);
+    for i in 1..10 {
+        let candidate = if i == 1 { nick.to_owned() } else { format!("{}{}", nick, i) };
+        match store.get_recipient_by_nick_opt(&candidate)? {
+            Some(r) if r.phone_number != num => continue,
+            _ => return Ok(Some(candidate))
+        }
+    }
+    Ok(None)
+}
+/// Create or update recipients for these phonebook entries.
+pub fn import_entries(store: &mut Store, cf_tx: &mut UnboundedSender<ContactFactoryCommand>, entries: Vec<PhonebookEntry>) -> Result<ImportStats> {
+    let mut stats = ImportStats::default();
+    let mut by_addr = BTreeMap::new();
+    for ent in entries {
+        match ent.number.parse::<PduAddress>() {
+            Ok(addr) if ent.name.len() > 0 && addr.number.0.len() > 0 => {
+                by_addr.insert(util::normalize_address(&addr), (addr, ent.name));
+            },
+            _ => {
+                debug!("Skipping invalid phonebook entry {:?}", ent);
+                stats.invalid += 1;
+            }
+        }
+    }
+    for (_, (addr, name)) in by_addr {
+        let nick = util::string_to_irc_nick(&name);
+        match store.get_recipient_by_addr_opt(&addr)? {
+            Some(recip) => {
+                if recip.nick == nick {
+                    stats.unchanged += 1;
+                    continue;
+                }
+                if !Recipient::should_replace_nick(recip.nicksrc, Recipient::NICKSRC_PHONEBOOK) {
+                    debug!("Not renaming {} to {} (nick source {})", recip.nick, nick, recip.nicksrc);
+                    stats.kept += 1;
+                    continue;
+                }
+                let nick = match free_nick(store, &addr, &nick)? {
+                    Some(n) => n,
+                    None => {
+                        stats.kept += 1;
+                        continue;
+                    }
+                };
+                info!("Renaming {} to {} from the phonebook", recip.nick, nick);
+                let cmd = ContactFactoryCommand::ForwardCommand(
+                    addr,
+                    ContactManagerCommand::ChangeNick(nick, Recipient::NICKSRC_PHONEBOOK)
+                );
+                cf_tx.unbounded_send(cmd).unwrap();
+                stats.renamed += 1;
+            },
+            None => {
+                let nick = match free_nick(store, &addr, &nick)? {
+                    Some(n) => n,
+                    None => {
+                        stats.invalid += 1;
+                        continue;
+                    }
+                };
+                info!("Creating new SMS recipient for {} (nick {}) from the phonebook", addr, nick);
+                store.store_recipient_with_nicksrc(&addr, &nick, Recipient::NICKSRC_PHONEBOOK)?;
+                cf_tx.unbounded_send(ContactFactoryCommand::SetupContact(addr)).unwrap();
+                stats.created += 1;
+            }
+        }
+    }
+    Ok(stats)
+}
@@ -0,0 +1,268 @@
+//! Tracks SMS delivery reports (SMS-STATUS-REPORT PDUs), and alerts if we don't get any.
+
+use tokio_timer::Interval;
+use huawei_modem::pdu::{PduAddress, AddressType, PhoneNumber};
+use chrono::prelude::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use futures::sync::mpsc::UnboundedSender;
+use unicode_segmentation::UnicodeSegmentation;
+use std::time::{Instant, Duration};
+use futures::{Future, Async, Poll, Stream};
+use failure::Error;
+
+use crate::comm::{ControlBotCommand, InitParameters};
+use crate::config::ModemConfig;
+use crate::util::Result;
+
+/// A decoded SMS-STATUS-REPORT PDU (3GPP TS 23.040 § 9.2.2.3).
+#[derive(Debug, Clone)]
+pub struct StatusReport {
+    /// TP-MR: the reference of the message this report is for.
+    pub reference: u8,
+    /// TP-RA: the address the original message was sent to.
+    pub recipient: PduAddress,
+    /// TP-ST: the delivery status.
+    pub status: u8
+}
+impl StatusReport {
+    pub fn from_pdu(data: &[u8]) -> Result<Self> {
+        let get = |i: usize| -> Result<u8> {
+            data.get(i)
+                .map(|x| *x)
+                .ok_or(format_err!("status report truncated at octet {}", i))
+        };
+        // Skip the SMSC address; its length is given in octets.
+        let mut i = 1 + get(0)? as usize;
+        let first_octet = get(i)?;
+        if first_octet & 0b11 != 0b10 {
+            Err(format_err!("PDU isn't a status report (first octet {:02X})", first_octet))?
+        }
+        let reference = get(i + 1)?;
+        // The recipient address length is given in semi-octets.
+        let ra_digits = get(i + 2)? as usize;
+        let toa = AddressType::try_from(get(i + 3)?)
+            .map_err(|e| format_err!("invalid recipient address type: {}", e))?;
+        i += 4;
+        let ra_len = (ra_digits + 1) / 2;
+        if data.len() < i + ra_len {
+            Err(format_err!("status report truncated in recipient address"))?
+        }
+        let number = PhoneNumber::from(&data[i..i + ra_len]);
+        i += ra_len;
+        // Skip TP-SCTS and TP-DT, which are 7 octets each.
+        i += 14;
+        let status = get(i)?;
+        Ok(Self {
+            reference,
+            recipient: PduAddress {
+                type_addr: toa,
+                number
+            },
+            status
+        })
+    }
+    /// Whether the SMSC has stopped trying to deliver the message.
+    pub fn is_final(&self) -> bool {
+        status_is_final(self.status)
+    }
+}
+pub fn status_is_final(st: u8) -> bool {
+    // 0x20-0x3F mean "temporary error, SC still trying".
+    st < 0x20 || st >= 0x40
+}
+pub fn status_is_error(st: u8) -> bool {
+    st >= 0x40
+}
+pub fn describe_status(st: u8) -> &'static str {
+    match st {
+        0x00 => "delivered",
+        0x01 => "forwarded, but unconfirmed",
+        0x02 => "replaced",
+        0x20 => "pending (congestion)",
+        0x21 => "pending (recipient busy)",
+        0x22 => "pending (no response from recipient)",
+        0x23 => "pending (service rejected)",
+        0x24 => "pending (quality of service unavailable)",
+        0x25 => "pending (error in recipient)",
+        0x20..=0x3F => "pending",
+        0x40 => "failed (remote procedure error)",
+        0x41 => "failed (incompatible destination)",
+        0x42 => "failed (connection rejected by recipient)",
+        0x43 => "failed (not obtainable)",
+        0x44 => "failed (quality of service unavailable)",
+        0x45 => "failed (no interworking available)",
+        0x46 => "failed (validity period expired)",
+        0x47 => "failed (deleted by sender)",
+        0x48 => "failed (deleted by SMSC)",
+        0x49 => "failed (message does not exist)",
+        0x40..=0x5F => "failed",
+        0x60 => "failed (congestion)",
+        0x61 => "failed (recipient busy)",
+        0x62 => "failed (no response from recipient)",
+        0x63 => "failed (service rejected)",
+        0x64 => "failed (quality of service unavailable)",
+        0x65 => "failed (error in recipient)",
+        _ => "failed (unknown status)"
+    }
+}
+
+pub struct SmsSendStatus {
+    sent_ts: DateTime<Utc>,
+    destination: PduAddress,
+    text: String,
+    /// Message reference and last status for each part of the message.
+    parts: Vec<(u8, Option<u8>)>,
+    alerted: bool
+}
+impl SmsSendStatus {
+    fn is_final(&self) -> bool {
+        self.parts.iter().all(|&(_, st)| st.map(status_is_final).unwrap_or(false))
+    }
+    fn describe(&self) -> String {
+        let mut statuses = self.parts.iter()
+            .map(|&(_, st)| st.map(describe_status).unwrap_or("no report"))
+            .collect::<Vec<_>>();
+        statuses.dedup();
+        statuses.join(", ")
+    }
+}
+pub struct SmsReceiptTracker {
+    cb_tx: UnboundedSender<ControlBotCommand>,
+    /// map of outbox ID -> status
+    outgoing_messages: HashMap<i32, SmsSendStatus>,
+    receipt_warn: u64,
+    receipt_expiry: u64,
+    timer: Interval,
+}
+impl Future for SmsReceiptTracker {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(_) = self.timer.poll()? {
+            self.check_receipts();
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl SmsReceiptTracker {
+    pub fn new(p: &InitParameters<ModemConfig>) -> Self {
+        let cb_tx = p.cm.cb_tx.clone();
+        let receipt_ivl = p.cfg2.receipt_check_interval_secs.unwrap_or(30);
+        let receipt_warn_ms = p.cfg2.receipt_warn_ms.unwrap_or(600000);
+        let receipt_expiry_ms = p.cfg2.receipt_expiry_ms.unwrap_or(3600000);
+        let timer = Interval::new(Instant::now(), Duration::new(receipt_ivl, 0));
+        Self {
+            receipt_warn: receipt_warn_ms,
+            receipt_expiry: receipt_expiry_ms,
+            outgoing_messages: HashMap::new(),
+            cb_tx, timer
+        }
+    }
+    pub fn register_send(&mut self, id: i32, to: PduAddress, text: String, refs: Vec<u32>) {
+        let sss = SmsSendStatus {
+            sent_ts: Utc::now(),
+            destination: to,
+            parts: refs.into_iter().map(|r| (r as u8, None)).collect(),
+            text,
+            alerted: false
+        };
+        self.outgoing_messages.insert(id, sss);
+    }
+    pub fn on_status_report(&mut self, rpt: StatusReport) {
+        // Message references wrap around after 255, so pick the most
+        // recent message to that recipient with a matching reference.
+        let mut best: Option<(i32, DateTime<Utc>)> = None;
+        for (id, sss) in self.outgoing_messages.iter() {
+            if sss.destination.number.0 != rpt.recipient.number.0 {
+                continue;
+            }
+            if !sss.parts.iter().any(|&(r, _)| r == rpt.reference) {
+                continue;
+            }
+            if best.map(|(_, ts)| sss.sent_ts > ts).unwrap_or(true) {
+                best = Some((*id, sss.sent_ts));
+            }
+        }
+        let id = match best {
+            Some((id, _)) => id,
+            None => {
+                debug!("Status report for unknown message (ref {}, to {}): {}", rpt.reference, rpt.recipient, describe_status(rpt.status));
+                return;
+            }
+        };
+        let sss = self.outgoing_messages.get_mut(&id).unwrap();
+        debug!("Status report for SMS #{} (ref {}): {}", id, rpt.reference, describe_status(rpt.status));
+        for part in sss.parts.iter_mut() {
+            if part.0 == rpt.reference {
+                part.1 = Some(rpt.status);
+            }
+        }
+        if rpt.is_final() && status_is_error(rpt.status) {
+            warn!("SMS #{} to {} failed: {}", id, sss.destination, describe_status(rpt.status));
+            Self::send_fail(&mut self.cb_tx, format!("Error: Delivering SMS to {} failed: {}", sss.destination, describe_status(rpt.status)));
+            sss.alerted = true;
+        }
+    }
+    pub fn print_receipts(&mut self) -> Vec<String> {
+        let now = Utc::now();
+        let mut lines = vec![];
+        let mut ids = self.outgoing_messages.keys().map(|x| *x).collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            let sss = self.outgoing_messages.get(&id).unwrap();
+            let delta = now - sss.sent_ts;
+            let mut summary = sss.text.clone();
+            if summary.len() > 15 {
+                summary = summary.graphemes(true)
+                    .take(10)
+                    .chain(std::iter::once("…"))
+                    .collect();
+            }
+            lines.push(format!("- \"\x1d{}\x1d\" to \x02{}\x02 ({}s ago) is \x02{}\x02",
+                               summary, sss.destination, delta.num_seconds(), sss.describe()));
+            let refs = sss.parts.iter()
+                .map(|&(r, _)| r.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            lines.push(format!("  (outbox ID \x11{}\x0f, message references \x11{}\x0f)", id, refs));
+        }
+        if lines.len() == 0 {
+            lines.push("No outgoing messages".into());
+        }
+        lines
+    }
+    fn send_fail<T: Into<String>>(cb_tx: &mut UnboundedSender<ControlBotCommand>, msg: T) {
+        cb_tx.unbounded_send(ControlBotCommand::ReportFailure(msg.into()))
+            .unwrap();
+    }
+    fn check_receipts(&mut self) {
+        trace!("Checking SMS receipts");
+        let now = Utc::now();
+        for (id, sss) in self.outgoing_messages.iter_mut() {
+            let delta = now - sss.sent_ts;
+            let delta_ms = delta.num_milliseconds() as u64;
+            if !sss.is_final() && delta_ms >= self.receipt_warn && !sss.alerted {
+                warn!("SMS #{} has had no delivery report for {} seconds!", id, delta.num_seconds());
+                Self::send_fail(&mut self.cb_tx, format!("Warning: SMS to {} (outbox ID {}) still hasn't been delivered after {} minutes.", sss.destination, id, delta.num_minutes()));
+                sss.alerted = true;
+            }
+        }
+        let receipt_expiry = self.receipt_expiry;
+        // Keep undelivered messages around for a while after we've warned
+        // about them, so the user can find out what happened with them.
+        let undelivered_expiry = self.receipt_warn + receipt_expiry;
+        self.outgoing_messages.retain(|id, m| {
+            let diff_ms = (now - m.sent_ts).num_milliseconds() as u64;
+            if !m.is_final() {
+                if diff_ms >= undelivered_expiry {
+                    debug!("Forgetting about undelivered SMS #{}", id);
+                    return false;
+                }
+                return true;
+            }
+            diff_ms < receipt_expiry
+        });
+    }
+}
@@ -0,0 +1,314 @@
+//! Routing commands between multiple modems (or other SMS backends).
+
+use futures::{Future, Stream, Poll, Async};
+use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
+use huawei_modem::pdu::PduAddress;
+use std::collections::HashMap;
+use failure::Error;
+use crate::comm::{ModemCommand, ControlBotCommand, InitParameters};
+use crate::modem::ModemManager;
+use crate::sms_http::HttpGateway;
+use crate::sms_smpp::SmppClient;
+use crate::store::Store;
+use crate::pdu_debug;
+use tokio_core::reactor::Handle;
+use crate::util::Result;
+
+/// The name given to the modem in the `[modem]` section, if it doesn't have one.
+pub static DEFAULT_MODEM_NAME: &str = "default";
+
+pub struct ModemRouter {
+    store: Store,
+    /// Name of the backend used when we don't know which one to use.
+    default: String,
+    /// Backend names, in configuration order.
+    names: Vec<String>,
+    modems: HashMap<String, UnboundedSender<ModemCommand>>,
+    rx: UnboundedReceiver<ModemCommand>,
+    cb_tx: UnboundedSender<ControlBotCommand>,
+}
+impl Future for ModemRouter {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(msg) = self.rx.poll().unwrap() {
+            let msg = msg.expect("rx stopped producing");
+            self.route(msg)?;
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl ModemRouter {
+    pub fn new<T>(p: InitParameters<T>) -> Result<Self> {
+        let mut names = vec![
+            p.cfg.modem.name.clone()
+                .unwrap_or_else(|| DEFAULT_MODEM_NAME.into())
+        ];
+        for mcfg in p.cfg.modems.iter() {
+            names.push(mcfg.name.clone()
+                       .ok_or(format_err!("every [[modems]] section needs a name"))?);
+        }
+        for gcfg in p.cfg.http_gateways.iter() {
+            names.push(gcfg.name.clone());
+        }
+        for scfg in p.cfg.smpp.iter() {
+            names.push(scfg.name.clone());
+        }
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                Err(format_err!("more than one SMS backend is called '{}'", name))?
+            }
+        }
+        // If the [modem] section is disabled, it's probably only there
+        // because it always is; prefer whatever's actually configured.
+        let default = if p.cfg.modem.modem_path.is_none() && p.cfg.modem.usb_vendor.is_none() && names.len() > 1 {
+            names[1].clone()
+        }
+        else {
+            names[0].clone()
+        };
+        let mut modems = HashMap::new();
+        let configs = ::std::iter::once(&p.cfg.modem)
+            .chain(p.cfg.modems.iter());
+        for (mcfg, name) in configs.zip(names.iter()) {
+            debug!("Initializing modem {}", name);
+            let mm = ModemManager::new(InitParameters {
+                cfg: p.cfg,
+                cfg2: mcfg,
+                store: p.store.clone(),
+                cm: &mut *p.cm,
+                hdl: p.hdl
+            }, name.clone(), *name == default)?;
+            modems.insert(name.clone(), mm.sender());
+            Self::spawn_backend(p.hdl, name, mm);
+        }
+        for gcfg in p.cfg.http_gateways.iter() {
+            debug!("Initializing HTTP gateway {}", gcfg.name);
+            let gw = HttpGateway::new(InitParameters {
+                cfg: p.cfg,
+                cfg2: gcfg,
+                store: p.store.clone(),
+                cm: &mut *p.cm,
+                hdl: p.hdl
+            }, gcfg.name == default)?;
+            modems.insert(gcfg.name.clone(), gw.sender());
+            Self::spawn_backend(p.hdl, &gcfg.name, gw);
+        }
+        for scfg in p.cfg.smpp.iter() {
+            debug!("Initializing SMPP client {}", scfg.name);
+            let sc = SmppClient::new(InitParameters {
+                cfg: p.cfg,
+                cfg2: scfg,
+                store: p.store.clone(),
+                cm: &mut *p.cm,
+                hdl: p.hdl
+            }, scfg.name == default)?;
+            modems.insert(scfg.name.clone(), sc.sender());
+            Self::spawn_backend(p.hdl, &scfg.name, sc);
+        }
+        let rx = p.cm.modem_rx.take().unwrap();
+        let cb_tx = p.cm.cb_tx.clone();
+        Ok(Self {
+            store: p.store,
+            default, names, modems, rx, cb_tx
+        })
+    }
+    fn spawn_backend<F>(hdl: &Handle, name: &str, fut: F) where F: Future<Item = (), Error = Error> + 'static {
+        let name = name.to_owned();
+        hdl.spawn(fut.map_err(move |e| {
+            // FIXME: restartability
+
+            error!("SMS backend {} failed: {}", name, e);
+            panic!("sms backend failed");
+        }));
+    }
+    fn route(&mut self, msg: ModemCommand) -> Result<()> {
+        use self::ModemCommand::*;
+
+        match msg {
+            ForModem(name, cmd) => {
+                if self.modems.contains_key(&name) {
+                    self.send_to(&name, *cmd);
+                }
+                else {
+                    self.respond(format!("Error: there's no modem called \x02{}\x02 (try \x02MODEM LIST\x02)", name));
+                }
+            },
+            SendMessage(addr, msg) => {
+                let name = self.modem_for(&addr)?;
+                debug!("Routing SMS to {} via modem {}", addr, name);
+                self.send_to(&name, SendMessage(addr, msg));
+            },
+            SetRecipientModem(nick, name) => self.set_recipient_modem(nick, name)?,
+            SetRecipientTransliterate(nick, tl) => self.set_recipient_transliterate(nick, tl)?,
+            SetRecipientReplyable(nick, r) => self.set_recipient_replyable(nick, r)?,
+            ListModems => self.list_modems(),
+            ListPdus => self.list_pdus()?,
+            ShowPdu(id) => self.show_pdu(id)?,
+            DecodePdu(hex) => self.decode_pdu(&hex),
+            // These aren't specific to any one modem.
+            x @ MakeContact(_) | x @ ListPartials | x @ Ussd(_) | x @ PhonebookImport => {
+                let default = self.default.clone();
+                self.send_to(&default, x);
+            },
+            // This one's meant for the modem in the [modem] section.
+            UpdatePath(path) => {
+                let first = self.names[0].clone();
+                self.send_to(&first, UpdatePath(path));
+            },
+            DoCmgl => self.broadcast(|| DoCmgl),
+            ProcessOutbox => self.broadcast(|| ProcessOutbox),
+            RequestCsq => self.broadcast(|| RequestCsq),
+            RequestReg => self.broadcast(|| RequestReg),
+            PrintReceipts => self.broadcast(|| PrintReceipts),
+            ForceReinit => self.broadcast(|| ForceReinit),
+            RequestStatus => self.broadcast(|| RequestStatus),
+            RequestUsage => self.broadcast(|| RequestUsage),
+            CmglComplete(_) | CmglFailed(_) | OutboxSent(..) | OutboxFailed(..) | OutboxPartlySent(..) | CommandTimeout | CheckUsb(_) | CheckPartials | IncomingText(..) | UssdReceived(..) | HealthSampled(_) | Phonebook(_) => {
+                warn!("Modem router got a modem-internal command; ignoring");
+            }
+        }
+        Ok(())
+    }
+    /// Work out which modem to send SMS messages to `addr` through.
+    fn modem_for(&mut self, addr: &PduAddress) -> Result<String> {
+        if let Some(recip) = self.store.get_recipient_by_addr_opt(addr)? {
+            for name in recip.modem.iter().chain(recip.last_modem.iter()) {
+                if self.modems.contains_key(name) {
+                    return Ok(name.clone());
+                }
+                warn!("Recipient {} wants modem {}, which doesn't exist any more", addr, name);
+            }
+        }
+        Ok(self.default.clone())
+    }
+    fn set_recipient_modem(&mut self, nick: String, name: Option<String>) -> Result<()> {
+        let recip = match self.store.get_recipient_by_nick_opt(&nick)? {
+            Some(r) => r,
+            None => {
+                self.respond(format!("Error: no ghost called \x02{}\x02", nick));
+                return Ok(());
+            }
+        };
+        if let Some(ref n) = name {
+            if !self.modems.contains_key(n) {
+                self.respond(format!("Error: there's no modem called \x02{}\x02 (try \x02MODEM LIST\x02)", n));
+                return Ok(());
+            }
+        }
+        let addr = recip.get_addr()?;
+        self.store.update_recipient_modem(&addr, name.as_ref().map(|x| x as &str))?;
+        match name {
+            Some(n) => self.respond(format!("SMS messages to \x02{}\x02 will be sent via modem \x02{}\x02.", nick, n)),
+            None => self.respond(format!("SMS messages to \x02{}\x02 will be sent via the modem they last used.", nick))
+        }
+        Ok(())
+    }
+    fn set_recipient_transliterate(&mut self, nick: String, tl: bool) -> Result<()> {
+        let recip = match self.store.get_recipient_by_nick_opt(&nick)? {
+            Some(r) => r,
+            None => {
+                self.respond(format!("Error: no ghost called \x02{}\x02", nick));
+                return Ok(());
+            }
+        };
+        let addr = recip.get_addr()?;
+        self.store.update_recipient_transliterate(&addr, tl)?;
+        if tl {
+            self.respond(format!("SMS messages to \x02{}\x02 will be transliterated into the GSM 7-bit alphabet.", nick));
+        }
+        else {
+            self.respond(format!("SMS messages to \x02{}\x02 will be sent as written.", nick));
+        }
+        Ok(())
+    }
+    fn set_recipient_replyable(&mut self, nick: String, r: bool) -> Result<()> {
+        let recip = match self.store.get_recipient_by_nick_opt(&nick)? {
+            Some(r) => r,
+            None => {
+                self.respond(format!("Error: no ghost called \x02{}\x02", nick));
+                return Ok(());
+            }
+        };
+        let addr = recip.get_addr()?;
+        self.store.update_recipient_replyable(&addr, r)?;
+        if r {
+            self.respond(format!("Messages to \x02{}\x02 will be sent.", nick));
+        }
+        else {
+            self.respond(format!("Messages to \x02{}\x02 will be refused, since they can't be replied to.", nick));
+        }
+        Ok(())
+    }
+    fn list_pdus(&mut self) -> Result<()> {
+        let msgs = self.store.get_all_pdu_messages()?;
+        if msgs.len() == 0 {
+            self.respond("No SMS messages are waiting to be delivered.".into());
+        }
+        for msg in msgs {
+            self.respond(pdu_debug::describe_message(&msg));
+        }
+        Ok(())
+    }
+    fn show_pdu(&mut self, id: i32) -> Result<()> {
+        let msg = match self.store.get_message_by_id_opt(id)? {
+            Some(m) => m,
+            None => {
+                self.respond(format!("Error: there's no stored message #{}", id));
+                return Ok(());
+            }
+        };
+        self.respond(pdu_debug::describe_message(&msg));
+        if let Some(pdu) = msg.pdu {
+            self.describe_pdu(&pdu);
+        }
+        Ok(())
+    }
+    fn decode_pdu(&mut self, hex: &str) {
+        match pdu_debug::parse_hex(hex) {
+            Ok(pdu) => {
+                self.respond(format!("Decoded: {}", pdu_debug::decode_result(&pdu)));
+                self.describe_pdu(&pdu);
+            },
+            Err(e) => self.respond(format!("Error: {}", e))
+        }
+    }
+    fn describe_pdu(&mut self, pdu: &[u8]) {
+        use huawei_modem::pdu::HexData;
+
+        self.respond(format!("PDU: {}", HexData(pdu)));
+        for line in pdu_debug::dissect_deliver(pdu) {
+            self.respond(line);
+        }
+    }
+    fn list_modems(&mut self) {
+        let mut lines = vec![];
+        for name in self.names.iter() {
+            if *name == self.default {
+                lines.push(format!("- \x02{}\x02 (default)", name));
+            }
+            else {
+                lines.push(format!("- \x02{}\x02", name));
+            }
+        }
+        for line in lines {
+            self.respond(line);
+        }
+    }
+    fn send_to(&mut self, name: &str, cmd: ModemCommand) {
+        self.modems.get(name)
+            .expect("send_to() with a nonexistent modem")
+            .unbounded_send(cmd)
+            .unwrap();
+    }
+    fn broadcast<F: Fn() -> ModemCommand>(&mut self, f: F) {
+        for tx in self.modems.values() {
+            tx.unbounded_send(f()).unwrap();
+        }
+    }
+    fn respond(&mut self, msg: String) {
+        self.cb_tx.unbounded_send(ControlBotCommand::CommandResponse(msg))
+            .unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+//! Checking (and unlocking) the SIM card while the modem starts up.
+
+use huawei_modem::HuaweiModem;
+use huawei_modem::at::{AtResponse, AtResponsePacket, AtResultCode, AtCommand, AtValue};
+use futures::{future, Future};
+use failure::Error;
+use tokio_timer::Delay;
+use std::time::{Instant, Duration};
+use std::fs;
+
+use crate::config::ModemConfig;
+use crate::util::Result;
+
+/// How long to give the SIM to get going after it's been unlocked.
+static UNLOCK_SETTLE_MS: u64 = 3000;
+
+/// A problem with the SIM that retrying won't fix, so the modem shouldn't
+/// keep on restarting because of it.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct SimError(pub String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SimState {
+    Ready,
+    PinRequired,
+    PukRequired,
+    NotInserted,
+    /// The SIM is still starting up (or is busy doing something else).
+    Busy,
+    /// The SIM wants something else (like a PH-SIM PIN), is broken, or we
+    /// didn't understand what the modem said.
+    Other(String)
+}
+/// How to unlock the SIM, from the modem's configuration.
+#[derive(Debug, Clone)]
+pub struct SimUnlock {
+    pin: Option<String>,
+    pin_file: Option<String>,
+    min_attempts: u32
+}
+impl SimUnlock {
+    pub fn new(cfg: &ModemConfig) -> Self {
+        Self {
+            pin: cfg.pin.clone(),
+            pin_file: cfg.pin_file.clone(),
+            min_attempts: cfg.pin_min_attempts.unwrap_or(2)
+        }
+    }
+    /// Get the PIN. The file is read every time, so it can be fixed without
+    /// restarting the bridge.
+    fn get_pin(&self) -> Result<Option<String>> {
+        if let Some(ref pin) = self.pin {
+            return Ok(Some(pin.clone()));
+        }
+        if let Some(ref path) = self.pin_file {
+            let pin = fs::read_to_string(path)
+                .map_err(|e| SimError(format!("couldn't read SIM PIN file {}: {}", path, e)))?;
+            return Ok(Some(pin.trim().to_owned()));
+        }
+        Ok(None)
+    }
+}
+fn value_text(val: &AtValue) -> Option<&str> {
+    match *val {
+        AtValue::String(ref s) | AtValue::Unknown(ref s) => Some(s.trim()),
+        AtValue::Array(ref v) => v.get(0).and_then(value_text),
+        _ => None
+    }
+}
+/// Work out the SIM's state from an `AT+CPIN?` response.
+pub fn parse_cpin(pkt: &AtResponsePacket) -> SimState {
+    match pkt.status {
+        AtResultCode::Ok => {},
+        // 10 is "SIM not inserted"; 13 is "SIM failure"; 14 is "SIM busy".
+        AtResultCode::CmeError(10) => return SimState::NotInserted,
+        AtResultCode::CmeError(13) => return SimState::Other("SIM failure".into()),
+        AtResultCode::CmeError(14) => return SimState::Busy,
+        AtResultCode::CmeErrorString(ref s) if s.to_lowercase().contains("not inserted") => {
+            return SimState::NotInserted;
+        },
+        AtResultCode::CmeErrorString(ref s) if s.to_lowercase().contains("busy") || s.to_lowercase().contains("not ready") => {
+            return SimState::Busy;
+        },
+        ref x => return SimState::Other(format!("{:?}", x))
+    }
+    let state = pkt.responses.iter()
+        .filter_map(|r| match *r {
+            AtResponse::InformationResponse { ref param, ref response } if param == "+CPIN" => value_text(response),
+            _ => None
+        })
+        .next();
+    match state {
+        Some("READY") => SimState::Ready,
+        Some("SIM PIN") => SimState::PinRequired,
+        Some("SIM PUK") => SimState::PukRequired,
+        Some("NOT READY") | Some("SIM BUSY") => SimState::Busy,
+        Some(x) => SimState::Other(x.into()),
+        None => SimState::Other("no +CPIN response".into())
+    }
+}
+/// Get the number of PIN attempts left out of a Huawei `AT^CPIN?` response
+/// (`^CPIN: <code>,[<times>],<puk_times>,<pin_times>,<puk2_times>,<pin2_times>`).
+pub fn parse_pin_attempts(pkt: &AtResponsePacket) -> Option<u32> {
+    pkt.responses.iter()
+        .filter_map(|r| match *r {
+            AtResponse::InformationResponse { ref param, response: AtValue::Array(ref v) } if param == "^CPIN" => {
+                match v.get(3) {
+                    Some(&AtValue::Integer(n)) => Some(n),
+                    _ => None
+                }
+            },
+            _ => None
+        })
+        .next()
+}
+fn enter_pin(mut modem: HuaweiModem, pin: String, attempts: Option<u32>) -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
+    info!("Entering SIM PIN ({} attempts left)", attempts.map(|x| x.to_string()).unwrap_or("unknown".into()));
+    let cmd = AtCommand::Equals {
+        param: "+CPIN".into(),
+        value: AtValue::String(pin)
+    };
+    let fut = modem.send_raw(cmd)
+        .map_err(|e| Error::from(e))
+        .and_then(move |pkt| {
+            let wrong_pin = match pkt.status {
+                AtResultCode::Ok => false,
+                // 16 is "incorrect password".
+                AtResultCode::CmeError(16) => true,
+                AtResultCode::CmeErrorString(ref s) if s.to_lowercase().contains("incorrect password") => true,
+                ref x => return Err(format_err!("entering the SIM PIN failed: {:?}", x))
+            };
+            if wrong_pin {
+                // Don't try again: that would just use up another attempt.
+                let left = attempts.map(|x| format!("{}", x.saturating_sub(1))).unwrap_or("an unknown number of".into());
+                let msg = format!("the SIM rejected the configured PIN ({} attempts left). Fix the PIN, then use MODEM RESTART.", left);
+                return Err(SimError(msg).into());
+            }
+            info!("SIM unlocked");
+            let delay = Delay::new(Instant::now() + Duration::from_millis(UNLOCK_SETTLE_MS))
+                .map_err(|e| Error::from(e))
+                .map(move |_| modem);
+            Ok(delay)
+        })
+        .flatten();
+    Box::new(fut)
+}
+/// Check that the SIM is ready to go, unlocking it if it needs a PIN.
+///
+/// Problems that need a human to sort out (no SIM, a PUK lock, or a PIN we
+/// can't or won't enter) fail with a `SimError`; anything else fails with an
+/// ordinary error, so the modem gets restarted and the SIM checked again.
+pub fn unlock(mut modem: HuaweiModem, sim: SimUnlock) -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
+    let fut = modem.send_raw(AtCommand::Read { param: "+CPIN".into() })
+        .map_err(|e| Error::from(e))
+        .and_then(move |pkt| -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
+            let state = parse_cpin(&pkt);
+            debug!("SIM state: {:?}", state);
+            let err = |msg: String| -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
+                Box::new(future::err(SimError(msg).into()))
+            };
+            match state {
+                SimState::Ready => Box::new(future::ok(modem)),
+                SimState::NotInserted => err("there's no SIM card in the modem.".into()),
+                SimState::PukRequired => err("the SIM is PUK-locked (too many wrong PINs). Unlock it with the PUK in a phone, then use MODEM RESTART.".into()),
+                SimState::Busy => Box::new(future::err(format_err!("the SIM isn't ready yet"))),
+                SimState::Other(s) => Box::new(future::err(format_err!("the SIM isn't usable ({})", s))),
+                SimState::PinRequired => {
+                    let pin = match sim.get_pin() {
+                        Ok(Some(p)) => p,
+                        Ok(None) => return err("the SIM is PIN-locked; set `pin` or `pin_file` in the modem's configuration.".into()),
+                        Err(e) => return Box::new(future::err(e))
+                    };
+                    let min_attempts = sim.min_attempts;
+                    // Not all modems support ^CPIN; if it fails, we carry on
+                    // without knowing how many attempts are left.
+                    let fut = modem.send_raw(AtCommand::Read { param: "^CPIN".into() })
+                        .then(move |res| -> Box<dyn Future<Item = HuaweiModem, Error = Error>> {
+                            let attempts = res.ok().and_then(|pkt| parse_pin_attempts(&pkt));
+                            if let Some(n) = attempts {
+                                if n < min_attempts {
+                                    let msg = format!("the SIM is PIN-locked, but only {} PIN attempt(s) are left, so it wasn't unlocked. Unlock it in a phone, then use MODEM RESTART.", n);
+                                    return Box::new(future::err(SimError(msg).into()));
+                                }
+                            }
+                            enter_pin(modem, pin, attempts)
+                        });
+                    Box::new(fut)
+                }
+            }
+        });
+    Box::new(fut)
+}
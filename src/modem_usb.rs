@@ -0,0 +1,203 @@
+//! Finding a USB modem's serial port from sysfs, so it can be found again when
+//! it comes back as a different `/dev/ttyUSBn` (e.g. after a USB reset).
+
+use futures::sync::mpsc::UnboundedSender;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::{mem, thread};
+
+use crate::comm::ModemCommand;
+use crate::config::ModemConfig;
+use crate::util::Result;
+
+static SYSFS_TTY: &str = "/sys/class/tty";
+
+/// Which USB device (and interface on it) the modem is.
+#[derive(Debug, Clone)]
+pub struct UsbId {
+    vendor: String,
+    product: String,
+    serial: Option<String>,
+    interface: Option<u8>
+}
+fn read_attr(dir: &Path, attr: &str) -> Option<String> {
+    fs::read_to_string(dir.join(attr))
+        .ok()
+        .map(|s| s.trim().to_owned())
+}
+/// Get the vendor/product/serial directory and interface directory a tty
+/// belongs to, by walking up its sysfs device path.
+fn usb_dirs(tty: &Path) -> Option<(PathBuf, PathBuf)> {
+    let mut dir = fs::canonicalize(tty.join("device")).ok()?;
+    let mut interface = None;
+    loop {
+        if interface.is_none() && dir.join("bInterfaceNumber").exists() {
+            interface = Some(dir.clone());
+        }
+        else if dir.join("idVendor").exists() {
+            return interface.map(|i| (dir, i));
+        }
+        if !dir.pop() {
+            return None;
+        }
+    }
+}
+/// Check that `id` looks like a USB vendor or product ID (four hex digits).
+fn check_usb_id(field: &str, id: &str) -> Result<String> {
+    if id.len() != 4 || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+        Err(format_err!("{} should be four hex digits (like \"12d1\"), not \"{}\"", field, id))?
+    }
+    Ok(id.to_lowercase())
+}
+/// If a kernel uevent is about a tty being added or removed, say which tty
+/// (like `ttyUSB2`) and whether it was added.
+///
+/// Uevents look like `ACTION@DEVPATH`, followed by `KEY=value` pairs, all
+/// separated by NULs.
+fn tty_hotplug(msg: &[u8]) -> Option<(String, bool)> {
+    let mut fields = msg.split(|&b| b == 0)
+        .map(|f| String::from_utf8_lossy(f));
+    let added = match fields.next()?.split('@').next() {
+        Some("add") => true,
+        Some("remove") => false,
+        _ => return None
+    };
+    let mut is_tty = false;
+    let mut name = String::new();
+    for f in fields {
+        if f == "SUBSYSTEM=tty" {
+            is_tty = true;
+        }
+        else if let Some(n) = f.strip_prefix("DEVNAME=") {
+            name = n.to_owned();
+        }
+    }
+    if is_tty {
+        Some((name, added))
+    }
+    else {
+        None
+    }
+}
+/// Listen for ttys being plugged in or unplugged, sending a `CheckUsb` to
+/// `tx` whenever one is (with the tty's name, if it was plugged in).
+///
+/// Fails if we can't listen for kernel uevents (e.g. because we're not
+/// allowed to); the caller should fall back to polling in that case.
+pub fn watch_hotplug(tx: UnboundedSender<ModemCommand>) -> Result<()> {
+    let sock = unsafe {
+        let fd = libc::socket(libc::AF_NETLINK, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, libc::NETLINK_KOBJECT_UEVENT);
+        if fd < 0 {
+            Err(io::Error::last_os_error())?
+        }
+        let mut addr: libc::sockaddr_nl = mem::zeroed();
+        addr.nl_family = libc::AF_NETLINK as _;
+        // Multicast group 1 gets the kernel's own uevents, which (unlike
+        // udev's) are there whether or not udev is running.
+        addr.nl_groups = 1;
+        if libc::bind(fd, &addr as *const _ as *const libc::sockaddr, mem::size_of::<libc::sockaddr_nl>() as _) < 0 {
+            let e = io::Error::last_os_error();
+            libc::close(fd);
+            Err(e)?
+        }
+        File::from_raw_fd(fd)
+    };
+    thread::spawn(move || {
+        let mut sock = sock;
+        let mut buf = vec![0; 8192];
+        loop {
+            let n = match sock.read(&mut buf) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("Failed to read USB hotplug events: {}", e);
+                    return;
+                }
+            };
+            if let Some((name, added)) = tty_hotplug(&buf[..n]) {
+                trace!("tty hotplug event: {:?}", String::from_utf8_lossy(&buf[..n]));
+                let added = if added { Some(name) } else { None };
+                if tx.unbounded_send(ModemCommand::CheckUsb(added)).is_err() {
+                    // The modem's gone away.
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+impl UsbId {
+    /// Get the USB identification from the modem's configuration, if there is any.
+    pub fn new(cfg: &ModemConfig) -> Result<Option<Self>> {
+        let (vendor, product) = match (cfg.usb_vendor.as_ref(), cfg.usb_product.as_ref()) {
+            (Some(v), Some(p)) => (check_usb_id("usb_vendor", v)?, check_usb_id("usb_product", p)?),
+            (None, None) => {
+                if cfg.usb_serial.is_some() || cfg.usb_interface.is_some() {
+                    Err(format_err!("usb_serial and usb_interface need usb_vendor and usb_product too"))?
+                }
+                return Ok(None);
+            },
+            _ => Err(format_err!("usb_vendor and usb_product have to be given together"))?
+        };
+        Ok(Some(Self {
+            vendor, product,
+            serial: cfg.usb_serial.clone(),
+            interface: cfg.usb_interface
+        }))
+    }
+    fn matches(&self, device: &Path, interface: &Path) -> bool {
+        if read_attr(device, "idVendor").as_ref() != Some(&self.vendor) ||
+            read_attr(device, "idProduct").as_ref() != Some(&self.product) {
+            return false;
+        }
+        if let Some(ref serial) = self.serial {
+            if read_attr(device, "serial").as_ref() != Some(serial) {
+                return false;
+            }
+        }
+        if let Some(num) = self.interface {
+            // bInterfaceNumber is in hex, like "02".
+            let found = read_attr(interface, "bInterfaceNumber")
+                .and_then(|n| u8::from_str_radix(&n, 16).ok());
+            if found != Some(num) {
+                return false;
+            }
+        }
+        true
+    }
+    /// Look for the modem's tty in sysfs, returning the path to its device
+    /// node if it's plugged in.
+    ///
+    /// If more than one tty matches (which happens if `usb_interface` isn't
+    /// set, since modems have several), the first one in name order wins.
+    pub fn resolve(&self) -> Result<Option<String>> {
+        let mut names = fs::read_dir(SYSFS_TTY)?
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let tty = Path::new(SYSFS_TTY).join(&name);
+            if let Some((device, interface)) = usb_dirs(&tty) {
+                if self.matches(&device, &interface) {
+                    return Ok(Some(format!("/dev/{}", name)));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+impl ::std::fmt::Display for UsbId {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "USB {}:{}", self.vendor, self.product)?;
+        if let Some(ref serial) = self.serial {
+            write!(f, " serial {}", serial)?;
+        }
+        if let Some(num) = self.interface {
+            write!(f, " interface {}", num)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+//! Picking apart SMS PDUs, for debugging modem quirks from IRC.
+
+use huawei_modem::pdu::{PduAddress, AddressType, PhoneNumber, DeliverPdu, HexData};
+use chrono::Utc;
+use std::convert::TryFrom;
+
+use crate::models::Message;
+use crate::util::Result;
+
+/// How much of a message's text to show in listings.
+static PREVIEW_CHARS: usize = 40;
+
+/// Decode a semi-octet (swapped nibble) BCD value, as used in timestamps.
+fn semi_octet(b: u8) -> u8 {
+    (b & 0x0F) * 10 + ((b >> 4) & 0x0F)
+}
+fn describe_dcs(dcs: u8) -> String {
+    let alphabet = match dcs >> 4 {
+        0x0..=0x3 => match (dcs >> 2) & 0b11 {
+            0 => "GSM 7-bit",
+            1 => "8-bit data",
+            2 => "UCS-2",
+            _ => "reserved alphabet"
+        },
+        0xF => if dcs & 0b100 != 0 { "8-bit data" } else { "GSM 7-bit" },
+        0xC | 0xD => "GSM 7-bit (message waiting)",
+        0xE => "UCS-2 (message waiting)",
+        _ => "reserved coding group"
+    };
+    let class = if ((dcs >> 4) <= 0x3 && dcs & 0b1_0000 != 0) || dcs >> 4 == 0xF {
+        format!(", class {}", dcs & 0b11)
+    }
+    else {
+        String::new()
+    };
+    format!("{}{}", alphabet, class)
+}
+fn describe_udh(udh: &[u8]) -> Vec<String> {
+    let mut ret = vec![];
+    let mut i = 0;
+    while i + 2 <= udh.len() {
+        let (iei, len) = (udh[i], udh[i + 1] as usize);
+        let data = match udh.get(i + 2..i + 2 + len) {
+            Some(d) => d,
+            None => {
+                ret.push(format!("  IE {:02X}: truncated", iei));
+                break;
+            }
+        };
+        let desc = match (iei, data) {
+            (0x00, &[rf, total, seq]) => format!("concatenated SMS, reference {}, part {}/{}", rf, seq, total),
+            (0x08, &[rf1, rf2, total, seq]) => format!("concatenated SMS, 16-bit reference {}, part {}/{}", (rf1 as u16) << 8 | rf2 as u16, seq, total),
+            (0x24, &[lang]) => format!("national language single shift table {}", lang),
+            (0x25, &[lang]) => format!("national language locking shift table {}", lang),
+            _ => format!("unknown, data {}", HexData(data))
+        };
+        ret.push(format!("  IE {:02X}: {}", iei, desc));
+        i += 2 + len;
+    }
+    ret
+}
+/// Describe an SMS-DELIVER PDU (with the SMSC address in front, as the modem
+/// gives it to us) field by field, as far as it can be parsed.
+pub fn dissect_deliver(data: &[u8]) -> Vec<String> {
+    let mut ret = vec![];
+    if let Err(e) = dissect_deliver_inner(data, &mut ret) {
+        ret.push(format!("\x0304Parsing stopped:\x0f {}", e));
+    }
+    ret
+}
+fn slice(data: &[u8], i: usize, len: usize) -> Result<&[u8]> {
+    data.get(i..i + len)
+        .ok_or(format_err!("PDU truncated at octet {} (wanted {} octets)", i, len))
+}
+fn dissect_deliver_inner(data: &[u8], out: &mut Vec<String>) -> Result<()> {
+    let get = |i: usize| -> Result<u8> {
+        data.get(i)
+            .map(|x| *x)
+            .ok_or(format_err!("PDU truncated at octet {}", i))
+    };
+    // The SMSC address's length is given in octets (including the type).
+    let sca_len = get(0)? as usize;
+    if sca_len == 0 {
+        out.push("SMSC: (none given)".into());
+    }
+    else {
+        let sca = slice(data, 1, sca_len)?;
+        out.push(format!("SMSC: type {:02X}, number {}", sca[0], HexData(&sca[1..])));
+    }
+    let mut i = 1 + sca_len;
+    let fo = get(i)?;
+    out.push(format!("First octet: {:02X} (MTI {}, MMS {}, SRI {}, UDHI {}, RP {})",
+                     fo, fo & 0b11, (fo >> 2) & 1, (fo >> 5) & 1, (fo >> 6) & 1, (fo >> 7) & 1));
+    if fo & 0b11 != 0b00 {
+        Err(format_err!("MTI is {}, so this isn't an SMS-DELIVER", fo & 0b11))?
+    }
+    let udhi = fo & 0b0100_0000 != 0;
+    // The originating address's length is given in semi-octets.
+    let oa_digits = get(i + 1)? as usize;
+    let toa = get(i + 2)?;
+    i += 3;
+    let oa = slice(data, i, (oa_digits + 1) / 2)?;
+    let decoded = AddressType::try_from(toa).ok()
+        .map(|type_addr| PduAddress { type_addr, number: PhoneNumber::from(oa) }.to_string())
+        .unwrap_or("(invalid address type)".into());
+    out.push(format!("Originating address: type {:02X}, {} semi-octets, raw {} = {}", toa, oa_digits, HexData(oa), decoded));
+    i += oa.len();
+    out.push(format!("Protocol identifier: {:02X}", get(i)?));
+    let dcs = get(i + 1)?;
+    out.push(format!("Data coding scheme: {:02X} ({})", dcs, describe_dcs(dcs)));
+    let ts = slice(data, i + 2, 7)?;
+    let tz = semi_octet(ts[6] & 0xF7) as i32 * 15;
+    let tz = if ts[6] & 0x08 != 0 { -tz } else { tz };
+    out.push(format!("SMSC timestamp: 20{:02}-{:02}-{:02} {:02}:{:02}:{:02} (UTC{:+}m)",
+                     semi_octet(ts[0]), semi_octet(ts[1]), semi_octet(ts[2]),
+                     semi_octet(ts[3]), semi_octet(ts[4]), semi_octet(ts[5]), tz));
+    i += 9;
+    let udl = get(i)?;
+    let ud = &data[(i + 1).min(data.len())..];
+    out.push(format!("User data length: {} ({}), {} octets present", udl, if describe_dcs(dcs).starts_with("GSM") { "septets" } else { "octets" }, ud.len()));
+    if udhi {
+        let udhl = get(i + 1)? as usize;
+        let udh = slice(data, i + 2, udhl)?;
+        out.push(format!("User data header: {} octets", udhl));
+        out.extend(describe_udh(udh));
+    }
+    out.push(format!("User data: {}", HexData(ud)));
+    Ok(())
+}
+/// Try to decode a PDU the way sms-irc normally would, describing the result.
+pub fn decode_result(data: &[u8]) -> String {
+    let pdu = match DeliverPdu::try_from(data) {
+        Ok(p) => p,
+        Err(e) => return format!("\x0304undecodable PDU:\x0f {}", e)
+    };
+    match pdu.get_message_data().decode_message() {
+        Ok(m) => {
+            let concat = m.udh.and_then(|x| x.get_concatenated_sms_data())
+                .map(|cd| format!(" [part {}/{} of {}]", cd.sequence, cd.parts, cd.reference))
+                .unwrap_or_default();
+            let mut preview = m.text.chars().take(PREVIEW_CHARS).collect::<String>();
+            if m.text.chars().count() > PREVIEW_CHARS {
+                preview.push_str("...");
+            }
+            format!("\"{}\"{}", preview, concat)
+        },
+        Err(e) => format!("\x0304indecipherable:\x0f {}", e)
+    }
+}
+/// Summarise a stored message, for listings.
+pub fn describe_message(msg: &Message) -> String {
+    let age = Utc::now().naive_utc().signed_duration_since(msg.ts).num_minutes();
+    let via = msg.modem.as_ref().map(|m| format!(" via {}", m)).unwrap_or_default();
+    let content = match (msg.pdu.as_ref(), msg.text.as_ref()) {
+        (Some(pdu), _) => decode_result(pdu),
+        (None, Some(text)) => format!("plain text, {} characters", text.chars().count()),
+        (None, None) => "(empty)".into()
+    };
+    format!("#{} from \x02{}\x02{}, {}m ago: {}", msg.id, msg.phone_number, via, age, content)
+}
+/// Parse a hex PDU pasted by the admin (spaces are allowed).
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    let hex = hex.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    let data = HexData::decode(&hex)
+        .map_err(|e| format_err!("invalid hex: {}", e))?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dissect(hex: &str) -> Vec<String> {
+        dissect_deliver(&parse_hex(hex).unwrap())
+    }
+
+    #[test]
+    fn dissects_gsm7_deliver() {
+        let lines = dissect("0791447758100650 04 0C91447700091032 00 00 91207121436500 05 E8329BFD06");
+        assert_eq!(lines, vec![
+            "SMSC: type 91, number 447758100650",
+            "First octet: 04 (MTI 0, MMS 1, SRI 0, UDHI 0, RP 0)",
+            "Originating address: type 91, 12 semi-octets, raw 447700091032 = +447700900123",
+            "Protocol identifier: 00",
+            "Data coding scheme: 00 (GSM 7-bit)",
+            "SMSC timestamp: 2019-02-17 12:34:56 (UTC+0m)",
+            "User data length: 5 (septets), 5 octets present",
+            "User data: E8329BFD06",
+        ]);
+    }
+
+    #[test]
+    fn dissects_ucs2_deliver() {
+        let lines = dissect("0791447758100650040C914477000910320008912071214365000400680069");
+        assert_eq!(lines[4], "Data coding scheme: 08 (UCS-2)");
+        assert_eq!(lines[6], "User data length: 4 (octets), 4 octets present");
+        assert_eq!(lines[7], "User data: 00680069");
+    }
+
+    #[test]
+    fn dissects_concatenated_deliver() {
+        let lines = dissect("0791447758100650440C914477000910320008912071214365000A0500032A020100680069");
+        assert_eq!(lines[1], "First octet: 44 (MTI 0, MMS 1, SRI 0, UDHI 1, RP 0)");
+        assert_eq!(lines[7], "User data header: 5 octets");
+        assert_eq!(lines[8], "  IE 00: concatenated SMS, reference 42, part 1/2");
+        assert_eq!(lines.len(), 10);
+    }
+
+    #[test]
+    fn dissects_alphanumeric_sender() {
+        let lines = dissect("000407D049A7F10900009120712143650005E8329BFD06");
+        assert_eq!(lines[0], "SMSC: (none given)");
+        assert!(lines[2].starts_with("Originating address: type D0, 7 semi-octets, raw 49A7F109 = "));
+        assert_eq!(lines[3], "Protocol identifier: 00");
+        assert_eq!(lines[7], "User data: E8329BFD06");
+    }
+
+    #[test]
+    fn stops_at_truncation() {
+        let lines = dissect("0791447758100650040C91447700091032000091207121");
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[5], "\x0304Parsing stopped:\x0f PDU truncated at octet 19 (wanted 7 octets)");
+    }
+
+    #[test]
+    fn stops_at_non_deliver() {
+        let lines = dissect("0001");
+        assert_eq!(lines[2], "\x0304Parsing stopped:\x0f MTI is 1, so this isn't an SMS-DELIVER");
+    }
+
+    #[test]
+    fn describes_dcs() {
+        assert_eq!(describe_dcs(0x00), "GSM 7-bit");
+        assert_eq!(describe_dcs(0x04), "8-bit data");
+        assert_eq!(describe_dcs(0x08), "UCS-2");
+        assert_eq!(describe_dcs(0x0C), "reserved alphabet");
+        assert_eq!(describe_dcs(0x10), "GSM 7-bit, class 0");
+        assert_eq!(describe_dcs(0x19), "UCS-2, class 1");
+        assert_eq!(describe_dcs(0xF6), "8-bit data, class 2");
+        assert_eq!(describe_dcs(0xC0), "GSM 7-bit (message waiting)");
+        assert_eq!(describe_dcs(0x80), "reserved coding group");
+    }
+
+    #[test]
+    fn describes_udh() {
+        assert_eq!(describe_udh(&[0x08, 0x04, 0x01, 0x02, 0x03, 0x01, 0x24, 0x01, 0x03]), vec![
+            "  IE 08: concatenated SMS, 16-bit reference 258, part 1/3",
+            "  IE 24: national language single shift table 3",
+        ]);
+        assert_eq!(describe_udh(&[0x70, 0x01, 0xAB]), vec!["  IE 70: unknown, data AB"]);
+        assert_eq!(describe_udh(&[0x00, 0x03, 0x2A]), vec!["  IE 00: truncated"]);
+    }
+}
@@ -6,6 +6,7 @@ table! {
         participants -> Array<Int4>,
         admins -> Array<Int4>,
         topic -> Varchar,
+        avatar_url -> Nullable<Varchar>,
     }
 }
 
@@ -19,6 +20,8 @@ table! {
         text -> Nullable<Varchar>,
         source -> Int4,
         ts -> Timestamp,
+        modem -> Nullable<Varchar>,
+        wa_msgid -> Nullable<Varchar>,
     }
 }
 
@@ -31,6 +34,34 @@ table! {
         avatar_url -> Nullable<Varchar>,
         notify -> Nullable<Varchar>,
         nicksrc -> Int4,
+        modem -> Nullable<Varchar>,
+        last_modem -> Nullable<Varchar>,
+        transliterate -> Bool,
+        replyable -> Bool,
+    }
+}
+
+table! {
+    sms_outbox (id) {
+        id -> Int4,
+        phone_number -> Varchar,
+        text -> Varchar,
+        state -> Int4,
+        attempts -> Int4,
+        next_attempt -> Timestamp,
+        last_error -> Nullable<Varchar>,
+        ts -> Timestamp,
+        modem -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    sms_usage (id) {
+        id -> Int4,
+        phone_number -> Varchar,
+        modem -> Varchar,
+        segments -> Int4,
+        ts -> Timestamp,
     }
 }
 
@@ -47,12 +78,26 @@ table! {
     }
 }
 
+table! {
+    wa_outbox (id) {
+        id -> Int4,
+        jid -> Varchar,
+        text -> Varchar,
+        mid -> Varchar,
+        ts -> Timestamp,
+        reply_to -> Nullable<Varchar>,
+    }
+}
+
 joinable!(messages -> groups (group_target));
 
 allow_tables_to_appear_in_same_query!(
     groups,
     messages,
     recipients,
+    sms_outbox,
+    sms_usage,
     wa_msgids,
+    wa_outbox,
     wa_persistence,
 );
@@ -7,15 +7,52 @@ use huawei_modem::pdu::DeliverPdu;
 use crate::store::Store;
 use crate::util::Result;
 use std::convert::TryFrom;
+use std::collections::{BTreeMap, HashMap};
+use crate::config::Config;
 
 /// The maximum message size sent over IRC.
 static MESSAGE_MAX_LEN: usize = 350;
+/// How long to wait for the rest of a concatenated SMS, if not configured.
+pub static DEFAULT_REASSEMBLY_TIMEOUT_SECS: u64 = 600;
+
+/// How long to wait for the rest of a concatenated SMS, depending on which
+/// SMS backend it arrived on.
+#[derive(Debug, Clone)]
+pub struct ReassemblyTimeouts {
+    default: u64,
+    /// Timeouts for modems that set their own.
+    modems: HashMap<String, u64>
+}
+impl ReassemblyTimeouts {
+    pub fn new(cfg: &Config) -> Self {
+        let default = cfg.modem.reassembly_timeout_secs.unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT_SECS);
+        let modems = cfg.modems.iter()
+            .filter_map(|m| Some((m.name.clone()?, m.reassembly_timeout_secs?)))
+            .collect();
+        Self { default, modems }
+    }
+    /// Get the timeout (in seconds) for messages that arrived on `backend`.
+    pub fn get(&self, backend: Option<&str>) -> u64 {
+        backend.and_then(|b| self.modems.get(b))
+            .map(|x| *x)
+            .unwrap_or(self.default)
+    }
+}
 
 pub trait Sender {
     fn report_error(&mut self, _from_nick: &str, _err: String) -> Result<()>;
     fn store(&mut self) -> &mut Store;
     fn private_target(&mut self) -> String;
     fn send_irc_message(&mut self, _from_nick: &str, _to: &str, _msg: &str) -> Result<()>;
+    /// Like `send_irc_message`, but for a message that has an ID (i.e. a
+    /// WhatsApp message ID), for frontends that can tag messages with it.
+    fn send_irc_message_with_id(&mut self, from_nick: &str, to: &str, msg: &str, _msgid: Option<&str>) -> Result<()> {
+        self.send_irc_message(from_nick, to, msg)
+    }
+    /// How long (in seconds) to wait for all the parts of a concatenated SMS
+    /// that arrived on `backend` (if known), before giving up and sending
+    /// the parts we have.
+    fn reassembly_timeout(&mut self, backend: Option<&str>) -> u64;
     /// Ensure that the admin user is joined to the given channel, if possible.
     ///
     /// This uses, e.g. SVSJOIN to force-join the user to the channel.
@@ -23,6 +60,11 @@ pub trait Sender {
         Ok(())
     }
     fn send_raw_message(&mut self, from_nick: &str, msg: &str, group_target: Option<i32>) -> Result<()> {
+        self.send_raw_message_with_id(from_nick, msg, group_target, None)
+    }
+    /// Send a message, tagging the first line of it with `msgid` (if the
+    /// frontend supports it).
+    fn send_raw_message_with_id(&mut self, from_nick: &str, msg: &str, group_target: Option<i32>, mut msgid: Option<&str>) -> Result<()> {
         let dest = if let Some(g) = group_target {
             let grp = self.store().get_group_by_id(g)?;
             self.ensure_joined(&grp.channel)?;
@@ -55,7 +97,7 @@ pub trait Sender {
             });
             for chunk in iter {
                 if chunk.len() > 0 {
-                    self.send_irc_message(from_nick, &dest, chunk)?;
+                    self.send_irc_message_with_id(from_nick, &dest, chunk, msgid.take())?;
                 }
             }
         }
@@ -85,31 +127,53 @@ pub trait Sender {
         }
         write!(&mut output, "{}", text)?;
 
-        self.send_raw_message(nick, &output, msg.group_target)?;
+        self.send_raw_message_with_id(nick, &output, msg.group_target, msg.wa_msgid.as_ref().map(|x| x as &str))?;
         self.store().delete_message(msg.id)?;
         Ok(())
     }
     fn process_msg_pdu(&mut self, nick: &str, msg: Message, pdu: DeliverPdu) -> Result<()> {
+        use std::fmt::Write;
+
         match pdu.get_message_data().decode_message() {
             Ok(m) => {
                 if let Some(cd) = m.udh.and_then(|x| x.get_concatenated_sms_data()) {
                     debug!("Message is concatenated: {:?}", cd);
                     let msgs = self.store().get_all_concatenated(&msg.phone_number, cd.reference as _)?;
-                    if msgs.len() != (cd.parts as usize) {
-                        debug!("Not enough messages: have {}, need {}", msgs.len(), cd.parts);
+                    if msgs.len() == 0 {
+                        // Another part of this message already delivered (and
+                        // deleted) it, earlier in the same processing run.
+                        debug!("Concatenated message already processed");
                         return Ok(());
                     }
-                    let mut concatenated = String::new();
-                    let mut pdus = vec![];
+                    let mut parts = BTreeMap::new();
                     for msg in msgs.iter() {
                         let dec = DeliverPdu::try_from(msg.pdu.as_ref().expect("csms message has no pdu") as &[u8])?
                             .get_message_data()
                             .decode_message()?;
-                        pdus.push(dec);
+                        let seq = dec.udh.as_ref()
+                            .and_then(|x| x.get_concatenated_sms_data())
+                            .map(|x| x.sequence)
+                            .filter(|&x| x >= 1 && x <= cd.parts);
+                        if let Some(seq) = seq {
+                            parts.insert(seq, dec.text);
+                        }
+                    }
+                    if parts.len() < (cd.parts as usize) {
+                        let oldest = msgs.iter().map(|x| x.ts).min().unwrap_or(msg.ts);
+                        let age = chrono::Utc::now().naive_utc().signed_duration_since(oldest);
+                        let timeout = self.reassembly_timeout(msg.modem.as_ref().map(|x| x as &str));
+                        if age.num_seconds() < timeout as i64 {
+                            debug!("Not enough messages: have {}, need {}", parts.len(), cd.parts);
+                            return Ok(());
+                        }
+                        warn!("Timed out reassembling message from {}: have {} parts, need {}", msg.phone_number, parts.len(), cd.parts);
                     }
-                    pdus.sort_by_key(|p| p.udh.as_ref().unwrap().get_concatenated_sms_data().unwrap().sequence);
-                    for pdu in pdus {
-                        concatenated.push_str(&pdu.text);
+                    let mut concatenated = String::new();
+                    for seq in 1..=cd.parts {
+                        match parts.remove(&seq) {
+                            Some(text) => concatenated.push_str(&text),
+                            None => write!(&mut concatenated, "\x0304[missing part {}/{}]\x0f", seq, cd.parts)?
+                        }
                     }
                     self.send_raw_message(nick, &concatenated, msg.group_target)?;
                     for msg in msgs.iter() {
@@ -0,0 +1,298 @@
+//! Common behaviours for SMS backends (things which send and receive SMS messages).
+
+use futures::{Future, Stream};
+use futures::sync::mpsc::UnboundedSender;
+use tokio_core::reactor::Handle;
+use tokio_timer::Interval;
+use std::time::{Instant, Duration};
+use huawei_modem::pdu::{PduAddress, DeliverPdu};
+use huawei_modem::gsm_encoding::GsmMessageData;
+use std::collections::{HashSet, BTreeMap};
+use std::convert::TryFrom;
+use chrono::Utc;
+use failure::Error;
+use crate::comm::{ModemCommand, ContactFactoryCommand, ContactManagerCommand, ControlBotCommand};
+use crate::config::Config;
+use crate::models::{Message, OutgoingSms};
+use crate::modem_budget::{SmsBudget, BudgetCheck};
+use crate::store::Store;
+use crate::util::{self, Result};
+
+/// Get the prefix a backend should put on messages to the admin, so they
+/// know which backend is talking (if there's more than one).
+pub fn backend_prefix(cfg: &Config, name: &str) -> String {
+    if cfg.modems.len() + cfg.http_gateways.len() + cfg.smpp.len() > 0 {
+        format!("[\x02{}\x02] ", name)
+    }
+    else {
+        String::new()
+    }
+}
+
+/// Spawn a timer that asks a backend to look at its outbox every
+/// `retry_ms` milliseconds, so that messages waiting to be retried get sent.
+pub fn spawn_outbox_timer(hdl: &Handle, retry_ms: u32, tx: UnboundedSender<ModemCommand>) {
+    let timer = Interval::new(Instant::now(), Duration::from_millis(retry_ms as _))
+        .map_err(|e| {
+            error!("SMS outbox timer failed: {}", e);
+            panic!("timer failed!");
+        }).for_each(move |_| {
+            trace!("SMS outbox timer triggered.");
+            tx.unbounded_send(ModemCommand::ProcessOutbox).unwrap();
+            Ok(())
+        });
+    hdl.spawn(timer);
+}
+
+/// How often backends look for concatenated messages that have timed out.
+static PARTIALS_CHECK_SECS: u64 = 60;
+
+/// Spawn a timer that asks a backend to look for concatenated messages that
+/// have waited too long for their remaining parts.
+pub fn spawn_partials_timer(hdl: &Handle, tx: UnboundedSender<ModemCommand>) {
+    let timer = Interval::new(Instant::now(), Duration::from_secs(PARTIALS_CHECK_SECS))
+        .map_err(|e| {
+            error!("Concatenated message timer failed: {}", e);
+            panic!("timer failed!");
+        }).for_each(move |_| {
+            trace!("Concatenated message timer triggered.");
+            tx.unbounded_send(ModemCommand::CheckPartials).unwrap();
+            Ok(())
+        });
+    hdl.spawn(timer);
+}
+
+pub trait SmsBackend {
+    /// The name of this backend, as used for routing messages.
+    fn backend_name(&self) -> &str;
+    fn prefix(&self) -> &str;
+    /// Whether this backend also sends messages that were queued before
+    /// there was a choice of backend.
+    fn is_default(&self) -> bool;
+    fn outbox_retry_ms(&self) -> u32;
+    fn outbox_max_attempts(&self) -> u32;
+    /// How long (in seconds) to wait for all the parts of a concatenated message.
+    fn reassembly_timeout(&self) -> u64;
+    fn store(&mut self) -> &mut Store;
+    fn cf_tx(&mut self) -> &mut UnboundedSender<ContactFactoryCommand>;
+    fn cb_tx(&mut self) -> &mut UnboundedSender<ControlBotCommand>;
+    fn budget(&mut self) -> &mut SmsBudget;
+    /// Send the next message in the outbox, if we aren't already sending one.
+    fn process_outbox(&mut self) -> Result<()>;
+    /// Check for (and store) newly received messages.
+    fn check_messages(&mut self) -> Result<()>;
+    /// Tell the admin about the backend's current state.
+    fn request_status(&mut self) -> Result<()>;
+
+    fn respond(&mut self, msg: &str) {
+        let msg = format!("{}{}", self.prefix(), msg);
+        self.cb_tx().unbounded_send(ControlBotCommand::CommandResponse(msg))
+            .unwrap();
+    }
+    fn report_failure(&mut self, msg: &str) {
+        let msg = format!("{}{}", self.prefix(), msg);
+        self.cb_tx().unbounded_send(ControlBotCommand::ReportFailure(msg))
+            .unwrap();
+    }
+    fn send_message(&mut self, addr: PduAddress, msg: String) -> Result<()> {
+        let name = self.backend_name().to_owned();
+        let sms = self.store().store_outgoing_sms(&addr, &msg, &name)?;
+        debug!("Queued outgoing SMS #{} to {} on {}", sms.id, addr, name);
+        self.process_outbox()?;
+        Ok(())
+    }
+    /// Store a received SMS-DELIVER PDU (`raw` being its encoded form).
+    fn store_deliver_pdu(&mut self, pdu: &DeliverPdu, raw: &[u8]) -> Result<()> {
+        let csms_data = match pdu.get_message_data().decode_message() {
+            Ok(m) => {
+                m.udh
+                    .and_then(|x| x.get_concatenated_sms_data())
+                    .map(|x| x.reference as i32)
+            },
+            Err(e) => {
+                // The error is reported when the message is sent
+                // via the ContactManager, not here.
+                debug!("Error decoding message - but it'll be reported later: {:?}", e);
+                None
+            }
+        };
+        if let Some(d) = csms_data {
+            trace!("Message is concatenated: {:?}", d);
+        }
+        let name = self.backend_name().to_owned();
+        self.store().store_sms_message(&pdu.originating_address, raw, csms_data, &name)?;
+        Ok(())
+    }
+    /// Store a message that arrived as text, rather than as a PDU.
+    fn store_text_message(&mut self, addr: PduAddress, text: String) -> Result<()> {
+        let name = self.backend_name().to_owned();
+        debug!("Received SMS from {} on {}", addr, name);
+        self.store().store_plain_sms_message(&addr, &text, &name)?;
+        self.cf_tx().unbounded_send(ContactFactoryCommand::ProcessMessages).unwrap();
+        Ok(())
+    }
+    fn make_contact(&mut self, addr: PduAddress) -> Result<()> {
+        if self.store().get_recipient_by_addr_opt(&addr)?.is_none() {
+            let nick = util::make_nick_for_address(&addr);
+            self.store().store_recipient(&addr, &nick)?;
+            info!("Creating new SMS recipient for {} (nick {})", addr, nick);
+            // Their messages arrived before the recipient existed, so
+            // `store_sms_message` couldn't note which backend they used.
+            let last_modem = self.store().get_messages_for_recipient(&addr)?
+                .into_iter()
+                .rev()
+                .filter_map(|m| m.modem)
+                .next();
+            if let Some(m) = last_modem {
+                self.store().update_recipient_last_modem(&addr, &m)?;
+            }
+            self.cf_tx().unbounded_send(ContactFactoryCommand::SetupContact(addr.clone()))
+                .unwrap();
+            self.cf_tx().unbounded_send(ContactFactoryCommand::ProcessMessages).unwrap();
+        }
+        Ok(())
+    }
+    /// Get the next message to send from the outbox, along with its
+    /// recipient's address. Messages with an unparseable recipient are
+    /// marked as failed (and skipped), so they can't wedge the outbox.
+    fn next_outgoing_sms(&mut self) -> Result<Option<(OutgoingSms, PduAddress)>> {
+        let now = Utc::now().naive_utc();
+        let name = self.backend_name().to_owned();
+        let is_default = self.is_default();
+        let mut blocked = HashSet::new();
+        for sms in self.store().get_queued_outgoing_sms(&name, is_default)? {
+            // Don't let later messages to a recipient overtake an earlier
+            // one that's still waiting to be retried.
+            if blocked.contains(&sms.phone_number) {
+                continue;
+            }
+            if sms.next_attempt > now {
+                blocked.insert(sms.phone_number);
+                continue;
+            }
+            match sms.get_addr() {
+                Ok(addr) => return Ok(Some((sms, addr))),
+                Err(e) => {
+                    warn!("Not sending SMS #{}: {}", sms.id, e);
+                    let err = e.to_string();
+                    self.store().update_outgoing_sms_retry(sms.id, OutgoingSms::STATE_FAILED, sms.attempts, sms.next_attempt, &err)?;
+                    self.report_failure(&format!("Failed to send message #{}: {}", sms.id, err));
+                }
+            }
+        }
+        Ok(None)
+    }
+    /// Get the next message from the outbox that the budget and rate limit
+    /// allow us to send now, along with its recipient's address and how
+    /// many segments it takes. Messages that are over budget are marked as
+    /// failed, and skipped.
+    fn next_sendable_sms(&mut self) -> Result<Option<(OutgoingSms, PduAddress, usize)>> {
+        while let Some((sms, addr)) = self.next_outgoing_sms()? {
+            let segments = GsmMessageData::encode_message(&sms.text).len();
+            match self.budget().check(&addr, segments)? {
+                BudgetCheck::Allowed => return Ok(Some((sms, addr, segments))),
+                BudgetCheck::RateLimited => {
+                    debug!("Not sending message #{} yet; over the rate limit", sms.id);
+                    return Ok(None);
+                },
+                BudgetCheck::Refused(why) => {
+                    warn!("Refusing to send message #{} to {}: {}", sms.id, addr, why);
+                    let err = format!("over budget: {}", why);
+                    self.store().update_outgoing_sms_retry(sms.id, OutgoingSms::STATE_FAILED, sms.attempts, sms.next_attempt, &err)?;
+                    self.report_failure(&format!("Refused to send message to {} ({})", addr, err));
+                }
+            }
+        }
+        Ok(None)
+    }
+    /// Record that `segments` segments were sent to `addr`, for the budget
+    /// and MODEM USAGE.
+    fn record_usage(&mut self, addr: &PduAddress, segments: usize) -> Result<()> {
+        let name = self.backend_name().to_owned();
+        self.store().record_sms_usage(addr, &name, segments)?;
+        self.budget().note_sent(segments);
+        Ok(())
+    }
+    fn request_usage(&mut self) -> Result<()> {
+        for line in self.budget().report()? {
+            self.respond(&line);
+        }
+        Ok(())
+    }
+    /// Record that sending outbox message `id` failed, scheduling a retry
+    /// (or giving up, if we've tried too many times already).
+    fn record_send_failure(&mut self, id: i32, e: Error) -> Result<()> {
+        let sms = self.store().get_outgoing_sms_by_id(id)?;
+        let addr = sms.get_addr()?;
+        let attempts = sms.attempts + 1;
+        let err = e.to_string();
+        if attempts as u32 >= self.outbox_max_attempts() {
+            warn!("Giving up on SMS #{} to {} after {} attempts: {}", id, addr, attempts, e);
+            self.store().update_outgoing_sms_retry(id, OutgoingSms::STATE_FAILED, attempts, sms.next_attempt, &err)?;
+            self.report_failure(&format!("Failed to send message to {} (gave up after {} attempts): {}", addr, attempts, e));
+        }
+        else {
+            // Exponential backoff, capped at 64 times the base delay.
+            let backoff_ms = (self.outbox_retry_ms() as i64) << ::std::cmp::min(attempts - 1, 6);
+            let next = Utc::now().naive_utc() + chrono::Duration::milliseconds(backoff_ms);
+            warn!("Failed to send SMS #{} to {} (attempt {}); retrying in {}s: {}", id, addr, attempts, backoff_ms / 1000, e);
+            self.store().update_outgoing_sms_retry(id, OutgoingSms::STATE_QUEUED, attempts, next, &err)?;
+            if attempts == 1 {
+                self.report_failure(&format!("Failed to send message to {} (will retry): {}", addr, e));
+            }
+        }
+        Ok(())
+    }
+    /// Get senders to deliver concatenated messages received by this
+    /// backend that have timed out waiting for their remaining parts.
+    fn check_stale_partials(&mut self) -> Result<()> {
+        let cutoff = Utc::now().naive_utc() - chrono::Duration::seconds(self.reassembly_timeout() as i64);
+        let name = self.backend_name().to_owned();
+        let is_default = self.is_default();
+        let mut addrs = HashSet::new();
+        for msg in self.store().get_concatenated_older_than(cutoff)? {
+            // Messages from before there was a choice of backend belong
+            // to the default one.
+            if msg.modem.as_ref().map(|m| *m == name).unwrap_or(is_default) {
+                addrs.insert(msg.get_addr()?);
+            }
+        }
+        for addr in addrs {
+            debug!("Concatenated message from {} timed out", addr);
+            self.cf_tx().unbounded_send(ContactFactoryCommand::ForwardCommand(addr, ContactManagerCommand::ProcessMessages))
+                .unwrap();
+        }
+        Ok(())
+    }
+    fn list_partials(&mut self) -> Result<()> {
+        let now = Utc::now().naive_utc();
+        let mut partials: BTreeMap<(String, i32), Vec<Message>> = BTreeMap::new();
+        for msg in self.store().get_all_concatenated_messages()? {
+            let key = (msg.phone_number.clone(), msg.csms_data.unwrap());
+            partials.entry(key).or_insert_with(Vec::new).push(msg);
+        }
+        let mut lines = vec![];
+        for ((_, rf), msgs) in partials {
+            let addr = msgs[0].get_addr()?;
+            let total = msgs.iter()
+                .filter_map(|m| m.pdu.as_ref())
+                .filter_map(|pdu| DeliverPdu::try_from(pdu as &[u8]).ok())
+                .filter_map(|pdu| pdu.get_message_data().decode_message().ok())
+                .filter_map(|dec| dec.udh.and_then(|x| x.get_concatenated_sms_data()))
+                .map(|cd| cd.parts.to_string())
+                .next()
+                .unwrap_or("?".into());
+            let oldest = msgs.iter().map(|m| m.ts).min().unwrap();
+            let age = now.signed_duration_since(oldest);
+            lines.push(format!("- from \x02{}\x02 (reference {}): have \x02{}/{}\x02 parts, first received {}m ago",
+                               addr, rf, msgs.len(), total, age.num_minutes()));
+        }
+        if lines.len() == 0 {
+            lines.push("No incomplete concatenated messages".into());
+        }
+        for line in lines {
+            self.respond(&line);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+//! Preparing messages from IRC to be sent by SMS: transliterating them into the
+//! GSM 7-bit alphabet, and checking before sending ones that'd take lots of parts.
+
+use huawei_modem::pdu::PduAddress;
+use huawei_modem::gsm_encoding::GsmMessageData;
+use std::collections::HashMap;
+use std::time::{Instant, Duration};
+
+use crate::config::Config;
+use crate::gsm7;
+use crate::sender_common::Sender;
+use crate::util::{self, Result};
+
+/// Replace characters that can't be sent in GSM 7-bit encoding (like emoji or
+/// curly quotes) with lookalikes that can, so the message doesn't get sent as UCS-2.
+pub fn transliterate(text: &str) -> String {
+    let mut ret = String::new();
+    for ch in text.chars() {
+        if gsm7::gsm7_len(ch).is_some() {
+            ret.push(ch);
+            continue;
+        }
+        let mut buf = [0; 4];
+        let replacement = unidecode::unidecode(ch.encode_utf8(&mut buf))
+            .chars()
+            .filter(|&c| gsm7::gsm7_len(c).is_some())
+            .collect::<String>();
+        if replacement.len() > 0 {
+            ret.push_str(&replacement);
+        }
+        else {
+            ret.push('?');
+        }
+    }
+    ret
+}
+/// Explain why `text` has to be sent as UCS-2 (if it does).
+fn ucs2_note(text: &str) -> String {
+    let mut bad = vec![];
+    for ch in text.chars() {
+        if gsm7::gsm7_len(ch).is_none() && !bad.contains(&ch) {
+            bad.push(ch);
+        }
+    }
+    if bad.len() == 0 {
+        return String::new();
+    }
+    let shown = bad.iter().take(5).collect::<String>();
+    format!(" (using UCS-2 encoding, because of \x02{}\x02)", shown)
+}
+
+/// How long a message waits to be confirmed, if not configured.
+static DEFAULT_CONFIRM_TIMEOUT_SECS: u64 = 300;
+
+/// Settings for checking messages with the admin (from the `[modem]` section).
+#[derive(Debug, Clone, Copy)]
+pub struct ComposeConfig {
+    /// Check with the admin before sending messages that take more SMS
+    /// messages than this.
+    confirm_segments: Option<usize>,
+    /// How long to wait for the admin to confirm a message.
+    confirm_timeout: Duration
+}
+impl ComposeConfig {
+    pub fn new(cfg: &Config) -> Self {
+        Self {
+            confirm_segments: cfg.modem.confirm_segments,
+            confirm_timeout: Duration::from_secs(cfg.modem.confirm_timeout_secs.unwrap_or(DEFAULT_CONFIRM_TIMEOUT_SECS))
+        }
+    }
+}
+/// Messages waiting for the admin to confirm them, by recipient.
+pub struct PendingSms {
+    cfg: ComposeConfig,
+    msgs: HashMap<PduAddress, (String, Instant)>
+}
+impl PendingSms {
+    pub fn new(cfg: ComposeConfig) -> Self {
+        Self {
+            cfg,
+            msgs: HashMap::new()
+        }
+    }
+    /// Take the message waiting for `addr`, and whether it waited too long.
+    fn take(&mut self, addr: &PduAddress) -> Option<(String, bool)> {
+        let timeout = self.cfg.confirm_timeout;
+        self.msgs.remove(addr)
+            .map(|(text, ts)| (text, ts.elapsed() > timeout))
+    }
+    fn insert(&mut self, addr: PduAddress, text: String) {
+        let timeout = self.cfg.confirm_timeout;
+        // Nothing else gets rid of expired messages for recipients the
+        // admin never talks to again.
+        self.msgs.retain(|_, &mut (_, ts)| ts.elapsed() <= timeout);
+        self.msgs.insert(addr, (text, Instant::now()));
+    }
+}
+
+pub trait SmsComposer: Sender {
+    fn pending_sms(&mut self) -> &mut PendingSms;
+
+    /// Work out what to send, when the admin sends `msg` to the ghost
+    /// (`from_nick`) for `addr`.
+    ///
+    /// Returns `None` if nothing should be sent yet, because the message needs
+    /// confirming first (or the admin just cancelled it).
+    fn compose_sms(&mut self, from_nick: &str, addr: &PduAddress, msg: String) -> Result<Option<String>> {
+        let target = self.private_target();
+        if let Some((pending, expired)) = self.pending_sms().take(addr) {
+            match (&msg.trim().to_lowercase() as &str, expired) {
+                ("yes", true) | ("y", true) | ("send", true) => {
+                    self.send_irc_message(from_nick, &target, "That message waited too long to be confirmed, so it wasn't sent; send it again if you still want to.")?;
+                    return Ok(None);
+                },
+                ("yes", _) | ("y", _) | ("send", _) => return Ok(Some(pending)),
+                ("no", _) | ("n", _) | ("cancel", _) => {
+                    self.send_irc_message(from_nick, &target, "OK, that message won't be sent.")?;
+                    return Ok(None);
+                },
+                (_, true) => {},
+                _ => {
+                    self.send_irc_message(from_nick, &target, "(Discarded the message that was waiting to be confirmed.)")?;
+                }
+            }
+        }
+        let recip = self.store().get_recipient_by_addr_opt(addr)?;
+        if let Some(ref r) = recip {
+            if !r.replyable {
+                let what = util::describe_unreplyable(addr).unwrap_or("a sender that can't receive messages");
+                let reply = format!("This is {}, so it can't be replied to; your message wasn't sent. (If it can, use GHOST {} REPLYABLE true.)", what, r.nick);
+                self.send_irc_message(from_nick, &target, &reply)?;
+                return Ok(None);
+            }
+        }
+        let translit = recip.map(|r| r.transliterate).unwrap_or(false);
+        let text = if translit { transliterate(&msg) } else { msg };
+        if let Some(max) = self.pending_sms().cfg.confirm_segments {
+            let segments = GsmMessageData::encode_message(&text).len();
+            if segments > max {
+                let reply = format!("That would be sent as \x02{}\x02 SMS messages{}. Reply \x02yes\x02 to send it anyway, or \x02no\x02 to cancel.",
+                                    segments, ucs2_note(&text));
+                self.send_irc_message(from_nick, &target, &reply)?;
+                self.pending_sms().insert(addr.clone(), text);
+                return Ok(None);
+            }
+        }
+        Ok(Some(text))
+    }
+}
@@ -0,0 +1,596 @@
+//! Sending and receiving SMS messages via a generic HTTP SMS gateway.
+//!
+//! Messages are sent by POSTing a configurable template to the gateway, and
+//! received by the gateway calling a webhook that we listen for.
+
+use futures::{Future, Stream, Poll, Async};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use huawei_modem::pdu::PduAddress;
+use huawei_modem::gsm_encoding::GsmMessageData;
+use std::time::Duration;
+use std::net::{TcpListener, TcpStream};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::collections::HashMap;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc as std_mpsc;
+use reqwest::header::{CONTENT_TYPE, USER_AGENT};
+use failure::Error;
+use url::form_urlencoded;
+use crate::comm::{ModemCommand, ContactFactoryCommand, ControlBotCommand, InitParameters};
+use crate::config::HttpGatewayConfig;
+use crate::models::OutgoingSms;
+use crate::modem_budget::SmsBudget;
+use crate::sender_common::DEFAULT_REASSEMBLY_TIMEOUT_SECS;
+use crate::sms_backend::{SmsBackend, backend_prefix, spawn_outbox_timer, spawn_partials_timer};
+use crate::store::Store;
+use crate::util::{self, Result};
+
+/// The largest webhook request body we're willing to read.
+static WEBHOOK_MAX_BODY: usize = 65536;
+/// The longest request line or header line we're willing to read.
+static WEBHOOK_MAX_LINE: usize = 8192;
+/// The most header lines we're willing to read.
+static WEBHOOK_MAX_HEADERS: usize = 64;
+/// How many webhook requests are handled at once.
+static WEBHOOK_WORKERS: usize = 4;
+/// How many webhook connections can wait for a worker before we start
+/// turning them away.
+static WEBHOOK_QUEUE: usize = 16;
+
+/// Escape `val` so it can be substituted into a template of the given content type.
+fn escape_for(content_type: &str, val: &str) -> String {
+    if content_type.contains("json") {
+        let ret = serde_json::to_string(val).unwrap();
+        // Strip the quotes; the template has its own.
+        ret[1..ret.len()-1].to_owned()
+    }
+    else {
+        form_urlencoded::byte_serialize(val.as_bytes()).collect()
+    }
+}
+fn render_template(tmpl: &str, content_type: &str, to: &str, text: &str) -> String {
+    tmpl.replace("{to}", &escape_for(content_type, to))
+        .replace("{text}", &escape_for(content_type, text))
+}
+
+struct WebhookParams {
+    token: Option<String>,
+    from_field: String,
+    text_field: String
+}
+impl WebhookParams {
+    fn run(self, listener: TcpListener, tx: UnboundedSender<ModemCommand>) {
+        let params = Arc::new(self);
+        // A fixed number of workers handle requests, so one slow (or
+        // malicious) client can't stop other webhook requests getting
+        // through, and lots of them can't start lots of threads.
+        let (conn_tx, conn_rx) = std_mpsc::sync_channel::<TcpStream>(WEBHOOK_QUEUE);
+        let conn_rx = Arc::new(Mutex::new(conn_rx));
+        for _ in 0..WEBHOOK_WORKERS {
+            let params = params.clone();
+            let tx = tx.clone();
+            let conn_rx = conn_rx.clone();
+            thread::spawn(move || {
+                loop {
+                    let stream = match conn_rx.lock().unwrap().recv() {
+                        Ok(s) => s,
+                        Err(_) => return
+                    };
+                    if let Err(e) = params.handle_conn(stream, &tx) {
+                        warn!("Failed to handle webhook request: {}", e);
+                    }
+                }
+            });
+        }
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("Failed to accept webhook connection: {}", e);
+                    continue;
+                }
+            };
+            if let Err(std_mpsc::TrySendError::Full(mut stream)) = conn_tx.try_send(stream) {
+                warn!("Too many webhook requests at once; turning one away");
+                let _ = stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+            }
+        }
+    }
+    fn handle_conn(&self, mut stream: TcpStream, tx: &UnboundedSender<ModemCommand>) -> Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (target, headers) = match Self::read_head(&mut reader)? {
+            Some(h) => h,
+            None => {
+                debug!("Webhook request with oversized header");
+                let resp = "Request header too large";
+                write!(stream, "HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                       resp.len(), resp)?;
+                return Ok(());
+            }
+        };
+        let len = headers.get("content-length")
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(0);
+        let (status, resp) = if len > WEBHOOK_MAX_BODY {
+            ("413 Payload Too Large", "Request body too large".to_owned())
+        }
+        else {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            match self.parse_request(&target, &headers, &body) {
+                Ok(Some((addr, text))) => {
+                    tx.unbounded_send(ModemCommand::IncomingText(addr, text))
+                        .unwrap();
+                    ("200 OK", "OK".to_owned())
+                },
+                Ok(None) => ("403 Forbidden", "Invalid token".to_owned()),
+                Err(e) => ("400 Bad Request", e.to_string())
+            }
+        };
+        debug!("Webhook request to {}: {}", target, status);
+        write!(stream, "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+               status, resp.len(), resp)?;
+        Ok(())
+    }
+    /// Read the request target and headers of a webhook request.
+    ///
+    /// Returns `None` if a line was too long, or there were too many headers.
+    fn read_head<R: BufRead>(reader: &mut R) -> Result<Option<(String, HashMap<String, String>)>> {
+        let line = match Self::read_line(reader)? {
+            Some(l) => l,
+            None => return Ok(None)
+        };
+        let target = line.split_whitespace().nth(1).unwrap_or("/").to_owned();
+        let mut headers = HashMap::new();
+        // One more line than the limit, for the blank one at the end.
+        for _ in 0..=WEBHOOK_MAX_HEADERS {
+            let line = match Self::read_line(reader)? {
+                Some(l) => l,
+                None => return Ok(None)
+            };
+            let line = line.trim_end();
+            if line.len() == 0 {
+                return Ok(Some((target, headers)));
+            }
+            let mut split = line.splitn(2, ':');
+            let name = split.next().unwrap().trim().to_lowercase();
+            let value = split.next().unwrap_or("").trim().to_owned();
+            headers.insert(name, value);
+        }
+        Ok(None)
+    }
+    /// Read a line (which is empty at the end of the stream), or `None` if
+    /// it's longer than `WEBHOOK_MAX_LINE`.
+    fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>> {
+        let mut line = vec![];
+        reader.by_ref().take(WEBHOOK_MAX_LINE as u64).read_until(b'\n', &mut line)?;
+        if line.len() >= WEBHOOK_MAX_LINE && !line.ends_with(b"\n") {
+            return Ok(None);
+        }
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+    /// Get the sender and text out of a webhook request.
+    ///
+    /// Returns `None` if the request didn't have the right token.
+    fn parse_request(&self, target: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<Option<(PduAddress, String)>> {
+        let mut fields = HashMap::new();
+        if let Some(i) = target.find('?') {
+            fields.extend(form_urlencoded::parse(target[i+1..].as_bytes()).into_owned());
+        }
+        let ct = headers.get("content-type").map(|x| x as &str).unwrap_or("");
+        if body.len() > 0 {
+            if ct.contains("json") {
+                let val: serde_json::Value = serde_json::from_slice(body)?;
+                let obj = val.as_object()
+                    .ok_or(format_err!("JSON body isn't an object"))?;
+                for (k, v) in obj.iter() {
+                    let v = match *v {
+                        serde_json::Value::String(ref s) => s.clone(),
+                        ref x => x.to_string()
+                    };
+                    fields.insert(k.clone(), v);
+                }
+            }
+            else {
+                fields.extend(form_urlencoded::parse(body).into_owned());
+            }
+        }
+        if let Some(ref tok) = self.token {
+            let given = headers.get("x-webhook-token")
+                .or(fields.get("token"))
+                .map(|x| x as &str)
+                .unwrap_or("");
+            if !util::constant_time_eq(given.as_bytes(), tok.as_bytes()) {
+                return Ok(None);
+            }
+        }
+        let from = fields.get(&self.from_field)
+            .ok_or(format_err!("missing field '{}'", self.from_field))?;
+        let text = fields.get(&self.text_field)
+            .ok_or(format_err!("missing field '{}'", self.text_field))?;
+        let addr: PduAddress = from.parse()
+            .map_err(|e| format_err!("invalid sender '{}': {:?}", from, e))?;
+        Ok(Some((addr, text.clone())))
+    }
+}
+
+pub struct HttpGateway {
+    name: String,
+    prefix: String,
+    is_default: bool,
+    cfg: HttpGatewayConfig,
+    store: Store,
+    outbox_retry_ms: u32,
+    outbox_max_attempts: u32,
+    reassembly_timeout: u64,
+    outbox_busy: bool,
+    sent: u64,
+    received: u64,
+    last_error: Option<String>,
+    rx: UnboundedReceiver<ModemCommand>,
+    int_tx: UnboundedSender<ModemCommand>,
+    cf_tx: UnboundedSender<ContactFactoryCommand>,
+    cb_tx: UnboundedSender<ControlBotCommand>,
+    budget: SmsBudget,
+}
+impl Future for HttpGateway {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(msg) = self.rx.poll().unwrap() {
+            use self::ModemCommand::*;
+
+            let msg = msg.expect("rx stopped producing");
+            match msg {
+                DoCmgl => self.check_messages()?,
+                SendMessage(addr, msg) => self.send_message(addr, msg)?,
+                ProcessOutbox => self.process_outbox()?,
+                OutboxSent(id, _) => self.outbox_sent(id)?,
+                OutboxFailed(id, e) => self.outbox_failed(id, e)?,
+                IncomingText(addr, text) => {
+                    self.received += 1;
+                    self.store_text_message(addr, text)?;
+                },
+                ListPartials => self.list_partials()?,
+                CheckPartials => self.check_stale_partials()?,
+                RequestStatus => self.request_status()?,
+                MakeContact(a) => self.make_contact(a)?,
+                Ussd(_) => self.respond("USSD isn't supported by HTTP gateways."),
+                PhonebookImport => self.respond("HTTP gateways don't have a phonebook."),
+                RequestUsage => self.request_usage()?,
+                RequestCsq | RequestReg | PrintReceipts | ForceReinit | UpdatePath(_) => {
+                    debug!("HTTP gateway {} ignoring modem-only command", self.name);
+                },
+                CmglComplete(_) | CmglFailed(_) | OutboxPartlySent(..) | CommandTimeout | CheckUsb(_) | ForModem(..) | SetRecipientModem(..) | SetRecipientTransliterate(..) | SetRecipientReplyable(..) | ListPdus | ShowPdu(_) | DecodePdu(_) | ListModems | UssdReceived(..) | HealthSampled(_) | Phonebook(_) => {
+                    warn!("HTTP gateway {} got a command it shouldn't have", self.name);
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl HttpGateway {
+    /// Get a sender for commands to this gateway.
+    pub fn sender(&self) -> UnboundedSender<ModemCommand> {
+        self.int_tx.clone()
+    }
+    pub fn new(p: InitParameters<HttpGatewayConfig>, is_default: bool) -> Result<Self> {
+        let cfg = p.cfg2.clone();
+        let name = cfg.name.clone();
+        let prefix = backend_prefix(p.cfg, &name);
+        let outbox_retry_ms = cfg.outbox_retry_ms.unwrap_or(10000);
+        let outbox_max_attempts = cfg.outbox_max_attempts.unwrap_or(10);
+        let reassembly_timeout = p.cfg.modem.reassembly_timeout_secs.unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT_SECS);
+        let (int_tx, rx) = mpsc::unbounded();
+        let cf_tx = p.cm.cf_tx.clone();
+        let cb_tx = p.cm.cb_tx.clone();
+
+        if let Some(ref addr) = cfg.webhook_listen {
+            let listener = TcpListener::bind(addr)
+                .map_err(|e| format_err!("couldn't listen for webhooks on {}: {}", addr, e))?;
+            info!("HTTP gateway {} listening for webhooks on {}", name, addr);
+            let params = WebhookParams {
+                token: cfg.webhook_token.clone(),
+                from_field: cfg.webhook_from_field.clone().unwrap_or("from".into()),
+                text_field: cfg.webhook_text_field.clone().unwrap_or("text".into())
+            };
+            let tx = int_tx.clone();
+            thread::spawn(move || params.run(listener, tx));
+        }
+        spawn_outbox_timer(p.hdl, outbox_retry_ms, int_tx.clone());
+        spawn_partials_timer(p.hdl, int_tx.clone());
+        let budget = SmsBudget::new(p.store.clone(), &name, cfg.daily_segment_budget, cfg.recipient_daily_segment_budget, cfg.segments_per_minute);
+        Ok(Self {
+            name, prefix, is_default, cfg, outbox_retry_ms, outbox_max_attempts, reassembly_timeout,
+            rx, int_tx, cf_tx, cb_tx,
+            budget,
+            store: p.store,
+            outbox_busy: false,
+            sent: 0,
+            received: 0,
+            last_error: None
+        })
+    }
+    fn outbox_sent(&mut self, id: i32) -> Result<()> {
+        self.outbox_busy = false;
+        self.sent += 1;
+        self.last_error = None;
+        info!("Outgoing SMS #{} sent via {}!", id, self.name);
+        self.store.update_outgoing_sms_state(id, OutgoingSms::STATE_SENT)?;
+        let sms = self.store.get_outgoing_sms_by_id(id)?;
+        let segments = GsmMessageData::encode_message(&sms.text).len();
+        self.record_usage(&sms.get_addr()?, segments)?;
+        self.process_outbox()?;
+        Ok(())
+    }
+    fn outbox_failed(&mut self, id: i32, e: Error) -> Result<()> {
+        self.outbox_busy = false;
+        self.last_error = Some(e.to_string());
+        self.record_send_failure(id, e)?;
+        self.process_outbox()?;
+        Ok(())
+    }
+    fn post(cfg: &HttpGatewayConfig, to: &str, text: &str) -> Result<()> {
+        let ct = cfg.send_content_type.clone()
+            .unwrap_or("application/x-www-form-urlencoded".into());
+        let url = render_template(&cfg.send_url, "", to, text);
+        let body = render_template(&cfg.send_template, &ct, to, text);
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(cfg.send_timeout_ms.unwrap_or(30000)))
+            .build()?;
+        let mut req = client.post(&url)
+            .header(USER_AGENT, "sms-irc")
+            .header(CONTENT_TYPE, ct);
+        for (k, v) in cfg.send_headers.iter() {
+            req = req.header(k as &str, v as &str);
+        }
+        let mut resp = req.body(body).send()?;
+        debug!("response: {:?}", resp);
+        if !resp.status().is_success() {
+            let text = resp.text().unwrap_or_default();
+            Err(format_err!("gateway returned {}: {}", resp.status().as_u16(), text.trim()))?
+        }
+        Ok(())
+    }
+}
+impl SmsBackend for HttpGateway {
+    fn backend_name(&self) -> &str {
+        &self.name
+    }
+    fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+    fn outbox_retry_ms(&self) -> u32 {
+        self.outbox_retry_ms
+    }
+    fn outbox_max_attempts(&self) -> u32 {
+        self.outbox_max_attempts
+    }
+    fn reassembly_timeout(&self) -> u64 {
+        self.reassembly_timeout
+    }
+    fn store(&mut self) -> &mut Store {
+        &mut self.store
+    }
+    fn cf_tx(&mut self) -> &mut UnboundedSender<ContactFactoryCommand> {
+        &mut self.cf_tx
+    }
+    fn cb_tx(&mut self) -> &mut UnboundedSender<ControlBotCommand> {
+        &mut self.cb_tx
+    }
+    fn budget(&mut self) -> &mut SmsBudget {
+        &mut self.budget
+    }
+    fn check_messages(&mut self) -> Result<()> {
+        // Incoming messages are pushed to us by the webhook.
+        Ok(())
+    }
+    fn request_status(&mut self) -> Result<()> {
+        let webhook = self.cfg.webhook_listen.clone()
+            .map(|x| format!("listening for webhooks on \x02{}\x02", x))
+            .unwrap_or("not listening for webhooks".into());
+        let status = format!("HTTP gateway \x02{}\x02 ({}): {} sent, {} received since startup",
+                             self.cfg.send_url, webhook, self.sent, self.received);
+        self.respond(&status);
+        if let Some(e) = self.last_error.clone() {
+            self.respond(&format!("Last send failed: {}", e));
+        }
+        Ok(())
+    }
+    fn process_outbox(&mut self) -> Result<()> {
+        if self.outbox_busy {
+            return Ok(());
+        }
+        let (sms, addr, _) = match self.next_sendable_sms()? {
+            Some(s) => s,
+            None => return Ok(())
+        };
+        self.store.update_outgoing_sms_state(sms.id, OutgoingSms::STATE_SENDING)?;
+        debug!("Sending message #{} to {} via {} (attempt {})...", sms.id, addr, self.name, sms.attempts + 1);
+        trace!("Message content: {}", sms.text);
+        let cfg = self.cfg.clone();
+        let tx = self.int_tx.clone();
+        let to = addr.to_string();
+        self.outbox_busy = true;
+        thread::spawn(move || {
+            let cmd = match Self::post(&cfg, &to, &sms.text) {
+                Ok(()) => ModemCommand::OutboxSent(sms.id, vec![]),
+                Err(e) => ModemCommand::OutboxFailed(sms.id, e)
+            };
+            tx.unbounded_send(cmd).unwrap();
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gateway_config(send_url: String, template: &str, ct: Option<&str>) -> HttpGatewayConfig {
+        HttpGatewayConfig {
+            name: "test".into(),
+            send_url,
+            send_template: template.into(),
+            send_content_type: ct.map(|x| x.into()),
+            send_headers: Default::default(),
+            send_timeout_ms: Some(5000),
+            webhook_listen: None,
+            webhook_token: None,
+            webhook_from_field: None,
+            webhook_text_field: None,
+            outbox_retry_ms: None,
+            outbox_max_attempts: None,
+            daily_segment_budget: None,
+            recipient_daily_segment_budget: None,
+            segments_per_minute: None
+        }
+    }
+
+    /// Run a stub HTTP server that accepts one request, answers it with
+    /// `status`, and hands the raw request back over the returned channel.
+    fn stub_server(status: &'static str) -> (String, std_mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = std_mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut req = String::new();
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.to_lowercase().starts_with("content-length:") {
+                    len = line[15..].trim().parse().unwrap();
+                }
+                req.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            req.push_str(&String::from_utf8(body).unwrap());
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 4\r\nConnection: close\r\n\r\nnope", status).unwrap();
+            tx.send(req).unwrap();
+        });
+        (format!("http://{}", addr), rx)
+    }
+
+    /// Send `req` to a webhook listener, returning the response.
+    fn webhook_request(params: WebhookParams, req: &str) -> (String, UnboundedReceiver<ModemCommand>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded();
+        thread::spawn(move || params.run(listener, tx));
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(req.as_bytes()).unwrap();
+        let mut resp = String::new();
+        stream.read_to_string(&mut resp).unwrap();
+        (resp, rx)
+    }
+
+    fn params(token: Option<&str>) -> WebhookParams {
+        WebhookParams {
+            token: token.map(|x| x.into()),
+            from_field: "from".into(),
+            text_field: "text".into()
+        }
+    }
+
+    #[test]
+    fn post_renders_form_template() {
+        let (url, rx) = stub_server("200 OK");
+        let cfg = gateway_config(format!("{}/send?to={{to}}", url), "to={to}&text={text}", None);
+        HttpGateway::post(&cfg, "+447700900123", "hello & goodbye").unwrap();
+        let req = rx.recv().unwrap();
+        assert!(req.starts_with("POST /send?to=%2B447700900123 HTTP/1.1\r\n"));
+        assert!(req.contains("application/x-www-form-urlencoded"));
+        assert!(req.ends_with("to=%2B447700900123&text=hello+%26+goodbye"));
+    }
+
+    #[test]
+    fn post_renders_json_template() {
+        let (url, rx) = stub_server("200 OK");
+        let cfg = gateway_config(url, r#"{"to":"{to}","text":"{text}"}"#, Some("application/json"));
+        HttpGateway::post(&cfg, "+447700900123", "say \"hi\"").unwrap();
+        let req = rx.recv().unwrap();
+        assert!(req.ends_with(r#"{"to":"+447700900123","text":"say \"hi\""}"#));
+    }
+
+    #[test]
+    fn post_reports_gateway_errors() {
+        let (url, _rx) = stub_server("500 Internal Server Error");
+        let cfg = gateway_config(url, "{text}", None);
+        let err = HttpGateway::post(&cfg, "+447700900123", "hi").unwrap_err();
+        assert_eq!(err.to_string(), "gateway returned 500: nope");
+    }
+
+    #[test]
+    fn webhook_accepts_form_body() {
+        let body = "from=%2B447700900123&text=hi+there";
+        let req = format!("POST /hook HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let (resp, rx) = webhook_request(params(None), &req);
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+        match rx.wait().next() {
+            Some(Ok(ModemCommand::IncomingText(addr, text))) => {
+                assert_eq!(addr.to_string(), "+447700900123");
+                assert_eq!(text, "hi there");
+            },
+            _ => panic!("webhook didn't deliver the message")
+        }
+    }
+
+    #[test]
+    fn webhook_accepts_json_body_with_token() {
+        let body = r#"{"from":"+447700900123","text":"hi"}"#;
+        let req = format!("POST /hook HTTP/1.1\r\nContent-Type: application/json\r\nX-Webhook-Token: sekrit\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let (resp, _rx) = webhook_request(params(Some("sekrit")), &req);
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn webhook_rejects_bad_token() {
+        for tok in &["", "&token=sekri", "&token=sekrit2", "&token=Sekrit"] {
+            let req = format!("GET /hook?from=%2B447700900123&text=hi{} HTTP/1.1\r\n\r\n", tok);
+            let (resp, _rx) = webhook_request(params(Some("sekrit")), &req);
+            assert!(resp.starts_with("HTTP/1.1 403 Forbidden"), "token {:?} accepted", tok);
+        }
+        let req = "GET /hook?from=%2B447700900123&text=hi&token=sekrit HTTP/1.1\r\n\r\n";
+        let (resp, _rx) = webhook_request(params(Some("sekrit")), req);
+        assert!(resp.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn webhook_rejects_missing_fields() {
+        let req = "GET /hook?from=%2B447700900123 HTTP/1.1\r\n\r\n";
+        let (resp, _rx) = webhook_request(params(None), req);
+        assert!(resp.starts_with("HTTP/1.1 400 Bad Request"));
+        assert!(resp.ends_with("missing field 'text'"));
+    }
+
+    #[test]
+    fn webhook_rejects_huge_headers() {
+        let mut req = "GET /hook?from=".to_owned();
+        while req.len() < WEBHOOK_MAX_LINE {
+            req.push('1');
+        }
+        let (resp, _rx) = webhook_request(params(None), &req);
+        assert!(resp.starts_with("HTTP/1.1 431 Request Header Fields Too Large"));
+    }
+
+    #[test]
+    fn webhook_rejects_huge_bodies() {
+        let req = format!("POST /hook HTTP/1.1\r\nContent-Length: {}\r\n\r\n", WEBHOOK_MAX_BODY + 1);
+        let (resp, _rx) = webhook_request(params(None), &req);
+        assert!(resp.starts_with("HTTP/1.1 413 Payload Too Large"));
+    }
+}
@@ -0,0 +1,765 @@
+//! Sending and receiving SMS messages by talking SMPP to an SMSC.
+
+use futures::{Future, Stream, Sink, Poll, Async, AsyncSink};
+use futures::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
+use futures::sync::oneshot;
+use huawei_modem::pdu::{PduAddress, DeliverPdu};
+use huawei_modem::gsm_encoding::GsmMessageData;
+use tokio_core::net::{TcpStream, TcpStreamNew};
+use tokio_core::reactor::Handle;
+use tokio_codec::Framed;
+use tokio_timer::{Delay, Interval};
+use std::time::{Instant, Duration};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use failure::Error;
+use crate::comm::{ModemCommand, ContactFactoryCommand, ControlBotCommand, InitParameters};
+use crate::config::SmppConfig;
+use crate::models::OutgoingSms;
+use crate::modem_budget::SmsBudget;
+use crate::sender_common::DEFAULT_REASSEMBLY_TIMEOUT_SECS;
+use crate::sms_backend::{SmsBackend, backend_prefix, spawn_outbox_timer, spawn_partials_timer};
+use crate::sms_smpp_pdu::*;
+use crate::store::Store;
+use crate::util::Result;
+
+/// How long to keep message IDs around waiting for delivery receipts.
+static RECEIPT_EXPIRY_SECS: u64 = 86400;
+
+enum SmppConn {
+    Waiting(Delay),
+    /// Looking up the SMSC's address, on another thread (since it blocks).
+    Resolving(oneshot::Receiver<Result<SocketAddr>>),
+    Connecting(TcpStreamNew),
+    Connected(Framed<TcpStream, SmppCodec>)
+}
+/// Something that happened to the connection while polling it.
+enum ConnEvent {
+    Reconnect,
+    Resolved(SocketAddr),
+    Connected(TcpStream),
+    Received(Vec<SmppPdu>)
+}
+struct SmppReceipt {
+    to: PduAddress,
+    sent: Instant,
+    status: Option<String>
+}
+
+pub struct SmppClient {
+    name: String,
+    prefix: String,
+    is_default: bool,
+    cfg: SmppConfig,
+    store: Store,
+    hdl: Handle,
+    conn: SmppConn,
+    /// Whether the SMSC has accepted our bind_transceiver.
+    bound: bool,
+    /// Whether the SMSC refused our last bind_transceiver.
+    bind_refused: bool,
+    /// How many times in a row we've failed to connect and bind.
+    failures: u32,
+    outbox: Vec<SmppPdu>,
+    next_seq: u32,
+    backoff_ms: u64,
+    /// Sequence number of an enquire_link we haven't had a response to.
+    enquire_pending: Option<u32>,
+    /// The submit_sm we're waiting on: (sequence number, outbox ID, time sent).
+    submitting: Option<(u32, i32, Instant)>,
+    /// Messages we're expecting delivery receipts for, by SMSC message ID.
+    receipts: HashMap<String, SmppReceipt>,
+    csms_ref: u8,
+    keepalive: Interval,
+    outbox_retry_ms: u32,
+    outbox_max_attempts: u32,
+    reassembly_timeout: u64,
+    sent: u64,
+    received: u64,
+    last_error: Option<String>,
+    rx: UnboundedReceiver<ModemCommand>,
+    int_tx: UnboundedSender<ModemCommand>,
+    cf_tx: UnboundedSender<ContactFactoryCommand>,
+    cb_tx: UnboundedSender<ControlBotCommand>,
+    budget: SmsBudget,
+}
+impl Future for SmppClient {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(msg) = self.rx.poll().unwrap() {
+            use self::ModemCommand::*;
+
+            let msg = msg.expect("rx stopped producing");
+            match msg {
+                DoCmgl => self.check_messages()?,
+                SendMessage(addr, msg) => self.send_message(addr, msg)?,
+                ProcessOutbox => self.process_outbox()?,
+                PrintReceipts => self.print_receipts(),
+                ListPartials => self.list_partials()?,
+                CheckPartials => self.check_stale_partials()?,
+                RequestStatus => self.request_status()?,
+                MakeContact(a) => self.make_contact(a)?,
+                ForceReinit => {
+                    self.connection_failed(format_err!("reconnect requested by admin"))?;
+                },
+                Ussd(_) => self.respond("USSD isn't supported over SMPP."),
+                PhonebookImport => self.respond("SMPP connections don't have a phonebook."),
+                RequestUsage => self.request_usage()?,
+                RequestCsq | RequestReg | UpdatePath(_) => {
+                    debug!("SMPP client {} ignoring modem-only command", self.name);
+                },
+                CmglComplete(_) | CmglFailed(_) | OutboxSent(..) | OutboxFailed(..) | OutboxPartlySent(..) | CommandTimeout | CheckUsb(_) | IncomingText(..) | ForModem(..) | SetRecipientModem(..) | SetRecipientTransliterate(..) | SetRecipientReplyable(..) | ListPdus | ShowPdu(_) | DecodePdu(_) | ListModems | UssdReceived(..) | HealthSampled(_) | Phonebook(_) => {
+                    warn!("SMPP client {} got a command it shouldn't have", self.name);
+                }
+            }
+        }
+        while let Async::Ready(_) = self.keepalive.poll()? {
+            self.keepalive_tick()?;
+        }
+        loop {
+            match self.poll_conn() {
+                Ok(()) => break,
+                Err(e) => self.connection_failed(e)?
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl SmppClient {
+    /// Get a sender for commands to this client.
+    pub fn sender(&self) -> UnboundedSender<ModemCommand> {
+        self.int_tx.clone()
+    }
+    pub fn new(p: InitParameters<SmppConfig>, is_default: bool) -> Result<Self> {
+        let cfg = p.cfg2.clone();
+        let name = cfg.name.clone();
+        let prefix = backend_prefix(p.cfg, &name);
+        let outbox_retry_ms = cfg.outbox_retry_ms.unwrap_or(10000);
+        let outbox_max_attempts = cfg.outbox_max_attempts.unwrap_or(10);
+        let reassembly_timeout = p.cfg.modem.reassembly_timeout_secs.unwrap_or(DEFAULT_REASSEMBLY_TIMEOUT_SECS);
+        let enquire_link_secs = cfg.enquire_link_secs.unwrap_or(30);
+        let keepalive = Interval::new(Instant::now(), Duration::from_secs(enquire_link_secs));
+        let (int_tx, rx) = mpsc::unbounded();
+        let cf_tx = p.cm.cf_tx.clone();
+        let cb_tx = p.cm.cb_tx.clone();
+
+        spawn_outbox_timer(p.hdl, outbox_retry_ms, int_tx.clone());
+        spawn_partials_timer(p.hdl, int_tx.clone());
+        let budget = SmsBudget::new(p.store.clone(), &name, cfg.daily_segment_budget, cfg.recipient_daily_segment_budget, cfg.segments_per_minute);
+        Ok(Self {
+            name, prefix, is_default, outbox_retry_ms, outbox_max_attempts, reassembly_timeout,
+            keepalive, rx, int_tx, cf_tx, cb_tx,
+            backoff_ms: cfg.reconnect_min_ms.unwrap_or(1000),
+            cfg,
+            budget,
+            store: p.store,
+            hdl: p.hdl.clone(),
+            conn: SmppConn::Waiting(Delay::new(Instant::now())),
+            bound: false,
+            bind_refused: false,
+            failures: 0,
+            outbox: vec![],
+            next_seq: 1,
+            enquire_pending: None,
+            submitting: None,
+            receipts: HashMap::new(),
+            csms_ref: 0,
+            sent: 0,
+            received: 0,
+            last_error: None
+        })
+    }
+    fn next_seq(&mut self) -> u32 {
+        let ret = self.next_seq;
+        // Sequence numbers only go up to 0x7FFFFFFF.
+        self.next_seq = if ret >= 0x7FFF_FFFF { 1 } else { ret + 1 };
+        ret
+    }
+    fn poll_conn(&mut self) -> Result<()> {
+        loop {
+            let event = match self.conn {
+                SmppConn::Waiting(ref mut d) => {
+                    match d.poll()? {
+                        Async::Ready(()) => ConnEvent::Reconnect,
+                        Async::NotReady => return Ok(())
+                    }
+                },
+                SmppConn::Resolving(ref mut rx) => {
+                    match rx.poll() {
+                        Ok(Async::Ready(addr)) => ConnEvent::Resolved(addr?),
+                        Ok(Async::NotReady) => return Ok(()),
+                        Err(_) => return Err(format_err!("address lookup thread went away"))
+                    }
+                },
+                SmppConn::Connecting(ref mut f) => {
+                    match f.poll()? {
+                        Async::Ready(s) => ConnEvent::Connected(s),
+                        Async::NotReady => return Ok(())
+                    }
+                },
+                SmppConn::Connected(ref mut conn) => {
+                    let mut pdus = vec![];
+                    while let Async::Ready(pdu) = conn.poll()? {
+                        let pdu = pdu.ok_or(format_err!("SMSC closed the connection"))?;
+                        trace!("<--smpp {:?}", pdu);
+                        pdus.push(pdu);
+                    }
+                    ConnEvent::Received(pdus)
+                }
+            };
+            match event {
+                ConnEvent::Reconnect => {
+                    let host = self.cfg.host.clone();
+                    let port = self.cfg.port;
+                    let (tx, rx) = oneshot::channel();
+                    thread::spawn(move || {
+                        let addr = (&host as &str, port)
+                            .to_socket_addrs()
+                            .map_err(|e| format_err!("couldn't look up {}: {}", host, e))
+                            .and_then(|mut a| a.nth(0).ok_or(format_err!("no addresses found for {}", host)));
+                        let _ = tx.send(addr);
+                    });
+                    self.conn = SmppConn::Resolving(rx);
+                },
+                ConnEvent::Resolved(addr) => {
+                    info!("SMPP client {} connecting to {}", self.name, addr);
+                    self.conn = SmppConn::Connecting(TcpStream::connect(&addr, &self.hdl));
+                },
+                ConnEvent::Connected(s) => {
+                    debug!("SMPP client {} connected; binding", self.name);
+                    self.conn = SmppConn::Connected(Framed::new(s, SmppCodec));
+                    let seq = self.next_seq();
+                    let system_type = self.cfg.system_type.clone().unwrap_or_default();
+                    self.outbox.push(SmppPdu::bind_transceiver(seq, &self.cfg.system_id, &self.cfg.password, &system_type));
+                    self.flush()?;
+                },
+                ConnEvent::Received(pdus) => {
+                    let done = pdus.len() == 0;
+                    for pdu in pdus {
+                        self.handle_pdu(pdu)?;
+                    }
+                    self.flush()?;
+                    if done {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+    fn flush(&mut self) -> Result<()> {
+        if let SmppConn::Connected(ref mut conn) = self.conn {
+            for pdu in ::std::mem::replace(&mut self.outbox, vec![]) {
+                trace!("-->smpp {:?}", pdu);
+                if let AsyncSink::NotReady(p) = conn.start_send(pdu)? {
+                    self.outbox.push(p);
+                }
+            }
+            conn.poll_complete()?;
+        }
+        Ok(())
+    }
+    fn connection_failed(&mut self, e: Error) -> Result<()> {
+        let was_bound = self.bound;
+        self.bound = false;
+        self.outbox.clear();
+        self.enquire_pending = None;
+        let max = self.cfg.reconnect_max_ms.unwrap_or(60000);
+        if ::std::mem::replace(&mut self.bind_refused, false) {
+            // Bad credentials aren't going to fix themselves quickly, so
+            // don't hammer the SMSC with binds it'll refuse anyway.
+            self.backoff_ms = max;
+        }
+        warn!("SMPP client {} disconnected: {}; reconnecting in {}ms", self.name, e, self.backoff_ms);
+        if was_bound {
+            self.report_failure(&format!("Lost connection to SMSC: {}", e));
+        }
+        else if self.failures == 0 {
+            // Only report the first of a run of failures, so the admin
+            // isn't told about every retry.
+            self.report_failure(&format!("Couldn't connect to SMSC (will keep retrying): {}", e));
+        }
+        if !was_bound {
+            self.failures += 1;
+        }
+        self.last_error = Some(e.to_string());
+        if let Some((_, id, _)) = self.submitting.take() {
+            self.record_send_failure(id, format_err!("lost connection to SMSC"))?;
+        }
+        self.conn = SmppConn::Waiting(Delay::new(Instant::now() + Duration::from_millis(self.backoff_ms)));
+        self.backoff_ms = ::std::cmp::min(self.backoff_ms * 2, max);
+        Ok(())
+    }
+    fn handle_pdu(&mut self, pdu: SmppPdu) -> Result<()> {
+        match pdu.command_id {
+            BIND_TRANSCEIVER_RESP => {
+                if pdu.status != ESME_ROK {
+                    self.bind_refused = true;
+                    Err(format_err!("SMSC refused bind: {}", describe_status(pdu.status)))?
+                }
+                info!("SMPP client {} bound to {}:{}", self.name, self.cfg.host, self.cfg.port);
+                if self.failures > 0 {
+                    self.respond(&format!("Connected to SMSC after {} failed attempts.", self.failures));
+                }
+                self.bound = true;
+                self.failures = 0;
+                self.backoff_ms = self.cfg.reconnect_min_ms.unwrap_or(1000);
+                self.process_outbox()?;
+            },
+            SUBMIT_SM_RESP | GENERIC_NACK => {
+                match self.submitting {
+                    Some((seq, id, _)) if seq == pdu.sequence => {
+                        self.submitting = None;
+                        self.submit_complete(id, &pdu)?;
+                    },
+                    _ => debug!("SMPP client {} got a response to an old submit_sm", self.name)
+                }
+            },
+            ENQUIRE_LINK_RESP => {
+                if self.enquire_pending == Some(pdu.sequence) {
+                    self.enquire_pending = None;
+                }
+            },
+            DELIVER_SM => {
+                let status = match DeliverSm::parse(&pdu.body) {
+                    Ok(dsm) => {
+                        match self.process_deliver(dsm) {
+                            Ok(()) => ESME_ROK,
+                            Err(e) => {
+                                warn!("Failed to process deliver_sm: {}", e);
+                                ESME_RSYSERR
+                            }
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Invalid deliver_sm: {}", e);
+                        ESME_RSYSERR
+                    }
+                };
+                // The body of a deliver_sm_resp is an empty message_id.
+                self.outbox.push(pdu.response(status, vec![0]));
+            },
+            ENQUIRE_LINK => {
+                self.outbox.push(pdu.response(ESME_ROK, vec![]));
+            },
+            UNBIND => {
+                self.outbox.push(pdu.response(ESME_ROK, vec![]));
+                self.flush()?;
+                Err(format_err!("SMSC unbound"))?
+            },
+            x if pdu.is_response() => {
+                debug!("SMPP client {} ignoring response {:08x}", self.name, x);
+            },
+            x => {
+                warn!("SMPP client {} got unsupported command {:08x}", self.name, x);
+                self.outbox.push(SmppPdu::generic_nack(pdu.sequence, ESME_RINVCMDID));
+            }
+        }
+        Ok(())
+    }
+    fn submit_complete(&mut self, id: i32, pdu: &SmppPdu) -> Result<()> {
+        if pdu.command_id != SUBMIT_SM_RESP || pdu.status != ESME_ROK {
+            let e = format_err!("SMSC rejected message: {}", describe_status(pdu.status));
+            self.last_error = Some(e.to_string());
+            self.record_send_failure(id, e)?;
+        }
+        else {
+            let msgid = pdu.message_id()?;
+            info!("Outgoing SMS #{} sent via {} (message ID {})", id, self.name, msgid);
+            self.sent += 1;
+            let sms = self.store.get_outgoing_sms_by_id(id)?;
+            self.store.update_outgoing_sms_state(id, OutgoingSms::STATE_SENT)?;
+            let segments = GsmMessageData::encode_message(&sms.text).len();
+            self.record_usage(&sms.get_addr()?, segments)?;
+            if self.cfg.delivery_receipts {
+                self.receipts.insert(msgid, SmppReceipt {
+                    to: sms.get_addr()?,
+                    sent: Instant::now(),
+                    status: None
+                });
+            }
+        }
+        self.process_outbox()?;
+        Ok(())
+    }
+    fn process_deliver(&mut self, dsm: DeliverSm) -> Result<()> {
+        if dsm.is_receipt() {
+            self.process_receipt(dsm);
+            return Ok(());
+        }
+        let csms_ref = self.csms_ref;
+        self.csms_ref = self.csms_ref.wrapping_add(1);
+        for raw in dsm.to_deliver_pdus(csms_ref)? {
+            let pdu = DeliverPdu::try_from(&raw as &[u8])
+                .map_err(|e| format_err!("couldn't parse generated PDU: {:?}", e))?;
+            self.store_deliver_pdu(&pdu, &raw)?;
+        }
+        self.received += 1;
+        self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessMessages).unwrap();
+        Ok(())
+    }
+    fn process_receipt(&mut self, dsm: DeliverSm) {
+        let (id, stat) = dsm.receipt_info();
+        let id = match id {
+            Some(i) => i,
+            None => {
+                warn!("SMPP client {} got a delivery receipt without a message ID", self.name);
+                return;
+            }
+        };
+        let stat = stat.unwrap_or("UNKNOWN".into());
+        let to = match self.receipts.get_mut(&id) {
+            Some(r) => {
+                r.status = Some(stat.clone());
+                r.to.clone()
+            },
+            None => {
+                debug!("Delivery receipt for unknown message {} ({})", id, stat);
+                return;
+            }
+        };
+        debug!("Message {} to {} is now {}", id, to, stat);
+        match &stat as &str {
+            "UNDELIV" | "REJECTD" | "EXPIRED" | "DELETED" => {
+                self.report_failure(&format!("Message to {} was not delivered ({})", to, stat));
+            },
+            _ => {}
+        }
+    }
+    fn keepalive_tick(&mut self) -> Result<()> {
+        if !self.bound {
+            return Ok(());
+        }
+        if let Some(seq) = self.enquire_pending {
+            return self.connection_failed(format_err!("no response to enquire_link #{}", seq));
+        }
+        let timeout = Duration::from_millis(self.cfg.response_timeout_ms.unwrap_or(30000));
+        if let Some((_, id, sent)) = self.submitting {
+            if sent.elapsed() > timeout {
+                self.submitting = None;
+                self.record_send_failure(id, format_err!("no response from SMSC"))?;
+                self.process_outbox()?;
+            }
+        }
+        let seq = self.next_seq();
+        self.enquire_pending = Some(seq);
+        self.outbox.push(SmppPdu::enquire_link(seq));
+        let expiry = Duration::from_secs(RECEIPT_EXPIRY_SECS);
+        self.receipts.retain(|_, r| r.sent.elapsed() < expiry);
+        Ok(())
+    }
+    fn print_receipts(&mut self) {
+        let mut lines = vec![];
+        for (id, r) in self.receipts.iter() {
+            let status = r.status.as_ref().map(|x| x as &str).unwrap_or("no receipt yet");
+            lines.push(format!("- message {} to \x02{}\x02, sent {}s ago: {}",
+                               id, r.to, r.sent.elapsed().as_secs(), status));
+        }
+        if lines.len() == 0 {
+            lines.push("No outstanding delivery receipts".into());
+        }
+        for line in lines {
+            self.respond(&line);
+        }
+    }
+    /// Work out the type-of-number and numbering-plan for an address.
+    fn ton_npi(addr: &str) -> (u8, u8, String) {
+        if addr.starts_with('+') {
+            (1, 1, addr[1..].to_owned())
+        }
+        else if addr.len() > 0 && addr.chars().all(|c| c.is_ascii_digit()) {
+            (0, 1, addr.to_owned())
+        }
+        else {
+            // Alphanumeric.
+            (5, 0, addr.to_owned())
+        }
+    }
+}
+impl SmsBackend for SmppClient {
+    fn backend_name(&self) -> &str {
+        &self.name
+    }
+    fn prefix(&self) -> &str {
+        &self.prefix
+    }
+    fn is_default(&self) -> bool {
+        self.is_default
+    }
+    fn outbox_retry_ms(&self) -> u32 {
+        self.outbox_retry_ms
+    }
+    fn outbox_max_attempts(&self) -> u32 {
+        self.outbox_max_attempts
+    }
+    fn reassembly_timeout(&self) -> u64 {
+        self.reassembly_timeout
+    }
+    fn store(&mut self) -> &mut Store {
+        &mut self.store
+    }
+    fn cf_tx(&mut self) -> &mut UnboundedSender<ContactFactoryCommand> {
+        &mut self.cf_tx
+    }
+    fn cb_tx(&mut self) -> &mut UnboundedSender<ControlBotCommand> {
+        &mut self.cb_tx
+    }
+    fn budget(&mut self) -> &mut SmsBudget {
+        &mut self.budget
+    }
+    fn check_messages(&mut self) -> Result<()> {
+        // Incoming messages are pushed to us by the SMSC.
+        Ok(())
+    }
+    fn request_status(&mut self) -> Result<()> {
+        let state = match self.conn {
+            SmppConn::Waiting(_) => "disconnected (waiting to reconnect)",
+            SmppConn::Resolving(_) => "looking up address",
+            SmppConn::Connecting(_) => "connecting",
+            SmppConn::Connected(_) if !self.bound => "binding",
+            SmppConn::Connected(_) => "bound"
+        };
+        let status = format!("SMPP connection to \x02{}:{}\x02 as \x02{}\x02: {}; {} sent, {} received since startup",
+                             self.cfg.host, self.cfg.port, self.cfg.system_id, state, self.sent, self.received);
+        self.respond(&status);
+        if let Some(e) = self.last_error.clone() {
+            self.respond(&format!("Last error: {}", e));
+        }
+        Ok(())
+    }
+    fn process_outbox(&mut self) -> Result<()> {
+        if !self.bound || self.submitting.is_some() {
+            return Ok(());
+        }
+        let (sms, addr, _) = match self.next_sendable_sms()? {
+            Some(s) => s,
+            None => return Ok(())
+        };
+        self.store.update_outgoing_sms_state(sms.id, OutgoingSms::STATE_SENDING)?;
+        debug!("Sending message #{} to {} via {} (attempt {})...", sms.id, addr, self.name, sms.attempts + 1);
+        trace!("Message content: {}", sms.text);
+        let (source_ton, source_npi, source_addr) = match self.cfg.source_addr {
+            Some(ref s) => Self::ton_npi(s),
+            None => (0, 0, String::new())
+        };
+        let (dest_ton, dest_npi, dest_addr) = Self::ton_npi(&addr.to_string());
+        let submit = SubmitSm {
+            source_ton, source_npi, source_addr,
+            dest_ton, dest_npi, dest_addr,
+            text: sms.text,
+            registered_delivery: self.cfg.delivery_receipts
+        };
+        let seq = self.next_seq();
+        self.outbox.push(SmppPdu::submit_sm(seq, &submit));
+        self.submitting = Some((seq, sms.id, Instant::now()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    //! These talk to a scripted SMSC, and need a database to store messages
+    //! in, so they're ignored by default: set `SMS_IRC_TEST_DATABASE_URL` and
+    //! run `cargo test -- --ignored`.
+
+    use super::*;
+    use bytes::BytesMut;
+    use tokio_codec::{Decoder, Encoder};
+    use tokio_core::reactor::Core;
+    use tokio_timer::Timeout;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::comm::ChannelMaker;
+    use crate::config::Config;
+
+    /// One connection from the client, as seen by the scripted SMSC.
+    struct Peer {
+        stream: ::std::net::TcpStream,
+        buf: BytesMut
+    }
+    impl Peer {
+        fn accept(listener: &TcpListener) -> Self {
+            let (stream, _) = listener.accept().unwrap();
+            Self { stream, buf: BytesMut::new() }
+        }
+        fn recv(&mut self) -> SmppPdu {
+            loop {
+                if let Some(pdu) = SmppCodec.decode(&mut self.buf).unwrap() {
+                    return pdu;
+                }
+                let mut data = [0; 4096];
+                let n = self.stream.read(&mut data).unwrap();
+                assert!(n > 0, "client closed the connection");
+                self.buf.extend_from_slice(&data[..n]);
+            }
+        }
+        fn send(&mut self, pdu: SmppPdu) {
+            let mut buf = BytesMut::new();
+            SmppCodec.encode(pdu, &mut buf).unwrap();
+            self.stream.write_all(&buf).unwrap();
+        }
+        /// Wait for a bind_transceiver, and answer it with `status`.
+        fn bind(&mut self, status: u32) {
+            let bind = self.recv();
+            assert_eq!(bind.command_id, BIND_TRANSCEIVER);
+            self.send(bind.response(status, b"smsc\0".to_vec()));
+        }
+    }
+
+    struct Harness {
+        core: Core,
+        store: Store,
+        cm: ChannelMaker,
+        tx: UnboundedSender<ModemCommand>
+    }
+    impl Harness {
+        /// Start an SMPP client called `name`, talking to `listener`.
+        fn new(name: &str, listener: &TcpListener) -> Self {
+            let url = ::std::env::var("SMS_IRC_TEST_DATABASE_URL")
+                .expect("SMS_IRC_TEST_DATABASE_URL must be set to run this test");
+            let cfg: Config = toml::from_str(&format!(r#"
+                database_url = "{}"
+                [[smpp]]
+                name = "{}"
+                host = "127.0.0.1"
+                port = {}
+                system_id = "sms-irc"
+                password = "hunter2"
+                reconnect_min_ms = 10
+                reconnect_max_ms = 50
+            "#, url, name, listener.local_addr().unwrap().port())).unwrap();
+            let core = Core::new().unwrap();
+            let store = Store::new(&cfg).unwrap();
+            let mut cm = ChannelMaker::new();
+            let client = SmppClient::new(InitParameters {
+                cfg: &cfg,
+                cfg2: &cfg.smpp[0],
+                store: store.clone(),
+                cm: &mut cm,
+                hdl: &core.handle()
+            }, false).unwrap();
+            let tx = client.sender();
+            core.handle().spawn(client.map_err(|e| panic!("SMPP client failed: {}", e)));
+            Self { core, store, cm, tx }
+        }
+        fn next_cb(&mut self) -> ControlBotCommand {
+            let rx = self.cm.cb_rx.as_mut().unwrap();
+            let fut = rx.by_ref().into_future()
+                .map(|(x, _)| x.expect("control bot channel closed"))
+                .map_err(|_| ());
+            self.core.run(Timeout::new(fut, Duration::from_secs(10)))
+                .unwrap_or_else(|_| panic!("timed out waiting for the control bot"))
+        }
+        /// Run the client until `cond` is true.
+        fn run_until<F: FnMut(&mut Store) -> bool>(&mut self, mut cond: F) {
+            let start = Instant::now();
+            while !cond(&mut self.store) {
+                assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+                self.core.turn(Some(Duration::from_millis(50)));
+            }
+        }
+    }
+
+    fn deliver_sm(from: &str, text: &str) -> SmppPdu {
+        let mut body = vec![];
+        // service_type
+        body.push(0);
+        body.extend(&[1, 1]);
+        body.extend(from.as_bytes());
+        body.push(0);
+        // dest_addr_ton, dest_addr_npi, dest_addr
+        body.extend(&[0, 0, 0]);
+        // esm_class, protocol_id, priority_flag, schedule_delivery_time,
+        // validity_period, registered_delivery, replace_if_present_flag,
+        // data_coding, sm_default_msg_id
+        body.extend(&[0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        body.push(text.len() as u8);
+        body.extend(text.as_bytes());
+        SmppPdu::new(DELIVER_SM, 1, body)
+    }
+
+    #[test]
+    #[ignore]
+    fn refused_bind_is_reported_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut h = Harness::new("smpp-test-refused", &listener);
+        thread::spawn(move || {
+            for _ in 0..2 {
+                Peer::accept(&listener).bind(0x0E);
+            }
+            let mut peer = Peer::accept(&listener);
+            peer.bind(ESME_ROK);
+            // Keep the connection open until the test is done.
+            let _ = peer.stream.read(&mut [0]);
+        });
+        match h.next_cb() {
+            ControlBotCommand::ReportFailure(msg) => {
+                assert!(msg.contains("SMSC refused bind: invalid password"), "{}", msg);
+            },
+            _ => panic!("bind failure wasn't reported")
+        }
+        match h.next_cb() {
+            ControlBotCommand::CommandResponse(msg) => {
+                assert!(msg.contains("after 2 failed attempts"), "{}", msg);
+            },
+            _ => panic!("retried bind failure was reported")
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn sends_queued_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut h = Harness::new("smpp-test-send", &listener);
+        let (peer_tx, peer_rx) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.bind(ESME_ROK);
+            let submit = peer.recv();
+            peer.send(submit.response(ESME_ROK, b"msg1\0".to_vec()));
+            peer_tx.send(submit).unwrap();
+            let _ = peer.stream.read(&mut [0]);
+        });
+        let addr: PduAddress = "+447700900101".parse().unwrap();
+        h.tx.unbounded_send(ModemCommand::SendMessage(addr.clone(), "hello".into())).unwrap();
+        h.run_until(|store| {
+            store.get_queued_outgoing_sms("smpp-test-send", false).unwrap().len() == 0
+        });
+        let submit = peer_rx.recv().unwrap();
+        assert_eq!(submit.command_id, SUBMIT_SM);
+        let body = String::from_utf8_lossy(&submit.body).into_owned();
+        assert!(body.contains("447700900101\0"), "{:?}", body);
+        assert!(body.ends_with("hello"), "{:?}", body);
+    }
+
+    #[test]
+    #[ignore]
+    fn stores_delivered_message() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut h = Harness::new("smpp-test-deliver", &listener);
+        let (peer_tx, peer_rx) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut peer = Peer::accept(&listener);
+            peer.bind(ESME_ROK);
+            peer.send(deliver_sm("447700900102", "hi there"));
+            peer_tx.send(peer.recv()).unwrap();
+            let _ = peer.stream.read(&mut [0]);
+        });
+        let addr: PduAddress = "+447700900102".parse().unwrap();
+        h.run_until(|store| {
+            store.get_messages_for_recipient(&addr).unwrap().len() > 0
+        });
+        let resp = peer_rx.recv().unwrap();
+        assert_eq!(resp.command_id, DELIVER_SM | GENERIC_NACK);
+        assert_eq!(resp.status, ESME_ROK);
+        for msg in h.store.get_messages_for_recipient(&addr).unwrap() {
+            let pdu = DeliverPdu::try_from(msg.pdu.as_ref().unwrap() as &[u8]).unwrap();
+            assert_eq!(pdu.get_message_data().decode_message().unwrap().text, "hi there");
+            h.store.delete_message(msg.id).unwrap();
+        }
+    }
+}
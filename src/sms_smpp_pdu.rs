@@ -0,0 +1,462 @@
+//! SMPP 3.4 PDUs, and turning them into something the rest of sms-irc understands.
+
+use bytes::{BytesMut, BufMut};
+use tokio_codec::{Decoder, Encoder};
+use chrono::prelude::*;
+use failure::Error;
+use crate::gsm7;
+use crate::util::Result;
+
+pub const GENERIC_NACK: u32 = 0x8000_0000;
+pub const BIND_TRANSCEIVER: u32 = 0x0000_0009;
+pub const BIND_TRANSCEIVER_RESP: u32 = 0x8000_0009;
+pub const SUBMIT_SM: u32 = 0x0000_0004;
+pub const SUBMIT_SM_RESP: u32 = 0x8000_0004;
+pub const DELIVER_SM: u32 = 0x0000_0005;
+pub const UNBIND: u32 = 0x0000_0006;
+pub const ENQUIRE_LINK: u32 = 0x0000_0015;
+pub const ENQUIRE_LINK_RESP: u32 = 0x8000_0015;
+
+pub const ESME_ROK: u32 = 0x00;
+pub const ESME_RINVCMDID: u32 = 0x03;
+pub const ESME_RSYSERR: u32 = 0x08;
+
+const TLV_RECEIPTED_MESSAGE_ID: u16 = 0x001E;
+const TLV_MESSAGE_PAYLOAD: u16 = 0x0424;
+const TLV_MESSAGE_STATE: u16 = 0x0427;
+
+/// The largest PDU we're willing to accept.
+const MAX_PDU_LEN: usize = 65536;
+
+pub fn describe_status(st: u32) -> String {
+    let desc = match st {
+        0x00 => "no error",
+        0x01 => "message length is invalid",
+        0x02 => "command length is invalid",
+        0x03 => "invalid command ID",
+        0x04 => "incorrect bind status for given command",
+        0x05 => "already bound",
+        0x08 => "system error",
+        0x0A => "invalid source address",
+        0x0B => "invalid destination address",
+        0x0D => "bind failed",
+        0x0E => "invalid password",
+        0x0F => "invalid system ID",
+        0x14 => "message queue full",
+        0x45 => "submit_sm failed",
+        0x58 => "throttling error",
+        0x61 => "invalid scheduled delivery time",
+        0x62 => "invalid validity period",
+        0x64 => "message queue full",
+        _ => "unknown error"
+    };
+    format!("{} (0x{:02X})", desc, st)
+}
+
+/// Reads the fields of a PDU body.
+struct BodyReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+impl<'a> BodyReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+    fn u8(&mut self) -> Result<u8> {
+        let ret = *self.data.get(self.pos)
+            .ok_or(format_err!("PDU truncated at octet {}", self.pos))?;
+        self.pos += 1;
+        Ok(ret)
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(((self.u8()? as u16) << 8) | self.u8()? as u16)
+    }
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.pos + n > self.data.len() {
+            Err(format_err!("PDU truncated at octet {}", self.pos))?
+        }
+        let ret = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(ret)
+    }
+    fn c_string(&mut self) -> Result<String> {
+        let len = self.data[self.pos..].iter()
+            .position(|&x| x == 0)
+            .ok_or(format_err!("unterminated C-Octet String at octet {}", self.pos))?;
+        let ret = String::from_utf8_lossy(&self.data[self.pos..self.pos + len]).into_owned();
+        self.pos += len + 1;
+        Ok(ret)
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+fn put_c_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend(s.as_bytes());
+    buf.push(0);
+}
+
+#[derive(Debug, Clone)]
+pub struct SmppPdu {
+    pub command_id: u32,
+    pub status: u32,
+    pub sequence: u32,
+    pub body: Vec<u8>
+}
+impl SmppPdu {
+    pub fn new(command_id: u32, sequence: u32, body: Vec<u8>) -> Self {
+        Self { command_id, status: ESME_ROK, sequence, body }
+    }
+    pub fn is_response(&self) -> bool {
+        self.command_id & GENERIC_NACK != 0
+    }
+    /// Make a response to this PDU.
+    pub fn response(&self, status: u32, body: Vec<u8>) -> Self {
+        Self {
+            command_id: self.command_id | GENERIC_NACK,
+            sequence: self.sequence,
+            status, body
+        }
+    }
+    pub fn generic_nack(sequence: u32, status: u32) -> Self {
+        Self { command_id: GENERIC_NACK, status, sequence, body: vec![] }
+    }
+    pub fn bind_transceiver(sequence: u32, system_id: &str, password: &str, system_type: &str) -> Self {
+        let mut body = vec![];
+        put_c_string(&mut body, system_id);
+        put_c_string(&mut body, password);
+        put_c_string(&mut body, system_type);
+        // interface_version, addr_ton, addr_npi, address_range
+        body.extend(&[0x34, 0, 0, 0]);
+        Self::new(BIND_TRANSCEIVER, sequence, body)
+    }
+    pub fn enquire_link(sequence: u32) -> Self {
+        Self::new(ENQUIRE_LINK, sequence, vec![])
+    }
+    pub fn submit_sm(sequence: u32, sm: &SubmitSm) -> Self {
+        let (dc, data) = encode_text(&sm.text);
+        // One SMS holds 140 octets; if it doesn't fit, send the lot in
+        // the message_payload TLV, and let the SMSC split it up.
+        let fits = if dc == 0 { data.len() <= 160 } else { data.len() <= 140 };
+        let mut body = vec![];
+        put_c_string(&mut body, "");
+        body.push(sm.source_ton);
+        body.push(sm.source_npi);
+        put_c_string(&mut body, &sm.source_addr);
+        body.push(sm.dest_ton);
+        body.push(sm.dest_npi);
+        put_c_string(&mut body, &sm.dest_addr);
+        // esm_class, protocol_id, priority_flag
+        body.extend(&[0, 0, 0]);
+        put_c_string(&mut body, "");
+        put_c_string(&mut body, "");
+        body.push(if sm.registered_delivery { 1 } else { 0 });
+        // replace_if_present_flag
+        body.push(0);
+        body.push(dc);
+        // sm_default_msg_id
+        body.push(0);
+        if fits {
+            body.push(data.len() as u8);
+            body.extend(&data);
+        }
+        else {
+            body.push(0);
+            body.extend(&[(TLV_MESSAGE_PAYLOAD >> 8) as u8, TLV_MESSAGE_PAYLOAD as u8]);
+            body.extend(&[(data.len() >> 8) as u8, data.len() as u8]);
+            body.extend(&data);
+        }
+        Self::new(SUBMIT_SM, sequence, body)
+    }
+    /// Get the message_id out of a submit_sm_resp.
+    pub fn message_id(&self) -> Result<String> {
+        BodyReader::new(&self.body).c_string()
+    }
+}
+
+/// Encode text for a submit_sm, returning the data_coding value and the data.
+///
+/// Text that fits in the GSM 7-bit default alphabet is sent as unpacked septets
+/// (the usual SMSC default alphabet); anything else goes as UCS-2.
+fn encode_text(text: &str) -> (u8, Vec<u8>) {
+    let septets = text.chars()
+        .map(gsm7::char_to_gsm7)
+        .collect::<Option<Vec<_>>>();
+    if let Some(s) = septets {
+        return (0, s);
+    }
+    let mut data = vec![];
+    for unit in text.encode_utf16() {
+        data.push((unit >> 8) as u8);
+        data.push(unit as u8);
+    }
+    (8, data)
+}
+
+pub struct SubmitSm {
+    pub source_ton: u8,
+    pub source_npi: u8,
+    pub source_addr: String,
+    pub dest_ton: u8,
+    pub dest_npi: u8,
+    pub dest_addr: String,
+    pub text: String,
+    pub registered_delivery: bool
+}
+
+#[derive(Debug)]
+pub struct DeliverSm {
+    pub source_ton: u8,
+    pub source_addr: String,
+    pub esm_class: u8,
+    pub data_coding: u8,
+    pub short_message: Vec<u8>,
+    pub message_payload: Option<Vec<u8>>,
+    pub receipted_message_id: Option<String>,
+    pub message_state: Option<u8>
+}
+impl DeliverSm {
+    pub fn parse(body: &[u8]) -> Result<Self> {
+        let mut r = BodyReader::new(body);
+        let _service_type = r.c_string()?;
+        let source_ton = r.u8()?;
+        let _source_npi = r.u8()?;
+        let source_addr = r.c_string()?;
+        let _dest_ton = r.u8()?;
+        let _dest_npi = r.u8()?;
+        let _dest_addr = r.c_string()?;
+        let esm_class = r.u8()?;
+        let _protocol_id = r.u8()?;
+        let _priority_flag = r.u8()?;
+        let _schedule_delivery_time = r.c_string()?;
+        let _validity_period = r.c_string()?;
+        let _registered_delivery = r.u8()?;
+        let _replace_if_present = r.u8()?;
+        let data_coding = r.u8()?;
+        let _sm_default_msg_id = r.u8()?;
+        let sm_length = r.u8()? as usize;
+        let short_message = r.bytes(sm_length)?.to_vec();
+        let mut ret = Self {
+            source_ton, source_addr, esm_class, data_coding, short_message,
+            message_payload: None,
+            receipted_message_id: None,
+            message_state: None
+        };
+        while !r.is_empty() {
+            let tag = r.u16()?;
+            let len = r.u16()? as usize;
+            let val = r.bytes(len)?;
+            match tag {
+                TLV_MESSAGE_PAYLOAD => ret.message_payload = Some(val.to_vec()),
+                TLV_RECEIPTED_MESSAGE_ID => {
+                    let id = val.iter().take_while(|&&x| x != 0).cloned().collect::<Vec<_>>();
+                    ret.receipted_message_id = Some(String::from_utf8_lossy(&id).into_owned());
+                },
+                TLV_MESSAGE_STATE => ret.message_state = val.get(0).cloned(),
+                _ => {}
+            }
+        }
+        Ok(ret)
+    }
+    /// Whether this is an SMSC delivery receipt, rather than a message.
+    pub fn is_receipt(&self) -> bool {
+        self.esm_class & 0x3C == 0x04
+    }
+    fn payload(&self) -> &[u8] {
+        self.message_payload.as_ref().unwrap_or(&self.short_message)
+    }
+    /// Get the message ID and final state out of a delivery receipt.
+    ///
+    /// These come from TLVs if the SMSC provides them, and the receipt text
+    /// (`id:... sub:... dlvrd:... stat:DELIVRD ...`) otherwise.
+    pub fn receipt_info(&self) -> (Option<String>, Option<String>) {
+        let text = String::from_utf8_lossy(self.payload());
+        let field = |name: &str| {
+            text.split_whitespace()
+                .filter(|x| x.to_lowercase().starts_with(name))
+                .map(|x| x[name.len()..].to_owned())
+                .next()
+        };
+        let id = self.receipted_message_id.clone().or_else(|| field("id:"));
+        let state = match self.message_state {
+            Some(1) => Some("ENROUTE".into()),
+            Some(2) => Some("DELIVRD".into()),
+            Some(3) => Some("EXPIRED".into()),
+            Some(4) => Some("DELETED".into()),
+            Some(5) => Some("UNDELIV".into()),
+            Some(6) => Some("ACCEPTD".into()),
+            Some(7) => Some("UNKNOWN".into()),
+            Some(8) => Some("REJECTD".into()),
+            _ => field("stat:")
+        };
+        (id, state)
+    }
+    /// Make SMS-DELIVER TPDUs containing this message, as if it had been
+    /// received by a modem, so it can take the same path through sms-irc.
+    ///
+    /// Messages too long for one PDU get split into several, as a
+    /// concatenated message with reference `csms_ref`. Messages that
+    /// came with their own UDH can't be split, so they're refused.
+    pub fn to_deliver_pdus(&self, csms_ref: u8) -> Result<Vec<Vec<u8>>> {
+        let mut data = self.payload();
+        let mut udh = None;
+        if self.esm_class & 0x40 != 0 {
+            let udhl = *data.get(0).ok_or(format_err!("empty message with UDHI set"))? as usize;
+            if data.len() < udhl + 1 {
+                Err(format_err!("UDH longer than message"))?
+            }
+            udh = Some(data[..udhl + 1].to_vec());
+            data = &data[udhl + 1..];
+        }
+        let (dcs, units, part_len) = match self.data_coding {
+            // SMSC default alphabet (GSM 7-bit, unpacked) and IA5 (ASCII).
+            0 => (0x00, data.to_vec(), 153),
+            1 => {
+                let septets = data.iter()
+                    .map(|&x| gsm7::char_to_gsm7(x as char).unwrap_or(0x3F))
+                    .collect();
+                (0x00, septets, 153)
+            },
+            // Latin-1; every Latin-1 character is also a UCS-2 one.
+            3 => {
+                let ucs2 = data.iter()
+                    .flat_map(|&x| vec![0, x])
+                    .collect();
+                (0x08, ucs2, 134)
+            },
+            8 => (0x08, data.to_vec(), 134),
+            _ => (0x04, data.to_vec(), 134)
+        };
+        let max_len = if dcs == 0x00 { 160 } else { 140 };
+        let mut parts = vec![];
+        if udh.is_some() || units.len() <= max_len {
+            parts.push((udh, &units as &[u8]));
+        }
+        else {
+            let chunks = units.chunks(part_len).collect::<Vec<_>>();
+            let total = chunks.len();
+            if total > 255 {
+                Err(format_err!("message too long ({} parts)", total))?
+            }
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                // IEI 0x00: concatenated short message, 8-bit reference
+                let udh = vec![0x05, 0x00, 0x03, csms_ref, total as u8, (i + 1) as u8];
+                parts.push((Some(udh), chunk));
+            }
+        }
+        let oa = self.originating_address();
+        let scts = make_timestamp(Utc::now());
+        let mut ret = vec![];
+        for (udh, part) in parts {
+            // No SMSC address
+            let mut pdu = vec![0x00];
+            // TP-MTI = SMS-DELIVER, TP-MMS = no more messages, TP-UDHI
+            pdu.push(if udh.is_some() { 0x44 } else { 0x04 });
+            pdu.extend(&oa);
+            // TP-PID
+            pdu.push(0x00);
+            pdu.push(dcs);
+            pdu.extend(&scts);
+            let udh = udh.unwrap_or_default();
+            if dcs == 0x00 {
+                // The UDH is padded to a septet boundary, and counted in septets.
+                let fill_bits = (7 - (udh.len() * 8) % 7) % 7;
+                let udh_septets = (udh.len() * 8 + fill_bits) / 7;
+                if udh_septets + part.len() > 160 {
+                    Err(format_err!("message with UDH too long for one PDU ({} septets)", udh_septets + part.len()))?
+                }
+                pdu.push((udh_septets + part.len()) as u8);
+                pdu.extend(&udh);
+                pdu.extend(gsm7::pack_septets(part, fill_bits));
+            }
+            else {
+                if udh.len() + part.len() > 140 {
+                    Err(format_err!("message with UDH too long for one PDU ({} octets)", udh.len() + part.len()))?
+                }
+                pdu.push((udh.len() + part.len()) as u8);
+                pdu.extend(&udh);
+                pdu.extend(part);
+            }
+            ret.push(pdu);
+        }
+        Ok(ret)
+    }
+    /// Encode the source address as a TP-OA.
+    fn originating_address(&self) -> Vec<u8> {
+        if self.source_ton == 5 {
+            // Alphanumeric, encoded in GSM 7-bit.
+            let septets = self.source_addr.chars()
+                .map(|x| gsm7::char_to_gsm7(x).unwrap_or(0x3F))
+                .collect::<Vec<_>>();
+            let packed = gsm7::pack_septets(&septets, 0);
+            let mut ret = vec![((septets.len() * 7 + 3) / 4) as u8, 0xD0];
+            ret.extend(packed);
+            return ret;
+        }
+        let digits = self.source_addr.chars()
+            .filter_map(|x| x.to_digit(10))
+            .map(|x| x as u8)
+            .collect::<Vec<_>>();
+        let international = self.source_ton == 1 || self.source_addr.starts_with('+');
+        let mut ret = vec![digits.len() as u8, if international { 0x91 } else { 0x81 }];
+        for pair in digits.chunks(2) {
+            let hi = pair.get(1).cloned().unwrap_or(0x0F);
+            ret.push((hi << 4) | pair[0]);
+        }
+        ret
+    }
+}
+/// Make a TP-SCTS (in UTC) for the given time.
+fn make_timestamp(ts: DateTime<Utc>) -> Vec<u8> {
+    let swap = |x: u32| -> u8 { (((x % 10) << 4) | (x / 10)) as u8 };
+    vec![
+        swap(ts.year() as u32 % 100),
+        swap(ts.month()),
+        swap(ts.day()),
+        swap(ts.hour()),
+        swap(ts.minute()),
+        swap(ts.second()),
+        0x00
+    ]
+}
+
+pub struct SmppCodec;
+impl Decoder for SmppCodec {
+    type Item = SmppPdu;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<SmppPdu>> {
+        if buf.len() < 16 {
+            return Ok(None);
+        }
+        let word = |b: &[u8]| ((b[0] as u32) << 24) | ((b[1] as u32) << 16) | ((b[2] as u32) << 8) | b[3] as u32;
+        let len = word(&buf[0..4]) as usize;
+        if len < 16 || len > MAX_PDU_LEN {
+            Err(format_err!("invalid PDU length {}", len))?
+        }
+        if buf.len() < len {
+            return Ok(None);
+        }
+        let data = buf.split_to(len);
+        Ok(Some(SmppPdu {
+            command_id: word(&data[4..8]),
+            status: word(&data[8..12]),
+            sequence: word(&data[12..16]),
+            body: data[16..].to_vec()
+        }))
+    }
+}
+impl Encoder for SmppCodec {
+    type Item = SmppPdu;
+    type Error = Error;
+
+    fn encode(&mut self, pdu: SmppPdu, buf: &mut BytesMut) -> Result<()> {
+        buf.reserve(16 + pdu.body.len());
+        buf.put_u32_be((16 + pdu.body.len()) as u32);
+        buf.put_u32_be(pdu.command_id);
+        buf.put_u32_be(pdu.status);
+        buf.put_u32_be(pdu.sequence);
+        buf.put_slice(&pdu.body);
+        Ok(())
+    }
+}
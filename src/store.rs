@@ -11,7 +11,7 @@ use serde_json;
 use whatsappweb::session::PersistentSession;
 use whatsappweb::Jid;
 use crate::util::{self, Result};
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, Utc};
 use regex::Regex;
 use crate::models::*;
 
@@ -53,24 +53,78 @@ impl Store {
         }
         Ok(ret)
     }
-    pub fn store_sms_message(&mut self, addr: &PduAddress, pdu: &[u8], csms_data: Option<i32>) -> Result<Message> {
-        use crate::schema::messages;
+    pub fn store_sms_message(&mut self, addr: &PduAddress, pdu: &[u8], csms_data: Option<i32>, modem: &str) -> Result<Message> {
+        use crate::schema::{messages, recipients};
 
         let num = util::normalize_address(addr);
         let nm = NewMessage {
             phone_number: &num,
             pdu,
             csms_data,
-            source: Message::SOURCE_SMS
+            source: Message::SOURCE_SMS,
+            modem: Some(modem)
+        };
+        let conn = self.inner.get()?;
+
+        let res = ::diesel::insert_into(messages::table)
+            .values(&nm)
+            .get_result(&*conn)?;
+        ::diesel::update(recipients::table)
+            .filter(recipients::phone_number.eq(&num))
+            .set(recipients::last_modem.eq(modem))
+            .execute(&*conn)?;
+        Ok(res)
+    }
+    pub fn store_outgoing_sms(&mut self, addr: &PduAddress, text: &str, modem: &str) -> Result<OutgoingSms> {
+        use crate::schema::sms_outbox;
+
+        let num = util::normalize_address(addr);
+        let nos = NewOutgoingSms {
+            phone_number: &num,
+            text,
+            modem
+        };
+        let conn = self.inner.get()?;
+
+        let res = ::diesel::insert_into(sms_outbox::table)
+            .values(&nos)
+            .get_result(&*conn)?;
+        Ok(res)
+    }
+    /// Store an SMS message that we only have the text of (i.e. one from an
+    /// HTTP gateway, instead of a modem).
+    pub fn store_plain_sms_message(&mut self, addr: &PduAddress, text: &str, modem: &str) -> Result<Message> {
+        self.store_plain_message(addr, text, modem, Message::SOURCE_SMS)
+    }
+    /// Store a notice about `addr` (see `Message::SOURCE_NOTICE`) that came from `modem`.
+    pub fn store_notice_message(&mut self, addr: &PduAddress, text: &str, modem: &str) -> Result<Message> {
+        self.store_plain_message(addr, text, modem, Message::SOURCE_NOTICE)
+    }
+    fn store_plain_message(&mut self, addr: &PduAddress, text: &str, modem: &str, source: i32) -> Result<Message> {
+        use crate::schema::{messages, recipients};
+
+        let num = util::normalize_address(addr);
+        let nm = NewPlainMessage {
+            phone_number: &num,
+            text,
+            group_target: None,
+            source,
+            ts: Utc::now().naive_utc(),
+            modem: Some(modem),
+            wa_msgid: None
         };
         let conn = self.inner.get()?;
 
         let res = ::diesel::insert_into(messages::table)
             .values(&nm)
             .get_result(&*conn)?;
+        ::diesel::update(recipients::table)
+            .filter(recipients::phone_number.eq(&num))
+            .set(recipients::last_modem.eq(modem))
+            .execute(&*conn)?;
         Ok(res)
     }
-    pub fn store_wa_message(&mut self, addr: &PduAddress, text: &str, group_target: Option<i32>, ts: NaiveDateTime) -> Result<Message> {
+    pub fn store_wa_message(&mut self, addr: &PduAddress, text: &str, group_target: Option<i32>, ts: NaiveDateTime, wa_msgid: Option<&str>) -> Result<Message> {
         use crate::schema::messages;
 
         let num = util::normalize_address(addr);
@@ -79,7 +133,9 @@ impl Store {
             text,
             group_target,
             source: Message::SOURCE_WA,
-            ts
+            ts,
+            modem: None,
+            wa_msgid
         };
         let conn = self.inner.get()?;
 
@@ -121,6 +177,16 @@ impl Store {
             .get_result(&*conn)?;
         Ok(res)
     }
+    pub fn update_group_avatar(&mut self, j: &Jid, url: Option<&str>) -> Result<()> {
+        use crate::schema::groups::dsl::*;
+        let j = j.to_string();
+        let conn = self.inner.get()?;
+
+        ::diesel::update(groups.filter(jid.eq(&j)))
+            .set(avatar_url.eq(url))
+            .execute(&*conn)?;
+        Ok(())
+    }
     pub fn update_group(&mut self, j: &Jid, parts: Vec<i32>, adms: Vec<i32>, tpc: &str) -> Result<Group> {
         use crate::schema::groups::dsl::*;
         let j = j.to_string();
@@ -167,7 +233,43 @@ impl Store {
             .execute(&*conn)?;
         Ok(())
     }
+    pub fn store_outgoing_wa_message(&mut self, j: &Jid, txt: &str, id: &str, reply_to: Option<&str>) -> Result<OutgoingWaMessage> {
+        use crate::schema::wa_outbox;
+
+        let jid = j.to_string();
+        let nowm = NewOutgoingWaMessage {
+            jid: &jid,
+            text: txt,
+            mid: id,
+            reply_to
+        };
+        let conn = self.inner.get()?;
+
+        let res = ::diesel::insert_into(wa_outbox::table)
+            .values(&nowm)
+            .get_result(&*conn)?;
+        Ok(res)
+    }
+    pub fn get_outgoing_wa_messages(&mut self) -> Result<Vec<OutgoingWaMessage>> {
+        use crate::schema::wa_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = wa_outbox.order(id.asc())
+            .load(&*conn)?;
+        Ok(res)
+    }
+    pub fn delete_outgoing_wa_message(&mut self, oid: i32) -> Result<()> {
+        use crate::schema::wa_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        ::diesel::delete(wa_outbox.filter(id.eq(oid)))
+            .execute(&*conn)?;
+        Ok(())
+    }
     pub fn store_recipient(&mut self, addr: &PduAddress, nick: &str) -> Result<Recipient> {
+        self.store_recipient_with_nicksrc(addr, nick, Recipient::NICKSRC_AUTO)
+    }
+    pub fn store_recipient_with_nicksrc(&mut self, addr: &PduAddress, nick: &str, nicksrc: i32) -> Result<Recipient> {
         use crate::schema::recipients;
 
         let num = util::normalize_address(addr);
@@ -177,7 +279,8 @@ impl Store {
             whatsapp: false,
             avatar_url: None,
             notify: None,
-            nicksrc: Recipient::NICKSRC_AUTO
+            nicksrc,
+            replyable: util::describe_unreplyable(addr).is_none()
         };
         let conn = self.inner.get()?;
 
@@ -196,7 +299,8 @@ impl Store {
             whatsapp: true,
             avatar_url: None,
             notify: notify,
-            nicksrc
+            nicksrc,
+            replyable: true
         };
         let conn = self.inner.get()?;
 
@@ -227,6 +331,61 @@ impl Store {
             .execute(&*conn)?;
         Ok(())
     }
+    pub fn update_recipient_modem(&mut self, addr: &PduAddress, m: Option<&str>) -> Result<()> {
+        use crate::schema::recipients::dsl::*;
+        let conn = self.inner.get()?;
+        let num = util::normalize_address(addr);
+
+        ::diesel::update(recipients)
+            .filter(phone_number.eq(num))
+            .set(modem.eq(m))
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn update_recipient_avatar(&mut self, addr: &PduAddress, url: Option<&str>) -> Result<()> {
+        use crate::schema::recipients::dsl::*;
+        let conn = self.inner.get()?;
+        let num = util::normalize_address(addr);
+
+        ::diesel::update(recipients)
+            .filter(phone_number.eq(num))
+            .set(avatar_url.eq(url))
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn update_recipient_transliterate(&mut self, addr: &PduAddress, tl: bool) -> Result<()> {
+        use crate::schema::recipients::dsl::*;
+        let conn = self.inner.get()?;
+        let num = util::normalize_address(addr);
+
+        ::diesel::update(recipients)
+            .filter(phone_number.eq(num))
+            .set(transliterate.eq(tl))
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn update_recipient_replyable(&mut self, addr: &PduAddress, r: bool) -> Result<()> {
+        use crate::schema::recipients::dsl::*;
+        let conn = self.inner.get()?;
+        let num = util::normalize_address(addr);
+
+        ::diesel::update(recipients)
+            .filter(phone_number.eq(num))
+            .set(replyable.eq(r))
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn update_recipient_last_modem(&mut self, addr: &PduAddress, m: &str) -> Result<()> {
+        use crate::schema::recipients::dsl::*;
+        let conn = self.inner.get()?;
+        let num = util::normalize_address(addr);
+
+        ::diesel::update(recipients)
+            .filter(phone_number.eq(num))
+            .set(last_modem.eq(m))
+            .execute(&*conn)?;
+        Ok(())
+    }
     pub fn update_recipient_wa(&mut self, addr: &PduAddress, wa: bool) -> Result<()> {
         use crate::schema::recipients::dsl::*;
         let conn = self.inner.get()?;
@@ -292,6 +451,89 @@ impl Store {
             .load(&*conn)?;
         Ok(res)
     }
+    /// Get queued messages for the given modem (and, if `unassigned` is set, messages
+    /// that were queued before there was a choice of modem).
+    pub fn get_queued_outgoing_sms(&mut self, m: &str, unassigned: bool) -> Result<Vec<OutgoingSms>> {
+        use crate::schema::sms_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = if unassigned {
+            sms_outbox.filter(state.eq(OutgoingSms::STATE_QUEUED)
+                              .and(modem.eq(m).or(modem.is_null())))
+                .order(id.asc())
+                .load(&*conn)?
+        }
+        else {
+            sms_outbox.filter(state.eq(OutgoingSms::STATE_QUEUED)
+                              .and(modem.eq(m)))
+                .order(id.asc())
+                .load(&*conn)?
+        };
+        Ok(res)
+    }
+    pub fn get_outgoing_sms_by_id(&mut self, sid: i32) -> Result<OutgoingSms> {
+        use crate::schema::sms_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = sms_outbox.filter(id.eq(sid))
+            .first(&*conn)?;
+        Ok(res)
+    }
+    pub fn update_outgoing_sms_state(&mut self, sid: i32, st: i32) -> Result<()> {
+        use crate::schema::sms_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        ::diesel::update(sms_outbox.filter(id.eq(sid)))
+            .set(state.eq(st))
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn update_outgoing_sms_retry(&mut self, sid: i32, st: i32, att: i32, next: NaiveDateTime, err: &str) -> Result<()> {
+        use crate::schema::sms_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        ::diesel::update(sms_outbox.filter(id.eq(sid)))
+            .set((state.eq(st), attempts.eq(att), next_attempt.eq(next), last_error.eq(err)))
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn record_sms_usage(&mut self, addr: &PduAddress, modem: &str, segments: usize) -> Result<()> {
+        use crate::schema::sms_usage;
+
+        let num = util::normalize_address(addr);
+        let nu = NewSmsUsage {
+            phone_number: &num,
+            modem,
+            segments: segments as i32,
+            ts: Utc::now().naive_utc()
+        };
+        let conn = self.inner.get()?;
+
+        ::diesel::insert_into(sms_usage::table)
+            .values(&nu)
+            .execute(&*conn)?;
+        Ok(())
+    }
+    pub fn get_sms_usage_since(&mut self, m: &str, since: NaiveDateTime) -> Result<Vec<SmsUsage>> {
+        use crate::schema::sms_usage::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = sms_usage.filter(modem.eq(m))
+            .filter(ts.ge(since))
+            .load(&*conn)?;
+        Ok(res)
+    }
+    /// Put messages that were in the middle of being sent (e.g. when we were
+    /// last shut down) back into the queue.
+    pub fn requeue_sending_sms(&mut self) -> Result<usize> {
+        use crate::schema::sms_outbox::dsl::*;
+        let conn = self.inner.get()?;
+
+        let rows_affected = ::diesel::update(sms_outbox.filter(state.eq(OutgoingSms::STATE_SENDING)))
+            .set(state.eq(OutgoingSms::STATE_QUEUED))
+            .execute(&*conn)?;
+        Ok(rows_affected)
+    }
     pub fn get_group_by_id(&mut self, gid: i32) -> Result<Group> {
         use crate::schema::groups::dsl::*;
         let conn = self.inner.get()?;
@@ -356,6 +598,44 @@ impl Store {
             .load(&*conn)?;
         Ok(res)
     }
+    pub fn get_message_by_id_opt(&mut self, mid: i32) -> Result<Option<Message>> {
+        use crate::schema::messages::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = messages.filter(id.eq(mid))
+            .first(&*conn)
+            .optional()?;
+        Ok(res)
+    }
+    /// Get all the stored messages that have PDUs (i.e. SMS messages from modems
+    /// that haven't been delivered to IRC yet).
+    pub fn get_all_pdu_messages(&mut self) -> Result<Vec<Message>> {
+        use crate::schema::messages::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = messages.filter(pdu.is_not_null())
+            .order((ts.asc(), id.asc()))
+            .load(&*conn)?;
+        Ok(res)
+    }
+    pub fn get_all_concatenated_messages(&mut self) -> Result<Vec<Message>> {
+        use crate::schema::messages::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = messages.filter(csms_data.is_not_null())
+            .order((ts.asc(), id.asc()))
+            .load(&*conn)?;
+        Ok(res)
+    }
+    pub fn get_concatenated_older_than(&mut self, cutoff: NaiveDateTime) -> Result<Vec<Message>> {
+        use crate::schema::messages::dsl::*;
+        let conn = self.inner.get()?;
+
+        let res = messages.filter(csms_data.is_not_null()
+                                  .and(ts.lt(cutoff)))
+            .load(&*conn)?;
+        Ok(res)
+    }
     pub fn delete_group_with_id(&mut self, i: i32) -> Result<()> {
         use crate::schema::groups::dsl::*;
         let conn = self.inner.get()?;
@@ -42,6 +42,29 @@ pub fn address_to_jid(addr: &PduAddress) -> Result<Jid> {
     let ret = Jid::from_phonenumber(addr.to_string())?;
     Ok(ret)
 }
+/// Type of number for alphanumeric sender IDs (3GPP TS 23.040 § 9.1.2.5).
+static TON_ALPHANUMERIC: u8 = 0b101;
+static TON_INTERNATIONAL: u8 = 0b001;
+/// Numbers (that aren't international) with this many digits or fewer are short codes.
+static SHORT_CODE_MAX_DIGITS: usize = 6;
+
+/// If messages can't be sent to `addr` (because it's an alphanumeric sender ID
+/// or a short code), say what it is.
+///
+/// The `replyable` migration backfills existing recipients with the same rules
+/// in SQL, so keep the two in step.
+pub fn describe_unreplyable(addr: &PduAddress) -> Option<&'static str> {
+    let toa: u8 = addr.type_addr.into();
+    let ton = (toa >> 4) & 0b111;
+    if ton == TON_ALPHANUMERIC {
+        return Some("an alphanumeric sender ID");
+    }
+    let digits = addr.to_string().chars().filter(|c| c.is_ascii_digit()).count();
+    if ton != TON_INTERNATIONAL && digits <= SHORT_CODE_MAX_DIGITS {
+        return Some("a short code");
+    }
+    None
+}
 pub fn normalize_address(addr: &PduAddress) -> String {
     let ton: u8 = addr.type_addr.into();
     let mut ret = format!("{:02X}", ton);
@@ -107,3 +130,43 @@ pub fn make_nick_for_address(addr: &PduAddress) -> String {
     let inp = addr.to_string();
     string_to_irc_nick(&inp)
 }
+/// Compare two secrets in time that doesn't depend on where they differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter())
+        .fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(toa: u8, digits: &[u8]) -> PduAddress {
+        PduAddress {
+            type_addr: AddressType::try_from(toa).unwrap(),
+            number: PhoneNumber(digits.to_vec())
+        }
+    }
+
+    #[test]
+    fn alphanumeric_senders_are_unreplyable() {
+        assert_eq!(describe_unreplyable(&addr(0xD0, &[4, 9, 10, 7, 15, 1, 9])), Some("an alphanumeric sender ID"));
+        assert_eq!(describe_unreplyable(&addr(0x50, &[4, 9, 10, 7, 15, 1, 9])), Some("an alphanumeric sender ID"));
+    }
+
+    #[test]
+    fn international_numbers_are_replyable() {
+        assert_eq!(describe_unreplyable(&"+447700900123".parse().unwrap()), None);
+        // Even short ones: some countries have very short numbers.
+        assert_eq!(describe_unreplyable(&addr(0x91, &[2, 9, 9, 1, 2, 3])), None);
+    }
+
+    #[test]
+    fn short_national_numbers_are_short_codes() {
+        assert_eq!(describe_unreplyable(&addr(0x81, &[8, 4, 0, 0, 1])), Some("a short code"));
+        assert_eq!(describe_unreplyable(&addr(0x81, &[1, 2, 3, 4, 5, 6])), Some("a short code"));
+        assert_eq!(describe_unreplyable(&addr(0x81, &[0, 7, 7, 0, 0, 9, 0, 0, 1, 2, 3])), None);
+    }
+}
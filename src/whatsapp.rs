@@ -5,7 +5,7 @@ use whatsappweb::Contact as WaContact;
 use whatsappweb::Chat as WaChat;
 use whatsappweb::GroupMetadata;
 use whatsappweb::message::ChatMessage as WaMessage;
-use whatsappweb::message::{ChatMessageContent, Peer, MessageId};
+use whatsappweb::message::{ChatMessageContent, QuotedChatMessage, Peer, MessageId};
 use whatsappweb::session::PersistentSession as WaPersistentSession;
 use whatsappweb::event::WaEvent;
 use whatsappweb::req::WaRequest;
@@ -13,25 +13,43 @@ use whatsappweb::errors::WaError;
 use whatsappweb::errors::DisconnectReason as WaDisconnectReason;
 use huawei_modem::pdu::PduAddress;
 use futures::sync::mpsc::{UnboundedSender, UnboundedReceiver};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use image::Luma;
 use qrcode::QrCode;
-use futures::{Future, Async, Poll, Stream, Sink};
+use futures::{Future, Async, AsyncSink, Poll, Stream, Sink};
 use failure::Error;
 use chrono::prelude::*;
 use std::time::{Instant, Duration};
+use tokio_timer::Interval;
 use std::collections::VecDeque;
 
-use crate::comm::{WhatsappCommand, ContactFactoryCommand, ControlBotCommand, InitParameters};
+use crate::comm::{WhatsappCommand, ContactFactoryCommand, ControlBotCommand, InitParameters, GroupChange};
 use crate::util::{self, Result};
+use crate::formatting;
 use crate::models::Recipient;
-use crate::whatsapp_media::MediaResult;
+use crate::whatsapp_media::{self, MediaResult, AvatarInfo};
 use crate::store::Store;
 use crate::whatsapp_conn::{WebConnectionWrapper, WebConnectionWrapperConfig};
 use crate::whatsapp_msg::{IncomingMessage, WaMessageProcessor};
 use crate::whatsapp_ack::WaAckTracker;
+use crate::whatsapp_upload::{MediaSend, PreparedMedia, UploadStep};
+use crate::whatsapp_history::{WaHistory, ReplySelector};
 
+/// How long to wait for WA to answer a group request before giving up on it.
+static GROUP_REQUEST_TIMEOUT_SECS: u64 = 120;
+/// How often to look for group requests that have timed out.
+static GROUP_REQUEST_CHECK_SECS: u64 = 10;
+
+/// A request about a WA group that's waiting for WA's answer.
+enum PendingGroupRequest {
+    /// A change made on IRC, by the nick `origin`.
+    Change { chan: String, origin: String },
+    /// Creating a group, to bridge to `chan`.
+    Create { chan: String, subject: String },
+    /// Leaving the group with this ID, bridged to `chan`.
+    Leave { chan: String, id: i32, jid: Jid }
+}
 pub struct WhatsappManager {
     conn: WebConnectionWrapper,
     rx: UnboundedReceiver<WhatsappCommand>,
@@ -52,7 +70,24 @@ pub struct WhatsappManager {
     track_presence: bool,
     our_jid: Option<Jid>,
     prev_jid: Option<Jid>,
-    outbox: VecDeque<WaRequest>
+    outbox: VecDeque<WaRequest>,
+    /// Whether there might be messages in the persistent outbox (`wa_outbox`).
+    outbox_pending: bool,
+    /// Messages handed to the connection that haven't been flushed yet, with
+    /// the `wa_outbox` row they came from (if any).
+    unflushed: Vec<(String, Option<i32>)>,
+    /// Media waiting for an upload URL, by encrypted SHA256.
+    uploads: HashMap<Vec<u8>, PreparedMedia>,
+    history: WaHistory,
+    /// Groups we joined with an invite link, which need a channel making for them.
+    joined_groups: HashSet<Jid>,
+    /// Group requests WA hasn't answered yet, by request tag, with when
+    /// they were made.
+    pending_requests: HashMap<String, (PendingGroupRequest, Instant)>,
+    /// Timer for giving up on group requests WA never answers.
+    request_timer: Interval,
+    /// JIDs we've asked for the profile pictures of since starting.
+    avatars_requested: HashSet<Jid>
 }
 impl Future for WhatsappManager {
     type Item = ();
@@ -76,6 +111,7 @@ impl Future for WhatsappManager {
                 self.handle_int_rx(com)?;
                 cont = true;
             }
+            self.send_queued()?;
             if self.outbox.len() > 0 {
                 if self.conn.is_connected() {
                     while let Some(req) = self.outbox.pop_front() {
@@ -86,14 +122,21 @@ impl Future for WhatsappManager {
                     }
                 }
                 else {
-                    warn!("Disconnected, so discarding messages in outbox");
+                    // Messages are kept in `wa_outbox`, so these are only
+                    // things like presence subscriptions and read receipts.
+                    warn!("Disconnected, so discarding requests in outbox");
                     self.outbox.clear();
                 }
             }
             if self.conn.is_connected() {
-                self.conn.poll_complete()?;
+                if let Async::Ready(()) = self.conn.poll_complete()? {
+                    self.on_flushed()?;
+                }
             }
         }
+        while let Async::Ready(_) = self.request_timer.poll()? {
+            self.expire_group_requests();
+        }
         self.ackp.poll()?;
         Ok(Async::NotReady)
     }
@@ -115,6 +158,7 @@ impl WhatsappManager {
         let autoupdate_nicks = p.cfg.whatsapp.autoupdate_nicks;
         let backoff_time_ms = p.cfg.whatsapp.backoff_time_ms.unwrap_or(10000);
         let track_presence = p.cfg.whatsapp.track_presence;
+        let quote_history = p.cfg.whatsapp.quote_history.unwrap_or(20);
 
         wa_tx.unbounded_send(WhatsappCommand::LogonIfSaved)
             .unwrap();
@@ -135,6 +179,14 @@ impl WhatsappManager {
             prev_jid: None,
             presence_requests: HashMap::new(),
             outbox: VecDeque::new(),
+            outbox_pending: true,
+            unflushed: vec![],
+            uploads: HashMap::new(),
+            history: WaHistory::new(quote_history),
+            joined_groups: HashSet::new(),
+            pending_requests: HashMap::new(),
+            request_timer: Interval::new(Instant::now(), Duration::from_secs(GROUP_REQUEST_CHECK_SECS)),
+            avatars_requested: HashSet::new(),
             backlog_start,
             rx, cf_tx, cb_tx, qr_path, store, msgproc, autocreate,
             mark_read, autoupdate_nicks, track_presence, ackp
@@ -146,16 +198,24 @@ impl WhatsappManager {
         match c {
             StartRegistration => self.start_registration()?,
             LogonIfSaved => self.logon_if_saved()?,
-            SendGroupMessage(to, cont) => self.send_group_message(to, cont)?,
-            SendDirectMessage(to, cont) => self.send_direct_message(to, cont)?,
+            SendGroupMessage(to, cont, re) => self.send_group_message(to, cont, re)?,
+            SendDirectMessage(to, cont, re) => self.send_direct_message(to, cont, re)?,
             GroupAssociate(jid, to) => self.group_associate_handler(jid, to)?,
             GroupList => self.group_list()?,
             GroupUpdateAll => self.group_update_all()?,
             GroupRemove(grp) => self.group_remove(grp)?,
+            GroupCreate(chan, subj, nicks) => self.group_create(chan, subj, nicks)?,
+            GroupLeave(chan) => self.group_leave(chan)?,
+            GroupInviteLink(chan, revoke) => self.group_invite_link(chan, revoke)?,
+            GroupJoin(code) => self.group_join(code)?,
             MediaFinished(r) => self.media_finished(r)?,
+            MediaUpload(step) => self.media_upload(step)?,
             PrintAcks => self.print_acks()?,
             MakeContact(a) => self.make_contact(a)?,
-            SubscribePresence(a) => self.subscribe_presence(a)?
+            SubscribePresence(a) => self.subscribe_presence(a)?,
+            GroupChange(chan, origin, change) => self.group_change(chan, origin, change)?,
+            AvatarFinished(jid, r) => self.avatar_finished(jid, r)?,
+            GetAvatar(nick) => self.get_avatar(nick)?
         }
         Ok(())
     }
@@ -200,7 +260,7 @@ impl WhatsappManager {
         match r.result {
             Ok(ret) => {
                 debug!("Media download/decryption job for {} / mid {:?} complete.", r.from.to_string(), r.mi);
-                self.store_message(&r.from, &ret, r.group, r.ts)?;
+                self.store_message_with_id(&r.from, &ret, r.group, r.ts, Some(&r.mi.0))?;
             },
             Err(e) => {
                 // FIXME: We could possibly retry the download somehow.
@@ -220,6 +280,211 @@ impl WhatsappManager {
         }
         Ok(())
     }
+    /// Send a message from IRC, which might be a `!send` command, or a reply
+    /// (quoting `reply_to`, or using the reply syntax).
+    fn send_from_irc(&mut self, content: String, jid: Jid, reply_to: Option<String>) -> Result<()> {
+        if content.starts_with("!send ") {
+            self.start_media_send(&content["!send ".len()..], jid);
+            return Ok(());
+        }
+        let (text, reply_to) = match ReplySelector::parse(&content) {
+            Some((sel, rest)) if reply_to.is_none() => {
+                let quoted = self.history.find(&jid, &sel, self.our_jid.as_ref());
+                if quoted.is_none() {
+                    let err = format!("Couldn't find the message to reply to in {}, so it was sent without quoting anything.", jid);
+                    self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err)).unwrap();
+                }
+                (rest.to_owned(), quoted.map(|q| q.id.0))
+            },
+            _ => (content.clone(), reply_to)
+        };
+        let text = if jid.is_group {
+            self.msgproc.process_irc_mentions(&text, &jid)?
+        }
+        else {
+            text
+        };
+        let text = formatting::irc_to_wa(&text);
+        self.queue_message(text, jid, reply_to)
+    }
+    /// Start sending media, for `!send <url-or-path> [caption]`.
+    fn start_media_send(&mut self, args: &str, jid: Jid) {
+        let mut parts = args.trim().splitn(2, ' ');
+        let source = parts.next().unwrap_or("").to_owned();
+        let caption = parts.next()
+            .map(|s| s.trim().to_owned())
+            .filter(|s| s.len() > 0);
+        if source.len() == 0 {
+            let err = "Usage: !send <url-or-path> [caption]";
+            self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err.into())).unwrap();
+            return;
+        }
+        let mid = MessageId::generate();
+        debug!("Sending media {} to {}: message ID {}", source, jid, mid.0);
+        self.ackp.register_upload(jid.clone(), format!("!send {}", source), mid.0.clone());
+        let ms = MediaSend { mid, to: jid, source, caption };
+        ms.start(self.msgproc.wa_tx.clone());
+    }
+    fn media_upload(&mut self, step: UploadStep) -> Result<()> {
+        match step {
+            UploadStep::Prepared(_, Ok(p)) => {
+                if self.connected && self.conn.is_connected() {
+                    self.request_upload_url(&p);
+                }
+                // Otherwise, on_established() does it.
+                self.uploads.insert(p.enc_sha256.clone(), p);
+            },
+            UploadStep::Prepared(mid, Err(e)) => {
+                warn!("Preparing media for mid {:?} failed: {}", mid, e);
+                self.ackp.on_upload_failed(&mid.0, &e.to_string());
+            },
+            UploadStep::Uploaded(p, Ok(url)) => self.send_media(p, url)?,
+            UploadStep::Uploaded(p, Err(e)) => {
+                warn!("Uploading media for mid {:?} failed: {}", p.send.mid, e);
+                self.ackp.on_upload_failed(&p.send.mid.0, &e.to_string());
+            }
+        }
+        Ok(())
+    }
+    fn request_upload_url(&mut self, p: &PreparedMedia) {
+        debug!("Requesting upload URL for {} ({})", p.filename, p.mime);
+        self.outbox.push_back(WaRequest::RequestFileUpload {
+            hash: p.enc_sha256.clone(),
+            media_type: p.ty
+        });
+    }
+    fn on_upload_url(&mut self, hash: Vec<u8>, url: ::std::result::Result<String, WaError>) {
+        let p = match self.uploads.remove(&hash) {
+            Some(p) => p,
+            None => {
+                debug!("Got an upload URL for unknown media");
+                return;
+            }
+        };
+        match url {
+            Ok(url) => p.start_upload(url, self.msgproc.wa_tx.clone()),
+            Err(e) => {
+                warn!("Requesting upload URL for mid {:?} failed: {}", p.send.mid, e);
+                self.ackp.on_upload_failed(&p.send.mid.0, &format!("couldn't get an upload URL: {}", e));
+            }
+        }
+    }
+    fn send_media(&mut self, p: PreparedMedia, url: String) -> Result<()> {
+        let (to, mid) = (p.send.to.clone(), p.send.mid.clone());
+        let (content, caption) = p.into_content(url);
+        if !self.send_message(content, to.clone(), mid.clone(), None, vec![], None)? {
+            self.ackp.on_upload_failed(&mid.0, "disconnected before it could be sent; try again");
+            return Ok(());
+        }
+        if let Some(caption) = caption {
+            self.queue_message(caption, to, None)?;
+        }
+        Ok(())
+    }
+    /// Ask WA for the profile picture of `jid`; unless `force` is set, this
+    /// is only done once (since it's done for everyone on logon).
+    fn request_avatar(&mut self, jid: Jid, force: bool) {
+        if !self.avatars_requested.insert(jid.clone()) && !force {
+            return;
+        }
+        debug!("Requesting profile picture for {}", jid);
+        self.outbox.push_back(WaRequest::GetProfilePicture(jid));
+    }
+    /// Request profile pictures for the contacts and groups that don't have one yet.
+    fn request_missing_avatars(&mut self) -> Result<()> {
+        for recip in self.store.get_all_recipients()? {
+            if !recip.whatsapp || recip.avatar_url.is_some() {
+                continue;
+            }
+            if let Ok(jid) = util::address_to_jid(&recip.get_addr()?) {
+                self.request_avatar(jid, false);
+            }
+        }
+        for grp in self.store.get_all_groups()? {
+            if grp.avatar_url.is_some() {
+                continue;
+            }
+            if let Ok(jid) = grp.jid.parse() {
+                self.request_avatar(jid, false);
+            }
+        }
+        Ok(())
+    }
+    fn on_profile_picture(&mut self, jid: Jid, url: Option<String>) -> Result<()> {
+        match url {
+            Some(url) => {
+                let ai = AvatarInfo {
+                    jid, url,
+                    path: self.msgproc.media_path.clone(),
+                    dl_path: self.msgproc.dl_path.clone(),
+                    tx: self.msgproc.wa_tx.clone()
+                };
+                ai.start();
+            },
+            None => {
+                debug!("{} has no profile picture", jid);
+                self.set_avatar(&jid, None)?;
+            }
+        }
+        Ok(())
+    }
+    fn avatar_finished(&mut self, jid: Jid, r: Result<String>) -> Result<()> {
+        match r {
+            Ok(url) => {
+                debug!("Avatar for {} downloaded to {}", jid, url);
+                self.set_avatar(&jid, Some(&url))?;
+            },
+            Err(e) => {
+                warn!("Failed to download avatar for {}: {}", jid, e);
+            }
+        }
+        Ok(())
+    }
+    fn set_avatar(&mut self, jid: &Jid, url: Option<&str>) -> Result<()> {
+        if url.is_none() {
+            whatsapp_media::remove_avatar(&self.msgproc.media_path, jid);
+        }
+        if jid.is_group {
+            let old = match self.store.get_group_by_jid_opt(jid)? {
+                Some(grp) => grp.avatar_url,
+                None => return Ok(())
+            };
+            if old.is_none() && url.is_none() {
+                return Ok(());
+            }
+            self.store.update_group_avatar(jid, url)?;
+        }
+        else if let Some(addr) = util::jid_to_address(jid) {
+            let old = self.get_wa_recipient(jid)?.avatar_url;
+            if old.is_none() && url.is_none() {
+                return Ok(());
+            }
+            self.store.update_recipient_avatar(&addr, url)?;
+        }
+        self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessAvatar(jid.clone()))
+            .unwrap();
+        Ok(())
+    }
+    fn get_avatar(&mut self, nick: String) -> Result<()> {
+        let recip = match self.store.get_recipient_by_nick_opt(&nick)? {
+            Some(r) => r,
+            None => {
+                self.cb_respond(format!("no ghost with nick {} found!", nick));
+                return Ok(());
+            }
+        };
+        match recip.avatar_url {
+            Some(ref url) => self.cb_respond(format!("Avatar for {}: {}", nick, url)),
+            None => self.cb_respond(format!("{} doesn't have an avatar (yet?)", nick))
+        }
+        if recip.whatsapp && self.connected {
+            if let Ok(jid) = util::address_to_jid(&recip.get_addr()?) {
+                self.cb_respond("Refreshing it from WhatsApp.");
+                self.request_avatar(jid, true);
+            }
+        }
+        Ok(())
+    }
     fn logon_if_saved(&mut self) -> Result<()> {
         if let Some(wap) = self.store.get_wa_persistence_opt()? {
             info!("Logging on to WhatsApp Web using stored persistence data");
@@ -250,22 +515,89 @@ impl WhatsappManager {
         self.cb_respond("NB: The code is only valid for a few seconds, so scan quickly!");
         Ok(())
     }
-    fn queue_message(&mut self, content: ChatMessageContent, jid: Jid) {
+    /// Put a message in the persistent outbox, to be sent as soon as we're connected.
+    fn queue_message(&mut self, text: String, jid: Jid, reply_to: Option<String>) -> Result<()> {
         let mid = MessageId::generate();
         debug!("Queued send to {}: message ID {}", jid, mid.0);
-        self.ackp.register_send(jid, content, mid.0, true);
+        self.store.store_outgoing_wa_message(&jid, &text, &mid.0, reply_to.as_ref().map(|x| x as &str))?;
+        self.ackp.register_send(jid, ChatMessageContent::Text(text), mid.0, true);
+        self.outbox_pending = true;
         if self.conn.is_disabled() {
             let err = "Warning: WhatsApp Web is currently not set up, but you've tried to send something.";
             self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err.into())).unwrap();
         }
+        Ok(())
+    }
+    /// Send everything in the persistent outbox, in order, if we're connected.
+    fn send_queued(&mut self) -> Result<()> {
+        if !self.outbox_pending || !self.connected || !self.conn.is_connected() {
+            return Ok(());
+        }
+        for owm in self.store.get_outgoing_wa_messages()? {
+            // The message ID gets stored when the message is sent, so if it's
+            // there already, we sent it before getting the chance to remove it.
+            if self.store.is_wa_msgid_stored(&owm.mid)? {
+                debug!("Not resending message ID {}, which was already sent", owm.mid);
+                self.store.delete_outgoing_wa_message(owm.id)?;
+                continue;
+            }
+            if self.unflushed.iter().any(|&(_, row)| row == Some(owm.id)) {
+                continue;
+            }
+            let jid = owm.jid.parse().expect("bad jid in DB");
+            // If we've forgotten the quoted message (e.g. we restarted), the
+            // reply gets sent without the quote.
+            let quoted = owm.reply_to
+                .and_then(|id| self.history.find(&jid, &ReplySelector::Id(id), None));
+            let mentions = if jid.is_group {
+                self.msgproc.mentioned_jids(&owm.text, &jid)?
+            }
+            else {
+                vec![]
+            };
+            if !self.send_message(ChatMessageContent::Text(owm.text), jid, MessageId(owm.mid), quoted, mentions, Some(owm.id))? {
+                return Ok(());
+            }
+        }
+        self.outbox_pending = false;
+        Ok(())
+    }
+    /// Mark everything handed to the connection so far as sent, now that it's
+    /// been flushed.
+    fn on_flushed(&mut self) -> Result<()> {
+        for (mid, row) in ::std::mem::replace(&mut self.unflushed, vec![]) {
+            self.store.store_wa_msgid(mid)?;
+            if let Some(row) = row {
+                self.store.delete_outgoing_wa_message(row)?;
+            }
+        }
+        Ok(())
     }
-    fn send_message(&mut self, content: ChatMessageContent, jid: Jid) -> Result<()> {
+    /// Hand a message to the connection; returns whether it was accepted.
+    ///
+    /// The message only counts as sent (and `row` is only removed from the
+    /// outbox) once the connection has been flushed; see `on_flushed()`.
+    fn send_message(&mut self, content: ChatMessageContent, jid: Jid, mid: MessageId, quoted: Option<QuotedChatMessage>, mentions: Vec<Jid>, row: Option<i32>) -> Result<bool> {
         let (c, j) = (content.clone(), jid.clone());
-        let m = WaMessage::new(jid, content);
-        debug!("Send to {}: message ID {}", j, m.id.0);
-        self.store.store_wa_msgid(m.id.0.clone())?;
-        self.ackp.register_send(j.clone(), c, m.id.0.clone(), false);
-        self.outbox.push_back(WaRequest::SendMessage(m));
+        let mut m = WaMessage::new(jid, content);
+        m.id = mid;
+        m.quoted = quoted;
+        m.mentions = mentions;
+        let id = m.id.0.clone();
+        debug!("Send to {}: message ID {}", j, id);
+        match self.conn.start_send(WaRequest::SendMessage(m)) {
+            Ok(AsyncSink::Ready) => {},
+            Ok(AsyncSink::NotReady(_)) => return Ok(false),
+            Err(e) => {
+                self.on_wa_error(e);
+                return Ok(false);
+            }
+        }
+        self.unflushed.push((id.clone(), row));
+        if let Some(us) = self.our_jid.clone() {
+            self.history.record(j.clone(), MessageId(id.clone()), us, c.clone());
+        }
+        self.ackp.register_send(j.clone(), c, id, false);
         if !j.is_group && self.track_presence {
             let mut update = true;
             let now = Instant::now();
@@ -282,20 +614,14 @@ impl WhatsappManager {
                 self.outbox.push_back(WaRequest::SubscribePresence(j));
             }
         }
-        Ok(())
+        Ok(true)
     }
-    fn send_direct_message(&mut self, addr: PduAddress, content: String) -> Result<()> {
+    fn send_direct_message(&mut self, addr: PduAddress, content: String, reply_to: Option<String>) -> Result<()> {
         debug!("Sending direct message to {}...", addr);
         trace!("Message contents: {}", content);
         match Jid::from_phonenumber(format!("{}", addr)) {
             Ok(jid) => {
-                let content = ChatMessageContent::Text(content);
-                if !self.connected || !self.conn.is_connected() {
-                    self.queue_message(content, jid);
-                }
-                else {
-                    self.send_message(content, jid)?;
-                }
+                self.send_from_irc(content, jid, reply_to)?;
             },
             Err(e) => {
                 warn!("Couldn't send WA message to {}: {}", addr, e);
@@ -305,18 +631,12 @@ impl WhatsappManager {
         }
         Ok(())
     }
-    fn send_group_message(&mut self, chan: String, content: String) -> Result<()> {
+    fn send_group_message(&mut self, chan: String, content: String, reply_to: Option<String>) -> Result<()> {
         debug!("Sending message to group with chan {}...", chan);
         trace!("Message contents: {}", content);
         if let Some(grp) = self.store.get_group_by_chan_opt(&chan)? {
             let jid = grp.jid.parse().expect("bad jid in DB");
-            let content = ChatMessageContent::Text(content);
-            if !self.connected || !self.conn.is_connected() {
-                self.queue_message(content, jid);
-            }
-            else {
-                self.send_message(content, jid)?;
-            }
+            self.send_from_irc(content, jid, reply_to)?;
         }
         else {
             error!("Tried to send WA message to nonexistent group {}", chan);
@@ -371,6 +691,10 @@ impl WhatsappManager {
                 }
             }
         };
+        if !is_ours && group.as_ref().map(|g| g.id != "status").unwrap_or(true) {
+            let chat = group.clone().unwrap_or(from.clone());
+            self.history.record(chat, id.clone(), from.clone(), content.clone());
+        }
         let group = match group {
             Some(gid) => {
                 if gid.id == "status" {
@@ -408,7 +732,7 @@ impl WhatsappManager {
         let (msgs, is_media) = self.msgproc.process_wa_incoming(inc)?;
         let num_msgs = msgs.len();
         for msg in msgs {
-            self.store_message(&msg.from, &msg.text, msg.group, msg.ts)?;
+            self.store_message_with_id(&msg.from, &msg.text, msg.group, msg.ts, msg.id.as_ref().map(|x| x as &str))?;
         }
         // The > 0 check is here to avoid us storing a message ID when we actually never
         // got the message, because it was sent as a missing-ciphertext stub earlier or
@@ -435,9 +759,12 @@ impl WhatsappManager {
         Ok(())
     }
     fn store_message(&mut self, from: &Jid, text: &str, group: Option<i32>, ts: NaiveDateTime) -> Result<()> {
+        self.store_message_with_id(from, text, group, ts, None)
+    }
+    fn store_message_with_id(&mut self, from: &Jid, text: &str, group: Option<i32>, ts: NaiveDateTime, mid: Option<&str>) -> Result<()> {
         if let Some(addr) = util::jid_to_address(from) {
             let _ = self.get_wa_recipient(from)?;
-            self.store.store_wa_message(&addr, &text, group, ts)?;
+            self.store.store_wa_message(&addr, &text, group, ts, mid)?;
             self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessMessages)
                 .unwrap();
         }
@@ -519,7 +846,7 @@ impl WhatsappManager {
             },
             None => {
                 warn!("Got metadata for unbridged group '{}' (jid {})", grp.subject, grp.id);
-                if self.autocreate.is_some() {
+                if self.joined_groups.remove(&grp.id) || self.autocreate.is_some() {
                     match self.group_autocreate(grp.clone()) {
                         Ok((id, chan)) => {
                             self.cb_respond(format!("Automatically bridged new group '{}' to channel {} (id {})", grp.subject, chan, id));
@@ -597,7 +924,9 @@ impl WhatsappManager {
     }
     fn group_autocreate(&mut self, meta: GroupMetadata) -> Result<(i32, String)> {
         let irc_subject = util::string_to_irc_chan(&meta.subject);
-        let chan = format!("{}-{}", self.autocreate.as_ref().unwrap(), irc_subject);
+        // Groups joined with GROUP JOIN get autocreated even if autocreation is off.
+        let prefix = self.autocreate.as_ref().map(|x| x as &str).unwrap_or("#wa");
+        let chan = format!("{}-{}", prefix, irc_subject);
         let id = self.group_associate(meta.id.clone(), chan.clone(), false)?;
         self.on_got_group_metadata(meta)?;
         Ok((id, chan))
@@ -630,6 +959,155 @@ impl WhatsappManager {
         }
         Ok(())
     }
+    /// Get the JID of the group bridged to `chan`, complaining if there isn't one.
+    fn group_jid_for_chan(&mut self, chan: &str) -> Result<Option<(i32, Jid)>> {
+        let grp = match self.store.get_group_by_chan_opt(chan)? {
+            Some(g) => g,
+            None => {
+                self.cb_respond(format!("no group with channel {} found!", chan));
+                return Ok(None);
+            }
+        };
+        match grp.jid.parse() {
+            Ok(j) => Ok(Some((grp.id, j))),
+            Err(e) => bail!("invalid jid {} for group {}: {}", grp.jid, chan, e)
+        }
+    }
+    fn check_connected(&mut self) -> bool {
+        if !self.connected || !self.conn.is_connected() {
+            self.cb_respond("we aren't connected to WhatsApp!");
+            return false;
+        }
+        true
+    }
+    fn group_create(&mut self, chan: String, subject: String, nicks: Vec<String>) -> Result<()> {
+        if !self.check_connected() {
+            return Ok(());
+        }
+        if let Some(grp) = self.store.get_group_by_chan_opt(&chan)? {
+            self.cb_respond(format!("that channel is already used for a group (jid {})!", grp.jid));
+            return Ok(());
+        }
+        let mut participants = vec![];
+        for nick in nicks {
+            let recip = match self.store.get_recipient_by_nick_opt(&nick)? {
+                Some(r) => r,
+                None => {
+                    self.cb_respond(format!("no ghost with nick {} found!", nick));
+                    return Ok(());
+                }
+            };
+            match util::address_to_jid(&recip.get_addr()?) {
+                Ok(j) if recip.whatsapp => participants.push(j),
+                _ => {
+                    self.cb_respond(format!("{} isn't a WhatsApp ghost!", nick));
+                    return Ok(());
+                }
+            }
+        }
+        info!("Creating WA group '{}' with {} participants, for channel {}", subject, participants.len(), chan);
+        self.cb_respond(format!("Creating group '{}'; it'll be bridged to {} when WhatsApp tells us it exists.", subject, chan));
+        let tag = self.track_group_request(PendingGroupRequest::Create { chan, subject: subject.clone() });
+        self.outbox.push_back(WaRequest::GroupCreate { tag, subject, participants });
+        Ok(())
+    }
+    /// Bridge a group we asked to create, now WA's told us whether it worked.
+    fn on_group_create_result(&mut self, chan: String, subject: String, jid: ::std::result::Result<Jid, WaError>) -> Result<()> {
+        match jid {
+            Ok(jid) => match self.group_associate(jid, chan.clone(), true) {
+                Ok(id) => self.cb_respond(format!("Created group '{}' and bridged it to {} (id {})", subject, chan, id)),
+                Err(e) => self.cb_respond(format!("Created group '{}', but bridging it failed: {}", subject, e))
+            },
+            Err(e) => {
+                warn!("Creating group '{}' failed: {}", subject, e);
+                self.cb_respond(format!("Failed to create group '{}': {}", subject, e));
+            }
+        }
+        Ok(())
+    }
+    fn group_leave(&mut self, chan: String) -> Result<()> {
+        if !self.check_connected() {
+            return Ok(());
+        }
+        if let Some((id, jid)) = self.group_jid_for_chan(&chan)? {
+            info!("Leaving WA group {} (channel {})", jid, chan);
+            self.cb_respond(format!("Leaving group with jid {}; it'll be unbridged when WhatsApp says we've left.", jid));
+            let tag = self.track_group_request(PendingGroupRequest::Leave { chan, id, jid: jid.clone() });
+            self.outbox.push_back(WaRequest::GroupLeave { tag, jid });
+        }
+        Ok(())
+    }
+    /// Unbridge a group we asked to leave, now WA's told us whether it worked.
+    fn on_group_leave_result(&mut self, chan: String, id: i32, jid: Jid, result: ::std::result::Result<(), WaError>) -> Result<()> {
+        match result {
+            Ok(()) => {
+                self.store.delete_group_with_id(id)?;
+                self.on_groups_changed();
+                self.cb_respond(format!("Left group with jid {}, and unbridged it from {}.", jid, chan));
+            },
+            Err(e) => {
+                warn!("Leaving group {} failed: {}", jid, e);
+                self.cb_respond(format!("Failed to leave group with jid {} (still bridged to {}): {}", jid, chan, e));
+            }
+        }
+        Ok(())
+    }
+    fn group_invite_link(&mut self, chan: String, revoke: bool) -> Result<()> {
+        if !self.check_connected() {
+            return Ok(());
+        }
+        if let Some((_, jid)) = self.group_jid_for_chan(&chan)? {
+            if revoke {
+                info!("Revoking invite link for group {}", jid);
+                self.outbox.push_back(WaRequest::RevokeGroupInviteCode(jid));
+            }
+            else {
+                info!("Getting invite link for group {}", jid);
+                self.outbox.push_back(WaRequest::GetGroupInviteCode(jid));
+            }
+        }
+        Ok(())
+    }
+    fn on_group_invite_code(&mut self, jid: Jid, code: ::std::result::Result<String, WaError>) -> Result<()> {
+        let chan = self.store.get_group_by_jid_opt(&jid)?
+            .map(|g| g.channel)
+            .unwrap_or_else(|| jid.to_string());
+        match code {
+            Ok(code) => self.cb_respond(format!("Invite link for {}: https://chat.whatsapp.com/{}", chan, code)),
+            Err(e) => {
+                warn!("Getting invite code for {} failed: {}", jid, e);
+                self.cb_respond(format!("Failed to get the invite link for {}: {}", chan, e));
+            }
+        }
+        Ok(())
+    }
+    fn group_join(&mut self, code: String) -> Result<()> {
+        if !self.check_connected() {
+            return Ok(());
+        }
+        info!("Joining WA group with invite code {}", code);
+        self.outbox.push_back(WaRequest::AcceptGroupInvite(code));
+        Ok(())
+    }
+    fn on_group_joined(&mut self, code: String, jid: ::std::result::Result<Jid, WaError>) -> Result<()> {
+        match jid {
+            Ok(jid) => {
+                if let Some(grp) = self.store.get_group_by_jid_opt(&jid)? {
+                    self.cb_respond(format!("Joined group, which is already bridged to {}.", grp.channel));
+                    return Ok(());
+                }
+                self.cb_respond(format!("Joined group with jid {}; making a channel for it...", jid));
+                // The channel gets made once we know the subject.
+                self.joined_groups.insert(jid.clone());
+                self.request_update_group(jid)?;
+            },
+            Err(e) => {
+                warn!("Joining group with invite code {} failed: {}", code, e);
+                self.cb_respond(format!("Failed to join group: {}", e));
+            }
+        }
+        Ok(())
+    }
     fn on_groups_changed(&mut self) {
         debug!("Groups changed!");
         self.cf_tx.unbounded_send(ContactFactoryCommand::ProcessGroups)
@@ -648,6 +1126,181 @@ impl WhatsappManager {
         }
         Ok(())
     }
+    /// Tell whoever made a group change on IRC that it couldn't be made, with
+    /// an IRC numeric.
+    fn reject_group_change(&mut self, origin: &str, numeric: &str, args: Vec<String>, text: &str) {
+        warn!("Rejecting group change by {}: {} {:?} {}", origin, numeric, args, text);
+        self.cb_tx.unbounded_send(ControlBotCommand::ReportNumeric(origin.into(), numeric.into(), args, text.into()))
+            .unwrap();
+        // The change might already have happened on the IRC side (e.g. a
+        // /KICK over an InspIRCd link), so put things back how they were.
+        self.on_groups_changed();
+    }
+    /// Like `reject_group_change()`, but for errors without a numeric.
+    fn fail_group_change(&mut self, origin: &str, text: String) {
+        warn!("Group change by {} failed: {}", origin, text);
+        self.cb_tx.unbounded_send(ControlBotCommand::ReportNotice(origin.into(), text))
+            .unwrap();
+        self.on_groups_changed();
+    }
+    /// Remember a group request we're about to make, returning the tag to
+    /// send it with.
+    fn track_group_request(&mut self, req: PendingGroupRequest) -> String {
+        let tag = MessageId::generate().0;
+        self.pending_requests.insert(tag.clone(), (req, Instant::now()));
+        tag
+    }
+    /// Give up on group requests WA hasn't answered in time.
+    fn expire_group_requests(&mut self) {
+        let timeout = Duration::from_secs(GROUP_REQUEST_TIMEOUT_SECS);
+        let expired = self.pending_requests.iter()
+            .filter(|(_, (_, sent))| sent.elapsed() > timeout)
+            .map(|(t, _)| t.clone())
+            .collect::<Vec<_>>();
+        for tag in expired {
+            warn!("Group request {} expired", tag);
+            let (req, _) = self.pending_requests.remove(&tag).unwrap();
+            self.give_up_group_request(req, "WhatsApp never said");
+        }
+    }
+    /// Tell whoever made a group request that we don't know whether it
+    /// worked; `why` starts the sentence.
+    fn give_up_group_request(&mut self, req: PendingGroupRequest, why: &str) {
+        use self::PendingGroupRequest::*;
+
+        match req {
+            Change { chan, origin } => {
+                self.fail_group_change(&origin, format!("{} whether your change to {} worked.", why, chan));
+            },
+            Create { chan, subject } => {
+                self.cb_respond(format!("{} whether creating group '{}' for {} worked.", why, subject, chan));
+            },
+            Leave { chan, jid, .. } => {
+                self.cb_respond(format!("{} whether leaving group {} worked, so it's still bridged to {}.", why, jid, chan));
+            }
+        }
+    }
+    /// Handle WA's answer to a group change or leave request.
+    fn on_group_change_result(&mut self, tag: String, result: ::std::result::Result<(), WaError>) -> Result<()> {
+        let (chan, origin) = match self.pending_requests.remove(&tag) {
+            Some((PendingGroupRequest::Change { chan, origin }, _)) => (chan, origin),
+            Some((PendingGroupRequest::Leave { chan, id, jid }, _)) => {
+                return self.on_group_leave_result(chan, id, jid, result);
+            },
+            Some((req, sent)) => {
+                warn!("Got a change result for group request {}, which isn't a change", tag);
+                self.pending_requests.insert(tag, (req, sent));
+                return Ok(());
+            },
+            None => {
+                debug!("Got a result for unknown group request {}", tag);
+                return Ok(());
+            }
+        };
+        match result {
+            Ok(()) => debug!("Group change {} to {} by {} worked", tag, chan, origin),
+            Err(WaError::StatusCode(401)) | Err(WaError::StatusCode(403)) => {
+                self.reject_group_change(&origin, "482", vec![chan], "You aren't an admin of this WhatsApp group.");
+            },
+            Err(e) => {
+                self.fail_group_change(&origin, format!("Changing the WhatsApp group for {} failed: {}", chan, e));
+            }
+        }
+        Ok(())
+    }
+    /// Handle WA's answer to a group creation request.
+    fn on_group_create_tagged(&mut self, tag: String, jid: ::std::result::Result<Jid, WaError>) -> Result<()> {
+        match self.pending_requests.remove(&tag) {
+            Some((PendingGroupRequest::Create { chan, subject }, _)) => {
+                self.on_group_create_result(chan, subject, jid)
+            },
+            Some((req, sent)) => {
+                warn!("Got a create result for group request {}, which isn't a create", tag);
+                self.pending_requests.insert(tag, (req, sent));
+                Ok(())
+            },
+            None => {
+                debug!("Got a create result for unknown group request {}", tag);
+                Ok(())
+            }
+        }
+    }
+    /// Make a change to a WA group, because its channel was changed on IRC.
+    ///
+    /// We don't update anything ourselves; WA tells us about the change if it works.
+    fn group_change(&mut self, chan: String, origin: String, change: GroupChange) -> Result<()> {
+        use whatsappweb::GroupParticipantsChange as WaChange;
+
+        let grp = match self.store.get_group_by_chan_opt(&chan)? {
+            Some(g) => g,
+            None => {
+                self.reject_group_change(&origin, "403", vec![chan], "That channel isn't bridged to a WhatsApp group.");
+                return Ok(());
+            }
+        };
+        let jid: Jid = match grp.jid.parse() {
+            Ok(j) => j,
+            Err(e) => bail!("invalid jid {} for group {}: {}", grp.jid, chan, e)
+        };
+        let us = match self.our_jid.clone() {
+            Some(j) if self.connected && self.conn.is_connected() => j,
+            _ => {
+                self.fail_group_change(&origin, format!("Couldn't change the WhatsApp group for {}, since we aren't connected to WhatsApp.", chan));
+                return Ok(());
+            }
+        };
+        if !grp.admins.contains(&self.get_wa_recipient(&us)?.id) {
+            self.reject_group_change(&origin, "482", vec![chan], "You aren't an admin of this WhatsApp group.");
+            return Ok(());
+        }
+        let (wa_change, addr) = match change {
+            GroupChange::Subject(subject) => {
+                info!("Changing subject of group {} to '{}'", jid, subject);
+                let tag = self.track_group_request(PendingGroupRequest::Change { chan, origin });
+                self.outbox.push_back(WaRequest::GroupSubjectChange { tag, jid, subject });
+                return Ok(());
+            },
+            GroupChange::Add(a) => (WaChange::Add, a),
+            GroupChange::Remove(a) => (WaChange::Remove, a),
+            GroupChange::Promote(a) => (WaChange::Promote, a),
+            GroupChange::Demote(a) => (WaChange::Demote, a)
+        };
+        let recip = match self.store.get_recipient_by_addr_opt(&addr)? {
+            Some(r) => r,
+            None => {
+                self.reject_group_change(&origin, "401", vec![addr.to_string()], "No such nick.");
+                return Ok(());
+            }
+        };
+        let participant = match util::address_to_jid(&addr) {
+            Ok(j) => j,
+            Err(_) => {
+                self.reject_group_change(&origin, "401", vec![recip.nick], "That nick isn't a WhatsApp user.");
+                return Ok(());
+            }
+        };
+        let present = grp.participants.contains(&recip.id);
+        match wa_change {
+            WaChange::Add if present => {
+                self.reject_group_change(&origin, "443", vec![recip.nick, chan], "They're already in the WhatsApp group.");
+                return Ok(());
+            },
+            WaChange::Remove | WaChange::Promote | WaChange::Demote if !present => {
+                self.reject_group_change(&origin, "441", vec![recip.nick, chan], "They aren't in the WhatsApp group.");
+                return Ok(());
+            },
+            _ => {}
+        }
+        info!("Group {} participant change: {:?} {}", jid, wa_change, participant);
+        let tag = self.track_group_request(PendingGroupRequest::Change { chan, origin });
+        self.outbox.push_back(WaRequest::GroupParticipantsChange {
+            tag,
+            jid,
+            change: wa_change,
+            participants: vec![participant]
+        });
+        Ok(())
+    }
     fn on_established(&mut self, jid: Jid, ps: WaPersistentSession) -> Result<()> {
         self.our_jid = Some(jid.clone());
         if self.our_jid != self.prev_jid {
@@ -661,17 +1314,23 @@ impl WhatsappManager {
         else {
             debug!("Logged in again after connection loss.");
         }
-        let unsent = self.ackp.extract_unsent();
-        if unsent.len() > 0 {
-            info!("Sending {} messages sent while offline", unsent.len());
-        }
-        for mss in unsent {
-            self.send_message(mss.content, mss.destination)?;
+        let queued = self.store.get_outgoing_wa_messages()?.len();
+        if queued > 0 {
+            info!("Sending {} messages sent while offline", queued);
         }
+        self.outbox_pending = true;
+        let uploads = self.uploads.values()
+            .map(|p| WaRequest::RequestFileUpload {
+                hash: p.enc_sha256.clone(),
+                media_type: p.ty
+            })
+            .collect::<Vec<_>>();
+        self.outbox.extend(uploads);
         self.store.store_wa_persistence(ps.clone())?;
         self.conn.set_persistent(Some(ps));
         self.prev_jid = Some(jid);
         self.connected = true;
+        self.request_missing_avatars()?;
         Ok(())
     }
     fn on_wa_error(&mut self, err: WaError) {
@@ -699,6 +1358,22 @@ impl WhatsappManager {
                 self.conn.disable();
             }
         }
+        for (mid, row) in ::std::mem::replace(&mut self.unflushed, vec![]) {
+            // Messages from the outbox are still there, and get resent when we
+            // reconnect; media has to be sent again by hand.
+            if row.is_none() {
+                self.ackp.on_upload_failed(&mid, "disconnected before it could be sent; try again");
+            }
+            else {
+                debug!("Message ID {} wasn't flushed before disconnecting; will resend", mid);
+            }
+            self.outbox_pending = true;
+        }
+        // WA won't answer these now, even if we reconnect.
+        for (tag, (req, _)) in ::std::mem::replace(&mut self.pending_requests, HashMap::new()) {
+            debug!("Giving up on group request {}, since we got disconnected", tag);
+            self.give_up_group_request(req, "We got disconnected from WhatsApp before it said");
+        }
         self.our_jid = None;
         self.connected = false;
     }
@@ -717,23 +1392,7 @@ impl WhatsappManager {
             }
             if recip.nick != new_nick {
                 debug!("New nick '{}' (src {}) for recipient {} (from '{}', src {})", new_nick, newsrc, addr, recip.nick, recip.nicksrc);
-                let should_update = match (recip.nicksrc, newsrc) {
-                    // Migrated nicks should always be changed.
-                    (Recipient::NICKSRC_MIGRATED, _) => true,
-                    // Don't replace ugly phone numbers with more ugly phone numbers.
-                    (Recipient::NICKSRC_AUTO, Recipient::NICKSRC_AUTO) => false,
-                    // But replace ugly phone numbers with anything else!
-                    (Recipient::NICKSRC_AUTO, _) => true,
-                    // Replace WA notify (i.e. name the contact gave themselves) with WA contact
-                    // (i.e. name the user gave the contact on their phone's address book)
-                    (Recipient::NICKSRC_WA_NOTIFY, Recipient::NICKSRC_WA_CONTACT) => true,
-                    // Allow users to update their address book names.
-                    (Recipient::NICKSRC_WA_CONTACT, Recipient::NICKSRC_WA_CONTACT) => true,
-                    // Update WA notify values as well.
-                    (Recipient::NICKSRC_WA_NOTIFY, Recipient::NICKSRC_WA_NOTIFY) => true,
-                    // Other changes are probably unwanted.
-                    _ => false
-                };
+                let should_update = Recipient::should_replace_nick(recip.nicksrc, newsrc);
                 if should_update && self.autoupdate_nicks {
                     info!("Automatically updating nick for {} to {} (oldsrc {}, newsrc {})", addr, new_nick, recip.nicksrc, newsrc);
                     let cmd = ContactFactoryCommand::ForwardCommand(
@@ -822,7 +1481,20 @@ impl WhatsappManager {
                 let is_new = if newly_created { " newly created" } else { "" };
                 let jid = meta.id.to_string();
                 info!("Introduced{} group '{}' (jid {})", is_new, meta.subject, jid);
-                if newly_created && self.autocreate.is_some() {
+                let creating = self.pending_requests.values().any(|(req, _)| match req {
+                    PendingGroupRequest::Create { subject, .. } => subject == &meta.subject,
+                    _ => false
+                });
+                if newly_created && self.store.get_group_by_jid_opt(&meta.id)?.is_some() {
+                    // We created it, and it's bridged already.
+                    self.on_got_group_metadata(meta)?;
+                }
+                else if newly_created && creating {
+                    // We're creating it, and WA hasn't told us it worked yet;
+                    // it gets bridged (and updated) when it does.
+                    debug!("Not autocreating group {}, which we're creating", jid);
+                }
+                else if newly_created && self.autocreate.is_some() {
                     self.group_autocreate_from_intro(meta)?;
                 }
             },
@@ -881,24 +1553,33 @@ impl WhatsappManager {
                 }
             },
             PictureChange { jid, removed } => {
-                if jid.is_group {
-                    warn!("Got picture change for non-user jid {}", jid);
-                    return Ok(());
+                let name = if jid.is_group {
+                    format!("group {}", jid)
                 }
-                let recip = self.get_wa_recipient(&jid)?;
+                else {
+                    self.get_wa_recipient(&jid)?.nick
+                };
                 if !removed {
-                    info!("{} changed their profile photo.", recip.nick);
+                    info!("{} changed their profile photo.", name);
+                    self.request_avatar(jid, true);
                 }
                 else {
-                    info!("{} removed their profile photo.", recip.nick);
+                    info!("{} removed their profile photo.", name);
+                    self.set_avatar(&jid, None)?;
                 }
             },
+            ProfilePicture { jid, url } => self.on_profile_picture(jid, url)?,
             MessageSendFail { mid, status } => {
                 error!("Got a MessageSendFail (status {}) for mid {}", status, mid.0);
                 let err = format!("Error: Sending WhatsApp message ID {} failed with code {}!", mid.0, status);
                 self.cb_tx.unbounded_send(ControlBotCommand::ReportFailure(err.into()))
                     .unwrap();
             },
+            FileUploadUrl { hash, url } => self.on_upload_url(hash, url),
+            GroupInviteCode { jid, code } => self.on_group_invite_code(jid, code)?,
+            GroupJoined { code, jid } => self.on_group_joined(code, jid)?,
+            GroupChangeResult { tag, result } => self.on_group_change_result(tag, result)?,
+            GroupCreateResult { tag, jid } => self.on_group_create_tagged(tag, jid)?,
             BatteryLevel(level) => {
                 // FIXME: warn when this gets low?
                 debug!("Phone battery level: {}", level);
@@ -17,9 +17,11 @@ use crate::comm::{ControlBotCommand, InitParameters};
 pub struct MessageSendStatus {
     ack_level: Option<MessageAckLevel>,
     sent_ts: DateTime<Utc>,
-    pub content: ChatMessageContent,
+    summary: String,
     pub destination: Jid,
     unsent: bool,
+    /// Whether this is media that's still being uploaded.
+    uploading: bool,
     alerted: bool,
     alerted_pending: bool,
 }
@@ -62,28 +64,40 @@ impl WaAckTracker {
         let mss = MessageSendStatus {
             ack_level: None,
             sent_ts: Utc::now(),
-            content,
+            summary: content.quoted_description(),
             destination: to,
             unsent: unsent,
+            uploading: false,
             alerted: false,
             alerted_pending: false
         };
         self.outgoing_messages.insert(mid, mss);
     }
-    pub fn extract_unsent(&mut self) -> Vec<MessageSendStatus> {
-        let ret = self.outgoing_messages.iter()
-            .filter(|(_, mss)| mss.unsent)
-            .map(|(_, x)| x.clone())
-            .collect();
-        self.outgoing_messages.retain(|_, mss| !mss.unsent);
-        ret
+    /// Track media that's being fetched and uploaded, before it can be sent.
+    pub fn register_upload(&mut self, to: Jid, summary: String, mid: String) {
+        let mss = MessageSendStatus {
+            ack_level: None,
+            sent_ts: Utc::now(),
+            summary,
+            destination: to,
+            unsent: true,
+            uploading: true,
+            alerted: false,
+            alerted_pending: false
+        };
+        self.outgoing_messages.insert(mid, mss);
+    }
+    pub fn on_upload_failed(&mut self, mid: &str, err: &str) {
+        if let Some(mss) = self.outgoing_messages.remove(mid) {
+            Self::send_fail(&mut self.cb_tx, format!("Error: Uploading \"{}\" (message ID {}) failed: {}", mss.summary, mid, err));
+        }
     }
     pub fn print_acks(&mut self) -> Vec<String> {
         let now = Utc::now();
         let mut lines = vec![];
         for (mid, mss) in self.outgoing_messages.iter_mut() {
             let delta = now - mss.sent_ts;
-            let mut summary = mss.content.quoted_description();
+            let mut summary = mss.summary.clone();
             if summary.len() > 15 {
                 summary = summary.graphemes(true)
                     .take(10)
@@ -92,6 +106,7 @@ impl WaAckTracker {
             }
             let al: std::borrow::Cow<str> = match mss.ack_level {
                 Some(al) => format!("{:?}", al).into(),
+                None if mss.uploading => "uploading".into(),
                 None => "undelivered".into()
             };
             lines.push(format!("- \"\x1d{}\x1d\" to \x02{}\x02 ({}s ago) is \x02{}\x02", 
@@ -129,7 +144,10 @@ impl WaAckTracker {
             if mss.ack_level.is_none() {
                 if delta_ms >= self.ack_warn && !mss.alerted {
                     warn!("Message {} has been un-acked for {} seconds!", mid, delta.num_seconds());
-                    if mss.unsent {
+                    if mss.uploading {
+                        Self::send_fail(&mut self.cb_tx, format!("Warning: Message ID {} is still being uploaded.", mid));
+                    }
+                    else if mss.unsent {
                         warn!("(still disconnected)");
                         Self::send_fail(&mut self.cb_tx, format!("Warning: Message ID {} is still unsent, because we aren't connected to WhatsApp Web.", mid));
                     }
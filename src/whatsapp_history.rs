@@ -0,0 +1,88 @@
+//! Remembering recent messages in each WA chat, so replies from IRC can quote them.
+
+use whatsappweb::Jid;
+use whatsappweb::message::{ChatMessageContent, QuotedChatMessage, MessageId};
+use std::collections::{HashMap, VecDeque};
+
+struct HistoryEntry {
+    id: MessageId,
+    participant: Jid,
+    content: ChatMessageContent
+}
+/// Which message a reply from IRC is quoting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplySelector {
+    /// `^N`: the Nth most recent message someone else sent in the chat.
+    Nth(usize),
+    /// `> fragment | ...`: the most recent message containing `fragment`.
+    Fragment(String),
+    /// A WA message ID (from an IRCv3 `+draft/reply` tag).
+    Id(String)
+}
+impl ReplySelector {
+    /// Parse reply syntax at the start of a message from IRC, returning what
+    /// it selects and the rest of the message.
+    ///
+    /// `^N text` replies to the Nth most recent message from someone else, and
+    /// `> fragment | text` replies to the most recent one containing `fragment`.
+    pub fn parse(text: &str) -> Option<(Self, &str)> {
+        if text.starts_with('^') {
+            let end = text.find(' ')?;
+            let n = text[1..end].parse().ok().filter(|&n| n > 0)?;
+            return Some((ReplySelector::Nth(n), text[end..].trim_start()));
+        }
+        if text.starts_with("> ") {
+            let sep = text.find(" | ")?;
+            let fragment = text[2..sep].trim();
+            if fragment.len() == 0 {
+                return None;
+            }
+            return Some((ReplySelector::Fragment(fragment.to_lowercase()), &text[sep + 3..]));
+        }
+        None
+    }
+}
+pub struct WaHistory {
+    chats: HashMap<Jid, VecDeque<HistoryEntry>>,
+    max: usize
+}
+impl WaHistory {
+    pub fn new(max: usize) -> Self {
+        Self {
+            chats: HashMap::new(),
+            max
+        }
+    }
+    /// Remember a message `participant` sent in `chat`.
+    pub fn record(&mut self, chat: Jid, id: MessageId, participant: Jid, content: ChatMessageContent) {
+        if self.max == 0 {
+            return;
+        }
+        let hist = self.chats.entry(chat).or_insert_with(VecDeque::new);
+        if hist.len() >= self.max {
+            hist.pop_front();
+        }
+        hist.push_back(HistoryEntry { id, participant, content });
+    }
+    /// Find the message in `chat` that `sel` selects, and make a quote of it.
+    ///
+    /// `us` is our own JID, so `^N` can skip our own messages.
+    pub fn find(&self, chat: &Jid, sel: &ReplySelector, us: Option<&Jid>) -> Option<QuotedChatMessage> {
+        let hist = self.chats.get(chat)?;
+        let mut iter = hist.iter().rev();
+        let entry = match *sel {
+            ReplySelector::Nth(n) => iter
+                .filter(|e| Some(&e.participant) != us)
+                .nth(n - 1),
+            ReplySelector::Fragment(ref frag) => iter
+                .find(|e| e.content.quoted_description().to_lowercase().contains(frag as &str)),
+            ReplySelector::Id(ref id) => iter
+                .find(|e| &e.id.0 == id)
+        }?;
+        Some(QuotedChatMessage {
+            id: entry.id.clone(),
+            participant: entry.participant.clone(),
+            content: entry.content.clone()
+        })
+    }
+}
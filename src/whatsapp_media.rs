@@ -8,7 +8,8 @@ use futures::sync::mpsc::UnboundedSender;
 use humansize::{FileSize, file_size_opts};
 use reqwest;
 use std::io::prelude::*;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io;
 use crate::util::Result;
 use uuid::Uuid;
 use std::sync::Arc;
@@ -114,3 +115,68 @@ impl MediaInfo {
         });
     }
 }
+/// The biggest avatar we'll download, in bytes (WA's are a few hundred KB).
+static MAX_AVATAR_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The name of the file the avatar of `jid` is stored in, which stays the
+/// same, so we don't keep every avatar someone's ever had.
+fn avatar_filename(jid: &Jid) -> String {
+    format!("avatar-{}.jpg", jid.id)
+}
+/// Delete the stored avatar of `jid` (in the media directory `path`), if any.
+pub fn remove_avatar(path: &str, jid: &Jid) {
+    let path = format!("{}/{}", path, avatar_filename(jid));
+    match fs::remove_file(&path) {
+        Ok(()) => debug!("Removed old avatar {}", path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => warn!("Failed to remove old avatar {}: {}", path, e)
+    }
+}
+/// A profile picture (of a contact or group) to download.
+pub struct AvatarInfo {
+    pub jid: Jid,
+    pub url: String,
+    pub path: String,
+    pub dl_path: String,
+    pub tx: Arc<UnboundedSender<WhatsappCommand>>
+}
+impl AvatarInfo {
+    fn run(&self) -> Result<String> {
+        // Profile pictures aren't encrypted, unlike other media.
+        let name = avatar_filename(&self.jid);
+        let path = format!("{}/{}", self.path, name);
+        let dl_path = format!("{}/{}", self.dl_path, name);
+        debug!("Downloading avatar for {} from {}", self.jid, self.url);
+        let client = reqwest::Client::new();
+        let resp = client.get(&self.url)
+            .header(USER_AGENT, "sms-irc")
+            .send()?;
+        if resp.status() != StatusCode::OK {
+            Err(format_err!("Status code {} when downloading", resp.status().as_u16()))?
+        }
+        if resp.content_length().map(|l| l > MAX_AVATAR_SIZE).unwrap_or(false) {
+            bail!("avatar is bigger than {} bytes", MAX_AVATAR_SIZE);
+        }
+        let mut data = vec![];
+        resp.take(MAX_AVATAR_SIZE + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > MAX_AVATAR_SIZE {
+            bail!("avatar is bigger than {} bytes", MAX_AVATAR_SIZE);
+        }
+        // Write it somewhere else first, so nobody sees half an avatar.
+        let tmp_path = format!("{}.part", path);
+        debug!("Creating file {}", tmp_path);
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&data)?;
+        file.flush()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(dl_path)
+    }
+    pub fn start(self) {
+        debug!("Starting avatar download job for {}", self.jid);
+        thread::spawn(move || {
+            let ret = self.run();
+            self.tx.unbounded_send(WhatsappCommand::AvatarFinished(self.jid, ret))
+                .unwrap();
+        });
+    }
+}
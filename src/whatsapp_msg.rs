@@ -7,12 +7,14 @@ use whatsappweb::message::{ChatMessageContent, QuotedChatMessage, MessageId, Pee
 use regex::{Regex, Captures};
 use huawei_modem::pdu::PduAddress;
 use std::sync::Arc;
+use std::collections::HashMap;
 use unicode_segmentation::UnicodeSegmentation;
 
 use crate::comm::WhatsappCommand;
 use crate::store::Store;
 use crate::whatsapp_media::{MediaInfo, self};
-use crate::util::Result;
+use crate::util::{self, Result};
+use crate::formatting;
 
 pub struct IncomingMessage {
     pub id: MessageId,
@@ -24,6 +26,8 @@ pub struct IncomingMessage {
     pub ts: NaiveDateTime
 }
 pub struct ProcessedIncomingMessage {
+    /// The WA message ID (unless this is a quote of another message).
+    pub id: Option<String>,
     pub from: Jid,
     pub text: String,
     pub group: Option<i32>,
@@ -36,6 +40,33 @@ pub struct WaMessageProcessor {
     pub(crate) wa_tx: Arc<UnboundedSender<WhatsappCommand>>
 }
 
+/// Replace mentions of the nicks in `participants` (which are lowercase) in
+/// `msg` with `@number`s.
+///
+/// Only "nick:" or "nick," at the start of the message, or an explicit "@nick",
+/// count; otherwise, every message with a common word for a nick in it would
+/// ping someone.
+fn replace_irc_mentions(msg: &str, participants: &HashMap<String, Jid>) -> String {
+    let mut ret = Vec::new();
+    for (i, word) in msg.split(' ').enumerate() {
+        let rest = word.trim_start_matches('@');
+        let explicit = rest.len() < word.len();
+        let core = rest.trim_end_matches(|c| ":,.!?;)".contains(c));
+        let suffix = &rest[core.len()..];
+        let addressed = i == 0 && (suffix == ":" || suffix == ",");
+        let jid = match participants.get(&core.to_lowercase()) {
+            Some(j) if core.len() > 0 && (explicit || addressed) => j,
+            _ => {
+                ret.push(word.to_owned());
+                continue;
+            }
+        };
+        // "alice: hi" becomes "@1234 hi", since that's how WA does it.
+        let suffix = if addressed { "" } else { suffix };
+        ret.push(format!("@{}{}", jid.id, suffix));
+    }
+    ret.join(" ")
+}
 impl WaMessageProcessor {
     fn process_incoming_media(&mut self, id: MessageId, peer: Option<Peer>, from: Jid, group: Option<i32>, ct: ChatMessageContent, ts: NaiveDateTime) -> Result<()> {
 
@@ -59,14 +90,11 @@ impl WaMessageProcessor {
     }
     fn process_wa_text_message<'a>(&mut self, msg: &'a str) -> String {
         lazy_static! {
-            static ref BOLD_RE: Regex = Regex::new(r#"\*([^\*]+)\*"#).unwrap();
-            static ref ITALICS_RE: Regex = Regex::new(r#"_([^_]+)_"#).unwrap();
             static ref MENTIONS_RE: Regex = Regex::new(r#"@(\d+)"#).unwrap();
         }
-        let emboldened = BOLD_RE.replace_all(msg, "\x02$1\x02");
-        let italicised = ITALICS_RE.replace_all(&emboldened, "\x1D$1\x1D");
+        let formatted = formatting::wa_to_irc(msg);
         let store = &mut self.store;
-        let ret = MENTIONS_RE.replace_all(&italicised, |caps: &Captures| {
+        let ret = MENTIONS_RE.replace_all(&formatted, |caps: &Captures| {
             let pdua: PduAddress = caps[0].replace("@", "+").parse().unwrap();
             match store.get_recipient_by_addr_opt(&pdua) {
                 Ok(Some(recip)) => recip.nick,
@@ -79,6 +107,46 @@ impl WaMessageProcessor {
         });
         ret.to_string()
     }
+    /// Get the JIDs of a WA group's participants, by lowercased nick.
+    fn group_participants(&mut self, group: &Jid) -> Result<HashMap<String, Jid>> {
+        let mut ret = HashMap::new();
+        if let Some(grp) = self.store.get_group_by_jid_opt(group)? {
+            for id in grp.participants {
+                if let Some(recip) = self.store.get_recipient_by_id_opt(id)? {
+                    if let Ok(jid) = util::address_to_jid(&recip.get_addr()?) {
+                        ret.insert(recip.nick.to_lowercase(), jid);
+                    }
+                }
+            }
+        }
+        Ok(ret)
+    }
+    /// Turn mentions of participants' nicks (like "alice: hi", or "hi @alice")
+    /// in a message from IRC to `group` into WA `@number` mentions.
+    pub fn process_irc_mentions(&mut self, msg: &str, group: &Jid) -> Result<String> {
+        let participants = self.group_participants(group)?;
+        if participants.len() == 0 {
+            return Ok(msg.into());
+        }
+        Ok(replace_irc_mentions(msg, &participants))
+    }
+    /// Find the participants of `group` that are `@number` mentioned in `msg`,
+    /// so WA can notify them.
+    pub fn mentioned_jids(&mut self, msg: &str, group: &Jid) -> Result<Vec<Jid>> {
+        lazy_static! {
+            static ref MENTIONS_RE: Regex = Regex::new(r#"@(\d+)"#).unwrap();
+        }
+        let participants = self.group_participants(group)?;
+        let mut ret: Vec<Jid> = vec![];
+        for caps in MENTIONS_RE.captures_iter(msg) {
+            if let Some(jid) = participants.values().find(|j| j.id == &caps[1]) {
+                if !ret.contains(jid) {
+                    ret.push(jid.clone());
+                }
+            }
+        }
+        Ok(ret)
+    }
     fn jid_to_nick(&mut self, jid: &Jid) -> Result<Option<String>> {
         if let Some(num) = jid.phonenumber() {
             if let Ok(pdua) = num.parse() {
@@ -174,6 +242,7 @@ impl WaMessageProcessor {
             }
             let quote = format!("\x0315> \x1d{}{}", nick, message);
             ret.push(ProcessedIncomingMessage {
+                id: None,
                 from: from.clone(),
                 text: quote,
                 group,
@@ -181,6 +250,7 @@ impl WaMessageProcessor {
             });
         }
         ret.push(ProcessedIncomingMessage {
+            id: Some(id.0.clone()),
             from,
             text,
             group,
@@ -189,3 +259,38 @@ impl WaMessageProcessor {
         Ok((ret, is_media))
     }
 }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn participants() -> HashMap<String, Jid> {
+        let mut ret = HashMap::new();
+        ret.insert("alice".to_owned(), "441234@s.whatsapp.net".parse().unwrap());
+        ret.insert("bob".to_owned(), "445678@s.whatsapp.net".parse().unwrap());
+        ret
+    }
+
+    #[test]
+    fn addressed_at_start() {
+        let p = participants();
+        assert_eq!(replace_irc_mentions("alice: hi", &p), "@441234 hi");
+        assert_eq!(replace_irc_mentions("Alice, hi", &p), "@441234 hi");
+        assert_eq!(replace_irc_mentions("BOB: hi", &p), "@445678 hi");
+    }
+
+    #[test]
+    fn explicit_mentions() {
+        let p = participants();
+        assert_eq!(replace_irc_mentions("hi @alice!", &p), "hi @441234!");
+        assert_eq!(replace_irc_mentions("@Bob: hi", &p), "@445678 hi");
+        assert_eq!(replace_irc_mentions("ask @bob, or @carol", &p), "ask @445678, or @carol");
+    }
+
+    #[test]
+    fn bare_nicks_are_left_alone() {
+        let p = participants();
+        assert_eq!(replace_irc_mentions("hi alice", &p), "hi alice");
+        assert_eq!(replace_irc_mentions("so bob: what now?", &p), "so bob: what now?");
+        assert_eq!(replace_irc_mentions("alice", &p), "alice");
+    }
+}
@@ -0,0 +1,415 @@
+//! Fetching, encrypting and uploading media to send over WA.
+
+use whatsappweb::message::{ChatMessageContent, FileInfo, MessageId};
+use whatsappweb::{MediaType, crypto, Jid};
+use std::thread;
+use std::fs::{self, File};
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+use std::time::Duration;
+use std::sync::Arc;
+use futures::sync::mpsc::UnboundedSender;
+use reqwest;
+use reqwest::header::{USER_AGENT, CONTENT_TYPE};
+use reqwest::StatusCode;
+use reqwest::multipart::{Form, Part};
+use mime_guess::guess_mime_type;
+use image::{GenericImageView, ImageOutputFormat};
+use serde_json::Value;
+use url::Url;
+
+use crate::comm::WhatsappCommand;
+use crate::formatting;
+use crate::util::Result;
+
+/// How big thumbnails are (in pixels, on the longest side).
+static THUMBNAIL_SIZE: u32 = 100;
+static THUMBNAIL_QUALITY: u8 = 70;
+/// Bitrates (in kbit/s) of MPEG-1 and MPEG-2 layer III audio, by bitrate index.
+static MP3_BITRATES: [[u64; 16]; 2] = [
+    [0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 0],
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160, 0]
+];
+
+/// Something the admin asked to send, with `!send <url-or-path> [caption]`.
+pub struct MediaSend {
+    pub mid: MessageId,
+    pub to: Jid,
+    pub source: String,
+    pub caption: Option<String>
+}
+/// Media that's been fetched and encrypted, and is waiting to be uploaded.
+pub struct PreparedMedia {
+    pub send: MediaSend,
+    pub ty: MediaType,
+    pub mime: String,
+    pub filename: String,
+    pub enc_sha256: Vec<u8>,
+    sha256: Vec<u8>,
+    key: Vec<u8>,
+    size: u64,
+    dimensions: (u32, u32),
+    duration: Duration,
+    thumbnail: Vec<u8>,
+    enc: Vec<u8>
+}
+pub enum UploadStep {
+    /// The media was fetched and encrypted (or not).
+    Prepared(MessageId, Result<PreparedMedia>),
+    /// The media was uploaded to the given URL (or not).
+    Uploaded(PreparedMedia, Result<String>)
+}
+/// Media that's been opened, but not read yet.
+struct Source {
+    reader: Box<dyn Read>,
+    /// How big it says it is, if it says.
+    len: Option<u64>,
+    mime: Option<String>,
+    filename: String
+}
+fn open(source: &str) -> Result<Source> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let url = Url::parse(source)?;
+        let filename = url.path_segments()
+            .and_then(|s| s.last())
+            .filter(|s| s.len() > 0)
+            .unwrap_or("file")
+            .to_owned();
+        debug!("Downloading {}", source);
+        let client = reqwest::Client::new();
+        let resp = client.get(url)
+            .header(USER_AGENT, "sms-irc")
+            .send()?;
+        if resp.status() != StatusCode::OK {
+            Err(format_err!("Status code {} when downloading", resp.status().as_u16()))?
+        }
+        let mime = resp.headers().get(CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .map(|s| s.split(';').next().unwrap_or(s).trim().to_owned());
+        let len = resp.content_length();
+        Ok(Source { reader: Box::new(resp), len, mime, filename })
+    }
+    else {
+        let path = source.trim_start_matches("file://");
+        let filename = Path::new(path).file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or("file".into());
+        debug!("Reading {}", path);
+        let file = File::open(path)?;
+        let len = Some(file.metadata()?.len());
+        Ok(Source { reader: Box::new(file), len, mime: None, filename })
+    }
+}
+/// The biggest media of type `ty` that WA will accept, in bytes.
+fn max_size(ty: MediaType) -> u64 {
+    match ty {
+        MediaType::Document => 100 * 1024 * 1024,
+        _ => 16 * 1024 * 1024
+    }
+}
+/// Read all of `reader`, as long as there's no more than `limit` bytes of it.
+fn read_limited(reader: Box<dyn Read>, len: Option<u64>, limit: u64) -> Result<Vec<u8>> {
+    if let Some(len) = len {
+        if len > limit {
+            bail!("it's {} bytes, but WhatsApp only allows {} bytes for this type of media", len, limit);
+        }
+    }
+    let mut data = vec![];
+    reader.take(limit + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > limit {
+        bail!("it's more than the {} bytes WhatsApp allows for this type of media", limit);
+    }
+    Ok(data)
+}
+fn media_type_for(mime: &str) -> MediaType {
+    if mime.starts_with("image/") {
+        MediaType::Image
+    }
+    else if mime.starts_with("video/") {
+        MediaType::Video
+    }
+    else if mime.starts_with("audio/") {
+        MediaType::Audio
+    }
+    else {
+        MediaType::Document
+    }
+}
+/// Read a big-endian number.
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+/// Read a little-endian number.
+fn le(bytes: &[u8]) -> u64 {
+    bytes.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+/// Find the contents of the first MP4 box called `name` in `data`.
+fn mp4_box<'a>(data: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    let mut rest = data;
+    while rest.len() >= 8 {
+        let (header, size) = match be(&rest[0..4]) {
+            0 => (8, rest.len() as u64),
+            1 => (16, be(rest.get(8..16)?)),
+            s => (8, s)
+        };
+        if size < header as u64 || size > rest.len() as u64 {
+            return None;
+        }
+        if &rest[4..8] == name {
+            return Some(&rest[header..size as usize]);
+        }
+        rest = &rest[size as usize..];
+    }
+    None
+}
+/// Get the duration of an MP4 (or QuickTime, or M4A) file from its `mvhd` box.
+fn mp4_duration(data: &[u8]) -> Option<Duration> {
+    let mvhd = mp4_box(mp4_box(data, b"moov")?, b"mvhd")?;
+    let (timescale, duration) = match *mvhd.get(0)? {
+        0 => (be(mvhd.get(12..16)?), be(mvhd.get(16..20)?)),
+        1 => (be(mvhd.get(20..24)?), be(mvhd.get(24..32)?)),
+        _ => return None
+    };
+    if timescale == 0 {
+        return None;
+    }
+    Some(Duration::from_millis(duration.checked_mul(1000)? / timescale))
+}
+/// Get the duration of an Ogg Opus or Vorbis file from the granule position
+/// of its last page.
+fn ogg_duration(data: &[u8]) -> Option<Duration> {
+    if !data.starts_with(b"OggS") {
+        return None;
+    }
+    let segments = *data.get(26)? as usize;
+    let packet = data.get(27 + segments..)?;
+    let (rate, pre_skip) = if packet.starts_with(b"OpusHead") {
+        (48000, le(packet.get(10..12)?))
+    }
+    else if packet.starts_with(b"\x01vorbis") {
+        (le(packet.get(12..16)?), 0)
+    }
+    else {
+        return None;
+    };
+    let last = data.windows(4).rposition(|w| w == b"OggS")?;
+    let granule = le(data.get(last + 6..last + 14)?);
+    if rate == 0 {
+        return None;
+    }
+    Some(Duration::from_millis(granule.saturating_sub(pre_skip).checked_mul(1000)? / rate))
+}
+/// Estimate the duration of an MP3 file from the bitrate of its first frame
+/// (so this is only exact for constant bitrate files).
+fn mp3_duration(data: &[u8]) -> Option<Duration> {
+    let mut start = 0;
+    if data.starts_with(b"ID3") {
+        // ID3v2 tag sizes are 'syncsafe', with 7 bits per byte.
+        start = 10 + data.get(6..10)?.iter().fold(0, |acc, &b| (acc << 7) | (b & 0x7f) as usize);
+    }
+    let hdr = data.get(start..start + 4)?;
+    if hdr[0] != 0xff || hdr[1] & 0xe0 != 0xe0 || hdr[1] & 0x06 != 0x02 {
+        return None;
+    }
+    let table = if hdr[1] & 0x18 == 0x18 { 0 } else { 1 };
+    let kbps = MP3_BITRATES[table][(hdr[2] >> 4) as usize];
+    if kbps == 0 {
+        return None;
+    }
+    Some(Duration::from_millis((data.len() - start) as u64 * 8 / kbps))
+}
+/// Work out how long some audio or video is, if it's in a format we understand.
+fn media_duration(data: &[u8]) -> Option<Duration> {
+    mp4_duration(data)
+        .or_else(|| ogg_duration(data))
+        .or_else(|| mp3_duration(data))
+}
+/// Get an image's size and a JPEG thumbnail of it.
+fn make_thumbnail(data: &[u8]) -> Result<((u32, u32), Vec<u8>)> {
+    let img = image::load_from_memory(data)?;
+    let mut thumbnail = vec![];
+    img.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .write_to(&mut thumbnail, ImageOutputFormat::JPEG(THUMBNAIL_QUALITY))?;
+    Ok((img.dimensions(), thumbnail))
+}
+impl MediaSend {
+    fn prepare(self) -> Result<PreparedMedia> {
+        let Source { reader, len, mime, filename } = open(&self.source)?;
+        let mime = mime.unwrap_or_else(|| guess_mime_type(&filename).to_string());
+        let mut ty = media_type_for(&mime);
+        let data = read_limited(reader, len, max_size(ty))?;
+        let (mut dimensions, mut thumbnail) = ((0, 0), vec![]);
+        if let MediaType::Image = ty {
+            match make_thumbnail(&data) {
+                Ok((d, t)) => {
+                    dimensions = d;
+                    thumbnail = t;
+                },
+                Err(e) => {
+                    // WA would show it as a broken image anyway.
+                    debug!("Sending {} as a document, since it couldn't be decoded: {}", filename, e);
+                    ty = MediaType::Document;
+                }
+            }
+        }
+        let mut duration = Duration::new(0, 0);
+        if let MediaType::Video | MediaType::Audio = ty {
+            match media_duration(&data) {
+                Some(d) => duration = d,
+                None => debug!("Couldn't work out the duration of {}", filename)
+            }
+        }
+        debug!("Encrypting {} ({}, {} bytes)", filename, mime, data.len());
+        let (enc, key) = crypto::encrypt_media_message(ty, &data);
+        Ok(PreparedMedia {
+            ty, mime, filename, dimensions, duration, thumbnail, key,
+            sha256: crypto::sha256(&data),
+            enc_sha256: crypto::sha256(&enc),
+            size: data.len() as _,
+            enc,
+            send: self
+        })
+    }
+    pub fn start(self, tx: Arc<UnboundedSender<WhatsappCommand>>) {
+        debug!("Starting media preparation job for {} / mid {:?}", self.to.to_string(), self.mid);
+        thread::spawn(move || {
+            let mid = self.mid.clone();
+            let ret = self.prepare();
+            tx.unbounded_send(WhatsappCommand::MediaUpload(UploadStep::Prepared(mid, ret)))
+                .unwrap();
+        });
+    }
+}
+impl PreparedMedia {
+    fn upload(&mut self, url: &str) -> Result<String> {
+        debug!("Uploading {} to {}", self.filename, url);
+        let enc = mem::replace(&mut self.enc, vec![]);
+        let form = Form::new()
+            .text("hash", base64::encode(&self.enc_sha256))
+            .part("file", Part::bytes(enc)
+                  .file_name("blob")
+                  .mime_str("application/octet-stream")?);
+        let client = reqwest::Client::new();
+        let mut resp = client.post(url)
+            .header(USER_AGENT, "sms-irc")
+            .multipart(form)
+            .send()?;
+        if resp.status() != StatusCode::OK {
+            Err(format_err!("Status code {} when uploading", resp.status().as_u16()))?
+        }
+        let json: Value = resp.json()?;
+        let url = json["url"].as_str()
+            .ok_or(format_err!("no URL in upload response"))?;
+        Ok(url.to_owned())
+    }
+    /// Upload the media to `url` (as given to us by WA).
+    pub fn start_upload(mut self, url: String, tx: Arc<UnboundedSender<WhatsappCommand>>) {
+        debug!("Starting media upload job for {} / mid {:?}", self.send.to.to_string(), self.send.mid);
+        thread::spawn(move || {
+            let ret = self.upload(&url);
+            tx.unbounded_send(WhatsappCommand::MediaUpload(UploadStep::Uploaded(self, ret)))
+                .unwrap();
+        });
+    }
+    /// Make the message to send, now the media's been uploaded to `url`.
+    ///
+    /// Also returns the caption, if this type of media can't have one and it
+    /// needs sending as a separate message.
+    pub fn into_content(self, url: String) -> (ChatMessageContent, Option<String>) {
+        let caption = self.send.caption.as_ref()
+            .map(|c| formatting::irc_to_wa(c));
+        let info = FileInfo {
+            url,
+            mime: self.mime,
+            sha256: self.sha256,
+            enc_sha256: self.enc_sha256,
+            size: self.size,
+            key: self.key
+        };
+        match self.ty {
+            MediaType::Image => (ChatMessageContent::Image {
+                info,
+                size: self.dimensions,
+                thumbnail: self.thumbnail,
+                caption
+            }, None),
+            MediaType::Video => (ChatMessageContent::Video {
+                info,
+                duration: self.duration,
+                thumbnail: self.thumbnail,
+                caption
+            }, None),
+            MediaType::Audio => (ChatMessageContent::Audio {
+                info,
+                duration: self.duration
+            }, caption),
+            MediaType::Document => (ChatMessageContent::Document {
+                info,
+                filename: self.filename
+            }, caption)
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_box(name: &[u8], contents: &[u8]) -> Vec<u8> {
+        let mut ret = (contents.len() as u32 + 8).to_be_bytes().to_vec();
+        ret.extend(name);
+        ret.extend(contents);
+        ret
+    }
+
+    #[test]
+    fn mp4() {
+        let mut mvhd = vec![0; 20];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&12345u32.to_be_bytes());
+        let mut moov = make_box(b"iods", &[0; 4]);
+        moov.extend(make_box(b"mvhd", &mvhd));
+        let mut file = make_box(b"ftyp", b"isom");
+        file.extend(make_box(b"moov", &moov));
+        assert_eq!(media_duration(&file), Some(Duration::from_millis(12345)));
+    }
+
+    #[test]
+    fn ogg_opus() {
+        let mut first = b"OggS".to_vec();
+        first.extend(&[0; 22]);
+        first.push(1);
+        first.push(19);
+        first.extend(b"OpusHead\x01\x02");
+        first.extend(&312u16.to_le_bytes());
+        first.extend(&[0; 7]);
+        let mut last = b"OggS\x00\x04".to_vec();
+        last.extend(&(48000u64 * 3 + 312).to_le_bytes());
+        last.extend(&[0; 13]);
+        first.extend(last);
+        assert_eq!(media_duration(&first), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn mp3() {
+        // MPEG-1 layer III, 128 kbit/s, after a 16-byte ID3 tag.
+        let mut file = b"ID3\x04\x00\x00\x00\x00\x00\x10".to_vec();
+        file.extend(&[0; 16]);
+        file.extend(&[0xff, 0xfb, 0x90, 0x00]);
+        file.extend(vec![0; 16000 - 4]);
+        assert_eq!(media_duration(&file), Some(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn unknown_duration() {
+        assert_eq!(media_duration(b"not media"), None);
+    }
+
+    #[test]
+    fn size_limit() {
+        let data = vec![1u8; 10];
+        assert_eq!(read_limited(Box::new(::std::io::Cursor::new(data.clone())), None, 10).unwrap(), data);
+        assert!(read_limited(Box::new(::std::io::Cursor::new(data.clone())), None, 9).is_err());
+        assert!(read_limited(Box::new(::std::io::Cursor::new(vec![])), Some(11), 10).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! End-to-end tests of the SMS pipeline: the fake modem talks to a real
+//! `ModemManager`, which puts messages in the database for a `Sender` to
+//! deliver (and sends messages from the outbox).
+//!
+//! These need a database to work with, so they're ignored by default: set
+//! `SMS_IRC_TEST_DATABASE_URL` and run `cargo test --features fake-modem -- --ignored`.
+
+use futures::Future;
+use huawei_modem::pdu::{PduAddress, DeliverPdu};
+use sms_irc::comm::{ChannelMaker, InitParameters, ModemCommand};
+use sms_irc::config::Config;
+use sms_irc::fake_modem::{self, FakeModem, Submitted};
+use sms_irc::modem::ModemManager;
+use sms_irc::sender_common::Sender;
+use sms_irc::store::Store;
+use sms_irc::util::Result;
+use futures::sync::mpsc::UnboundedSender;
+use std::convert::TryFrom;
+use std::time::{Duration, Instant};
+use tokio_core::reactor::Core;
+
+/// A `Sender` that just remembers what it was asked to send.
+struct TestSender {
+    store: Store,
+    sent: Vec<(String, String)>,
+    errors: Vec<String>
+}
+impl Sender for TestSender {
+    fn report_error(&mut self, _from_nick: &str, err: String) -> Result<()> {
+        self.errors.push(err);
+        Ok(())
+    }
+    fn store(&mut self) -> &mut Store {
+        &mut self.store
+    }
+    fn private_target(&mut self) -> String {
+        "admin".into()
+    }
+    fn send_irc_message(&mut self, from_nick: &str, _to: &str, msg: &str) -> Result<()> {
+        self.sent.push((from_nick.into(), msg.into()));
+        Ok(())
+    }
+    fn reassembly_timeout(&mut self, _backend: Option<&str>) -> u64 {
+        600
+    }
+}
+
+struct Harness {
+    core: Core,
+    store: Store,
+    modem: FakeModem,
+    tx: UnboundedSender<ModemCommand>,
+    // Kept around so the modem's channels stay open.
+    _cm: ChannelMaker
+}
+impl Harness {
+    /// Start a fake modem, and a `ModemManager` called `name` talking to it.
+    fn new(name: &str) -> Self {
+        let url = ::std::env::var("SMS_IRC_TEST_DATABASE_URL")
+            .expect("SMS_IRC_TEST_DATABASE_URL must be set to run this test");
+        let modem = FakeModem::start().unwrap();
+        let cfg: Config = toml::from_str(&format!(r#"
+            database_url = "{}"
+            [[modems]]
+            name = "{}"
+            modem_path = "{}"
+            cmgl_secs = 1
+            restart_delay_ms = 100
+            outbox_retry_ms = 100
+        "#, url, name, modem.path())).unwrap();
+        let core = Core::new().unwrap();
+        let store = Store::new(&cfg).unwrap();
+        let mut cm = ChannelMaker::new();
+        let mm = ModemManager::new(InitParameters {
+            cfg: &cfg,
+            cfg2: &cfg.modems[0],
+            store: store.clone(),
+            cm: &mut cm,
+            hdl: &core.handle()
+        }, name.into(), false).unwrap();
+        let tx = mm.sender();
+        core.handle().spawn(mm.map_err(|e| panic!("ModemManager failed: {}", e)));
+        Self { core, store, modem, tx, _cm: cm }
+    }
+    /// Run the modem until `cond` is true.
+    fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, mut cond: F) {
+        let start = Instant::now();
+        while !cond(self) {
+            assert!(start.elapsed() < Duration::from_secs(20), "timed out");
+            self.core.turn(Some(Duration::from_millis(50)));
+        }
+    }
+    /// Wait for `n` messages from `addr` to arrive in the database, then
+    /// hand them all to a `TestSender`.
+    fn deliver(&mut self, addr: &PduAddress, n: usize) -> TestSender {
+        self.run_until(|h| h.store.get_messages_for_recipient(addr).unwrap().len() >= n);
+        let mut sender = TestSender {
+            store: self.store.clone(),
+            sent: vec![],
+            errors: vec![]
+        };
+        for msg in self.store.get_messages_for_recipient(addr).unwrap() {
+            let pdu = DeliverPdu::try_from(msg.pdu.as_ref().unwrap() as &[u8]).unwrap();
+            sender.process_msg_pdu("sender", msg, pdu).unwrap();
+        }
+        sender
+    }
+    /// Wait for the bridge to send an SMS, while running the modem.
+    fn next_submit(&mut self) -> Submitted {
+        let start = Instant::now();
+        loop {
+            if let Some(pdu) = self.modem.next_submit(Duration::from_millis(10)) {
+                return fake_modem::decode_submit(&pdu).expect("invalid SMS-SUBMIT");
+            }
+            assert!(start.elapsed() < Duration::from_secs(20), "timed out");
+            self.core.turn(Some(Duration::from_millis(50)));
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn receive_message() {
+    let mut h = Harness::new("e2e-receive");
+    let addr: PduAddress = "+447700900201".parse().unwrap();
+    h.modem.control("sms +447700900201 hello from the fake modem").unwrap();
+    let sender = h.deliver(&addr, 1);
+    assert_eq!(sender.errors, Vec::<String>::new());
+    assert_eq!(sender.sent, vec![("sender".to_owned(), "hello from the fake modem".to_owned())]);
+    // The modem deletes messages once they're safely stored.
+    h.run_until(|h| h.modem.stored_messages() == 0);
+    assert_eq!(h.store.get_messages_for_recipient(&addr).unwrap().len(), 0);
+}
+
+#[test]
+#[ignore]
+fn receive_multipart_message() {
+    let mut h = Harness::new("e2e-multipart");
+    let addr: PduAddress = "+447700900202".parse().unwrap();
+    let text = "The quick brown fox jumps over the lazy dog. ".repeat(8);
+    let text = text.trim();
+    h.modem.control(&format!("sms +447700900202 {}", text)).unwrap();
+    let sender = h.deliver(&addr, 3);
+    assert_eq!(sender.errors, Vec::<String>::new());
+    let received = sender.sent.iter()
+        .map(|(_, msg)| msg as &str)
+        .collect::<String>();
+    assert_eq!(received, text);
+    assert_eq!(h.store.get_messages_for_recipient(&addr).unwrap().len(), 0);
+}
+
+#[test]
+#[ignore]
+fn send_message() {
+    let mut h = Harness::new("e2e-send");
+    let addr: PduAddress = "+447700900203".parse().unwrap();
+    h.tx.unbounded_send(ModemCommand::SendMessage(addr, "hello from IRC".into())).unwrap();
+    let sub = h.next_submit();
+    assert_eq!(sub.to, "+447700900203");
+    assert_eq!(sub.part, None);
+    assert_eq!(sub.text, "hello from IRC");
+    h.run_until(|h| h.store.get_queued_outgoing_sms("e2e-send", false).unwrap().len() == 0);
+}
+
+#[test]
+#[ignore]
+fn send_multipart_message() {
+    let mut h = Harness::new("e2e-send-multipart");
+    let addr: PduAddress = "+447700900205".parse().unwrap();
+    let text = "Pack my box with five dozen liquor jugs. ".repeat(6);
+    let text = text.trim();
+    h.tx.unbounded_send(ModemCommand::SendMessage(addr, text.into())).unwrap();
+    let parts = (0..2).map(|_| h.next_submit()).collect::<Vec<_>>();
+    let (rf, total, _) = parts[0].part.expect("first part isn't concatenated");
+    assert_eq!(total, 2);
+    let mut received = String::new();
+    for (i, sub) in parts.iter().enumerate() {
+        assert_eq!(sub.to, "+447700900205");
+        assert_eq!(sub.part, Some((rf, 2, i as u8 + 1)));
+        received.push_str(&sub.text);
+    }
+    assert_eq!(received, text);
+    h.run_until(|h| h.store.get_queued_outgoing_sms("e2e-send-multipart", false).unwrap().len() == 0);
+}
+
+#[test]
+#[ignore]
+fn send_failure_is_retried() {
+    let mut h = Harness::new("e2e-retry");
+    let addr: PduAddress = "+447700900204".parse().unwrap();
+    h.modem.control("fail-send").unwrap();
+    h.tx.unbounded_send(ModemCommand::SendMessage(addr, "try, try again".into())).unwrap();
+    // The first attempt fails, so this is the retry.
+    let sub = h.next_submit();
+    assert_eq!(sub.text, "try, try again");
+    h.run_until(|h| h.store.get_queued_outgoing_sms("e2e-retry", false).unwrap().len() == 0);
+}